@@ -0,0 +1,80 @@
+// Compares handing a member's queued messages to their writer one at a time
+// with handing them over in batches, the way `broker::receive_messages` does,
+// over a loopback socket. Run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use chatsapp::config::Config;
+use chatsapp::message::{Message, MessageKind, Protocol};
+use chatsapp::writer::WriterHandle;
+use tokio::io::{self, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// A multiple of every batch size, so each run sends the same amount
+const MESSAGES: usize = 131_072;
+
+const BATCHES: [usize; 4] = [1, 8, 32, 128];
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let msg = Message::new(
+        MessageKind::Chat,
+        // Written as is, without a `[room]` prefix
+        None,
+        Some("alice"),
+        1674000000000,
+        "the quick brown fox jumps over the lazy dog".into(),
+    );
+
+    let mut baseline = None;
+
+    for batch in BATCHES {
+        let elapsed = run(&msg, batch).await?;
+        let rate = MESSAGES as f64 / elapsed.as_secs_f64();
+        let speedup = rate / *baseline.get_or_insert(rate);
+
+        println!(
+            "batch {:>3}: {:>10.0} msgs/s ({:.1}x)",
+            batch, rate, speedup
+        );
+    }
+
+    Ok(())
+}
+
+// Time taken for the other end to read all `MESSAGES`, queueing `batch` at a
+// time for the writer like a member's task does
+async fn run(msg: &Message, batch: usize) -> io::Result<Duration> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let client = TcpStream::connect(listener.local_addr()?).await?;
+    let (server, _) = listener.accept().await?;
+
+    let expected = MESSAGES * msg.render(Protocol::Text).len();
+    let reader = tokio::spawn(drain(client, expected));
+
+    let (_, write_half) = server.into_split();
+    let stream = WriterHandle::spawn(Box::new(write_half), Config::default().max_write_bytes);
+    let msgs = vec![msg.clone(); batch];
+
+    let start = Instant::now();
+    for _ in 0..MESSAGES / batch {
+        stream.write_messages(msgs.clone()).await?;
+    }
+    reader.await??;
+
+    Ok(start.elapsed())
+}
+
+async fn drain(mut stream: TcpStream, expected: usize) -> io::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut read = 0;
+
+    while read < expected {
+        match stream.read(&mut buf).await? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,241 @@
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use redis::{AsyncCommands, FromRedisValue, ToRedisArgs};
+
+use crate::block;
+use crate::canonical::{self, Kind};
+use crate::permissions;
+use crate::pool::Pool;
+use crate::presence;
+use crate::room;
+
+#[derive(Debug)]
+pub enum AccountError {
+    FailedToSave,
+    FailedToFetch,
+    FailedToHash,
+    UsernameTaken,
+    InvalidCredentials,
+}
+
+impl std::fmt::Display for AccountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountError::FailedToSave => writeln!(f, "Error: Failed to save account"),
+            AccountError::FailedToFetch => writeln!(f, "Error: Failed to fetch account"),
+            AccountError::FailedToHash => writeln!(f, "Error: Failed to hash password"),
+            AccountError::UsernameTaken => writeln!(f, "Error: Username taken"),
+            AccountError::InvalidCredentials => writeln!(f, "Error: Invalid username or password"),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+pub async fn register(redis: &Pool, username: &str, password: &str) -> Result<(), AccountError> {
+    let mut conn = redis.get();
+
+    let key = gen_key(username);
+    let hash = hash_password(password).await.map_err(|e| {
+        dbg!("{}", e);
+        AccountError::FailedToHash
+    })?;
+
+    // `Alice` is taken if `alice` is
+    let reserved = canonical::reserve(redis, Kind::User, username)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            AccountError::FailedToSave
+        })?;
+    if !reserved {
+        Err(AccountError::UsernameTaken)?;
+    }
+
+    // HSETNX so two connections can't register the same name at once
+    let created: u8 = conn.hset_nx(key, "password", hash).await.map_err(|e| {
+        dbg!("{}", e);
+        AccountError::FailedToSave
+    })?;
+
+    if created == 0 {
+        Err(AccountError::UsernameTaken)?;
+    }
+
+    Ok(())
+}
+
+pub async fn login(redis: &Pool, username: &str, password: &str) -> Result<(), AccountError> {
+    let mut conn = redis.get();
+
+    let key = gen_key(username);
+
+    let hash: Option<String> = conn.hget(key, "password").await.map_err(|e| {
+        dbg!("{}", e);
+        AccountError::FailedToFetch
+    })?;
+
+    match hash {
+        Some(hash) if verify_password(password, &hash).await => Ok(()),
+        _ => Err(AccountError::InvalidCredentials),
+    }
+}
+
+pub async fn exists(redis: &Pool, username: &str) -> Result<bool, AccountError> {
+    let mut conn = redis.get();
+
+    let key = gen_key(username);
+
+    let exists: u8 = conn.exists(key).await.map_err(|e| {
+        dbg!("{}", e);
+        AccountError::FailedToFetch
+    })?;
+
+    Ok(exists == 1)
+}
+
+// Removes the account and everything kept about its owner outside of rooms:
+// their password, settings and key, who they blocked, what they've read and
+// their global role. The name is free again after.
+pub async fn forget(redis: &Pool, username: &str) -> Result<(), AccountError> {
+    redis::pipe()
+        .atomic()
+        .del(&[
+            gen_key(username),
+            block::gen_key(username),
+            room::gen_last_read_key(username),
+            presence::gen_key(username),
+        ])
+        .ignore()
+        .hdel(permissions::gen_key(None), username)
+        .ignore()
+        .query_async::<_, ()>(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            AccountError::FailedToSave
+        })?;
+
+    canonical::release(redis, Kind::User, username)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            AccountError::FailedToSave
+        })
+}
+
+// Settings like `>set bell on` are saved with the account, so they follow it
+// to new connections. They're kept as `pref:<name>` fields next to the
+// password.
+pub async fn set_preference<T: ToRedisArgs + Send + Sync>(
+    redis: &Pool,
+    username: &str,
+    name: &str,
+    value: T,
+) -> Result<(), AccountError> {
+    redis
+        .get()
+        .hset::<_, _, _, ()>(gen_key(username), gen_pref_field(name), value)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            AccountError::FailedToSave
+        })
+}
+
+// None if it's never been set
+pub async fn preference<T: FromRedisValue>(
+    redis: &Pool,
+    username: &str,
+    name: &str,
+) -> Result<Option<T>, AccountError> {
+    redis
+        .get()
+        .hget(gen_key(username), gen_pref_field(name))
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            AccountError::FailedToFetch
+        })
+}
+
+// Back to not being set
+pub async fn clear_preference(
+    redis: &Pool,
+    username: &str,
+    name: &str,
+) -> Result<(), AccountError> {
+    redis
+        .get()
+        .hdel::<_, _, ()>(gen_key(username), gen_pref_field(name))
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            AccountError::FailedToSave
+        })
+}
+
+// One preference for each of `usernames`, in the same order, in one round
+// trip. Guests have none.
+pub async fn preferences<T: FromRedisValue>(
+    redis: &Pool,
+    usernames: &[String],
+    name: &str,
+) -> Result<Vec<Option<T>>, AccountError> {
+    if usernames.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut conn = redis.get();
+    let mut pipe = redis::pipe();
+    for username in usernames {
+        pipe.hget(gen_key(username), gen_pref_field(name));
+    }
+
+    pipe.query_async(&mut conn).await.map_err(|e| {
+        dbg!("{}", e);
+        AccountError::FailedToFetch
+    })
+}
+
+fn gen_pref_field(name: &str) -> String {
+    format!("pref:{}", name)
+}
+
+pub(crate) fn gen_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+// Argon2 is slow on purpose, so hashing and verifying run on the blocking
+// pool rather than holding up other connections
+pub(crate) async fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let password = password.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+
+        Ok(hash.to_string())
+    })
+    .await
+    .unwrap_or_else(|e| {
+        eprintln!("Hashing failed: {}", e);
+        Err(argon2::password_hash::Error::Crypto)
+    })
+}
+
+pub(crate) async fn verify_password(password: &str, hash: &str) -> bool {
+    let (password, hash) = (password.to_owned(), hash.to_owned());
+
+    tokio::task::spawn_blocking(move || match PasswordHash::new(&hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    })
+    .await
+    .unwrap_or_else(|e| {
+        eprintln!("Verifying failed: {}", e);
+        false
+    })
+}
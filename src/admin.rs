@@ -0,0 +1,306 @@
+use std::fmt::Write;
+
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::announce;
+use crate::archive::{self, Format};
+use crate::audit;
+use crate::broker::{self, RoomMap};
+use crate::command::parse_duration;
+use crate::connections::{self, ConnectionMap};
+use crate::message::{Message, MessageKind};
+use crate::permissions::{self, Role};
+use crate::pool::Pool;
+use crate::reload::Reloader;
+use crate::report;
+use crate::room::get_time_in_ms;
+
+// Entries shown by `audit`
+const AUDIT_COUNT: usize = 50;
+
+const HELP: &str = "\
+connections       - List open connections
+rooms             - List running brokers with their queue depth and members
+disconnect user   - Close every connection logged in as user
+announce text     - Send a message to everyone, including people who connect soon after
+schedule in text  - Announce text after a delay like 90, 30m, 12h or 1d
+announcements     - List sent and scheduled announcements
+cancel id         - Remove an announcement
+audit user|room   - Show recent commands and connections for a user or room
+role user [role]  - Show or set someone's role in every room (admin, moderator, member, guest)
+archive room      - Write a room's history to archive_dir as JSON lines and delete it, add text for plain text
+reports           - List open reports with the messages around them
+resolve id        - Close a report once it's dealt with
+reload            - Reread the config, applying rate limits, the MOTD, retention, the wordlist, spam muting and proof of work
+help              - Display commands
+quit              - Close the console
+";
+
+// Serves the admin console on `addr`. It's plain text, one command per line,
+// and only answers connections from this machine.
+pub async fn serve(
+    addr: String,
+    conns: ConnectionMap,
+    rooms: RoomMap,
+    redis: Pool,
+    archive_dir: String,
+    reloader: Reloader,
+) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to start admin console on {}: {}", addr, e);
+            return;
+        }
+    };
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+
+        // Binding to a public address by mistake shouldn't expose this
+        if !peer.ip().is_loopback() {
+            eprintln!("Refusing admin connection from {}", peer);
+            continue;
+        }
+
+        let conns = conns.clone();
+        let rooms = rooms.clone();
+        let redis = redis.clone();
+        let archive_dir = archive_dir.clone();
+        let reloader = reloader.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) =
+                handle_console(stream, &conns, &rooms, &redis, &archive_dir, &reloader).await
+            {
+                eprintln!("{}", e);
+            }
+        });
+    }
+}
+
+async fn handle_console(
+    stream: TcpStream,
+    conns: &ConnectionMap,
+    rooms: &RoomMap,
+    redis: &Pool,
+    archive_dir: &str,
+    reloader: &Reloader,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    writer
+        .write_all(b"chatsapp admin, type help for commands\n")
+        .await?;
+
+    while let Some(line) = lines.next_line().await? {
+        let (command, arg) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+
+        let out = match (command, arg) {
+            ("connections", "") => list_connections(conns).await,
+            ("rooms", "") => list_rooms(rooms).await,
+            ("disconnect", user) if !user.is_empty() => {
+                let reason = "You were disconnected by an admin\n";
+                let count = connections::disconnect(conns, user, reason).await;
+
+                format!("Disconnected {} connection(s)\n", count)
+            }
+            ("announce", text) if !text.is_empty() => {
+                match announce::schedule(redis, text, get_time_in_ms()).await {
+                    Ok(id) => format!("Announced {}\n", id),
+                    Err(e) => e.to_string(),
+                }
+            }
+            ("schedule", rest) => match schedule(redis, rest).await {
+                Some(out) => out,
+                None => "Usage: schedule in text\n".to_owned(),
+            },
+            ("announcements", "") => list_announcements(redis).await,
+            ("cancel", id) if !id.is_empty() => match announce::cancel(redis, id).await {
+                Ok(()) => "Cancelled\n".to_owned(),
+                Err(e) => e.to_string(),
+            },
+            ("audit", subject) if !subject.is_empty() => {
+                match audit::query(redis, subject, AUDIT_COUNT).await {
+                    Ok(entries) if entries.is_empty() => "No entries\n".to_owned(),
+                    Ok(entries) => entries.join("\n") + "\n",
+                    Err(e) => e.to_string(),
+                }
+            }
+            ("role", rest) if !rest.is_empty() => role(redis, rest).await,
+            ("archive", rest) => match archive(redis, rooms, archive_dir, rest).await {
+                Some(out) => out,
+                None => "Usage: archive room [json|text]\n".to_owned(),
+            },
+            ("reports", "") => list_reports(redis).await,
+            ("resolve", id) if !id.is_empty() => match report::resolve(redis, id).await {
+                Ok(report) => format!(
+                    "Resolved {}'s report of {}\n",
+                    report.reporter, report.target
+                ),
+                Err(e) => e.to_string(),
+            },
+            ("reload", "") => match reloader.reload() {
+                Ok(()) => "Reloaded config\n".to_owned(),
+                Err(e) => e.to_string(),
+            },
+            ("help", "") => HELP.to_owned(),
+            ("quit", "") => break,
+            ("", "") => continue,
+            _ => "Invalid command, type help for commands\n".to_owned(),
+        };
+
+        writer.write_all(out.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+async fn list_connections(conns: &ConnectionMap) -> String {
+    let conns = conns.read().await;
+
+    let mut ids: Vec<_> = conns.keys().copied().collect();
+    ids.sort();
+
+    let mut out = String::new();
+    for id in ids {
+        let conn = &conns[&id];
+        let username = conn.username.as_deref().unwrap_or("-");
+
+        writeln!(out, "{} {} {}", id, conn.addr, username).unwrap();
+    }
+    writeln!(out, "{} connection(s)", conns.len()).unwrap();
+
+    out
+}
+
+async fn list_rooms(rooms: &RoomMap) -> String {
+    let mut rooms = broker::snapshot(rooms).await;
+    rooms.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut out = String::new();
+    for (room, tx) in &rooms {
+        let depth = tx.max_capacity() - tx.capacity();
+
+        let members = match broker::who(tx, broker::WHO_TIMEOUT).await {
+            Some(members) => members.join(", "),
+            None => "(busy)".to_owned(),
+        };
+
+        writeln!(out, "{} queue={} members: {}", room, depth, members).unwrap();
+    }
+    writeln!(out, "{} room(s)", rooms.len()).unwrap();
+
+    out
+}
+
+async fn schedule(redis: &Pool, rest: &str) -> Option<String> {
+    let (delay, text) = rest.split_once(' ')?;
+    let at = get_time_in_ms() + parse_duration(delay)? as isize * 1000;
+
+    Some(match announce::schedule(redis, text, at).await {
+        Ok(id) => format!("Scheduled {}\n", id),
+        Err(e) => e.to_string(),
+    })
+}
+
+// Writes the room to disk, then deletes it and sends everyone in it away
+async fn archive(redis: &Pool, rooms: &RoomMap, dir: &str, rest: &str) -> Option<String> {
+    let (room, format) = rest.split_once(' ').unwrap_or((rest, "json"));
+    let format: Format = format.parse().ok()?;
+
+    if room.is_empty() {
+        return None;
+    }
+
+    let (path, count) = match archive::to_disk(redis, room, dir, format).await {
+        Ok(archived) => archived,
+        Err(e) => return Some(e.to_string()),
+    };
+
+    let msg = Message::new(
+        MessageKind::System,
+        Some(room),
+        None,
+        get_time_in_ms(),
+        "The room was archived by an admin\n".to_owned(),
+    );
+    broker::close(room, rooms, msg).await;
+
+    Some(format!(
+        "Archived {} messages to {}\n",
+        count,
+        path.display()
+    ))
+}
+
+// This is how the first global admins are made
+async fn role(redis: &Pool, rest: &str) -> String {
+    let (user, role) = match rest.split_once(' ') {
+        Some((user, role)) => (user, Some(role)),
+        None => (rest, None),
+    };
+
+    let role = match role.map(str::parse::<Role>) {
+        Some(Ok(role)) => role,
+        Some(Err(e)) => return e.to_string(),
+        None => {
+            return match permissions::role(redis, None, user, true).await {
+                Ok(role) => format!("{} is a {}\n", user, role),
+                Err(e) => e.to_string(),
+            }
+        }
+    };
+
+    match permissions::grant(redis, None, user, role).await {
+        Ok(()) => format!("{} is a {} now\n", user, role),
+        Err(e) => e.to_string(),
+    }
+}
+
+async fn list_reports(redis: &Pool) -> String {
+    let reports = match report::open(redis).await {
+        Ok(reports) => reports,
+        Err(e) => return e.to_string(),
+    };
+
+    let mut out: String = reports.iter().map(report::Report::describe).collect();
+    writeln!(out, "{} open report(s)", reports.len()).unwrap();
+
+    out
+}
+
+async fn list_announcements(redis: &Pool) -> String {
+    let announcements = match announce::all(redis).await {
+        Ok(announcements) => announcements,
+        Err(e) => return e.to_string(),
+    };
+
+    let now = get_time_in_ms();
+    let mut out = String::new();
+    for announcement in &announcements {
+        let status = match announcement.at <= now {
+            true => "sent",
+            false => "scheduled",
+        };
+        let time = Message::format_time(announcement.at, 0);
+
+        writeln!(
+            out,
+            "{} {} {} UTC: {}",
+            announcement.id, status, time, announcement.text
+        )
+        .unwrap();
+    }
+    writeln!(out, "{} announcement(s)", announcements.len()).unwrap();
+
+    out
+}
@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::connections::ConnectionMap;
+use crate::id;
+use crate::message::Message;
+use crate::pool::Pool;
+use crate::room::get_time_in_ms;
+
+// Sorted set of announcements, scored by when they go out
+const KEY: &str = "announcements";
+
+// How often the scheduler looks for announcements that are due
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum AnnounceError {
+    FailedToSave,
+    FailedToFetch,
+    NotFound,
+}
+
+impl std::fmt::Display for AnnounceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnnounceError::FailedToSave => writeln!(f, "Error: Failed to save announcement"),
+            AnnounceError::FailedToFetch => writeln!(f, "Error: Failed to fetch announcements"),
+            AnnounceError::NotFound => writeln!(f, "Error: No announcement with that id"),
+        }
+    }
+}
+
+impl std::error::Error for AnnounceError {}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Announcement {
+    pub id: String,
+    pub text: String,
+    // When it goes out, in ms
+    #[serde(skip)]
+    pub at: isize,
+}
+
+impl Announcement {
+    pub fn to_message(&self) -> Message {
+        Message::system(&format!("Announcement: {}\n", self.text))
+    }
+}
+
+// Saves an announcement to go out at `at`, which can be now. Returns its id.
+pub async fn schedule(redis: &Pool, text: &str, at: isize) -> Result<String, AnnounceError> {
+    let mut conn = redis.get();
+
+    let announcement = Announcement {
+        id: id::gen(at),
+        text: text.to_owned(),
+        at,
+    };
+    let member = serde_json::to_string(&announcement).unwrap();
+
+    conn.zadd::<_, _, _, ()>(KEY, member, at)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            AnnounceError::FailedToSave
+        })?;
+
+    Ok(announcement.id)
+}
+
+pub async fn cancel(redis: &Pool, id: &str) -> Result<(), AnnounceError> {
+    let announcement = list(redis, "-inf", "+inf")
+        .await?
+        .into_iter()
+        .find(|a| a.id == id)
+        .ok_or(AnnounceError::NotFound)?;
+
+    let member = serde_json::to_string(&announcement).unwrap();
+    redis
+        .get()
+        .zrem::<_, _, ()>(KEY, member)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            AnnounceError::FailedToSave
+        })?;
+
+    Ok(())
+}
+
+// Announcements that went out within the last `window`, for people who
+// weren't connected at the time
+pub async fn recent(redis: &Pool, window: Duration) -> Result<Vec<Announcement>, AnnounceError> {
+    let now = get_time_in_ms();
+    let since = now - window.as_millis() as isize;
+
+    list(redis, since, now).await
+}
+
+// Everything still kept, sent or not, oldest first
+pub async fn all(redis: &Pool) -> Result<Vec<Announcement>, AnnounceError> {
+    list(redis, "-inf", "+inf").await
+}
+
+async fn list<M, N>(redis: &Pool, min: M, max: N) -> Result<Vec<Announcement>, AnnounceError>
+where
+    M: redis::ToRedisArgs + Send + Sync,
+    N: redis::ToRedisArgs + Send + Sync,
+{
+    let mut conn = redis.get();
+
+    let members: Vec<(String, isize)> = conn
+        .zrangebyscore_withscores(KEY, min, max)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            AnnounceError::FailedToFetch
+        })?;
+
+    Ok(members
+        .into_iter()
+        .filter_map(|(member, at)| {
+            let announcement: Announcement = serde_json::from_str(&member).ok()?;
+            Some(Announcement { at, ..announcement })
+        })
+        .collect())
+}
+
+// Writes each announcement to every connection on this server as it comes
+// due, whether they're in a room or not. Every server runs one, so it only
+// looks at what's come due since it started. Announcements older than
+// `window` are removed.
+pub fn spawn_scheduler(redis: Pool, conns: ConnectionMap, window: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(TICK);
+        let mut last = get_time_in_ms();
+
+        loop {
+            ticker.tick().await;
+
+            let now = get_time_in_ms();
+            let due = match list(&redis, format!("({}", last), now).await {
+                Ok(due) => due,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    continue;
+                }
+            };
+            last = now;
+
+            for announcement in due {
+                deliver(&conns, &announcement.to_message()).await;
+            }
+
+            let expired = format!("({}", now - window.as_millis() as isize);
+            if let Err(e) = redis
+                .get()
+                .zrembyscore::<_, _, _, ()>(KEY, "-inf", expired)
+                .await
+            {
+                eprintln!("{}", e);
+            }
+        }
+    });
+}
+
+// Returns how many connections it was written to
+pub async fn deliver(conns: &ConnectionMap, msg: &Message) -> usize {
+    let streams: Vec<_> = conns
+        .read()
+        .await
+        .values()
+        .map(|conn| conn.stream.clone())
+        .collect();
+
+    let mut count = 0;
+    for stream in streams {
+        match stream.write_message(msg.clone()).await {
+            Ok(()) => count += 1,
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    count
+}
@@ -0,0 +1,380 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::account::{self, AccountError};
+use crate::broker::{self, BrokerEvent, RoomMap};
+use crate::canonical::{self, Kind};
+use crate::config::Config;
+use crate::filter::Filters;
+use crate::http::{self, Request, Response};
+use crate::permissions::{self, Action, PermissionError};
+use crate::pool::Pool;
+use crate::ratelimit::{Failures, RateLimit};
+use crate::receipts::Receipts;
+use crate::room::{self, Kinds, RoomError, RoomEvent};
+use crate::validate;
+use crate::webhook;
+
+// Most messages one GET can ask for
+const MAX_LIMIT: usize = 100;
+
+// Wrong passwords allowed per address and per user, one more every minute
+pub const LOGIN_FAILURES: RateLimit = RateLimit {
+    capacity: 5.0,
+    refill_per_sec: 1.0 / 60.0,
+    max_warnings: 0,
+};
+
+// Handles every request needs, cloned into each connection
+#[derive(Clone)]
+pub struct Context {
+    pub redis: Pool,
+    pub rooms: RoomMap,
+    pub filters: Arc<Filters>,
+    pub config: Arc<Config>,
+    pub failed_logins: Arc<Failures>,
+}
+
+#[derive(Serialize)]
+struct RoomInfo<'a> {
+    name: &'a str,
+    topic: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Post {
+    body: String,
+}
+
+// Serves the HTTP API on `addr`, see the README for the routes
+pub async fn serve(addr: String, ctx: Context) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to start API on {}: {}", addr, e);
+            return;
+        }
+    };
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+
+        let ctx = ctx.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, peer.ip(), &ctx).await {
+                eprintln!("{}", e);
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    mut stream: TcpStream,
+    peer: IpAddr,
+    ctx: &Context,
+) -> tokio::io::Result<()> {
+    let response = match http::read_request(&mut stream).await {
+        Ok(Some(request)) => route(&request, peer, ctx).await,
+        Ok(None) => return Ok(()),
+        Err(e) => Response::error(400, &e.to_string()),
+    };
+
+    http::write_response(&mut stream, &response).await
+}
+
+async fn route(req: &Request, peer: IpAddr, ctx: &Context) -> Response {
+    match (req.method.as_str(), req.segments().as_slice()) {
+        ("GET", ["rooms"]) => list_rooms(ctx).await,
+        // Rooms in a namespace take up several segments, like /rooms/dev/rust/messages
+        (method, ["rooms", room @ .., action @ ("messages" | "bot")]) if !room.is_empty() => {
+            // /rooms/RUST/messages is `rust`'s, like >join-room RUST is
+            let room = match canonical::resolve(&ctx.redis, Kind::Room, &room.join("/")).await {
+                Ok(room) => room,
+                Err(e) => return Response::error(500, &e.to_string()),
+            };
+
+            match (method, *action) {
+                ("GET", "messages") => get_messages(req, peer, ctx, &room).await,
+                ("POST", "messages") => post_message(req, peer, ctx, &room).await,
+                ("POST", "bot") => post_bot_message(req, ctx, &room).await,
+                _ => Response::error(405, "Method not allowed"),
+            }
+        }
+        (_, ["rooms"]) => Response::error(405, "Method not allowed"),
+        _ => Response::error(404, "Not found"),
+    }
+}
+
+async fn list_rooms(ctx: &Context) -> Response {
+    let rooms = match room::list(&ctx.redis).await {
+        Ok(rooms) => rooms,
+        Err(e) => return room_error(e),
+    };
+
+    let names: Vec<&str> = rooms.iter().map(|r| &r[5..]).collect();
+    let topics = match room::topics(&ctx.redis, &names).await {
+        Ok(topics) => topics,
+        Err(e) => return room_error(e),
+    };
+
+    let rooms: Vec<RoomInfo> = names
+        .into_iter()
+        .zip(topics)
+        .map(|(name, topic)| RoomInfo { name, topic })
+        .collect();
+
+    Response::json(200, &rooms)
+}
+
+// ?limit=n&before=ts&types=chat pages back like >history
+async fn get_messages(req: &Request, peer: IpAddr, ctx: &Context, room: &str) -> Response {
+    let limit = match req.query.get("limit").map(|n| n.parse::<usize>()) {
+        Some(Ok(n)) if (1..=MAX_LIMIT).contains(&n) => n,
+        Some(_) => return Response::error(400, &format!("limit must be 1 to {}", MAX_LIMIT)),
+        None => ctx.config.history_size,
+    };
+    let before = match req.query.get("before").map(|ts| ts.parse::<isize>()) {
+        Some(Ok(ts)) => Some(ts),
+        Some(Err(_)) => return Response::error(400, "before must be a timestamp"),
+        None => None,
+    };
+    let kinds = match req.query.get("types").map(|types| types.parse::<Kinds>()) {
+        Some(Ok(kinds)) => kinds,
+        Some(Err(_)) => return Response::error(400, "types must be like chat,topic or -join"),
+        None => Kinds::All,
+    };
+
+    // Anyone can read an open room, like anyone can join one
+    let user = match req.basic_auth() {
+        Some((username, password)) => match login(ctx, peer, &username, &password).await {
+            Ok(()) => Some(username),
+            Err(response) => return response,
+        },
+        None => None,
+    };
+
+    if let Err(response) = check_access(req, ctx, room, user.as_deref()).await {
+        return response;
+    }
+
+    match room::history(&ctx.redis, room, limit, before, &kinds).await {
+        Ok(msgs) => Response::json(200, &msgs),
+        Err(e) => room_error(e),
+    }
+}
+
+// Body is `{"body": "text"}`, sent as the user in the Authorization header
+async fn post_message(req: &Request, peer: IpAddr, ctx: &Context, room: &str) -> Response {
+    let (username, password) = match req.basic_auth() {
+        Some(credentials) => credentials,
+        None => return Response::error(401, "Posting needs an account, use Basic auth"),
+    };
+
+    if let Err(response) = login(ctx, peer, &username, &password).await {
+        return response;
+    }
+
+    let post = match parse_post(req) {
+        Ok(post) => post,
+        Err(response) => return response,
+    };
+
+    if let Err(response) = check_access(req, ctx, room, Some(&username)).await {
+        return response;
+    }
+
+    send(ctx, room, &username, post).await
+}
+
+// Same body as posting a message, sent as the bot whose token is in an
+// `Authorization: Bearer` header. The token is all the access a bot needs.
+async fn post_bot_message(req: &Request, ctx: &Context, room: &str) -> Response {
+    let token = match req.bearer() {
+        Some(token) => token,
+        None => return Response::error(401, "Bots need a token, use Bearer auth"),
+    };
+
+    let name = match webhook::bot(&ctx.redis, room, token).await {
+        Ok(Some(name)) => name,
+        Ok(None) => return Response::error(401, "Invalid token"),
+        Err(e) => return Response::error(500, &e.to_string()),
+    };
+
+    let post = match parse_post(req) {
+        Ok(post) => post,
+        Err(response) => return response,
+    };
+
+    send(ctx, room, &webhook::bot_username(&name), post).await
+}
+
+// Basic auth is checked on every request, so failures are limited per
+// address and per user before the password is looked at. Otherwise the API
+// would be a way to guess passwords as fast as Argon2 allows.
+async fn login(
+    ctx: &Context,
+    peer: IpAddr,
+    username: &str,
+    password: &str,
+) -> Result<(), Response> {
+    let keys = [format!("ip:{}", peer), format!("user:{}", username)];
+    if !keys.iter().all(|key| ctx.failed_logins.allows(key)) {
+        return Err(Response::error(
+            429,
+            "Too many failed logins, try again later",
+        ));
+    }
+
+    match account::login(&ctx.redis, username, password).await {
+        Ok(()) => Ok(()),
+        Err(e @ AccountError::InvalidCredentials) => {
+            for key in &keys {
+                ctx.failed_logins.record(key);
+            }
+            Err(Response::error(401, &e.to_string()))
+        }
+        Err(e) => Err(Response::error(500, &e.to_string())),
+    }
+}
+
+fn parse_post(req: &Request) -> Result<Post, Response> {
+    let post: Post =
+        serde_json::from_str(&req.body).map_err(|e| Response::error(400, &e.to_string()))?;
+
+    validate::message(&post.body).map_err(|e| Response::error(400, &e.to_string()))?;
+
+    Ok(post)
+}
+
+// Persists the post and hands it to the room's broker
+async fn send(ctx: &Context, room: &str, username: &str, post: Post) -> Response {
+    let encrypted = match room::is_encrypted(&ctx.redis, room).await {
+        Ok(encrypted) => encrypted,
+        Err(e) => return room_error(e),
+    };
+
+    // Filters can't read ciphertext, so encrypted rooms only check that's
+    // what it is
+    let body = match encrypted {
+        true => match validate::ciphertext(&post.body) {
+            Ok(()) => post.body,
+            Err(e) => return Response::error(400, &e.to_string()),
+        },
+        false => match ctx.filters.run(username, room, post.body).await {
+            Ok(body) => match room::lang(&ctx.redis, room).await {
+                Ok(lang) => ctx.filters.transform(lang.as_deref(), room, body),
+                Err(e) => return room_error(e),
+            },
+            Err(reason) => return Response::error(400, &reason),
+        },
+    };
+
+    if let Err(e) = room::check_muted(&ctx.redis, room, username).await {
+        return room_error(e);
+    }
+
+    // Bots aren't accounts, so only people can be kept from posting
+    if let Err(e) = permissions::check(&ctx.redis, Some(room), username, true, Action::Send).await {
+        return match e {
+            PermissionError::NotAllowed(_) => Response::error(403, &e.to_string()),
+            _ => Response::error(500, &e.to_string()),
+        };
+    }
+
+    let fanout = ctx.config.pubsub.then(|| ctx.redis.clone());
+    let receipts = Receipts::new(ctx.redis.clone(), ctx.config.receipts_max_members);
+    let tx = match broker::get_or_spawn(
+        &ctx.redis,
+        room,
+        &ctx.rooms,
+        fanout,
+        receipts,
+        ctx.config.broker_shards,
+    )
+    .await
+    {
+        Ok(Some(tx)) => tx,
+        Ok(None) => return Response::error(404, "Room not found"),
+        Err(e) => return room_error(e),
+    };
+
+    let msg = match room::event(&ctx.redis, RoomEvent::Chat(body), room, username, true).await {
+        Ok(msg) => msg,
+        Err(e) => return room_error(e),
+    };
+
+    let event = BrokerEvent::Post { msg: msg.clone() };
+    if let Err(e) = tx.send(event).await {
+        return Response::error(500, &e.to_string());
+    }
+
+    Response::json(201, &msg)
+}
+
+// The same checks as joining: bans, invites and passwords. Room passwords
+// go in an `X-Room-Password` header.
+async fn check_access(
+    req: &Request,
+    ctx: &Context,
+    room: &str,
+    user: Option<&str>,
+) -> Result<(), Response> {
+    match room::exists(&ctx.redis, room).await {
+        Ok(true) => {}
+        Ok(false) => return Err(Response::error(404, "Room not found")),
+        Err(e) => return Err(room_error(e)),
+    }
+
+    if let Some(user) = user {
+        room::check_banned(&ctx.redis, room, user)
+            .await
+            .map_err(room_error)?;
+    }
+
+    // Only members of a private room can see into it
+    room::check_invited(&ctx.redis, room, user.unwrap_or_default(), None)
+        .await
+        .map_err(room_error)?;
+
+    room::check_password(&ctx.redis, room, req.header("x-room-password"))
+        .await
+        .map_err(room_error)?;
+
+    Ok(())
+}
+
+fn room_error(e: RoomError) -> Response {
+    let status = match e {
+        RoomError::FailedToSend | RoomError::FailedToFetch | RoomError::FailedToCheckRoomExists => {
+            500
+        }
+        RoomError::NotAuthenticated => 401,
+        RoomError::IncorrectPassword
+        | RoomError::Banned
+        | RoomError::NotInvited
+        | RoomError::NotAuthor
+        | RoomError::NotOwner
+        | RoomError::Muted(_) => 403,
+        RoomError::MessageNotFound
+        | RoomError::NotMuted
+        | RoomError::NoOffer
+        | RoomError::NoRequest => 404,
+        RoomError::RoomNameTaken
+        | RoomError::TooManyRooms
+        | RoomError::TooManyOwned(_)
+        | RoomError::NotInviteOnly
+        | RoomError::Signed => 400,
+    };
+
+    Response::error(status, &e.to_string())
+}
@@ -1,183 +1,3430 @@
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use redis::Client as RedisClient;
-use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
-use tokio::net::tcp::OwnedReadHalf;
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
-
-use crate::broker::{self, BrokerEvent, RoomMap, SharedStream};
-use crate::command::Command;
-use crate::room::{self, RoomEvent};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
+
+use crate::account;
+use crate::announce;
+use crate::archive::{Export, Format};
+use crate::audit::{self, AuditEvent};
+use crate::block::{self, Blocklist};
+use crate::bots;
+use crate::broker::{self, BrokerEvent, Quiet, RoomChange, RoomMap};
+use crate::canonical::{self, Kind};
+use crate::code::{Coded, ErrorCode};
+use crate::command::{self, Command};
+use crate::config::{Config, LiveConfig};
+use crate::connections::{self, Connection, ConnectionMap};
+use crate::dm::{self, DmError, UserMap};
+use crate::filter::Filters;
+use crate::hello::{Capability, Hello};
+use crate::message::{Envelope, Message, MessageKind, Protocol};
+use crate::metrics::Metrics;
+use crate::motd;
+use crate::names;
+use crate::namespace::{self, NamespaceCommand};
+use crate::paste;
+use crate::permissions::{self, Action, PermissionError, Role};
+use crate::plugin::{Plugins, Response};
+use crate::pool::Pool;
+use crate::pow::Challenge;
+use crate::presence;
+use crate::ratelimit::{TokenBucket, Verdict};
+use crate::receipts::{self, Receipts};
+use crate::report;
+use crate::retention::{self, Limit, Setting};
+use crate::room::{self, Kinds, Options, RoomError, RoomEvent};
+use crate::search;
+use crate::session::{self, Session};
+use crate::signing;
+use crate::spam::{self, Detector};
+use crate::stats;
+use crate::status::Status;
+use crate::validate;
+use crate::webhook::{self, WebhookCommand};
+use crate::writer::{WriteStream, WriterHandle};
+
+// Most messages a single >history can ask for
+const MAX_HISTORY: usize = 100;
+
+// Most missed messages >resume replays per room
+const MAX_REPLAY: usize = 500;
+
+// Most active users a >digest names
+const DIGEST_AUTHORS: usize = 5;
+
+// Rooms on each page of >list
+const LIST_PAGE_SIZE: usize = 50;
+
+// How long >ping waits on the room's broker before giving up on it
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct User {
     addr: String,
     username: Option<String>,
+    authenticated: bool,
+    // Identifies this connection in the name registry
+    owner: String,
+    // Whether `username` is held in the name registry by this connection
+    claimed: bool,
+    // Token for picking this session up again with >resume
+    session: String,
 }
 
-enum State {
-    Inside {
-        room: String,
-        tx: Sender<BrokerEvent>,
-    },
-    Outside,
+// What's written to a connection as it enters a room
+#[derive(Clone, Copy)]
+enum Replay {
+    // The latest messages, up to this many
+    Recent(usize),
+    // Messages sent after this time, when picking a session back up
+    Since(isize),
+}
+
+#[derive(Default)]
+struct Paste {
+    content: String,
+    // Past MAX_SIZE, so lines are dropped until the sentinel
+    too_large: bool,
+}
+
+#[derive(Default)]
+struct State {
+    // <Room, Sender for the room's broker>
+    joined: HashMap<String, Sender<BrokerEvent>>,
+    // Room that plain messages get sent to
+    active: Option<String>,
+    // Joined rooms that only take ciphertext
+    encrypted: HashSet<String>,
+    // What's been sent to each joined room lately. Locked since messages are
+    // sent through `&self`, but never held across an await.
+    spam: std::sync::Mutex<HashMap<String, Detector>>,
+}
+
+impl State {
+    fn active(&self) -> Option<(&str, &Sender<BrokerEvent>)> {
+        let room = self.active.as_ref()?;
+        self.joined.get(room).map(|tx| (room.as_str(), tx))
+    }
+
+    fn remove(&mut self, room: &str) -> Option<Sender<BrokerEvent>> {
+        let tx = self.joined.remove(room);
+        self.encrypted.remove(room);
+        self.spam.get_mut().unwrap().remove(room);
+
+        // Fall back to any other room they're still in
+        if self.active.as_deref() == Some(room) {
+            self.active = self.joined.keys().next().cloned();
+        }
+
+        tx
+    }
+
+    fn rename(&mut self, room: &str, to: String) {
+        if let Some(tx) = self.joined.remove(room) {
+            self.joined.insert(to.clone(), tx);
+        }
+        if self.encrypted.remove(room) {
+            self.encrypted.insert(to.clone());
+        }
+
+        let spam = self.spam.get_mut().unwrap();
+        if let Some(detector) = spam.remove(room) {
+            spam.insert(to.clone(), detector);
+        }
+
+        if self.active.as_deref() == Some(room) {
+            self.active = Some(to);
+        }
+    }
+}
+
+// Handles shared by every connection, cloned into each App
+#[derive(Clone)]
+pub struct Shared {
+    pub redis: Pool,
+    pub users: UserMap,
+    pub filters: Arc<Filters>,
+    pub config: Arc<LiveConfig>,
+    pub metrics: Arc<Metrics>,
+    pub conns: ConnectionMap,
+    pub plugins: Plugins,
+}
+
+// Where a connection's commands come from, see `WriteStream`
+pub type ReadStream = Box<dyn AsyncRead + Send + Sync + Unpin>;
+
+// Splits what a client sends into lines. Bytes that aren't UTF-8 become
+// U+FFFD instead of ending the connection like `Lines` would.
+struct LineReader {
+    reader: BufReader<ReadStream>,
+    // Kept between calls, so a line read partly before another branch of a
+    // `select!` won isn't lost
+    buf: Vec<u8>,
+}
+
+impl LineReader {
+    fn new(reader: ReadStream) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            buf: Vec::new(),
+        }
+    }
+
+    // None once the client hangs up
+    async fn next_line(&mut self) -> io::Result<Option<String>> {
+        if self.reader.read_until(b'\n', &mut self.buf).await? == 0 && self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let mut line = self.buf.as_slice();
+        line = line.strip_suffix(b"\n").unwrap_or(line);
+        line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        let line = String::from_utf8_lossy(line).into_owned();
+        self.buf.clear();
+
+        Ok(Some(line))
+    }
 }
 
 pub struct App {
-    redis: Arc<RedisClient>,
-    stream: SharedStream,
-    lines: Lines<BufReader<OwnedReadHalf>>,
+    redis: Pool,
+    users: UserMap,
+    filters: Arc<Filters>,
+    config: Arc<LiveConfig>,
+    metrics: Arc<Metrics>,
+    conns: ConnectionMap,
+    plugins: Plugins,
+    // This connection's key in `conns`
+    id: u64,
+    stream: WriterHandle,
+    // What the writer has been told, so reading a line doesn't have to ask it
+    protocol: Protocol,
+    ids: bool,
+    compressing: bool,
+    lines: LineReader,
     user: User,
     state: State,
+    bucket: TokenBucket,
+    // Set between >paste and the sentinel
+    paste: Option<Paste>,
+    // From the `Envelope` of the line being handled, added to every reply
+    reference: Option<String>,
+    // Loaded when logging in, and given to every room joined
+    blocked: Blocklist,
+    // Also loaded when logging in, for >set quiet
+    quiet: Quiet,
+    // Messages replayed on joining a room, `history_size` until they
+    // >set history
+    history: usize,
+    // And which kinds, all until they >set types
+    history_types: Kinds,
+    // Set with >status and loaded when logging in, since only accounts
+    // have one
+    status: Option<Status>,
+    // Brokers say here when they remove this user, or their room is renamed
+    changes_tx: Sender<RoomChange>,
+    changes: Receiver<RoomChange>,
+    // The admin console sends a reason here to hang up on this user
+    disconnect_tx: Sender<String>,
+    disconnect: Receiver<String>,
+    // The proof of work they were greeted with, until they answer it. None
+    // when `pow_difficulty` is 0.
+    challenge: Option<Challenge>,
 }
 
 impl App {
-    pub fn new(stream: TcpStream, addr: SocketAddr, redis: Arc<RedisClient>) -> Self {
+    pub fn new(stream: TcpStream, addr: SocketAddr, shared: Shared) -> Self {
         let (reader, writer) = stream.into_split();
-        let lines = BufReader::new(reader).lines();
-        let stream = Arc::new(Mutex::new(writer));
+
+        Self::from_halves(Box::new(reader), Box::new(writer), addr, shared)
+    }
+
+    // For connections that aren't sockets, like the ones `testing` makes
+    pub fn from_halves(
+        reader: ReadStream,
+        writer: WriteStream,
+        addr: SocketAddr,
+        shared: Shared,
+    ) -> Self {
+        let Shared {
+            redis,
+            users,
+            filters,
+            config,
+            metrics,
+            conns,
+            plugins,
+        } = shared;
+
+        let lines = LineReader::new(reader);
+        let stream = WriterHandle::spawn(writer, config.get().max_write_bytes);
+        let (changes_tx, changes) = mpsc::channel(10);
+        let (disconnect_tx, disconnect) = mpsc::channel(1);
+        let bucket = TokenBucket::new(config.get().rate_limit);
+        let history = config.get().history_size;
+        let difficulty = config.get().pow_difficulty;
+        let challenge = (difficulty > 0).then(|| Challenge::new(difficulty));
 
         Self {
             redis,
+            users,
+            filters,
+            config,
+            metrics,
+            conns,
+            plugins,
+            id: connections::next_id(),
             stream,
+            protocol: Protocol::Text,
+            ids: false,
+            compressing: false,
             lines,
             user: User {
                 addr: addr.to_string(),
                 username: None,
+                authenticated: false,
+                owner: names::gen_owner(),
+                claimed: false,
+                session: session::gen_token(),
             },
-            state: State::Outside,
+            state: State::default(),
+            bucket,
+            paste: None,
+            reference: None,
+            blocked: block::new_blocklist(),
+            quiet: Arc::new(AtomicBool::new(false)),
+            history,
+            history_types: Kinds::All,
+            status: None,
+            changes_tx,
+            changes,
+            disconnect_tx,
+            disconnect,
+            challenge,
         }
     }
 
     pub async fn run(mut self, room_map: RoomMap) -> io::Result<()> {
+        let conn = Connection {
+            addr: self.user.addr.clone(),
+            username: None,
+            stream: self.stream.clone(),
+            disconnect: self.disconnect_tx.clone(),
+        };
+        connections::register(&self.conns, self.id, conn).await;
+        self.audit(AuditEvent::Connect).await;
+
+        // Unregister even if the connection errors out
+        let res = self.serve(room_map).await;
+        connections::unregister(&self.conns, self.id).await;
+        self.audit(AuditEvent::Disconnect).await;
+
+        res
+    }
+
+    async fn audit(&self, event: AuditEvent<'_>) {
+        let username = self.user.username.as_deref();
+        let room = self.state.active.as_deref();
+
+        if let Err(e) = audit::record(&self.redis, event, &self.user.addr, username, room).await {
+            eprintln!("{}", e);
+        }
+    }
+
+    async fn serve(&mut self, room_map: RoomMap) -> io::Result<()> {
         self.write_greeting().await?;
+        self.write_recent_announcements().await?;
+
+        // Only connections that drop, rather than being closed on purpose,
+        // can be resumed
+        let mut resumable = true;
+
+        // Refresh presence well before the key expires
+        let mut heartbeat =
+            tokio::time::interval(Duration::from_secs(presence::TTL_SECS as u64 / 2));
+
+        loop {
+            let message = tokio::select! {
+                line = self.lines.next_line() => match line? {
+                    Some(message) => message,
+                    None => break,
+                },
+                Some(change) = self.changes.recv() => {
+                    match change {
+                        RoomChange::Removed(room) => self.handle_removed(room).await?,
+                        RoomChange::Renamed(room, to) => self.handle_renamed(room, to).await,
+                    }
+                    continue;
+                }
+                Some(reason) = self.disconnect.recv() => {
+                    self.write_all(&reason).await?;
+                    resumable = false;
+                    break;
+                }
+                _ = heartbeat.tick() => {
+                    self.refresh_presence().await;
+                    self.refresh_claim().await;
+                    continue;
+                }
+            };
+
+            // For >ping, which times everything from here
+            let received = Instant::now();
+
+            // Pasted lines skip the rate limit, MAX_SIZE bounds them instead
+            if self.paste.is_some() {
+                self.handle_paste_line(message).await?;
+                continue;
+            }
+
+            let message = self.open_envelope(message).await;
+
+            // Picks up a reloaded limit without forgetting what they've sent
+            self.bucket.set_limit(self.config().rate_limit);
+            match self.bucket.check() {
+                Verdict::Allow => {}
+                Verdict::Warn => {
+                    self.write_slow_down().await?;
+                    continue;
+                }
+                Verdict::Disconnect => {
+                    self.write_rate_limited().await?;
+                    resumable = false;
+                    break;
+                }
+            }
 
-        while let Some(message) = self.lines.next_line().await? {
-            let command = Command::parse(message);
+            // Nothing but the challenge and help with it until it's answered
+            if self.challenge.is_some() && !Command::parse(message.clone()).unchallenged() {
+                self.write_error_text(
+                    ErrorCode::ChallengeRequired,
+                    "Answer the challenge in the greeting with >pow first\n",
+                )
+                .await?;
+                continue;
+            }
+
+            // Chat is already saved to the room, so only commands are logged
+            if message.starts_with('>') {
+                self.audit(AuditEvent::Command(&message)).await;
+            }
+
+            // Anything we don't know may be for a bot in the room
+            let name = message.split(' ').next().unwrap_or_default();
+            if name.len() > 1 && name.starts_with('>') && command::find(name).is_none() {
+                // Plugins get the first say, then the room's bots
+                if !self.handle_plugin_command(&message).await? {
+                    self.handle_bot_command(message).await?;
+                }
+                continue;
+            }
+
+            let mut command = Command::parse(message);
+
+            if let Err(e) = validate::command(&command) {
+                self.write_error(e).await?;
+                continue;
+            }
+            if let Err(e) = canonical::command(&self.redis, &mut command).await {
+                self.write_error(e).await?;
+                continue;
+            }
+            // Sending anything means they're back
+            if matches!(
+                command,
+                Command::Message(_)
+                    | Command::Reply(_, _)
+                    | Command::Signed(_, _, _)
+                    | Command::DirectMessage(_, _)
+                    | Command::Whisper(_, _)
+            ) {
+                self.clear_away().await?;
+            }
             let stream = self.stream.clone();
 
             match command {
-                Command::Help => {
+                Command::Help | Command::Commands(false) => {
                     self.write_help().await?;
                 }
-                Command::List => {
-                    match room::list(&self.redis).await {
-                        Ok(list) => self.write_list(list, true).await?,
-                        Err(e) => self.write_error(e).await?,
-                    };
+                Command::Commands(true) => {
+                    self.write_all(&command::machine()).await?;
+                }
+                Command::List(namespace, active, mine, page) => {
+                    self.handle_list(namespace, active, mine, page, &room_map)
+                        .await?;
+                }
+                Command::Find(keywords) => {
+                    self.handle_find(keywords, &room_map).await?;
                 }
                 Command::Me => {
                     self.write_user_info().await?;
                 }
+                Command::Who => {
+                    self.handle_who().await?;
+                }
+                Command::Ping => {
+                    self.handle_ping(received.elapsed()).await?;
+                }
+                Command::Unread => {
+                    self.handle_unread().await?;
+                }
+                Command::Typing => {
+                    self.handle_typing().await?;
+                }
+                Command::Presence(user) => {
+                    self.handle_presence(user).await?;
+                }
+                Command::SetProtocol(protocol, compress) => {
+                    self.handle_set_protocol(protocol, compress).await?;
+                }
+                Command::SetTimestamps(on) => {
+                    self.stream.set_timestamps(on).await?;
+                }
+                Command::Hello(hello) => {
+                    self.handle_hello(hello).await?;
+                }
+                Command::Pow(answer) => {
+                    self.handle_pow(answer).await?;
+                }
+                Command::SetIds(on) => {
+                    self.stream.set_ids(on).await?;
+                    self.ids = on;
+                }
+                Command::SetColor(on) => {
+                    self.stream.set_color(on).await?;
+                }
+                Command::SetBell(on) => {
+                    self.handle_set_bell(on).await?;
+                }
+                Command::SetQuiet(on) => {
+                    self.handle_set_quiet(on).await?;
+                }
+                Command::SetStatus(status) => {
+                    self.handle_set_status(status).await?;
+                }
+                Command::SetHistoryTypes(kinds) => {
+                    self.handle_set_history_types(kinds).await?;
+                }
+                Command::SetHistory(count) => {
+                    self.handle_set_history(count).await?;
+                }
+                Command::SetTimezone(offset) => {
+                    self.stream.set_timezone(offset).await?;
+                }
                 Command::SetUsername(username) => {
-                    self.user.username = Some(username);
+                    self.handle_set_username(username).await?;
+                }
+                Command::Register(username, password) => {
+                    self.handle_register(username, password).await?;
                 }
-                Command::CreateRoom(room) => {
-                    if let Err(e) = room::new(&self.redis, &room).await {
-                        self.write_error(e).await?
+                Command::Login(username, password) => {
+                    match account::login(&self.redis, &username, &password).await {
+                        Ok(()) => self.set_authenticated(username).await?,
+                        Err(e) => self.write_error(e).await?,
                     };
+                }
+                Command::Resume(token) => {
+                    self.handle_resume(token, &room_map).await?;
+                }
+                Command::PublicKey(key) => {
+                    self.handle_public_key(key).await?;
+                }
+                Command::RemovePublicKey => {
+                    self.handle_remove_public_key().await?;
+                }
+                Command::CreateRoom(room, password, options) => {
+                    if !self.user.authenticated {
+                        self.write_login_required().await?;
+                        continue;
+                    }
+
+                    self.create_room(room, password, options, &room_map).await?;
+                }
+                Command::JoinRoom(room, password, history) => {
+                    if !self.user.authenticated {
+                        self.write_login_required().await?;
+                        continue;
+                    }
+
+                    let replay = Replay::Recent(if history { self.history } else { 0 });
+                    self.handle_join(stream, room, password, replay, &room_map)
+                        .await?;
+                }
+                Command::Message(msg) => {
+                    self.handle_message(msg).await?;
+                }
+                Command::History(count, before, kinds) => {
+                    self.handle_history(count, before, kinds).await?;
+                }
+                Command::Digest(room, period) => {
+                    self.handle_digest(room, period).await?;
+                }
+                Command::Stats(room) => {
+                    self.handle_stats(room).await?;
+                }
+                Command::Export(room, format) => {
+                    self.handle_export(room, format).await?;
+                }
+                Command::DirectMessage(to, msg) => {
+                    self.handle_direct_message(to, msg).await?;
+                }
+                Command::Dms => {
+                    self.handle_dms().await?;
+                }
+                Command::Whisper(to, msg) => {
+                    self.handle_whisper(to, msg).await?;
+                }
+                Command::KeyExchange(to, key) => {
+                    self.handle_key_exchange(to, key).await?;
+                }
+                Command::Leave => {
+                    self.handle_leave().await?;
+                }
+                Command::Switch(room) => {
+                    self.handle_switch(room).await?;
+                }
+                Command::Topic(topic) => {
+                    self.handle_topic(topic).await?;
+                }
+                Command::Lang(change) => {
+                    self.handle_lang(change).await?;
+                }
+                Command::Disappearing(secs) => {
+                    self.handle_disappearing(secs).await?;
+                }
+                Command::Retention(change) => {
+                    self.handle_retention(change).await?;
+                }
+                Command::Webhook(command) => {
+                    self.handle_webhook(command).await?;
+                }
+                Command::Reply(id, text) => {
+                    self.handle_reply(id, text).await?;
+                }
+                Command::Signed(timestamp, signature, text) => {
+                    self.handle_signed(timestamp, signature, text).await?;
+                }
+                Command::Paste => {
+                    self.handle_paste().await?;
+                }
+                Command::Fetch(id) => {
+                    self.handle_fetch(id).await?;
+                }
+                Command::Edit(id, text) => {
+                    self.handle_edit(id, text).await?;
+                }
+                Command::Delete(id) => {
+                    self.handle_delete(id).await?;
+                }
+                Command::React(id, emoji) => {
+                    self.handle_react(id, emoji).await?;
+                }
+                Command::DeleteRoom => {
+                    self.handle_delete_room(&room_map).await?;
+                }
+                Command::RenameRoom(to) => {
+                    self.handle_rename_room(to, &room_map).await?;
+                }
+                Command::TransferOwnership(target) => {
+                    self.handle_transfer_ownership(target).await?;
+                }
+                Command::AcceptOwnership(room) => {
+                    self.handle_accept_ownership(room).await?;
+                }
+                Command::Kick(user) => {
+                    self.handle_kick(user, false).await?;
+                }
+                Command::Invite(user) => {
+                    self.handle_invite(user).await?;
+                }
+                Command::Requests => {
+                    self.handle_requests().await?;
+                }
+                Command::Approve(user) => {
+                    self.handle_decide(user, true).await?;
+                }
+                Command::Deny(user) => {
+                    self.handle_decide(user, false).await?;
+                }
+                Command::Unmute(user) => {
+                    self.handle_unmute(user).await?;
+                }
+                Command::Namespace(name, command) => {
+                    self.handle_namespace(name, command).await?;
+                }
+                Command::Receipts(id) => {
+                    self.handle_receipts(id).await?;
+                }
+                Command::Block(user) => {
+                    self.handle_block(user).await?;
+                }
+                Command::Unblock(user) => {
+                    self.handle_unblock(user).await?;
+                }
+                Command::Ban(user) => {
+                    self.handle_kick(user, true).await?;
+                }
+                Command::Grant(user, role, global) => {
+                    self.handle_grant(user, role, global).await?;
+                }
+                Command::Announce(text) => {
+                    self.handle_announce(text).await?;
+                }
+                Command::Report(target, reason) => {
+                    self.handle_report(target, reason).await?;
+                }
+                Command::Reports => {
+                    self.handle_reports().await?;
+                }
+                Command::Resolve(id) => {
+                    self.handle_resolve(id).await?;
+                }
+                Command::Broadcast(text) => {
+                    self.handle_broadcast(text, &room_map).await?;
+                }
+                Command::ForgetUser(target) => {
+                    self.handle_forget_user(target).await?;
+                }
+                Command::SetMotd(text) => {
+                    self.handle_set_motd(text).await?;
+                }
+                Command::Claim(name) => {
+                    self.handle_claim(name).await?;
+                }
+                Command::Unclaim(name) => {
+                    self.handle_unclaim(name).await?;
+                }
+                Command::Invalid => {
+                    self.write_invalid().await?;
+                }
+                Command::Usage(e) => {
+                    self.write_error(e).await?;
+                }
+                Command::Exit => {
+                    resumable = false;
+                    break;
+                }
+            }
+        }
+
+        self.handle_disconnect(resumable).await?;
+
+        Ok(())
+    }
+
+    async fn handle_disconnect(&mut self, resumable: bool) -> io::Result<()> {
+        let rooms: Vec<String> = self.state.joined.keys().cloned().collect();
+        let active = self.state.active.clone();
+
+        self.leave_all().await?;
+        self.release_claim().await;
+
+        if let (true, Some(username)) = (resumable, &self.user.username) {
+            let session = Session {
+                username: username.clone(),
+                authenticated: self.user.authenticated,
+                rooms,
+                active,
+                last_seen: room::get_time_in_ms(),
+            };
+            let ttl = self.config().session_ttl_secs as usize;
+
+            if let Err(e) = session::save(&self.redis, &self.user.session, &session, ttl).await {
+                eprintln!("{}", e);
+            }
+        }
+
+        if let (true, Some(username)) = (self.user.authenticated, &self.user.username) {
+            // They may still be online from a newer connection
+            if dm::unregister(&self.users, username, &self.stream).await {
+                if let Err(e) = presence::set_offline(&self.redis, username).await {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write_user_info(&self) -> io::Result<()> {
+        let info = format!(
+            "Username: {:?}, Logged in: {}, IP: {}, Session: {}\n",
+            self.user.username, self.user.authenticated, self.user.addr, self.user.session
+        );
+
+        self.write_all(&info).await?;
+
+        Ok(())
+    }
+
+    // Unwraps a JSON client's enveloped line, remembering its reference
+    // until the next line
+    async fn open_envelope(&mut self, line: String) -> String {
+        self.reference = None;
+
+        if self.protocol != Protocol::Json {
+            return line;
+        }
+
+        match Envelope::parse(&line) {
+            Some(envelope) => {
+                self.reference = Some(envelope.reference);
+                envelope.line
+            }
+            None => line,
+        }
+    }
+
+    async fn handle_set_username(&mut self, username: String) -> io::Result<()> {
+        if self.user.username.as_ref() == Some(&username) {
+            return Ok(());
+        }
+
+        // Names that belong to an account can only be claimed with >login,
+        // whatever their case
+        let account = match canonical::resolve(&self.redis, Kind::User, &username).await {
+            Ok(account) => account,
+            Err(e) => {
+                self.write_error(e).await?;
+                return Ok(());
+            }
+        };
+        match account::exists(&self.redis, &account).await {
+            Ok(true) => {
+                self.write_username_registered().await?;
+                return Ok(());
+            }
+            Ok(false) => {}
+            Err(e) => {
+                self.write_error(e).await?;
+                return Ok(());
+            }
+        }
+
+        // Another connection may be using it as a guest, unless it's only
+        // changing the case of the name this one already holds
+        if !self.holds_name(&username) {
+            if let Err(e) = names::claim(&self.redis, &username, &self.user.owner).await {
+                self.write_error(e).await?;
+                return Ok(());
+            }
+
+            self.release_claim().await;
+        }
+        self.log_out_account().await;
+
+        // It belonged to the account
+        if self.status.take().is_some() {
+            self.stream.set_dnd(false).await?;
+        }
+
+        let old = self.user.username.replace(username);
+        self.user.authenticated = false;
+        self.user.claimed = true;
+        connections::set_username(&self.conns, self.id, self.user.username.clone()).await;
+
+        if let Some(old) = old {
+            self.rename_in_rooms(&old).await?;
+        }
+
+        Ok(())
+    }
+
+    // Stops DMs and presence for the account they were logged in to, if any
+    async fn log_out_account(&self) {
+        if let (true, Some(old)) = (self.user.authenticated, &self.user.username) {
+            if dm::unregister(&self.users, old, &self.stream).await {
+                if let Err(e) = presence::set_offline(&self.redis, old).await {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+    }
+
+    // Keeps them in the rooms they're in under their new name, telling
+    // everyone there who they used to be
+    async fn rename_in_rooms(&self, old: &str) -> io::Result<()> {
+        let new = self.user.username.as_ref().unwrap();
+
+        for (room, tx) in &self.state.joined {
+            let event = RoomEvent::Rename(old.to_owned());
+            let msg =
+                match room::event(&self.redis, event, room, new, self.user.authenticated).await {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        self.write_error(e).await?;
+                        continue;
+                    }
+                };
+
+            if let Err(e) = room::set_alias(&self.redis, room, old, new).await {
+                eprintln!("{}", e);
+            }
+
+            let rename = BrokerEvent::Rename {
+                user: old.to_owned(),
+                to: new.to_owned(),
+                msg,
+            };
+            if let Err(e) = tx.send(rename).await {
+                self.write_error(e).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_register(&mut self, username: String, password: String) -> io::Result<()> {
+        // Registering the guest name you're already using is fine
+        let holds_name = self.holds_name(&username);
+
+        if !holds_name {
+            if let Err(e) = names::claim(&self.redis, &username, &self.user.owner).await {
+                self.write_error(e).await?;
+                return Ok(());
+            }
+        }
+
+        if let Err(e) = account::register(&self.redis, &username, &password).await {
+            if !holds_name {
+                if let Err(e) = names::release(&self.redis, &username, &self.user.owner).await {
+                    eprintln!("{}", e);
+                }
+            }
+
+            self.write_error(e).await?;
+            return Ok(());
+        }
+
+        if !holds_name {
+            self.release_claim().await;
+        }
+        self.set_authenticated(username.clone()).await?;
+
+        // Keep holding the name until this connection goes away
+        self.user.claimed = true;
+
+        Ok(())
+    }
+
+    async fn set_authenticated(&mut self, username: String) -> io::Result<()> {
+        // Switching accounts mid-room would misattribute the leave message
+        self.leave_all().await?;
+        self.log_out_account().await;
+
+        dm::register(&self.users, username.clone(), self.stream.clone()).await;
+
+        // Accounts own their name, so a guest name isn't needed anymore
+        if self.user.username.as_ref() != Some(&username) {
+            self.release_claim().await;
+        }
+
+        if let Err(e) = block::load(&self.redis, &username, &self.blocked).await {
+            eprintln!("{}", e);
+        }
+
+        match account::preference(&self.redis, &username, "bell").await {
+            Ok(Some(on)) => self.stream.set_bell(on).await?,
+            Ok(None) => {}
+            Err(e) => eprintln!("{}", e),
+        }
+
+        match account::preference(&self.redis, &username, "quiet").await {
+            Ok(Some(on)) => self.quiet.store(on, Ordering::Relaxed),
+            Ok(None) => {}
+            Err(e) => eprintln!("{}", e),
+        }
+
+        match account::preference(&self.redis, &username, "history").await {
+            Ok(Some(count)) => self.history = count,
+            Ok(None) => {}
+            Err(e) => eprintln!("{}", e),
+        }
+
+        match account::preference::<String>(&self.redis, &username, "types").await {
+            Ok(Some(kinds)) => self.history_types = kinds.parse().unwrap_or_default(),
+            Ok(None) => {}
+            Err(e) => eprintln!("{}", e),
+        }
+
+        match account::preference::<String>(&self.redis, &username, "status").await {
+            Ok(status) => self.status = status.and_then(|status| status.parse().ok()),
+            Err(e) => eprintln!("{}", e),
+        }
+        let dnd = self.status.as_ref().is_some_and(Status::is_dnd);
+        self.stream.set_dnd(dnd).await?;
+
+        self.user.username = Some(username);
+        self.user.authenticated = true;
+        self.refresh_presence().await;
+        connections::set_username(&self.conns, self.id, self.user.username.clone()).await;
+
+        self.write_all("Logged in\n").await?;
+        self.write_queued_dms().await?;
+
+        Ok(())
+    }
+
+    async fn write_queued_dms(&self) -> io::Result<()> {
+        let user = self.user.username.as_ref().unwrap();
+        let ttl = Duration::from_secs(self.config().dm_queue_ttl_secs);
+
+        let queued = match dm::take_queued(&self.redis, user, ttl).await {
+            Ok(queued) => queued,
+            Err(e) => return self.write_error(e).await,
+        };
+        if queued.is_empty() {
+            return Ok(());
+        }
+
+        self.write_all("While you were away:\n").await?;
+        self.write_messages(queued).await
+    }
+
+    async fn handle_resume(&mut self, token: String, room_map: &RoomMap) -> io::Result<()> {
+        let session = match session::take(&self.redis, &token).await {
+            Ok(session) => session,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        // The token stood in for their password, so accounts skip >login
+        if session.authenticated {
+            self.set_authenticated(session.username.clone()).await?;
+        } else {
+            self.handle_set_username(session.username.clone()).await?;
+
+            // Someone else took the guest name in the meantime
+            if self.user.username.as_ref() != Some(&session.username) {
+                return Ok(());
+            }
+        }
+
+        // Keep the token so the next drop can be resumed the same way
+        self.user.session = token;
+
+        for room in session.rooms {
+            // They may have been banned while away, but passwords and
+            // invites were already checked when they first joined
+            if let Err(e) = room::check_banned(&self.redis, &room, &session.username).await {
+                self.write_error(e).await?;
+                continue;
+            }
+
+            self.enter(
+                self.stream.clone(),
+                room_map,
+                room,
+                Replay::Since(session.last_seen),
+            )
+            .await?;
+        }
+
+        if let Some(active) = session.active {
+            if self.state.joined.contains_key(&active) {
+                self.state.active = Some(active);
+                self.sync_active().await;
+            }
+        }
+
+        self.write_all("Resumed\n").await
+    }
+
+    async fn refresh_presence(&self) {
+        if let (true, Some(username)) = (self.user.authenticated, &self.user.username) {
+            if let Err(e) = presence::set_online(&self.redis, username).await {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    // The config as it is now, which a reload can change between calls
+    fn config(&self) -> Arc<Config> {
+        self.config.get()
+    }
+
+    // What brokers started by this connection publish through, if anything
+    fn fanout(&self) -> Option<Pool> {
+        self.config().pubsub.then(|| self.redis.clone())
+    }
+
+    // Whether we can do `action` in `room`, or anywhere without one
+    async fn check_permission(
+        &self,
+        room: Option<&str>,
+        action: Action,
+    ) -> Result<(), PermissionError> {
+        let user = self.user.username.as_deref().unwrap_or_default();
+
+        permissions::check(&self.redis, room, user, self.user.authenticated, action).await
+    }
+
+    // What brokers started by this connection record delivery through, if anything
+    fn receipts(&self) -> Option<Receipts> {
+        Receipts::new(self.redis.clone(), self.config().receipts_max_members)
+    }
+
+    async fn refresh_claim(&self) {
+        if let (true, Some(username)) = (self.user.claimed, &self.user.username) {
+            if let Err(e) = names::refresh(&self.redis, username, &self.user.owner).await {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    // Whether this connection's claim covers `username`. Claims are folded,
+    // so one on `bob` covers `Bob` too.
+    fn holds_name(&self, username: &str) -> bool {
+        match (self.user.claimed, &self.user.username) {
+            (true, Some(held)) => canonical::fold(held) == canonical::fold(username),
+            _ => false,
+        }
+    }
+
+    async fn release_claim(&mut self) {
+        if let (true, Some(username)) = (self.user.claimed, &self.user.username) {
+            if let Err(e) = names::release(&self.redis, username, &self.user.owner).await {
+                eprintln!("{}", e);
+            }
+        }
+
+        self.user.claimed = false;
+    }
+
+    async fn handle_presence(&self, user: String) -> io::Result<()> {
+        match presence::is_online(&self.redis, &user).await {
+            Ok(true) => self.write_all(&format!("{} is online\n", user)).await?,
+            Ok(false) => self.write_all(&format!("{} is offline\n", user)).await?,
+            Err(e) => self.write_error(e).await?,
+        }
+
+        Ok(())
+    }
+
+    async fn handle_typing(&self) -> io::Result<()> {
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        // Typing notices aren't worth persisting
+        let msg = Message::new(
+            MessageKind::Typing,
+            Some(room),
+            Some(user),
+            room::get_time_in_ms(),
+            String::new(),
+        );
+
+        if let Err(e) = tx
+            .send(BrokerEvent::Typing {
+                user: user.to_owned(),
+                msg,
+            })
+            .await
+        {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_direct_message(&self, to: String, msg: String) -> io::Result<()> {
+        if !self.user.authenticated {
+            self.write_login_required().await?;
+            return Ok(());
+        }
+
+        // Guests' names go with them, so only accounts can be sent to while
+        // they're offline
+        if dm::get_stream(&self.users, &to).await.is_err() {
+            match account::exists(&self.redis, &to).await {
+                Ok(true) => {}
+                Ok(false) => return self.write_error(DmError::UserNotOnline).await,
+                Err(e) => return self.write_error(e).await,
+            }
+        }
+
+        let from = self.user.username.as_ref().unwrap();
+
+        // Dropped without saying so, like a whisper to someone who blocked you
+        match block::is_blocked(&self.redis, from, &to).await {
+            Ok(false) => {}
+            Ok(true) => return Ok(()),
+            Err(e) => return self.write_error(e).await,
+        }
+
+        let msg = match dm::event(&self.redis, from, &to, &msg).await {
+            Ok(msg) => msg,
+            Err(e) => {
+                self.write_error(e).await?;
+                return Ok(());
+            }
+        };
+
+        match self.deliver_dm(&to, &msg).await {
+            true => Ok(()),
+            false => {
+                self.write_all(&format!(
+                    "{} is offline, they'll get it when they next log in\n",
+                    to
+                ))
+                .await
+            }
+        }
+    }
+
+    // Unread counts what the other side has sent since you last sent them
+    // something
+    async fn handle_dms(&self) -> io::Result<()> {
+        if !self.user.authenticated {
+            return self.write_login_required().await;
+        }
+        let user = self.user.username.as_ref().unwrap();
+
+        let conversations = match dm::conversations(&self.redis, user).await {
+            Ok(conversations) => conversations,
+            Err(e) => return self.write_error(e).await,
+        };
+        if conversations.is_empty() {
+            return self.write_all("No conversations\n").await;
+        }
+
+        let now = room::get_time_in_ms();
+        let list = conversations
+            .into_iter()
+            .map(|conversation| {
+                let unread = match conversation.unread {
+                    0 => String::new(),
+                    n => format!(", {} unread", n),
+                };
+                format!(
+                    "{} ({}{}) {}",
+                    conversation.with,
+                    describe_ago(now - conversation.at),
+                    unread,
+                    conversation.preview
+                )
+            })
+            .collect();
+
+        self.write_list(list).await
+    }
+
+    // Writes a DM that's already been saved to `to`, or queues it for when
+    // they next log in if they're not online, or their connection is going
+    // away. True if it was written.
+    async fn deliver_dm(&self, to: &str, msg: &Message) -> bool {
+        if let Ok(stream) = dm::get_stream(&self.users, to).await {
+            if stream.write_message(msg.clone()).await.is_ok() {
+                return true;
+            }
+        }
+
+        let ttl = Duration::from_secs(self.config().dm_queue_ttl_secs);
+        if let Err(e) = dm::queue(&self.redis, to, msg, ttl).await {
+            eprintln!("{}", e);
+        }
+
+        false
+    }
+
+    async fn handle_whisper(&self, to: String, msg: String) -> io::Result<()> {
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        let msg = match self.check_body(room, msg).await? {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
+
+        let msg = Message::new(
+            MessageKind::Whisper,
+            Some(room),
+            Some(user),
+            room::get_time_in_ms(),
+            msg,
+        );
+
+        self.send_to_member(tx, to, msg).await
+    }
+
+    // Relays a key like a whisper if it's for one member, or like chat if
+    // it's for everyone, without saving it
+    async fn handle_key_exchange(&self, to: Option<String>, key: String) -> io::Result<()> {
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        if !self.state.encrypted.contains(room) {
+            return self
+                .write_error_text(
+                    ErrorCode::InvalidArgument,
+                    "Keys are only exchanged in encrypted rooms\n",
+                )
+                .await;
+        }
+
+        let msg = Message::new(
+            MessageKind::KeyExchange,
+            Some(room),
+            Some(user),
+            room::get_time_in_ms(),
+            key,
+        );
+
+        if let Some(to) = to {
+            return self.send_to_member(tx, to, msg).await;
+        }
+
+        let event = BrokerEvent::Message {
+            user: user.to_owned(),
+            msg,
+        };
+
+        if let Err(e) = tx.send(event).await {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    // Has the broker deliver `msg` to one member of its room, which could be
+    // on another server
+    async fn send_to_member(
+        &self,
+        tx: &Sender<BrokerEvent>,
+        to: String,
+        msg: Message,
+    ) -> io::Result<()> {
+        let user = self.user.username.as_ref().unwrap();
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let event = BrokerEvent::Whisper {
+            user: user.to_owned(),
+            to,
+            msg,
+            reply: reply_tx,
+        };
+
+        if let Err(e) = tx.send(event).await {
+            return self.write_error(e).await;
+        }
+
+        match reply_rx.await {
+            Ok(true) => Ok(()),
+            Ok(false) => self.write_user_not_in_room().await,
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_who(&self) -> io::Result<()> {
+        let tx = match self.state.active() {
+            Some((_, tx)) => tx,
+            None => return self.write_not_in_room().await,
+        };
+
+        let members = match self.members(tx).await? {
+            Some(members) => members,
+            None => return Ok(()),
+        };
+
+        // Shown without statuses rather than not at all
+        let statuses = match account::preferences::<String>(&self.redis, &members, "status").await {
+            Ok(statuses) => statuses,
+            Err(e) => {
+                eprintln!("{}", e);
+                vec![None; members.len()]
+            }
+        };
+
+        let list = members
+            .into_iter()
+            .zip(statuses)
+            .map(
+                |(member, status)| match status.and_then(|s| s.parse::<Status>().ok()) {
+                    Some(status) => format!("{} ({})", member, status.describe()),
+                    None => member,
+                },
+            )
+            .collect();
+
+        self.write_list(list).await
+    }
+
+    // Times each step a message takes: reading and parsing the line, a write
+    // to Redis, the active room's broker, and getting to write to this
+    // connection, which waits on anything the room is still sending
+    async fn handle_ping(&self, parse: Duration) -> io::Result<()> {
+        let redis = match self.redis.ping(self.id).await {
+            Ok(redis) => redis,
+            Err(e) => return self.write_error(e).await,
+        };
+        let mut steps = vec![("parse", parse), ("redis", redis)];
+
+        if let Some((_, tx)) = self.state.active() {
+            match broker::ping(tx, PING_TIMEOUT).await {
+                Some(broker) => steps.push(("broker", broker)),
+                None => {
+                    return self
+                        .write_error_text(
+                            ErrorCode::Unavailable,
+                            "Your room's broker didn't answer\n",
+                        )
+                        .await
+                }
+            }
+        }
+
+        let start = Instant::now();
+        self.stream.flush().await?;
+        steps.push(("write", start.elapsed()));
+
+        let total = steps.iter().map(|(_, time)| *time).sum();
+        let steps: Vec<String> = steps
+            .iter()
+            .map(|(step, time)| format!("{} {}", step, describe_time(*time)))
+            .collect();
+
+        let pong = format!("Pong in {} ({})\n", describe_time(total), steps.join(", "));
+        let msg = Message::system(&pong).with_reference(self.reference.clone());
+
+        self.stream.write_message(msg).await
+    }
+
+    async fn members(&self, tx: &Sender<BrokerEvent>) -> io::Result<Option<Vec<String>>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        if let Err(e) = tx.send(BrokerEvent::Who { reply: reply_tx }).await {
+            self.write_error(e).await?;
+            return Ok(None);
+        }
+
+        match reply_rx.await {
+            Ok(members) => Ok(Some(members)),
+            Err(e) => {
+                self.write_error(e).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn handle_kick(&self, target: String, ban: bool) -> io::Result<()> {
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+        let action = if ban { Action::Ban } else { Action::Kick };
+
+        if let Err(e) = self.check_permission(Some(room), action).await {
+            return self.write_error(e).await;
+        }
+
+        if &target == user {
+            self.write_error_text(
+                ErrorCode::InvalidArgument,
+                "You can't remove yourself from your own room\n",
+            )
+            .await?;
+            return Ok(());
+        }
+
+        if let Err(e) = permissions::check_outranks(&self.redis, room, user, &target).await {
+            return self.write_error(e).await;
+        }
+
+        let event = if ban {
+            if let Err(e) = room::ban(&self.redis, room, &target).await {
+                self.write_error(e).await?;
+                return Ok(());
+            }
+
+            RoomEvent::Ban(target.clone())
+        } else {
+            // Bans apply whether or not they're here, kicks don't
+            match self.members(tx).await? {
+                Some(members) if members.contains(&target) => {}
+                Some(_) => return self.write_user_not_in_room().await,
+                None => return Ok(()),
+            }
+
+            RoomEvent::Kick(target.clone())
+        };
+
+        let msg = match room::event(&self.redis, event, room, user, self.user.authenticated).await {
+            Ok(msg) => msg,
+            Err(e) => {
+                self.write_error(e).await?;
+                return Ok(());
+            }
+        };
+
+        if let Err(e) = tx.send(BrokerEvent::Kick { user: target, msg }).await {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_removed(&mut self, room: String) -> io::Result<()> {
+        self.state.remove(&room);
+        self.sync_active().await;
+        self.forget_alias(&room).await;
+
+        self.write_all(&format!("You are no longer in {}\n", room))
+            .await
+    }
+
+    // The broker tells everyone who renamed it, so there's nothing to write
+    async fn handle_renamed(&mut self, room: String, to: String) {
+        self.state.rename(&room, to);
+        self.sync_active().await;
+    }
+
+    // Both go by the rooms that exist when it's checked, so rooms handed
+    // over with >transfer-ownership can take someone past their limit
+    async fn check_room_limit(&self) -> Result<(), room::RoomError> {
+        if self.config().max_rooms.is_none() && self.config().max_rooms_per_user.is_none() {
+            return Ok(());
+        }
+
+        let rooms = room::list(&self.redis).await?;
+
+        if let Some(max) = self.config().max_rooms {
+            if rooms.len() >= max {
+                Err(room::RoomError::TooManyRooms)?;
+            }
+        }
+
+        let max = match self.config().max_rooms_per_user {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+
+        let names: Vec<&str> = rooms.iter().map(|r| &r[5..]).collect();
+        let user = self.user.username.as_deref();
+        let owned = room::owners(&self.redis, &names)
+            .await?
+            .iter()
+            .filter(|owner| owner.as_deref() == user)
+            .count();
+
+        if owned >= max {
+            Err(room::RoomError::TooManyOwned(max))?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_list(
+        &self,
+        namespace: Option<String>,
+        active: bool,
+        mine: bool,
+        page: usize,
+        room_map: &RoomMap,
+    ) -> io::Result<()> {
+        let rooms = match room::list(&self.redis).await {
+            Ok(rooms) => rooms,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        // Remove `room:`, keeping only what's in the namespace if one was given
+        let prefix = namespace.map(|namespace| format!("{}/", namespace));
+        let mut names: Vec<&str> = rooms
+            .iter()
+            .map(|r| &r[5..])
+            .filter(|name| prefix.as_ref().is_none_or(|p| name.starts_with(p)))
+            .collect();
+        names.sort_unstable();
+
+        // Listed by name, only the page's rooms need looking up
+        let by_name = !active && !mine;
+        let mut total = names.len();
+        if by_name {
+            names = page_of(names, page);
+        }
+
+        let meta = tokio::try_join!(
+            room::topics(&self.redis, &names),
+            room::owners(&self.redis, &names),
+            room::last_activity(&self.redis, &names),
+        );
+        let (topics, owners, activity) = match meta {
+            Ok(meta) => meta,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        let user = self.user.username.as_deref();
+        let mut rooms: Vec<_> = names
+            .into_iter()
+            .zip(topics)
+            .zip(owners)
+            .zip(activity)
+            .filter(|(((_, _), owner), _)| !mine || owner.as_deref() == user)
+            .map(|(((name, topic), _), activity)| (name, topic, activity))
+            .collect();
+
+        if active {
+            rooms.sort_by_key(|room| std::cmp::Reverse(room.2));
+        }
+        if !by_name {
+            total = rooms.len();
+            rooms = page_of(rooms, page);
+        }
+
+        let now = room::get_time_in_ms();
+        let mut list = Vec::with_capacity(rooms.len());
+
+        for (name, topic, activity) in rooms {
+            let members = self.count_members(room_map, name).await;
+            let activity = match activity {
+                Some(ms) => format!("active {}", describe_ago(now - ms)),
+                None => "no messages".to_owned(),
+            };
+
+            let mut line = format!("{} ({} here, {})", name, members, activity);
+            if let Some(topic) = topic {
+                line.push_str(&format!(" - {}", topic));
+            }
+
+            list.push(line);
+        }
+
+        if list.is_empty() {
+            return match total {
+                0 => self.write_all("No rooms\n").await,
+                _ => self.write_all("No rooms on that page\n").await,
+            };
+        }
+
+        let pages = total.div_ceil(LIST_PAGE_SIZE);
+        if pages > 1 {
+            let mut footer = format!("Page {} of {} ({} rooms)", page, pages, total);
+            if page < pages {
+                footer.push_str(&format!(", add --page {} for the next", page + 1));
+            }
+            list.push(footer);
+        }
+
+        self.write_list(list).await
+    }
+
+    async fn handle_find(&self, keywords: String, room_map: &RoomMap) -> io::Result<()> {
+        let found = match search::find(&self.redis, &keywords).await {
+            Ok(found) => found,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        // Rooms that expired are still in the index
+        let mut rooms = Vec::new();
+        for room in found {
+            if rooms.len() == search::MAX_RESULTS {
+                break;
+            }
+
+            match room::exists(&self.redis, &room).await {
+                Ok(true) => rooms.push(room),
+                Ok(false) => {
+                    if let Err(e) = search::remove(&self.redis, &room).await {
+                        eprintln!("{}", e);
+                    }
+                }
+                Err(e) => return self.write_error(e).await,
+            }
+        }
+
+        let names: Vec<&str> = rooms.iter().map(String::as_str).collect();
+        let topics = match room::topics(&self.redis, &names).await {
+            Ok(topics) => topics,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        let mut list = Vec::with_capacity(names.len());
+        for (name, topic) in names.into_iter().zip(topics) {
+            let members = self.count_members(room_map, name).await;
+
+            let mut line = format!("{} ({} here)", name, members);
+            if let Some(topic) = topic {
+                line.push_str(&format!(" - {}", topic));
+            }
+
+            list.push(line);
+        }
+
+        if list.is_empty() {
+            return self.write_all("No rooms match that\n").await;
+        }
+
+        self.write_list(list).await
+    }
+
+    // People in the room on this server, or 0 if it has no broker here or
+    // it's too busy to answer
+    async fn count_members(&self, room_map: &RoomMap, room: &str) -> usize {
+        let tx = match room_map.read().await.get(room) {
+            Some(tx) => tx.clone(),
+            None => return 0,
+        };
+
+        broker::who(&tx, broker::WHO_TIMEOUT)
+            .await
+            .map(|members| members.len())
+            .unwrap_or_default()
+    }
+
+    // Switching protocol without zlib turns it off again
+    async fn handle_set_protocol(&mut self, protocol: Protocol, compress: bool) -> io::Result<()> {
+        self.stream.set_protocol(protocol).await?;
+        self.protocol = protocol;
+
+        if compress && !self.compressing {
+            // Written plain, so the client knows the zlib stream starts
+            // right after this line
+            self.write_all("Compressing with zlib\n").await?;
+        }
+
+        self.stream.set_compression(compress).await?;
+        self.compressing = compress;
+
+        Ok(())
+    }
+
+    // Answered before anything changes, so it comes in the protocol the
+    // client was using when it said hello
+    async fn handle_hello(&mut self, hello: Hello) -> io::Result<()> {
+        let agreed = hello.agree();
+        self.write_all(&agreed.to_string()).await?;
+
+        self.stream.set_color(agreed.has(Capability::Color)).await?;
+        self.stream
+            .set_timestamps(agreed.has(Capability::Timestamps))
+            .await?;
+        self.stream.set_ids(agreed.has(Capability::Ids)).await?;
+        self.ids = agreed.has(Capability::Ids);
+
+        let protocol = match agreed.has(Capability::Json) {
+            true => Protocol::Json,
+            false => Protocol::Text,
+        };
+        self.handle_set_protocol(protocol, agreed.has(Capability::Compress))
+            .await
+    }
+
+    // Saved with the account, so guests only have it until they disconnect
+    async fn handle_set_bell(&self, on: bool) -> io::Result<()> {
+        self.stream.set_bell(on).await?;
+
+        if let (true, Some(username)) = (self.user.authenticated, &self.user.username) {
+            if let Err(e) = account::set_preference(&self.redis, username, "bell", on).await {
+                return self.write_error(e).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Like the bell, saved if they're logged in
+    async fn handle_set_quiet(&self, on: bool) -> io::Result<()> {
+        self.quiet.store(on, Ordering::Relaxed);
+
+        if let (true, Some(username)) = (self.user.authenticated, &self.user.username) {
+            if let Err(e) = account::set_preference(&self.redis, username, "quiet", on).await {
+                return self.write_error(e).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_set_history_types(&mut self, kinds: Kinds) -> io::Result<()> {
+        if let (true, Some(username)) = (self.user.authenticated, &self.user.username) {
+            let saved = account::set_preference(&self.redis, username, "types", kinds.to_string());
+            if let Err(e) = saved.await {
+                return self.write_error(e).await;
+            }
+        }
+        self.history_types = kinds;
+
+        Ok(())
+    }
+
+    // Saved with their account, which is where >who looks for it
+    async fn handle_set_status(&mut self, status: Option<Status>) -> io::Result<()> {
+        if !self.user.authenticated {
+            return self.write_login_required().await;
+        }
+        let username = self.user.username.as_ref().unwrap();
+
+        let saved = match &status {
+            Some(status) => {
+                account::set_preference(&self.redis, username, "status", status.to_string()).await
+            }
+            None => account::clear_preference(&self.redis, username, "status").await,
+        };
+        if let Err(e) = saved {
+            return self.write_error(e).await;
+        }
+
+        let dnd = status.as_ref().is_some_and(Status::is_dnd);
+        self.stream.set_dnd(dnd).await?;
+
+        let reply = match &status {
+            Some(status) => format!("Your status is now {}\n", status.describe()),
+            None => "You're back\n".to_owned(),
+        };
+        self.status = status;
+
+        self.write_all(&reply).await
+    }
+
+    // When `clear_away_on_activity` is set. Do not disturb stays until
+    // they're back, since people often talk while they don't want to be
+    // interrupted.
+    async fn clear_away(&mut self) -> io::Result<()> {
+        if !self.config().clear_away_on_activity || !matches!(self.status, Some(Status::Away(_))) {
+            return Ok(());
+        }
+        let username = self.user.username.as_ref().unwrap();
+
+        if let Err(e) = account::clear_preference(&self.redis, username, "status").await {
+            return self.write_error(e).await;
+        }
+        self.status = None;
+
+        self.write_all("You're back\n").await
+    }
+
+    async fn handle_set_history(&mut self, count: usize) -> io::Result<()> {
+        self.history = count.min(MAX_HISTORY);
+
+        if let (true, Some(username)) = (self.user.authenticated, &self.user.username) {
+            let saved = account::set_preference(&self.redis, username, "history", self.history);
+            if let Err(e) = saved.await {
+                return self.write_error(e).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Shows the registered key without one
+    async fn handle_public_key(&self, key: Option<String>) -> io::Result<()> {
+        if !self.user.authenticated {
+            return self.write_login_required().await;
+        }
+        let user = self.user.username.as_ref().unwrap();
+
+        let key = match key {
+            Some(key) => key,
+            None => {
+                return match signing::key(&self.redis, user).await {
+                    Ok(Some(key)) => self.write_all(&format!("Your key is {}\n", key)).await,
+                    Ok(None) => self.write_all("You haven't registered a key\n").await,
+                    Err(e) => self.write_error(e).await,
+                };
+            }
+        };
+
+        match signing::set_key(&self.redis, user, &key).await {
+            Ok(()) => self.write_all("Key registered\n").await,
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_remove_public_key(&self) -> io::Result<()> {
+        if !self.user.authenticated {
+            return self.write_login_required().await;
+        }
+        let user = self.user.username.as_ref().unwrap();
+
+        match signing::remove_key(&self.redis, user).await {
+            Ok(()) => self.write_all("Key removed\n").await,
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    // Lists who's blocked without a user
+    async fn handle_block(&self, target: Option<String>) -> io::Result<()> {
+        if !self.user.authenticated {
+            return self.write_login_required().await;
+        }
+        let user = self.user.username.as_ref().unwrap();
+
+        let target = match target {
+            Some(target) => target,
+            None => {
+                let mut blocked: Vec<String> =
+                    self.blocked.read().unwrap().iter().cloned().collect();
+                blocked.sort();
+
+                return match blocked.is_empty() {
+                    true => self.write_all("You haven't blocked anyone\n").await,
+                    false => self.write_list(blocked).await,
+                };
+            }
+        };
+
+        match block::block(&self.redis, user, &target, &self.blocked).await {
+            Ok(()) => {
+                self.write_all(&format!("You won't see messages from {}\n", target))
+                    .await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_unblock(&self, target: String) -> io::Result<()> {
+        if !self.user.authenticated {
+            return self.write_login_required().await;
+        }
+        let user = self.user.username.as_ref().unwrap();
+
+        match block::unblock(&self.redis, user, &target, &self.blocked).await {
+            Ok(()) => self.write_all(&format!("Unblocked {}\n", target)).await,
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_namespace(&self, name: String, command: NamespaceCommand) -> io::Result<()> {
+        let (allow, target) = match command {
+            NamespaceCommand::Show => return self.show_namespace(&name).await,
+            NamespaceCommand::Allow(target) => (true, target),
+            NamespaceCommand::Revoke(target) => (false, target),
+        };
+
+        if !self.user.authenticated {
+            return self.write_login_required().await;
+        }
+        let user = self.user.username.as_ref().unwrap();
+
+        let res = match allow {
+            true => namespace::allow(&self.redis, &name, user, &target).await,
+            false => namespace::revoke(&self.redis, &name, user, &target).await,
+        };
+
+        match (res, allow) {
+            (Err(e), _) => self.write_error(e).await,
+            (Ok(()), true) => {
+                self.write_all(&format!("{} can create rooms in {} now\n", target, name))
+                    .await
+            }
+            (Ok(()), false) => {
+                self.write_all(&format!(
+                    "{} can't create rooms in {} anymore\n",
+                    target, name
+                ))
+                .await
+            }
+        }
+    }
+
+    async fn show_namespace(&self, name: &str) -> io::Result<()> {
+        let (owner, creators) = match namespace::info(&self.redis, name).await {
+            Ok(info) => info,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        match creators.is_empty() {
+            true => {
+                self.write_all(&format!("{} is owned by {}\n", name, owner))
+                    .await
+            }
+            false => {
+                self.write_all(&format!(
+                    "{} is owned by {}, who lets {} create rooms in it\n",
+                    name,
+                    owner,
+                    creators.join(", ")
+                ))
+                .await
+            }
+        }
+    }
+
+    async fn handle_receipts(&self, id: String) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+
+        if self.config().receipts_max_members == 0 {
+            return self
+                .write_error_text(ErrorCode::Disabled, "Read receipts are turned off\n")
+                .await;
+        }
+
+        match receipts::seen_by(&self.redis, room, &id).await {
+            Ok(users) if users.is_empty() => self.write_all("Nobody has seen that yet\n").await,
+            Ok(users) => {
+                self.write_all(&format!("Seen by {}\n", users.join(", ")))
+                    .await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    // Grants in the active room need its admins, --global ones a global admin
+    async fn handle_grant(&self, target: String, role: Role, global: bool) -> io::Result<()> {
+        let room = match (global, self.state.active()) {
+            (true, _) => None,
+            (false, Some((room, _))) => Some(room),
+            (false, None) => return self.write_not_in_room().await,
+        };
+
+        if let Err(e) = self.check_permission(room, Action::Grant).await {
+            return self.write_error(e).await;
+        }
+
+        let user = self.user.username.as_ref().unwrap();
+        if let Err(e) = permissions::check_grant(&self.redis, room, user, &target, role).await {
+            return self.write_error(e).await;
+        }
+
+        if let Err(e) = permissions::grant(&self.redis, room, &target, role).await {
+            return self.write_error(e).await;
+        }
+
+        self.write_all(&format!(
+            "{} is a {} in {} now\n",
+            target,
+            role,
+            room.unwrap_or("every room")
+        ))
+        .await
+    }
+
+    async fn handle_announce(&self, text: String) -> io::Result<()> {
+        if let Err(e) = self.check_permission(None, Action::Announce).await {
+            return self.write_error(e).await;
+        }
+
+        match announce::schedule(&self.redis, &text, room::get_time_in_ms()).await {
+            Ok(id) => self.write_all(&format!("Announced {}\n", id)).await,
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_report(&self, target: String, reason: Option<String>) -> io::Result<()> {
+        if !self.user.authenticated {
+            return self.write_login_required().await;
+        }
+        let user = self.user.username.as_ref().unwrap();
+
+        match account::exists(&self.redis, &target).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return self
+                    .write_error_text(
+                        ErrorCode::UserNotFound,
+                        &format!("There's no account called {}\n", target),
+                    )
+                    .await
+            }
+            Err(e) => return self.write_error(e).await,
+        }
+
+        // Encrypted rooms' messages are ciphertext, which wouldn't help
+        let room = self.state.active.as_deref();
+        let context = match room.filter(|room| !self.state.encrypted.contains(*room)) {
+            Some(room) => {
+                match room::recent_msgs(&self.redis, room, report::CONTEXT, &Kinds::All).await {
+                    Ok(msgs) => msgs.iter().map(|msg| msg.render(Protocol::Text)).collect(),
+                    Err(e) => return self.write_error(e).await,
+                }
+            }
+            None => Vec::new(),
+        };
+
+        match report::file(&self.redis, user, &target, room, reason.as_deref(), context).await {
+            Ok(_) => {
+                self.write_all(&format!(
+                    "Reported {}, an admin will look into it\n",
+                    target
+                ))
+                .await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_reports(&self) -> io::Result<()> {
+        if let Err(e) = self.check_permission(None, Action::Reports).await {
+            return self.write_error(e).await;
+        }
+
+        let reports = match report::open(&self.redis).await {
+            Ok(reports) => reports,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        if reports.is_empty() {
+            return self.write_all("No open reports\n").await;
+        }
+
+        let mut out: String = reports.iter().map(report::Report::describe).collect();
+        out.push_str(&format!(
+            "{} open report(s), >resolve id closes one\n",
+            reports.len()
+        ));
+
+        self.write_all(&out).await
+    }
+
+    async fn handle_resolve(&self, id: String) -> io::Result<()> {
+        if let Err(e) = self.check_permission(None, Action::Reports).await {
+            return self.write_error(e).await;
+        }
+
+        match report::resolve(&self.redis, &id).await {
+            Ok(report) => {
+                self.write_all(&format!(
+                    "Resolved {}'s report of {}\n",
+                    report.reporter, report.target
+                ))
+                .await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_set_motd(&self, text: Option<String>) -> io::Result<()> {
+        if let Err(e) = self.check_permission(None, Action::SetMotd).await {
+            return self.write_error(e).await;
+        }
+
+        match motd::set(&self.redis, text.as_deref()).await {
+            Ok(()) if text.is_some() => {
+                self.write_all(
+                    "Message of the day set, shown to everyone who connects from now on\n",
+                )
+                .await
+            }
+            Ok(()) => {
+                self.write_all("Message of the day reset to the configured one\n")
+                    .await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    // Goes to every room with a broker on this server, which with `pubsub`
+    // on reaches members of those rooms on other servers too
+    async fn handle_broadcast(&self, text: String, room_map: &RoomMap) -> io::Result<()> {
+        if let Err(e) = self.check_permission(None, Action::Broadcast).await {
+            return self.write_error(e).await;
+        }
+        let user = self.user.username.as_ref().unwrap();
+
+        let rooms = broker::snapshot(room_map).await;
+
+        let mut count = 0;
+        for (room, tx) in rooms {
+            let event = RoomEvent::Broadcast(text.clone());
+            let msg = match room::event(&self.redis, event, &room, user, true).await {
+                Ok(msg) => msg,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    continue;
+                }
+            };
+
+            match tx.send(BrokerEvent::Post { msg }).await {
+                Ok(()) => count += 1,
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+
+        self.write_all(&format!("Broadcast to {} room(s)\n", count))
+            .await
+    }
+
+    // Redacts what they said in every room, not just active ones, then
+    // removes their DMs and account. They're disconnected first so they
+    // can't add more while it runs.
+    async fn handle_forget_user(&self, target: String) -> io::Result<()> {
+        if let Err(e) = self.check_permission(None, Action::ForgetUser).await {
+            return self.write_error(e).await;
+        }
+
+        if self.user.username.as_ref() == Some(&target) {
+            return self
+                .write_error_text(ErrorCode::InvalidArgument, "You can't forget yourself\n")
+                .await;
+        }
+
+        match account::exists(&self.redis, &target).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return self
+                    .write_error_text(
+                        ErrorCode::UserNotFound,
+                        &format!("There's no account called {}\n", target),
+                    )
+                    .await
+            }
+            Err(e) => return self.write_error(e).await,
+        }
+
+        connections::disconnect(&self.conns, &target, "Your account has been deleted\n").await;
+
+        let rooms = match room::list(&self.redis).await {
+            Ok(rooms) => rooms,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        let mut redacted = 0;
+        for room in rooms {
+            // Remove `room:`
+            match room::redact(&self.redis, &room[5..], &target).await {
+                Ok(count) => redacted += count,
+                Err(e) => return self.write_error(e).await,
+            }
+        }
+
+        let dms = match dm::forget(&self.redis, &target).await {
+            Ok(dms) => dms,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        if let Err(e) = account::forget(&self.redis, &target).await {
+            return self.write_error(e).await;
+        }
+
+        self.write_all(&format!(
+            "Forgot {}: {} message(s) redacted, {} conversation(s) deleted\n",
+            target, redacted, dms
+        ))
+        .await
+    }
+
+    // Lists every claim without a name
+    async fn handle_claim(&self, name: Option<String>) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        let name = match name {
+            Some(name) => name,
+            None => {
+                return match bots::list(&self.redis, room).await {
+                    Ok(claims) if claims.is_empty() => {
+                        self.write_all("No bots answer commands here\n").await
+                    }
+                    Ok(claims) => {
+                        let claims = claims
+                            .into_iter()
+                            .map(|(name, bot)| format!(">{} - {}", name, bot))
+                            .collect();
+                        self.write_list(claims).await
+                    }
+                    Err(e) => self.write_error(e).await,
+                };
+            }
+        };
+
+        if let Err(e) = self.check_permission(Some(room), Action::Claim).await {
+            return self.write_error(e).await;
+        }
+
+        match bots::claim(&self.redis, room, &name, user).await {
+            Ok(()) => {
+                self.write_all(&format!(
+                    "You'll be sent >{} when it's used in {}\n",
+                    name, room
+                ))
+                .await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_unclaim(&self, name: String) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+
+        if let Err(e) = self.check_permission(Some(room), Action::Claim).await {
+            return self.write_error(e).await;
+        }
+
+        match bots::release(&self.redis, room, &name).await {
+            Ok(()) => {
+                self.write_all(&format!("Nobody answers >{} in {} now\n", name, room))
+                    .await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    // Passes the whole line to the bot that claimed the command, which
+    // answers in the room like anyone else
+    async fn handle_bot_command(&self, line: String) -> io::Result<()> {
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_invalid().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        let name = line[1..].split(' ').next().unwrap_or_default();
+        let bot = match bots::claimant(&self.redis, room, name).await {
+            Ok(Some(bot)) => bot,
+            Ok(None) => return self.write_invalid().await,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        if let Err(e) = validate::message(&line) {
+            return self.write_error(e).await;
+        }
+
+        if let Err(e) = self.check_permission(Some(room), Action::Send).await {
+            return self.write_error(e).await;
+        }
+
+        let msg = Message::new(
+            MessageKind::Command,
+            Some(room),
+            Some(user),
+            room::get_time_in_ms(),
+            line,
+        );
+
+        self.send_to_member(tx, bot, msg).await
+    }
+
+    async fn handle_unmute(&self, target: String) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+
+        if let Err(e) = self.check_permission(Some(room), Action::Unmute).await {
+            return self.write_error(e).await;
+        }
+
+        if let Err(e) = room::unmute(&self.redis, room, &target).await {
+            return self.write_error(e).await;
+        }
+
+        self.write_all(&format!("{} can talk in {} again\n", target, room))
+            .await
+    }
+
+    async fn handle_invite(&self, target: String) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        if let Err(e) = self.check_permission(Some(room), Action::Invite).await {
+            return self.write_error(e).await;
+        }
+
+        let ttl = self.config().invite_ttl_secs as usize;
+        let token = match room::invite(&self.redis, room, &target, ttl).await {
+            Ok(token) => token,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        let join = format!(">join-room {} {}", room, token);
+
+        // Sent as a DM so it reaches them even if they're offline
+        let invite = format!("You're invited to {}, join with {}", room, join);
+        match dm::event(&self.redis, user, &target, &invite).await {
+            Ok(msg) => {
+                self.deliver_dm(&target, &msg).await;
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+
+        self.write_all(&format!(
+            "Invited {}, they can join with {}\n",
+            target, join
+        ))
+        .await
+    }
+
+    // Whoever can invite decides, from inside the room. The owner is told
+    // if they're online, and anyone else finds it with >requests.
+    async fn request_join(&self, room: &str) -> io::Result<()> {
+        let user = self.user.username.as_ref().unwrap();
+
+        match room::request(&self.redis, room, user).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return self
+                    .write_all(&format!(
+                        "You've already asked to join {}, the owner hasn't decided yet\n",
+                        room
+                    ))
+                    .await
+            }
+            Err(e) => return self.write_error(e).await,
+        }
+
+        let owner = match room::owners(&self.redis, &[room]).await {
+            Ok(mut owners) => owners.pop().flatten(),
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        };
+        if let Some(owner) = owner {
+            if let Ok(stream) = dm::get_stream(&self.users, &owner).await {
+                let text = format!(
+                    "{} asked to join {}, >approve {} or >deny {} from inside it\n",
+                    user, room, user, user
+                );
+                let msg = Message::new(
+                    MessageKind::System,
+                    Some(room),
+                    Some(user),
+                    room::get_time_in_ms(),
+                    text,
+                );
+                if let Err(e) = stream.write_message(msg).await {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+
+        self.write_all(&format!(
+            "{} is invite only, so you've asked to be let in. You'll get a DM when it's decided\n",
+            room
+        ))
+        .await
+    }
+
+    async fn handle_requests(&self) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+
+        if let Err(e) = self.check_permission(Some(room), Action::Invite).await {
+            return self.write_error(e).await;
+        }
+
+        let requests = match room::requests(&self.redis, room).await {
+            Ok(requests) => requests,
+            Err(e) => return self.write_error(e).await,
+        };
+        if requests.is_empty() {
+            return self.write_all("Nobody is waiting to join\n").await;
+        }
+
+        let now = room::get_time_in_ms();
+        let list = requests
+            .into_iter()
+            .map(|(user, at)| format!("{} ({})", user, describe_ago(now - at)))
+            .collect();
+
+        self.write_list(list).await
+    }
+
+    // The requester hears either way, as a DM like invites so they see it
+    // even if they're offline. Only approvals are told to the room.
+    async fn handle_decide(&self, target: String, approve: bool) -> io::Result<()> {
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        if let Err(e) = self.check_permission(Some(room), Action::Invite).await {
+            return self.write_error(e).await;
+        }
+
+        if let Err(e) = room::decide(&self.redis, room, &target, approve).await {
+            return self.write_error(e).await;
+        }
+
+        let decision = match approve {
+            true => format!(
+                "You've been let into {}, join with >join-room {}",
+                room, room
+            ),
+            false => format!("Your request to join {} was turned down", room),
+        };
+        match dm::event(&self.redis, user, &target, &decision).await {
+            Ok(msg) => {
+                self.deliver_dm(&target, &msg).await;
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+
+        if !approve {
+            return self.write_all(&format!("Turned down {}\n", target)).await;
+        }
+
+        let event = RoomEvent::Approve(target);
+        let msg = match room::event(&self.redis, event, room, user, self.user.authenticated).await {
+            Ok(msg) => msg,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        if let Err(e) = tx.send(BrokerEvent::Post { msg }).await {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    // Nothing changes until they accept, so a room can't be pushed onto
+    // someone who doesn't want it
+    async fn handle_transfer_ownership(&self, target: String) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        if &target == user {
+            return self
+                .write_error_text(ErrorCode::InvalidArgument, "You already own it\n")
+                .await;
+        }
+
+        match account::exists(&self.redis, &target).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return self
+                    .write_error_text(
+                        ErrorCode::UserNotFound,
+                        &format!("There's no account called {}\n", target),
+                    )
+                    .await
+            }
+            Err(e) => return self.write_error(e).await,
+        }
+
+        if let Err(e) = room::offer(&self.redis, room, user, &target).await {
+            return self.write_error(e).await;
+        }
+
+        // As a DM like invites, so they see it even if they're offline
+        let offer = format!(
+            "{} is offering you {}, accept with >accept-ownership {}",
+            user, room, room
+        );
+        match dm::event(&self.redis, user, &target, &offer).await {
+            Ok(msg) => {
+                self.deliver_dm(&target, &msg).await;
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+
+        self.write_all(&format!(
+            "Offered {} to {}, it's yours until they accept\n",
+            room, target
+        ))
+        .await
+    }
+
+    async fn handle_accept_ownership(&self, room: String) -> io::Result<()> {
+        let user = match (self.user.authenticated, &self.user.username) {
+            (true, Some(user)) => user,
+            _ => return self.write_login_required().await,
+        };
+
+        let previous = match room::accept(&self.redis, &room, user).await {
+            Ok(previous) => previous,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        let accepted = format!("{} accepted {}, it's theirs now", user, room);
+        match dm::event(&self.redis, user, &previous, &accepted).await {
+            Ok(msg) => {
+                self.deliver_dm(&previous, &msg).await;
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+
+        self.write_all(&format!("You own {} now\n", room)).await
+    }
+
+    async fn handle_topic(&self, topic: String) -> io::Result<()> {
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        if let Err(e) = self.check_permission(Some(room), Action::Topic).await {
+            return self.write_error(e).await;
+        }
+
+        if let Err(e) = room::set_topic(&self.redis, room, &topic).await {
+            return self.write_error(e).await;
+        }
+
+        let event = RoomEvent::Topic(topic);
+        let msg = match room::event(&self.redis, event, room, user, self.user.authenticated).await {
+            Ok(msg) => msg,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        self.write_message(&msg).await?;
+
+        if let Err(e) = tx
+            .send(BrokerEvent::Message {
+                user: user.to_owned(),
+                msg,
+            })
+            .await
+        {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_lang(&self, change: Option<String>) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+
+        if let Some(tag) = change {
+            if let Err(e) = self.check_permission(Some(room), Action::Lang).await {
+                return self.write_error(e).await;
+            }
+
+            let lang = Some(tag.as_str()).filter(|&tag| tag != "off");
+            if let Err(e) = room::set_lang(&self.redis, room, lang).await {
+                return self.write_error(e).await;
+            }
+        }
+
+        match room::lang(&self.redis, room).await {
+            Ok(Some(lang)) => self.write_all(&format!("Language: {}\n", lang)).await,
+            Ok(None) => self.write_all("No language set\n").await,
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_disappearing(&self, secs: Option<u64>) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+
+        if let Some(secs) = secs {
+            if let Err(e) = self.check_permission(Some(room), Action::Retention).await {
+                return self.write_error(e).await;
+            }
+
+            if let Err(e) = room::set_disappearing(&self.redis, room, secs).await {
+                return self.write_error(e).await;
+            }
+        }
+
+        match room::disappearing(&self.redis, room).await {
+            Ok(secs) => {
+                self.write_all(&retention::describe_disappearing(secs))
+                    .await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_retention(&self, change: Option<(Limit, Setting)>) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+
+        if let Some((limit, setting)) = change {
+            if let Err(e) = self.check_permission(Some(room), Action::Retention).await {
+                return self.write_error(e).await;
+            }
+
+            if let Err(e) = room::set_retention(&self.redis, room, limit, setting).await {
+                return self.write_error(e).await;
+            }
+        }
+
+        // Anyone in the room can see what's kept
+        let (messages, age_secs) = match room::retention(&self.redis, room).await {
+            Ok(overrides) => overrides,
+            Err(e) => return self.write_error(e).await,
+        };
+        let policy = self.config().retention.with_overrides(messages, age_secs);
+
+        self.write_all(&policy.describe()).await
+    }
+
+    // Webhook urls and bot tokens can be secrets, so only the owner sees them
+    async fn handle_webhook(&self, command: WebhookCommand) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+
+        if let Err(e) = self.check_permission(Some(room), Action::Webhook).await {
+            return self.write_error(e).await;
+        }
+
+        let result = match command {
+            WebhookCommand::Add(url) => webhook::add(&self.redis, room, &url)
+                .await
+                .map(|()| format!("Messages in {} will be sent to {}\n", room, url)),
+            WebhookCommand::Remove(url) => webhook::remove(&self.redis, room, &url)
+                .await
+                .map(|()| format!("Removed webhook {}\n", url)),
+            WebhookCommand::List => self.list_webhooks(room).await,
+            WebhookCommand::Bot(name) => {
+                webhook::add_bot(&self.redis, room, &name)
+                    .await
+                    .map(|token| {
+                        format!(
+                            "{} can post to {} with the token {}\n",
+                            webhook::bot_username(&name),
+                            room,
+                            token
+                        )
+                    })
+            }
+            WebhookCommand::RemoveBot(name) => webhook::remove_bot(&self.redis, room, &name)
+                .await
+                .map(|()| format!("Revoked every token for {}\n", webhook::bot_username(&name))),
+        };
+
+        match result {
+            Ok(reply) => self.write_all(&reply).await,
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn list_webhooks(&self, room: &str) -> Result<String, webhook::WebhookError> {
+        let urls = webhook::urls(&self.redis, room).await?;
+        let mut bots: Vec<String> = webhook::bots(&self.redis, room)
+            .await?
+            .into_values()
+            .map(|name| webhook::bot_username(&name))
+            .collect();
+        bots.sort();
+        bots.dedup();
+
+        let mut reply = String::new();
+        match urls.is_empty() {
+            true => reply.push_str("No webhooks\n"),
+            false => reply.push_str(&format!("Webhooks: {}\n", urls.join(", "))),
+        }
+        match bots.is_empty() {
+            true => reply.push_str("No bots\n"),
+            false => reply.push_str(&format!("Bots: {}\n", bots.join(", "))),
+        }
+
+        Ok(reply)
+    }
+
+    async fn handle_paste(&mut self) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+
+        // Pastes are stored as they're sent
+        if self.state.encrypted.contains(room) {
+            return self
+                .write_error_text(
+                    ErrorCode::InvalidArgument,
+                    "Pastes can't be encrypted, send the text as a message instead\n",
+                )
+                .await;
+        }
+
+        self.paste = Some(Paste::default());
+
+        self.write_all(&format!(
+            "Pasting, send {} on its own line to finish\n",
+            paste::SENTINEL
+        ))
+        .await
+    }
+
+    async fn handle_paste_line(&mut self, line: String) -> io::Result<()> {
+        if line == paste::SENTINEL {
+            let paste = self.paste.take().unwrap_or_default();
+
+            if paste.too_large {
+                return self.write_error(paste::PasteError::TooLarge).await;
+            }
+
+            return self.finish_paste(paste.content).await;
+        }
+
+        let paste = self.paste.get_or_insert_with(Paste::default);
+        if paste.too_large {
+            return Ok(());
+        }
+
+        paste.content.push_str(&line);
+        paste.content.push('\n');
+
+        if paste.content.len() > paste::MAX_SIZE {
+            paste.content.clear();
+            paste.too_large = true;
+        }
+
+        Ok(())
+    }
+
+    async fn finish_paste(&self, content: String) -> io::Result<()> {
+        // Could have been kicked while pasting
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        let content = match self.filters.run(user, room, content).await {
+            Ok(content) => content,
+            Err(reason) => {
+                return self
+                    .write_error_text(ErrorCode::Filtered, &format!("{}\n", reason))
+                    .await
+            }
+        };
+
+        let ttl = self.config().paste_ttl_secs as usize;
+        let id = match paste::save(&self.redis, &content, ttl).await {
+            Ok(id) => id,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        let lines = content.lines().count();
+        let msg = format!("shared a paste, >fetch {} ({} lines)", id, lines);
+
+        self.send_message(tx, room, msg, RoomEvent::Chat).await?;
+
+        self.write_all(&format!("Shared as {}\n", id)).await
+    }
+
+    async fn handle_fetch(&self, id: String) -> io::Result<()> {
+        match paste::fetch(&self.redis, &id).await {
+            Ok(content) => self.write_all(&content).await,
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    async fn handle_edit(&self, id: String, text: String) -> io::Result<()> {
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        // Edits shouldn't be a way around the filters
+        let text = match self.check_body(room, text).await? {
+            Some(text) => text,
+            None => return Ok(()),
+        };
+
+        let msg = match room::edit(&self.redis, room, &id, user, text).await {
+            Ok(msg) => msg,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        self.write_message(&msg).await?;
+
+        if let Err(e) = tx
+            .send(BrokerEvent::Message {
+                user: user.to_owned(),
+                msg,
+            })
+            .await
+        {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_delete(&self, id: String) -> io::Result<()> {
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        let msg = match room::delete_msg(&self.redis, room, &id, user).await {
+            Ok(msg) => msg,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        self.write_message(&msg).await?;
+
+        if let Err(e) = tx
+            .send(BrokerEvent::Message {
+                user: user.to_owned(),
+                msg,
+            })
+            .await
+        {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_react(&self, id: String, emoji: String) -> io::Result<()> {
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        if let Err(e) = self.check_permission(Some(room), Action::Send).await {
+            return self.write_error(e).await;
+        }
+
+        let msg = match room::react(&self.redis, room, &id, user, &emoji).await {
+            Ok(msg) => msg,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        self.write_message(&msg).await?;
+
+        if let Err(e) = tx
+            .send(BrokerEvent::Message {
+                user: user.to_owned(),
+                msg,
+            })
+            .await
+        {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_delete_room(&self, room_map: &RoomMap) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        if let Err(e) = self.check_permission(Some(room), Action::DeleteRoom).await {
+            return self.write_error(e).await;
+        }
+
+        if let Err(e) = room::delete(&self.redis, room).await {
+            return self.write_error(e).await;
+        }
+
+        let msg = Message::new(
+            MessageKind::System,
+            Some(room),
+            Some(user),
+            room::get_time_in_ms(),
+            format!("{} deleted the room\n", user),
+        );
+
+        // Everyone inside, including us, gets removed by the broker
+        broker::close(room, room_map, msg).await;
+
+        Ok(())
+    }
+
+    async fn handle_rename_room(&self, to: String, room_map: &RoomMap) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+        let user = self.user.username.as_ref().unwrap();
+
+        if let Err(e) = self.check_permission(Some(room), Action::RenameRoom).await {
+            return self.write_error(e).await;
+        }
+
+        // Renaming into a namespace is the same as creating a room there
+        if let Some(namespace) = namespace::of(&to) {
+            if let Err(e) = namespace::check_create(&self.redis, namespace, user).await {
+                return self.write_error(e).await;
+            }
+        }
+
+        if let Err(e) = room::rename(&self.redis, room, &to).await {
+            return self.write_error(e).await;
+        }
+
+        // Saved under the new name, since that's where the history is now
+        let event = RoomEvent::RoomRename(room.to_owned());
+        let msg = match room::event(&self.redis, event, &to, user, true).await {
+            Ok(msg) => msg,
+            Err(e) => {
+                eprintln!("{}", e);
+
+                Message::new(
+                    MessageKind::RoomRename,
+                    Some(&to),
+                    Some(user),
+                    room::get_time_in_ms(),
+                    room.to_owned(),
+                )
+            }
+        };
+
+        // Everyone inside, including us, follows it to the new name
+        broker::rename(room, &to, room_map, msg).await;
+
+        Ok(())
+    }
+
+    async fn handle_history(
+        &self,
+        count: usize,
+        before: Option<isize>,
+        kinds: Kinds,
+    ) -> io::Result<()> {
+        let room = match self.state.active() {
+            Some((room, _)) => room,
+            None => return self.write_not_in_room().await,
+        };
+
+        let count = count.min(MAX_HISTORY);
+
+        match room::history(&self.redis, room, count, before, &kinds).await {
+            Ok(msgs) => self.write_messages(msgs).await?,
+            Err(e) => self.write_error(e).await?,
+        }
+
+        Ok(())
+    }
+
+    // Rooms you're in, or could join without a password or invite
+    async fn handle_digest(&self, room: String, period: u64) -> io::Result<()> {
+        let user = match &self.user.username {
+            Some(user) => user,
+            None => return self.write_login_required().await,
+        };
+
+        if !self.state.joined.contains_key(&room) {
+            match room::exists(&self.redis, &room).await {
+                Ok(true) => {}
+                Ok(false) => return self.write_room_not_found().await,
+                Err(e) => return self.write_error(e).await,
+            }
+
+            if let Err(e) = room::check_banned(&self.redis, &room, user).await {
+                return self.write_error(e).await;
+            }
+
+            if let Err(e) = room::check_invited(&self.redis, &room, user, None).await {
+                return self.write_error(e).await;
+            }
+
+            if let Err(e) = room::check_password(&self.redis, &room, None).await {
+                return self.write_error(e).await;
+            }
+        }
+
+        let now = room::get_time_in_ms();
+        let after = now - (period * 1000) as isize;
+
+        let digest = match room::digest(&self.redis, &room, after).await {
+            Ok(digest) => digest,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        let mut lines = vec![format!(
+            "{} in the last {}: {} messages",
+            room,
+            describe_period(period),
+            digest.messages
+        )];
+
+        if let (Some(first), Some(last)) = (digest.first, digest.last) {
+            lines.push(format!(
+                "First {}, last {}",
+                describe_ago(now - first),
+                describe_ago(now - last)
+            ));
+        }
+
+        if !digest.authors.is_empty() {
+            let authors: Vec<String> = digest
+                .authors
+                .iter()
+                .take(DIGEST_AUTHORS)
+                .map(|(author, count)| format!("{} ({})", author, count))
+                .collect();
+            lines.push(format!("Most active: {}", authors.join(", ")));
+        }
+
+        self.write_list(lines).await
+    }
+
+    // Only counts, so like >list anyone can see them
+    async fn handle_stats(&self, room: String) -> io::Result<()> {
+        match room::exists(&self.redis, &room).await {
+            Ok(true) => {}
+            Ok(false) => return self.write_room_not_found().await,
+            Err(e) => return self.write_error(e).await,
+        }
+
+        match stats::get(&self.redis, &room).await {
+            Ok(stats) => {
+                self.write_all(&format!(
+                    "{}: {} message(s) from {} participant(s), at most {} member(s) at once\n",
+                    room, stats.messages, stats.participants, stats.peak_members
+                ))
+                .await
+            }
+            Err(e) => self.write_error(e).await,
+        }
+    }
+
+    // Streams the room's history as `export` messages, a page at a time,
+    // between a line saying what's coming and one with how many there were
+    async fn handle_export(&self, room: String, format: Format) -> io::Result<()> {
+        match room::exists(&self.redis, &room).await {
+            Ok(true) => {}
+            Ok(false) => return self.write_room_not_found().await,
+            Err(e) => return self.write_error(e).await,
+        }
+
+        if let Err(e) = self.check_permission(Some(&room), Action::Export).await {
+            return self.write_error(e).await;
+        }
+
+        self.write_all(&format!("Exporting {} as {}\n", room, format.extension()))
+            .await?;
+
+        let mut export = Export::new(&room, format);
+        loop {
+            let lines = match export.next_page(&self.redis).await {
+                Ok(Some(lines)) => lines,
+                Ok(None) => break,
+                Err(e) => return self.write_error(e).await,
+            };
+
+            let msgs = lines
+                .into_iter()
+                .map(|line| {
+                    let line = format!("{}\n", line);
+                    let now = room::get_time_in_ms();
+                    Message::new(MessageKind::Export, Some(&room), None, now, line)
+                })
+                .collect();
+            self.write_messages(msgs).await?;
+        }
+
+        self.write_all(&format!(
+            "Exported {} messages from {}\n",
+            export.count, room
+        ))
+        .await
+    }
+
+    async fn handle_message(&mut self, msg: String) -> io::Result<()> {
+        match self.state.active() {
+            Some((room, tx)) => self.send_message(tx, room, msg, RoomEvent::Chat).await?,
+            None => self.write_not_in_room().await?,
+        }
+        Ok(())
+    }
+
+    async fn handle_reply(&mut self, parent: String, msg: String) -> io::Result<()> {
+        match self.state.active() {
+            Some((room, tx)) => {
+                self.send_message(tx, room, msg, |msg| RoomEvent::Reply(parent, msg))
+                    .await?
+            }
+            None => self.write_not_in_room().await?,
+        }
+        Ok(())
+    }
+
+    async fn handle_signed(
+        &mut self,
+        timestamp: isize,
+        signature: String,
+        text: String,
+    ) -> io::Result<()> {
+        let (room, tx) = match self.state.active() {
+            Some(active) => active,
+            None => return self.write_not_in_room().await,
+        };
+
+        if !self.user.authenticated {
+            return self.write_login_required().await;
+        }
+        let user = self.user.username.as_ref().unwrap();
+
+        if let Err(e) = signing::verify(&self.redis, user, room, timestamp, &signature, &text).await
+        {
+            return self.write_error(e).await;
+        }
+
+        // Only marked if the filters left it as it was signed
+        self.send_message(tx, room, text.clone(), |msg| match msg == text {
+            true => RoomEvent::Signed(msg),
+            false => RoomEvent::Chat(msg),
+        })
+        .await
+    }
 
-                    broker::spawn_broker(room, &room_map).await;
-                }
-                Command::JoinRoom(room) => {
-                    if self.user.username.is_none() {
-                        self.write_set_username().await?;
-                        continue;
+    async fn handle_join(
+        &mut self,
+        stream: WriterHandle,
+        new_room: String,
+        password: Option<String>,
+        replay: Replay,
+        room_map: &RoomMap,
+    ) -> io::Result<()> {
+        // Already listening to it, so just make it the active room
+        if self.state.joined.contains_key(&new_room) {
+            return self.handle_switch(new_room).await;
+        }
+
+        // Made for them instead of not being found, with the password they
+        // gave if any
+        if self.config().auto_create_rooms {
+            match room::exists(&self.redis, &new_room).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    // Checked here since joins don't check names otherwise
+                    if let Err(e) = validate::room_name(&new_room) {
+                        return self.write_error(e).await;
                     }
 
-                    self.handle_join(Arc::clone(&stream), room.clone(), &room_map)
+                    let created = self
+                        .create_room(
+                            new_room.clone(),
+                            password.clone(),
+                            Options::default(),
+                            room_map,
+                        )
                         .await?;
+                    if !created {
+                        return Ok(());
+                    }
                 }
-                Command::Message(msg) => {
-                    self.handle_message(msg).await?;
-                }
-                Command::Leave => {
-                    self.handle_leave().await?;
-                }
-                Command::Invalid => {
-                    self.write_invalid().await?;
-                }
-                Command::Exit => break,
+                Err(e) => return self.write_error(e).await,
             }
         }
 
-        Ok(())
-    }
+        let user = self.user.username.as_ref().unwrap();
 
-    async fn write_user_info(&self) -> io::Result<()> {
-        let info = format!(
-            "Username: {:?}, IP: {}\n",
-            self.user.username, self.user.addr
-        );
+        if let Err(e) = room::check_banned(&self.redis, &new_room, user).await {
+            self.write_error(e).await?;
+            return Ok(());
+        }
 
-        self.write_all(info.as_bytes()).await?;
+        // Private rooms take an invite token where other rooms take a password
+        match room::check_invited(&self.redis, &new_room, user, password.as_deref()).await {
+            Ok(()) => {}
+            // Without one, accounts can ask to be let in instead
+            Err(RoomError::NotInvited) if password.is_none() && self.user.authenticated => {
+                return self.request_join(&new_room).await;
+            }
+            Err(e) => return self.write_error(e).await,
+        }
 
-        Ok(())
+        if let Err(e) = room::check_password(&self.redis, &new_room, password.as_deref()).await {
+            self.write_error(e).await?;
+            return Ok(());
+        }
+
+        self.enter(stream, room_map, new_room, replay).await
     }
 
-    async fn handle_message(&mut self, msg: String) -> io::Result<()> {
-        match &self.state {
-            State::Inside { room, tx } => self.send_message(tx, room, msg).await?,
-            State::Outside => self.write_not_in_room().await?,
+    // For >create-room, and joining a room that doesn't exist when
+    // `auto_create_rooms` is on. Whether it was made.
+    async fn create_room(
+        &self,
+        room: String,
+        password: Option<String>,
+        options: Options,
+        room_map: &RoomMap,
+    ) -> io::Result<bool> {
+        if let Err(e) = self.check_room_limit().await {
+            self.write_error(e).await?;
+            return Ok(false);
         }
-        Ok(())
+
+        let owner = self.user.username.as_ref().unwrap();
+        if let Some(namespace) = namespace::of(&room) {
+            if let Err(e) = namespace::check_create(&self.redis, namespace, owner).await {
+                self.write_error(e).await?;
+                return Ok(false);
+            }
+        }
+
+        if let Err(e) = room::new(&self.redis, &room, owner, password.as_deref(), options).await {
+            self.write_error(e).await?;
+            return Ok(false);
+        };
+
+        broker::spawn_broker(
+            &self.redis,
+            room,
+            room_map,
+            self.fanout(),
+            self.receipts(),
+            self.config().broker_shards,
+        )
+        .await;
+
+        Ok(true)
     }
 
-    async fn handle_join(
+    // Joins a room that's passed every check
+    async fn enter(
         &mut self,
-        stream: SharedStream,
-        new_room: String,
+        stream: WriterHandle,
         room_map: &RoomMap,
+        new_room: String,
+        replay: Replay,
     ) -> io::Result<()> {
-        match &self.state {
-            State::Inside { room, tx } => {
-                self.leave_room(tx, room).await?;
+        let encrypted = match room::is_encrypted(&self.redis, &new_room).await {
+            Ok(encrypted) => encrypted,
+            Err(e) => return self.write_error(e).await,
+        };
 
-                if let Some(tx) = self.join_room(stream, room_map, &new_room).await? {
-                    // Update state
-                    self.state = State::Inside { room: new_room, tx }
-                };
-            }
-            State::Outside => {
-                if let Some(tx) = self.join_room(stream, &room_map, &new_room).await? {
-                    // Update state
-                    self.state = State::Inside { room: new_room, tx }
+        // Make it active before history is written so it isn't prefixed
+        let previous = self.state.active.replace(new_room.clone());
+        self.sync_active().await;
+
+        match self.join_room(stream, room_map, &new_room, replay).await? {
+            Some(tx) => {
+                self.mark_read(&new_room).await;
+
+                if encrypted {
+                    self.write_error_text(
+                        ErrorCode::InvalidArgument,
+                        "This room is end-to-end encrypted, use a client that encrypts messages\n",
+                    )
+                    .await?;
+                    self.state.encrypted.insert(new_room.clone());
                 }
+
+                self.state.joined.insert(new_room, tx);
+            }
+            None => {
+                self.state.active = previous;
+                self.sync_active().await;
             }
         }
 
         Ok(())
     }
 
+    async fn handle_switch(&mut self, room: String) -> io::Result<()> {
+        if !self.state.joined.contains_key(&room) {
+            return self.write_not_in_room().await;
+        }
+
+        self.state.active = Some(room);
+        self.sync_active().await;
+
+        Ok(())
+    }
+
     async fn handle_leave(&mut self) -> io::Result<()> {
-        match &self.state {
-            State::Inside { room, tx } => {
-                self.leave_room(tx, room).await?;
+        let room = match self.state.active() {
+            Some((room, _)) => room.to_owned(),
+            None => return self.write_not_in_room().await,
+        };
 
-                // Update state
-                self.state = State::Outside
-            }
-            State::Outside => self.write_not_in_room().await?,
+        self.leave(&room).await
+    }
+
+    async fn leave_all(&mut self) -> io::Result<()> {
+        let rooms: Vec<String> = self.state.joined.keys().cloned().collect();
+
+        for room in rooms {
+            self.leave(&room).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn leave(&mut self, room: &str) -> io::Result<()> {
+        if let Some(tx) = self.state.remove(room) {
+            self.leave_room(&tx, room).await?;
+            self.mark_read(room).await;
         }
 
+        self.sync_active().await;
+
         Ok(())
     }
 
+    // Lets the writer know which room to leave unprefixed
+    async fn sync_active(&self) {
+        if let Err(e) = self.stream.set_active_room(self.state.active.clone()).await {
+            eprintln!("{}", e);
+        }
+    }
+
     async fn send_message(
         &self,
         tx: &Sender<BrokerEvent>,
-        room: &String,
+        room: &str,
         msg: String,
+        // Given the body once it's been through the filters
+        to_event: impl FnOnce(String) -> RoomEvent,
     ) -> io::Result<()> {
         let user = self.user.username.as_ref().unwrap();
 
+        let msg = match self.check_body(room, msg).await? {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
+
+        if let Err(e) = room::check_muted(&self.redis, room, user).await {
+            return self.write_error(e).await;
+        }
+
+        if let Err(e) = self.check_permission(Some(room), Action::Send).await {
+            return self.write_error(e).await;
+        }
+
+        if let Some(reason) = self.detect_spam(room, &msg) {
+            let secs = self.config().spam_mute_secs;
+
+            if let Err(e) = room::mute(&self.redis, room, user, secs).await {
+                return self.write_error(e).await;
+            }
+
+            // An error, since the message that tripped it wasn't sent
+            let muted = format!(
+                "You've been muted in {} for {} seconds for {}\n",
+                room, secs, reason
+            );
+            return self.write_error_text(ErrorCode::Muted, &muted).await;
+        }
+
+        let event = to_event(msg);
+
         let msg = match room::event(
             &self.redis,
-            RoomEvent::Chat(msg),
+            event,
             room,
             self.user.username.as_ref().unwrap(),
+            self.user.authenticated,
         )
         .await
         {
@@ -188,6 +3435,22 @@ impl App {
             }
         };
 
+        self.notify_mentions(&msg).await;
+
+        // Only asked for by enveloped lines, and the id is missing if Redis
+        // was down and it's only been buffered
+        if self.reference.is_some() {
+            self.write_message(&Message::ack(&msg)).await?;
+        }
+
+        // Chat isn't echoed back, so this is the only way to learn the id
+        if self.ids {
+            if let Some(id) = &msg.id {
+                self.write_message(&Message::system("Sent\n").with_id(id.clone()))
+                    .await?;
+            }
+        }
+
         // Send broker event
         if let Err(e) = tx
             .send(BrokerEvent::Message {
@@ -197,26 +3460,142 @@ impl App {
             .await
         {
             self.write_error(e).await?;
+            return Ok(());
         }
 
+        self.metrics.message_sent();
+
         Ok(())
     }
 
+    // Why the message looks like spam, if it does and detection is on
+    fn detect_spam(&self, room: &str, body: &str) -> Option<spam::Reason> {
+        if self.config().spam_mute_secs == 0 {
+            return None;
+        }
+
+        self.state
+            .spam
+            .lock()
+            .unwrap()
+            .entry(room.to_owned())
+            .or_default()
+            .check(body, room::get_time_in_ms())
+    }
+
+    // Runs the message filters then the room's language hooks, or in
+    // encrypted rooms, which they can't read, checks the body is
+    // ciphertext. Writes why if it's rejected.
+    async fn check_body(&self, room: &str, body: String) -> io::Result<Option<String>> {
+        if self.state.encrypted.contains(room) {
+            return match validate::ciphertext(&body) {
+                Ok(()) => Ok(Some(body)),
+                Err(e) => self.write_error(e).await.map(|()| None),
+            };
+        }
+
+        let user = self.user.username.as_ref().unwrap();
+
+        let body = match self.filters.run(user, room, body).await {
+            Ok(body) => body,
+            Err(reason) => {
+                self.write_error_text(ErrorCode::Filtered, &format!("{}\n", reason))
+                    .await?;
+                return Ok(None);
+            }
+        };
+
+        match room::lang(&self.redis, room).await {
+            Ok(lang) => Ok(Some(self.filters.transform(lang.as_deref(), room, body))),
+            Err(e) => self.write_error(e).await.map(|()| None),
+        }
+    }
+
+    // Sends a separate notice to anyone @mentioned who is connected
+    async fn notify_mentions(&self, msg: &Message) {
+        let user = self.user.username.as_ref().unwrap();
+
+        for mentioned in Message::mentions(&msg.body) {
+            if mentioned == user {
+                continue;
+            }
+
+            if let Ok(true) | Err(_) = block::is_blocked(&self.redis, user, mentioned).await {
+                continue;
+            }
+
+            let stream = match dm::get_stream(&self.users, mentioned).await {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            let mut mention = msg.clone();
+            mention.kind = MessageKind::Mention;
+
+            if let Err(e) = stream.write_message(mention).await {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    async fn mark_read(&self, room: &str) {
+        let user = self.user.username.as_ref().unwrap();
+
+        if let Err(e) = room::mark_read(&self.redis, room, user).await {
+            eprintln!("{}", e);
+        }
+    }
+
+    async fn handle_unread(&self) -> io::Result<()> {
+        if !self.user.authenticated {
+            return self.write_login_required().await;
+        }
+        let user = self.user.username.as_ref().unwrap();
+
+        let unread = match room::unread(&self.redis, user).await {
+            Ok(unread) => unread,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        // Rooms they're in are being read as messages arrive
+        let list = unread
+            .into_iter()
+            .filter(|(room, count)| *count > 0 && !self.state.joined.contains_key(room))
+            .map(|(room, count)| format!("{}: {} unread", room, room::describe_unread(count)))
+            .collect();
+
+        self.write_list(list).await
+    }
+
     async fn join_room(
         &self,
-        stream: SharedStream,
+        stream: WriterHandle,
         room_map: &RoomMap,
-        room: &String,
+        room: &str,
+        replay: Replay,
     ) -> io::Result<Option<Sender<BrokerEvent>>> {
-        let room_map = room_map.read().await;
         let user = self.user.username.as_ref().unwrap();
 
         // Get new rooms tx
-        let tx = match room_map.get(room) {
-            Some(tx) => tx.clone(),
-            None => {
+        let tx = match broker::get_or_spawn(
+            &self.redis,
+            room,
+            room_map,
+            self.fanout(),
+            self.receipts(),
+            self.config().broker_shards,
+        )
+        .await
+        {
+            Ok(Some(tx)) => tx,
+            Ok(None) => {
                 self.write_room_not_found().await?;
 
+                return Ok(None);
+            }
+            Err(e) => {
+                self.write_error(e).await?;
+
                 return Ok(None);
             }
         };
@@ -225,8 +3604,9 @@ impl App {
         let join_msg = match room::event(
             &self.redis,
             RoomEvent::Join,
-            &room,
+            room,
             self.user.username.as_ref().unwrap(),
+            self.user.authenticated,
         )
         .await
         {
@@ -242,7 +3622,10 @@ impl App {
         if let Err(e) = tx
             .send(BrokerEvent::JoinRoom {
                 user: user.to_owned(),
-                stream: Arc::clone(&stream),
+                stream: stream.clone(),
+                changes: self.changes_tx.clone(),
+                blocked: Arc::clone(&self.blocked),
+                quiet: Arc::clone(&self.quiet),
                 msg: join_msg,
             })
             .await
@@ -252,8 +3635,15 @@ impl App {
             return Ok(None);
         };
 
-        // Write recent messages
-        let recent_msgs = match room::recent_msgs(&self.redis, &room).await {
+        // Write recent messages, or the ones missed while disconnected
+        let recent_msgs = match replay {
+            Replay::Since(since) => room::since(&self.redis, room, since, MAX_REPLAY).await,
+            Replay::Recent(0) => Ok(Vec::new()),
+            Replay::Recent(count) => {
+                room::recent_msgs(&self.redis, room, count, &self.history_types).await
+            }
+        };
+        let recent_msgs = match recent_msgs {
             Ok(m) => m,
             Err(e) => {
                 self.write_error(e).await?;
@@ -262,12 +3652,109 @@ impl App {
                 return Ok(Some(tx));
             }
         };
-        self.write_list(recent_msgs, false).await?;
+        let truncated = matches!(replay, Replay::Since(_)) && recent_msgs.len() == MAX_REPLAY;
+
+        // In the same write as what it's above, oldest first as they were sent
+        if !recent_msgs.is_empty() {
+            let what = match replay {
+                Replay::Since(_) => "missed",
+                Replay::Recent(_) => "earlier",
+            };
+            let separator = Message::system(&room::separator(recent_msgs.len(), what));
+
+            let mut msgs = vec![separator];
+            msgs.extend(recent_msgs);
+            self.write_messages(msgs).await?;
+        }
+
+        if truncated {
+            self.write_all("More was missed than can be replayed, see >history\n")
+                .await?;
+        }
+
+        match room::topic(&self.redis, room).await {
+            Ok(Some(topic)) => self.write_all(&format!("Topic: {}\n", topic)).await?,
+            Ok(None) => {}
+            Err(e) => self.write_error(e).await?,
+        }
+
+        match room::lang(&self.redis, room).await {
+            Ok(Some(lang)) => self.write_all(&format!("Language: {}\n", lang)).await?,
+            Ok(None) => {}
+            Err(e) => self.write_error(e).await?,
+        }
+
+        match room::disappearing(&self.redis, room).await {
+            Ok(Some(secs)) => {
+                self.write_all(&retention::describe_disappearing(Some(secs)))
+                    .await?
+            }
+            Ok(None) => {}
+            Err(e) => self.write_error(e).await?,
+        }
+
+        // Picking a session back up isn't joining again
+        if let Replay::Recent(_) = replay {
+            for (plugin, response) in self.plugins.on_join(user, room).await {
+                self.handle_plugin_response(&plugin, Some((room, &tx)), response)
+                    .await?;
+            }
+        }
 
         Ok(Some(tx))
     }
 
-    async fn leave_room(&self, tx: &Sender<BrokerEvent>, room: &String) -> io::Result<()> {
+    // Whether a plugin answered the command
+    async fn handle_plugin_command(&self, line: &str) -> io::Result<bool> {
+        let user = match &self.user.username {
+            Some(user) => user,
+            None => return Ok(false),
+        };
+        let active = self.state.active();
+
+        let room = active.map(|(room, _)| room);
+        let (plugin, response) = match self.plugins.on_command(user, room, line).await {
+            Some(answer) => answer,
+            None => return Ok(false),
+        };
+
+        self.handle_plugin_response(&plugin, active, response)
+            .await?;
+
+        Ok(true)
+    }
+
+    // Writes a plugin's reply to this user, and has it say anything it
+    // wants to in `room`, saved like anyone else's message
+    async fn handle_plugin_response(
+        &self,
+        plugin: &str,
+        room: Option<(&str, &Sender<BrokerEvent>)>,
+        response: Response,
+    ) -> io::Result<()> {
+        if let Some(reply) = response.reply {
+            self.write_message(&Message::system(&format!("{}\n", reply)))
+                .await?;
+        }
+
+        let (say, (room, tx)) = match (response.say, room) {
+            (Some(say), Some(room)) => (say, room),
+            _ => return Ok(()),
+        };
+
+        let msg = match room::event(&self.redis, RoomEvent::Chat(say), room, plugin, true).await {
+            Ok(msg) => msg,
+            Err(e) => return self.write_error(e).await,
+        };
+
+        if let Err(e) = tx.send(BrokerEvent::Post { msg }).await {
+            self.write_error(e).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn leave_room(&self, tx: &Sender<BrokerEvent>, room: &str) -> io::Result<()> {
         let user = self.user.username.as_ref().unwrap();
 
         // Leave msg
@@ -276,6 +3763,7 @@ impl App {
             RoomEvent::Leave,
             room,
             self.user.username.as_ref().unwrap(),
+            self.user.authenticated,
         )
         .await
         {
@@ -286,6 +3774,8 @@ impl App {
             }
         };
 
+        self.forget_alias(room).await;
+
         // Send broker event
         if let Err(e) = tx
             .send(BrokerEvent::LeaveRoom {
@@ -300,84 +3790,228 @@ impl App {
         Ok(())
     }
 
+    async fn forget_alias(&self, room: &str) {
+        let user = self.user.username.as_ref().unwrap();
+
+        if let Err(e) = room::remove_alias(&self.redis, room, user).await {
+            eprintln!("{}", e);
+        }
+    }
+
+    async fn handle_pow(&mut self, answer: String) -> io::Result<()> {
+        match &self.challenge {
+            None => self.write_all("There's no challenge to answer\n").await,
+            Some(challenge) if challenge.check(&answer) => {
+                self.challenge = None;
+                self.write_all("Challenge solved\n").await
+            }
+            Some(_) => {
+                self.write_error_text(
+                    ErrorCode::InvalidArgument,
+                    "That doesn't solve the challenge\n",
+                )
+                .await
+            }
+        }
+    }
+
+    // The message of the day from >set-motd, or the config's if it hasn't
+    // been set or Redis can't be reached
     async fn write_greeting(&self) -> io::Result<()> {
-        let greeting = b"Welcome to ChatsApp!
-Enter \">help\" for a list of commands and their usage.\n\n\n";
+        let motd = match motd::get(&self.redis).await {
+            Ok(motd) => motd,
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        };
+        let config = self.config.get();
+        let motd = motd.as_deref().unwrap_or(&config.motd);
+
+        // Before the help line, which clients take as the end of the greeting
+        let challenge = self
+            .challenge
+            .as_ref()
+            .map(Challenge::describe)
+            .unwrap_or_default();
+
+        self.write_all(&format!(
+            "{}\n{}Enter \">help\" for a list of commands and their usage.\n\n\n",
+            motd, challenge
+        ))
+        .await
+    }
 
-        self.write_all(greeting).await?;
+    async fn write_recent_announcements(&self) -> io::Result<()> {
+        let window = Duration::from_secs(self.config().announcement_window_secs);
+
+        match announce::recent(&self.redis, window).await {
+            Ok(announcements) => {
+                for announcement in announcements {
+                    self.write_message(&announcement.to_message()).await?;
+                }
+            }
+            Err(e) => eprintln!("{}", e),
+        }
 
         Ok(())
     }
 
     async fn write_invalid(&self) -> io::Result<()> {
-        let invalid = b"Invalid command.
+        let invalid = "Invalid command.
 Enter \">help\" for a list of commands and their usage.\n";
 
-        self.write_all(invalid).await?;
+        self.write_error_text(ErrorCode::InvalidCommand, invalid)
+            .await?;
 
         Ok(())
     }
 
     async fn write_help(&self) -> io::Result<()> {
-        let help = b"\
-Commands:
->help              - Display commands
->exit              - Close connection
->list              - List rooms
->me                - Your user info
->set-username name - Set username
->create-room room  - Create room
->join-room room    - Join room\n";
-
-        self.write_all(help).await?;
+        self.write_all(&command::help()).await?;
 
         Ok(())
     }
 
-    async fn write_list(&self, list: Vec<String>, new_line: bool) -> io::Result<()> {
+    async fn write_list(&self, list: Vec<String>) -> io::Result<()> {
         let mut res = String::new();
 
         for item in list {
             res.push_str(&item);
-            if new_line {
-                res.push_str("\n");
-            }
+            res.push('\n');
         }
 
-        self.write_all(res.as_bytes()).await?;
+        self.write_all(&res).await?;
 
         Ok(())
     }
 
-    async fn write_error(&self, error: impl std::error::Error) -> io::Result<()> {
-        self.write_all(error.to_string().as_bytes()).await?;
+    async fn write_error(&self, error: impl std::error::Error + Coded) -> io::Result<()> {
+        self.write_error_text(error.code(), &error.to_string())
+            .await
+    }
 
-        Ok(())
+    // For errors that aren't one of a module's
+    async fn write_error_text(&self, code: ErrorCode, text: &str) -> io::Result<()> {
+        self.write_message(&Message::error(code, text)).await
     }
 
     async fn write_not_in_room(&self) -> io::Result<()> {
-        self.write_all(b"You're not currently in a room.\n").await?;
+        self.write_error_text(ErrorCode::NotInRoom, "You're not currently in a room.\n")
+            .await?;
+
+        Ok(())
+    }
+
+    async fn write_user_not_in_room(&self) -> io::Result<()> {
+        self.write_error_text(ErrorCode::UserNotFound, "That user isn't in this room\n")
+            .await?;
 
         Ok(())
     }
 
     async fn write_room_not_found(&self) -> io::Result<()> {
-        self.write_all(b"Room not found\n").await?;
+        self.write_error_text(ErrorCode::RoomNotFound, "Room not found\n")
+            .await?;
 
         Ok(())
     }
 
-    async fn write_set_username(&self) -> io::Result<()> {
-        self.write_all(b"You need to pick a username before joining a room\n")
+    async fn write_login_required(&self) -> io::Result<()> {
+        self.write_error_text(ErrorCode::NotAuthenticated, "You need to log in first\n")
             .await?;
 
         Ok(())
     }
 
-    async fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
-        let mut stream = self.stream.lock().await;
-        stream.write_all(bytes).await?;
+    async fn write_username_registered(&self) -> io::Result<()> {
+        self.write_error_text(
+            ErrorCode::NameTaken,
+            "That username belongs to an account, use >login instead\n",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn write_slow_down(&self) -> io::Result<()> {
+        self.write_error_text(
+            ErrorCode::RateLimited,
+            "Slow down! That message was not sent.\n",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn write_rate_limited(&self) -> io::Result<()> {
+        self.write_error_text(
+            ErrorCode::RateLimited,
+            "Disconnected for sending too many messages.\n",
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn write_messages(&self, msgs: Vec<Message>) -> io::Result<()> {
+        let msgs: Vec<Message> = match &self.reference {
+            Some(_) => msgs
+                .into_iter()
+                .map(|msg| msg.with_reference(self.reference.clone()))
+                .collect(),
+            None => msgs,
+        };
+
+        self.stream.write_messages(msgs).await
+    }
+
+    async fn write_message(&self, msg: &Message) -> io::Result<()> {
+        let msg = match &self.reference {
+            Some(_) => msg.clone().with_reference(self.reference.clone()),
+            None => msg.clone(),
+        };
+
+        self.stream.write_message(msg).await
+    }
+
+    async fn write_all(&self, text: &str) -> io::Result<()> {
+        self.write_message(&Message::system(text)).await?;
 
         Ok(())
     }
 }
+
+// The largest whole unit of a >digest period, like `12h` or `90m`
+fn describe_period(secs: u64) -> String {
+    match secs {
+        s if s % 86400 == 0 => format!("{}d", s / 86400),
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+// In milliseconds to a tenth, for >ping
+fn describe_time(time: Duration) -> String {
+    format!("{:.1}ms", time.as_secs_f64() * 1000.0)
+}
+
+// The `page`th LIST_PAGE_SIZE of `items`, counting from 1
+fn page_of<T>(items: Vec<T>, page: usize) -> Vec<T> {
+    items
+        .into_iter()
+        .skip((page - 1).saturating_mul(LIST_PAGE_SIZE))
+        .take(LIST_PAGE_SIZE)
+        .collect()
+}
+
+// Roughly how long ago `ms` milliseconds was, for >list, >dms and >requests
+fn describe_ago(ms: isize) -> String {
+    match ms / 1000 {
+        s if s < 60 => "just now".to_owned(),
+        s if s < 3600 => format!("{}m ago", s / 60),
+        s if s < 86400 => format!("{}h ago", s / 3600),
+        s => format!("{}d ago", s / 86400),
+    }
+}
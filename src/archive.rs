@@ -0,0 +1,262 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Serialize;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use crate::pool::Pool;
+use crate::room::{self, get_time_in_ms, id_timestamp};
+
+// Entries read from Redis at a time, so a big room isn't held in memory
+const PAGE: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // One JSON object per message
+    Json,
+    // Each message as text clients saw it, after the time it was sent
+    Text,
+}
+
+impl FromStr for Format {
+    type Err = ArchiveError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "text" => Ok(Format::Text),
+            _ => Err(ArchiveError::InvalidFormat),
+        }
+    }
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "jsonl",
+            Format::Text => "txt",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    FailedToFetch,
+    FailedToWrite(std::io::Error),
+    FailedToDelete,
+    RoomNotFound,
+    InvalidFormat,
+}
+
+impl std::fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveError::FailedToFetch => writeln!(f, "Error: Failed to fetch room history"),
+            ArchiveError::FailedToWrite(e) => writeln!(f, "Error: Failed to write archive: {}", e),
+            ArchiveError::FailedToDelete => {
+                writeln!(f, "Error: Archived, but failed to delete the room")
+            }
+            ArchiveError::RoomNotFound => writeln!(f, "Error: Room not found"),
+            ArchiveError::InvalidFormat => writeln!(f, "Error: Archives are json or text"),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    room: &'a str,
+    id: &'a str,
+    timestamp: isize,
+    text: &'a str,
+}
+
+// Reads a room's whole history a page at a time, oldest first, as lines in
+// `format`. Messages sent while it's reading are included.
+pub struct Export {
+    room: String,
+    format: Format,
+    after: Option<String>,
+    done: bool,
+    // Messages read so far
+    pub count: usize,
+}
+
+impl Export {
+    pub fn new(room: &str, format: Format) -> Self {
+        Self {
+            room: room.to_owned(),
+            format,
+            after: None,
+            done: false,
+            count: 0,
+        }
+    }
+
+    // None once every message has been read
+    pub async fn next_page(&mut self, redis: &Pool) -> Result<Option<Vec<String>>, ArchiveError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let entries = room::entries(redis, &self.room, self.after.as_deref(), PAGE)
+            .await
+            .map_err(|e| {
+                dbg!("{}", e);
+                ArchiveError::FailedToFetch
+            })?;
+
+        self.done = entries.len() < PAGE;
+        self.after = entries.last().map(|(id, _)| id.clone());
+        self.count += entries.len();
+
+        if entries.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(
+            entries
+                .iter()
+                .map(|(id, text)| format_entry(self.format, &self.room, id, text))
+                .collect(),
+        ))
+    }
+}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::archive::{self, Format};
+///
+/// let id = "1674002400000-0";
+///
+/// assert_eq!(
+///     archive::format_entry(Format::Json, "rust", id, "bob: hi\n"),
+///     r#"{"room":"rust","id":"1674002400000-0","timestamp":1674002400000,"text":"bob: hi"}"#
+/// );
+/// assert_eq!(
+///     archive::format_entry(Format::Text, "rust", id, "bob: hi\n"),
+///     "[2023-01-18 00:40:00] bob: hi"
+/// );
+/// assert_eq!(
+///     archive::format_entry(Format::Text, "rust", "951782400000-0", "bob: leap\n"),
+///     "[2000-02-29 00:00:00] bob: leap"
+/// );
+/// ```
+pub fn format_entry(format: Format, room: &str, id: &str, text: &str) -> String {
+    let timestamp = id_timestamp(id).unwrap_or_default();
+    let text = text.trim_end_matches('\n');
+
+    match format {
+        Format::Json => serde_json::to_string(&Entry {
+            room,
+            id,
+            timestamp,
+            text,
+        })
+        .unwrap(),
+        Format::Text => format!("[{}] {}", format_date(timestamp), text),
+    }
+}
+
+// Writes the room's history to a new file in `dir` and deletes the room,
+// returning the file and how many messages went in it. The room is only
+// deleted once everything has been written.
+pub async fn to_disk(
+    redis: &Pool,
+    room: &str,
+    dir: &str,
+    format: Format,
+) -> Result<(PathBuf, usize), ArchiveError> {
+    match room::exists(redis, room).await {
+        Ok(true) => {}
+        Ok(false) => Err(ArchiveError::RoomNotFound)?,
+        Err(e) => {
+            dbg!("{}", e);
+            Err(ArchiveError::FailedToFetch)?
+        }
+    }
+
+    fs::create_dir_all(dir)
+        .await
+        .map_err(ArchiveError::FailedToWrite)?;
+
+    let path = Path::new(dir).join(gen_file_name(room, get_time_in_ms(), format));
+    let file = File::create(&path)
+        .await
+        .map_err(ArchiveError::FailedToWrite)?;
+    let mut file = BufWriter::new(file);
+
+    let mut export = Export::new(room, format);
+    while let Some(lines) = export.next_page(redis).await? {
+        for line in lines {
+            file.write_all(format!("{}\n", line).as_bytes())
+                .await
+                .map_err(ArchiveError::FailedToWrite)?;
+        }
+    }
+
+    file.flush().await.map_err(ArchiveError::FailedToWrite)?;
+
+    room::delete(redis, room).await.map_err(|e| {
+        dbg!("{}", e);
+        ArchiveError::FailedToDelete
+    })?;
+
+    Ok((path, export.count))
+}
+
+// Room names can have `/` in them, which would be a directory
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::archive::{self, Format};
+///
+/// assert_eq!(archive::gen_file_name("dev/rust", 1, Format::Json), "dev_rust-1.jsonl");
+/// ```
+pub fn gen_file_name(room: &str, timestamp: isize, format: Format) -> String {
+    format!(
+        "{}-{}.{}",
+        room.replace('/', "_"),
+        timestamp,
+        format.extension()
+    )
+}
+
+// UTC, like `2023-01-18 00:40:00`
+fn format_date(timestamp: isize) -> String {
+    let secs = timestamp as i64 / 1000;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let of_day = secs.rem_euclid(86400);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        of_day / 3600,
+        of_day % 3600 / 60,
+        of_day % 60
+    )
+}
+
+// Days since 1970-01-01 to a date, from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    (year, month, day)
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use redis::streams::{StreamMaxlen, StreamRangeReply};
+use redis::AsyncCommands;
+
+use crate::command;
+use crate::pool::Pool;
+
+const KEY: &str = "audit";
+
+// Roughly how many entries the stream keeps before trimming the oldest
+const MAX_LEN: usize = 100_000;
+
+// How far back a query looks, newest first
+const QUERY_SCAN: usize = 10_000;
+
+#[derive(Debug)]
+pub enum AuditError {
+    FailedToSave,
+    FailedToFetch,
+}
+
+impl std::fmt::Display for AuditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuditError::FailedToSave => writeln!(f, "Error: Failed to write audit log"),
+            AuditError::FailedToFetch => writeln!(f, "Error: Failed to read audit log"),
+        }
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+pub enum AuditEvent<'a> {
+    Connect,
+    Disconnect,
+    // The line as the client sent it, secrets are redacted before saving
+    Command(&'a str),
+}
+
+// Appends to the `audit` stream. Entry ids are timestamps, so they aren't
+// stored separately.
+pub async fn record(
+    redis: &Pool,
+    event: AuditEvent<'_>,
+    addr: &str,
+    username: Option<&str>,
+    room: Option<&str>,
+) -> Result<(), AuditError> {
+    let mut conn = redis.get();
+
+    let (event, detail) = match event {
+        AuditEvent::Connect => ("connect", String::new()),
+        AuditEvent::Disconnect => ("disconnect", String::new()),
+        AuditEvent::Command(line) => ("command", command::redact(line)),
+    };
+
+    let fields = [
+        ("event", event),
+        ("addr", addr),
+        ("user", username.unwrap_or_default()),
+        ("room", room.unwrap_or_default()),
+        ("detail", &detail),
+    ];
+
+    conn.xadd_maxlen::<_, _, _, _, ()>(KEY, StreamMaxlen::Approx(MAX_LEN), "*", &fields)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            AuditError::FailedToSave
+        })?;
+
+    Ok(())
+}
+
+// The latest `count` entries for a user or room, oldest first
+pub async fn query(redis: &Pool, subject: &str, count: usize) -> Result<Vec<String>, AuditError> {
+    let mut conn = redis.get();
+
+    let reply: StreamRangeReply = conn
+        .xrevrange_count(KEY, "+", "-", QUERY_SCAN)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            AuditError::FailedToFetch
+        })?;
+
+    let mut entries: Vec<String> = reply
+        .ids
+        .into_iter()
+        .filter_map(|entry| {
+            let fields: HashMap<String, String> = entry
+                .map
+                .iter()
+                .filter_map(|(k, v)| Some((k.clone(), redis::from_redis_value(v).ok()?)))
+                .collect();
+            let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+
+            if field("user") != subject && field("room") != subject {
+                return None;
+            }
+
+            Some(format!(
+                "{} {} {} user={} room={} {}",
+                entry.id,
+                field("event"),
+                field("addr"),
+                field("user"),
+                field("room"),
+                field("detail"),
+            ))
+        })
+        .take(count)
+        .collect();
+    entries.reverse();
+
+    Ok(entries)
+}
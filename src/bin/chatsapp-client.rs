@@ -0,0 +1,32 @@
+use chatsapp::client;
+use tokio::io;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:8000";
+
+const USAGE: &str = "Usage: chatsapp-client [addr] [--no-color]";
+
+// `chatsapp-client localhost:8000`, see `client::run`
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let mut addr = None;
+    let mut color = true;
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--no-color" => color = false,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if arg.starts_with('-') || addr.is_some() => {
+                eprintln!("{}", USAGE);
+                std::process::exit(2);
+            }
+            _ => addr = Some(arg),
+        }
+    }
+
+    let addr = addr.unwrap_or_else(|| DEFAULT_ADDR.to_owned());
+
+    client::run(addr, color).await
+}
@@ -0,0 +1,136 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+use redis::AsyncCommands;
+
+use crate::message::{Message, MessageKind};
+use crate::pool::Pool;
+
+// Who a user has blocked, shared between their connection and the brokers
+// of the rooms they're in so a new block applies straight away. Only locked
+// briefly, never across an await.
+pub type Blocklist = Arc<RwLock<HashSet<String>>>;
+
+#[derive(Debug)]
+pub enum BlockError {
+    FailedToSave,
+    FailedToFetch,
+    Yourself,
+    NotBlocked,
+}
+
+impl std::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockError::FailedToSave => writeln!(f, "Error: Failed to save block"),
+            BlockError::FailedToFetch => writeln!(f, "Error: Failed to fetch blocks"),
+            BlockError::Yourself => writeln!(f, "Error: You can't block yourself"),
+            BlockError::NotBlocked => writeln!(f, "Error: You haven't blocked that user"),
+        }
+    }
+}
+
+impl std::error::Error for BlockError {}
+
+pub fn new_blocklist() -> Blocklist {
+    Arc::new(RwLock::new(HashSet::new()))
+}
+
+// Replaces what's in `blocklist` with what `username` has saved
+pub async fn load(redis: &Pool, username: &str, blocklist: &Blocklist) -> Result<(), BlockError> {
+    let blocked: HashSet<String> = redis.get().smembers(gen_key(username)).await.map_err(|e| {
+        dbg!("{}", e);
+        BlockError::FailedToFetch
+    })?;
+
+    *blocklist.write().unwrap() = blocked;
+
+    Ok(())
+}
+
+pub async fn block(
+    redis: &Pool,
+    username: &str,
+    target: &str,
+    blocklist: &Blocklist,
+) -> Result<(), BlockError> {
+    if username == target {
+        Err(BlockError::Yourself)?;
+    }
+
+    redis
+        .get()
+        .sadd::<_, _, ()>(gen_key(username), target)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            BlockError::FailedToSave
+        })?;
+
+    blocklist.write().unwrap().insert(target.to_owned());
+
+    Ok(())
+}
+
+pub async fn unblock(
+    redis: &Pool,
+    username: &str,
+    target: &str,
+    blocklist: &Blocklist,
+) -> Result<(), BlockError> {
+    let removed: u8 = redis
+        .get()
+        .srem(gen_key(username), target)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            BlockError::FailedToSave
+        })?;
+
+    blocklist.write().unwrap().remove(target);
+
+    match removed {
+        0 => Err(BlockError::NotBlocked),
+        _ => Ok(()),
+    }
+}
+
+// For reaching users who may be on another server, where their Blocklist
+// can't be seen
+pub async fn is_blocked(redis: &Pool, username: &str, by: &str) -> Result<bool, BlockError> {
+    redis
+        .get()
+        .sismember(gen_key(by), username)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            BlockError::FailedToFetch
+        })
+}
+
+// Whether `msg` was written by someone on the list. Notices about them, like
+// joining or being kicked, still get through, so the member list stays right.
+pub fn hides(blocklist: &Blocklist, msg: &Message) -> bool {
+    let sent = matches!(
+        msg.kind,
+        MessageKind::Chat
+            | MessageKind::Whisper
+            | MessageKind::Command
+            | MessageKind::KeyExchange
+            | MessageKind::Typing
+            | MessageKind::Edit
+            | MessageKind::Delete
+            | MessageKind::Reaction
+            | MessageKind::Mention
+            | MessageKind::Dm
+    );
+
+    match &msg.user {
+        Some(user) if sent => blocklist.read().unwrap().contains(user),
+        _ => false,
+    }
+}
+
+pub(crate) fn gen_key(username: &str) -> String {
+    format!("blocks:{}", username)
+}
@@ -0,0 +1,99 @@
+use redis::{AsyncCommands, Script};
+
+use crate::command;
+use crate::pool::Pool;
+
+#[derive(Debug)]
+pub enum BotError {
+    FailedToSave,
+    FailedToFetch,
+    Builtin,
+    Taken(String),
+    NotClaimed,
+}
+
+impl std::fmt::Display for BotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BotError::FailedToSave => writeln!(f, "Error: Failed to save command"),
+            BotError::FailedToFetch => writeln!(f, "Error: Failed to fetch commands"),
+            BotError::Builtin => writeln!(f, "Error: That's already a command"),
+            BotError::Taken(bot) => writeln!(f, "Error: {} already answers that here", bot),
+            BotError::NotClaimed => writeln!(f, "Error: Nobody answers that here"),
+        }
+    }
+}
+
+impl std::error::Error for BotError {}
+
+// Connected bots can claim commands in a room, like `>roll`, which the server
+// doesn't know itself. Claims are kept in `bot-commands:<room>`, by name
+// without the `>`, and last until they're released or the room is deleted.
+pub async fn claim(redis: &Pool, room: &str, name: &str, bot: &str) -> Result<(), BotError> {
+    if command::find(&format!(">{}", name)).is_some() {
+        return Err(BotError::Builtin);
+    }
+
+    let script = Script::new(
+        r"
+        local bot = redis.call('HGET', KEYS[1], ARGV[1])
+        if not bot then
+            redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+            return ARGV[2]
+        end
+        return bot
+        ",
+    );
+
+    let claimant: String = script
+        .key(gen_key(room))
+        .arg(name)
+        .arg(bot)
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            BotError::FailedToSave
+        })?;
+
+    match claimant == bot {
+        true => Ok(()),
+        false => Err(BotError::Taken(claimant)),
+    }
+}
+
+pub async fn release(redis: &Pool, room: &str, name: &str) -> Result<(), BotError> {
+    let removed: usize = redis.get().hdel(gen_key(room), name).await.map_err(|e| {
+        dbg!("{}", e);
+        BotError::FailedToSave
+    })?;
+
+    match removed {
+        0 => Err(BotError::NotClaimed),
+        _ => Ok(()),
+    }
+}
+
+// The bot that answers `name` in `room`, if any
+pub async fn claimant(redis: &Pool, room: &str, name: &str) -> Result<Option<String>, BotError> {
+    redis.get().hget(gen_key(room), name).await.map_err(|e| {
+        dbg!("{}", e);
+        BotError::FailedToFetch
+    })
+}
+
+// Every claimed command in `room` with the bot that answers it, by name
+pub async fn list(redis: &Pool, room: &str) -> Result<Vec<(String, String)>, BotError> {
+    let mut claims: Vec<(String, String)> =
+        redis.get().hgetall(gen_key(room)).await.map_err(|e| {
+            dbg!("{}", e);
+            BotError::FailedToFetch
+        })?;
+    claims.sort();
+
+    Ok(claims)
+}
+
+pub(crate) fn gen_key(room: &str) -> String {
+    format!("bot-commands:{}", room)
+}
@@ -1,131 +1,1210 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    sync::Arc,
+    any::Any,
+    collections::{
+        hash_map::{DefaultHasher, Entry},
+        HashMap, HashSet,
+    },
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use redis::Client as RedisClient;
 use tokio::{
-    io::{self, AsyncWriteExt},
-    net::tcp::OwnedWriteHalf,
+    io,
     sync::{
-        mpsc::{self, Receiver, Sender},
-        Mutex, RwLock,
+        mpsc::{self, error::TrySendError, Receiver, Sender},
+        oneshot, Mutex, RwLock,
     },
 };
 
-use crate::room::{self, RoomError};
+use crate::block::{self, Blocklist};
+use crate::message::{Message, MessageKind};
+use crate::pool::Pool;
+use crate::pubsub::{self, Remote};
+use crate::receipts::Receipts;
+use crate::room::{self, get_time_in_ms, RoomError, RoomEvent};
+use crate::stats;
+use crate::writer::WriterHandle;
 
-pub type SharedStream = Arc<Mutex<OwnedWriteHalf>>;
+// Whether a user has asked not to be sent joins and leaves, shared between
+// their connection and the brokers of the rooms they're in like their
+// Blocklist, so >set quiet applies straight away
+pub type Quiet = Arc<AtomicBool>;
 
 #[derive(Debug)]
 pub enum BrokerEvent {
     JoinRoom {
         user: String,
-        stream: SharedStream,
-        msg: String,
+        stream: WriterHandle,
+        changes: Sender<RoomChange>,
+        blocked: Blocklist,
+        quiet: Quiet,
+        msg: Message,
     },
     LeaveRoom {
         user: String,
-        msg: String,
+        msg: Message,
     },
     Message {
         user: String,
-        msg: String,
+        msg: Message,
+    },
+    // A message from outside the room, like the HTTP API, so there's no
+    // member to check or skip
+    Post {
+        msg: Message,
+    },
+    Who {
+        reply: oneshot::Sender<Vec<String>>,
+    },
+    // Answered straight away, so the wait is how far behind the broker is
+    Ping {
+        reply: oneshot::Sender<()>,
+    },
+    Kick {
+        user: String,
+        msg: Message,
+    },
+    Typing {
+        user: String,
+        msg: Message,
+    },
+    // Keeps the member's place in the room under their new name
+    Rename {
+        user: String,
+        to: String,
+        msg: Message,
+    },
+    // Sends `msg` to `to` alone, replying with whether it could be delivered
+    Whisper {
+        user: String,
+        to: String,
+        msg: Message,
+        reply: oneshot::Sender<bool>,
     },
+    Close {
+        msg: Message,
+    },
+    // The room's keys have already been moved to `to`, and its entry in the
+    // map, so only the broker and its members are left to tell
+    RenameRoom {
+        to: String,
+        msg: Message,
+    },
+    // How each of the room's delivery shards is doing, none if it isn't
+    // sharded
+    Shards {
+        reply: oneshot::Sender<Vec<Shard>>,
+    },
+    // Published by a broker, possibly on another server
+    Remote(Remote),
+}
+
+// What a broker tells a member's connection about the room changing under it
+#[derive(Debug)]
+pub enum RoomChange {
+    // They're no longer in the room
+    Removed(String),
+    // The room's old name, and its new one
+    Renamed(String, String),
+}
+
+#[derive(Clone)]
+struct Member {
+    tx: Sender<Message>,
+    // Used to tell the user's connection it's no longer in this room, or
+    // that it's been renamed
+    changes: Sender<RoomChange>,
+    // Whose messages they don't want
+    blocked: Blocklist,
+    quiet: Quiet,
 }
 
 pub type RoomMap = Arc<RwLock<HashMap<String, Sender<BrokerEvent>>>>;
 
-// Since rooms are persisted in redis, this function fetches and
-// stores each room into map, spawning new brokers for each.
-pub async fn bootstrap_rooms(redis: &RedisClient) -> Result<RoomMap, RoomError> {
-    let room_map = Arc::new(RwLock::new(HashMap::new()));
+// How long observers, like >list, metrics scrapes and the admin console,
+// wait on a broker for `who` or `shards`
+pub const WHO_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Messages a member can fall behind by before they're dropped from the room
+const MEMBER_QUEUE_SIZE: usize = 100;
+
+// Most queued messages written to a member at once
+const MAX_BATCH: usize = 32;
+
+// Events a shard can have waiting before the broker waits on it
+const SHARD_QUEUE_SIZE: usize = 100;
+
+// How often the broker checks for members whose connection has stopped
+// taking messages without it hearing about it
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+// Wait before starting a broker that panicked again, so one that panics
+// straight away doesn't spin
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+// What a broker works on, kept by its supervisor rather than in the
+// broker's task so it outlives a panic
+struct Supervised {
+    room: String,
+    events: Receiver<BrokerEvent>,
+    // <User, Senders for the User>
+    users: HashMap<String, Member>,
+}
+
+enum ShardEvent {
+    Add {
+        user: String,
+        member: Member,
+    },
+    Remove {
+        user: String,
+    },
+    // The room was renamed, so receipts go under the new name
+    Rename {
+        to: String,
+    },
+    Deliver {
+        msg: Message,
+        sender: String,
+        // Whether to record who it reached
+        track: bool,
+        // When the broker handed it over, for the shard's latency
+        sent: Instant,
+    },
+}
+
+// What a shard has done, kept where the broker can read it without asking
+#[derive(Default)]
+struct ShardStats {
+    members: AtomicUsize,
+    fanouts: AtomicU64,
+    fanout_micros: AtomicU64,
+}
+
+// One of a room's shards, as of when it was asked for
+#[derive(Debug)]
+pub struct Shard {
+    pub members: usize,
+    // Messages it's sent on
+    pub fanouts: u64,
+    // Total time from the broker handing a message over to it being queued
+    // for every member in the shard
+    pub fanout_seconds: f64,
+}
+
+// A room's members split by name over several tasks, each sending to its
+// own, so a big room's messages aren't all copied out by the broker. The
+// tasks are spawned like any other, so tokio's scheduler spreads them, and
+// steals them, across its worker threads.
+struct Shards {
+    txs: Vec<Sender<ShardEvent>>,
+    stats: Vec<Arc<ShardStats>>,
+}
+
+impl Shards {
+    // Shards tell the broker about members who fell too far behind on
+    // `overflowed`, after dropping them themselves
+    fn start(
+        room: &str,
+        count: usize,
+        receipts: Option<Receipts>,
+        overflowed: Sender<String>,
+    ) -> Self {
+        let mut txs = Vec::with_capacity(count);
+        let mut stats = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let (tx, rx) = mpsc::channel(SHARD_QUEUE_SIZE);
+            let shard_stats = Arc::new(ShardStats::default());
+
+            tokio::spawn(shard(
+                room.to_owned(),
+                rx,
+                receipts.clone(),
+                overflowed.clone(),
+                shard_stats.clone(),
+            ));
+
+            txs.push(tx);
+            stats.push(shard_stats);
+        }
+
+        Self { txs, stats }
+    }
+
+    // Members stay on the same shard, so their messages stay in order
+    fn of(&self, user: &str) -> &Sender<ShardEvent> {
+        let mut hasher = DefaultHasher::new();
+        user.hash(&mut hasher);
+
+        &self.txs[hasher.finish() as usize % self.txs.len()]
+    }
+
+    async fn add(&self, user: &str, member: &Member) {
+        let event = ShardEvent::Add {
+            user: user.to_owned(),
+            member: member.clone(),
+        };
+
+        if let Err(e) = self.of(user).send(event).await {
+            eprintln!("{}", e);
+        }
+    }
+
+    async fn remove(&self, user: &str) {
+        let event = ShardEvent::Remove {
+            user: user.to_owned(),
+        };
+
+        if let Err(e) = self.of(user).send(event).await {
+            eprintln!("{}", e);
+        }
+    }
+
+    async fn rename(&self, to: &str) {
+        for tx in &self.txs {
+            let event = ShardEvent::Rename { to: to.to_owned() };
+
+            if let Err(e) = tx.send(event).await {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    async fn deliver(&self, msg: Message, sender: &str, track: bool) {
+        let sent = Instant::now();
 
-    // Get rooms:
-    let mut rooms = room::list(redis).await?;
+        for tx in &self.txs {
+            let event = ShardEvent::Deliver {
+                msg: msg.clone(),
+                sender: sender.to_owned(),
+                track,
+                sent,
+            };
 
-    // Spawn broker for each room
-    while let Some(mut room) = rooms.pop() {
+            if let Err(e) = tx.send(event).await {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Vec<Shard> {
+        self.stats
+            .iter()
+            .map(|stats| Shard {
+                members: stats.members.load(Ordering::Relaxed),
+                fanouts: stats.fanouts.load(Ordering::Relaxed),
+                fanout_seconds: stats.fanout_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            })
+            .collect()
+    }
+}
+
+// Rooms are persisted in Redis, so there's nothing to load on startup.
+// Brokers are started by `get_or_spawn` the first time someone joins a room,
+// whether it was created before or after this server started. This only
+// converts rooms from before streams and returns the empty map.
+//
+// Nobody is in any room yet, so if `ttl` is set every room starts expiring,
+// as `spawn_gc` would do once their brokers went idle.
+pub async fn bootstrap_rooms(redis: &Pool, ttl: Option<Duration>) -> Result<RoomMap, RoomError> {
+    for room in room::list(redis).await? {
         // Remove `room:`
-        room = room.split_off(5);
+        let room = &room[5..];
+
+        // Rooms from before streams are converted once, on the first start
+        if room::migrate(redis, room).await? {
+            eprintln!("Migrated {} to a stream", room);
+        }
 
-        spawn_broker(room, &room_map).await;
+        if let Some(ttl) = ttl {
+            room::expire(redis, room, ttl.as_secs() as usize).await?;
+        }
     }
 
-    Ok(room_map)
+    Ok(Arc::new(RwLock::new(HashMap::new())))
+}
+
+pub async fn spawn_broker(
+    redis: &Pool,
+    room: String,
+    rooms_map: &RoomMap,
+    fanout: Option<Pool>,
+    receipts: Option<Receipts>,
+    shards: usize,
+) {
+    let room_tx = start_broker(redis.clone(), room.clone(), fanout, receipts, shards);
+
+    rooms_map.write().await.insert(room, room_tx);
 }
 
-pub async fn spawn_broker(room: String, rooms_map: &RoomMap) {
+// Brokers given a `fanout` pool publish events through Redis instead of
+// sending them straight to members, so rooms can span several servers.
+// Given `receipts`, they record who each chat message was delivered to.
+// With more than one shard, members are sent to by that many tasks. `redis`
+// saves the leave of anyone whose connection goes away without leaving.
+fn start_broker(
+    redis: Pool,
+    room: String,
+    fanout: Option<Pool>,
+    receipts: Option<Receipts>,
+    shards: usize,
+) -> Sender<BrokerEvent> {
     let (room_tx, room_rx) = mpsc::channel(100);
 
-    tokio::spawn(broker(room_rx));
+    tokio::spawn(supervise(redis, room, room_rx, fanout, receipts, shards));
 
-    rooms_map.write().await.insert(room, room_tx);
+    room_tx
 }
 
-pub async fn broker(mut events: Receiver<BrokerEvent>) -> io::Result<()> {
-    // <User, Sender for the User>
-    let mut users: HashMap<String, Sender<String>> = HashMap::new();
+// Runs the room's broker, and starts it again if it panics. The channel and
+// the members are kept here, so Senders held by connections and the map
+// don't go dead and the room stays joinable, and everyone who was in it is
+// picked up again by the new broker. The event being handled when it
+// panicked is lost.
+async fn supervise(
+    redis: Pool,
+    room: String,
+    events: Receiver<BrokerEvent>,
+    fanout: Option<Pool>,
+    receipts: Option<Receipts>,
+    shards: usize,
+) {
+    let state = Arc::new(Mutex::new(Supervised {
+        room,
+        events,
+        users: HashMap::new(),
+    }));
+
+    loop {
+        let task = tokio::spawn(broker(
+            redis.clone(),
+            state.clone(),
+            fanout.clone(),
+            receipts.clone(),
+            shards,
+        ));
+
+        match task.await {
+            Ok(Ok(())) => break,
+            Ok(Err(e)) => {
+                eprintln!("{}", e);
+                break;
+            }
+            Err(e) if e.is_panic() => {
+                let panic = e.into_panic();
+                let state = state.lock().await;
+                eprintln!(
+                    "Broker for {} panicked ({}), restarting it with its {} members",
+                    state.room,
+                    panic_message(&*panic),
+                    state.users.len()
+                );
+            }
+            // The runtime is shutting down
+            Err(_) => break,
+        }
+
+        tokio::time::sleep(RESTART_DELAY).await;
+    }
+}
+
+// What it panicked with, which is usually a string
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => panic
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .unwrap_or("no message"),
+    }
+}
+
+// Returns the room's broker, starting one if the room exists in Redis but
+// has no broker on this server, because it hasn't been used since startup or
+// its broker was torn down for being idle.
+pub async fn get_or_spawn(
+    redis: &Pool,
+    room: &str,
+    rooms_map: &RoomMap,
+    fanout: Option<Pool>,
+    receipts: Option<Receipts>,
+    shards: usize,
+) -> Result<Option<Sender<BrokerEvent>>, RoomError> {
+    if let Some(tx) = rooms_map.read().await.get(room) {
+        return Ok(Some(tx.clone()));
+    }
+
+    if !room::exists(redis, room).await? {
+        return Ok(None);
+    }
+
+    // Someone else could have started it while we checked Redis
+    let tx = rooms_map
+        .write()
+        .await
+        .entry(room.to_owned())
+        .or_insert_with(|| start_broker(redis.clone(), room.to_owned(), fanout, receipts, shards))
+        .clone();
+
+    // It's in use again, so it shouldn't expire
+    room::persist(redis, room).await?;
+
+    Ok(Some(tx))
+}
+
+// Removes the room's broker, which tells everyone inside and then stops
+pub async fn close(room: &str, rooms_map: &RoomMap, msg: Message) {
+    let tx = match rooms_map.write().await.remove(room) {
+        Some(tx) => tx,
+        None => return,
+    };
+
+    if let Err(e) = tx.send(BrokerEvent::Close { msg }).await {
+        eprintln!("{}", e);
+    }
+}
+
+// Moves the room's broker to its new name in the map, and has it tell
+// everyone inside. Anyone joining by the old name from now on won't find it.
+pub async fn rename(room: &str, to: &str, rooms_map: &RoomMap, msg: Message) {
+    let tx = {
+        let mut map = rooms_map.write().await;
+
+        let tx = match map.remove(room) {
+            Some(tx) => tx,
+            None => return,
+        };
+        map.insert(to.to_owned(), tx.clone());
+
+        tx
+    };
+
+    let event = BrokerEvent::RenameRoom {
+        to: to.to_owned(),
+        msg,
+    };
+    if let Err(e) = tx.send(event).await {
+        eprintln!("{}", e);
+    }
+}
+
+// Every `interval`, removes brokers nobody has held a Sender for since the
+// last check. Dropping the map's Sender closes the channel, which ends the
+// broker task. If `ttl` is set, the room's keys are also set to expire.
+pub fn spawn_gc(redis: Pool, rooms_map: RoomMap, interval: Duration, ttl: Option<Duration>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        let mut idle: HashSet<String> = HashSet::new();
+
+        loop {
+            ticker.tick().await;
+
+            // Connections only clone a Sender while holding the read lock, so
+            // nobody can pick one up while we're deciding
+            let mut map = rooms_map.write().await;
+
+            let now_idle: HashSet<String> = map
+                .iter()
+                .filter(|(_, tx)| tx.strong_count() == 1)
+                .map(|(room, _)| room.clone())
+                .collect();
+
+            let mut removed = Vec::new();
+            for room in now_idle.intersection(&idle) {
+                map.remove(room);
+                removed.push(room.clone());
+            }
+            drop(map);
+
+            if let Some(ttl) = ttl {
+                for room in &removed {
+                    if let Err(e) = room::expire(&redis, room, ttl.as_secs() as usize).await {
+                        eprintln!("{}", e);
+                    }
+                }
+            }
+
+            idle = now_idle;
+            idle.retain(|room| !removed.contains(room));
+        }
+    });
+}
+
+// Every room with a broker and its sender. The senders are cloned so the map
+// isn't locked while the caller waits on brokers.
+pub async fn snapshot(rooms: &RoomMap) -> Vec<(String, Sender<BrokerEvent>)> {
+    rooms
+        .read()
+        .await
+        .iter()
+        .map(|(room, tx)| (room.clone(), tx.clone()))
+        .collect()
+}
+
+// Asks a broker who's in its room without waiting on it for long, for
+// observers that shouldn't be held up by a busy room
+pub async fn who(tx: &Sender<BrokerEvent>, timeout: Duration) -> Option<Vec<String>> {
+    let (reply, members) = oneshot::channel();
+
+    tx.try_send(BrokerEvent::Who { reply }).ok()?;
+
+    tokio::time::timeout(timeout, members).await.ok()?.ok()
+}
+
+// How long a broker takes to get to an event, for >ping. Unlike `who` this
+// waits for space in its queue, since that's part of what's being timed.
+pub async fn ping(tx: &Sender<BrokerEvent>, timeout: Duration) -> Option<Duration> {
+    let start = Instant::now();
+    let (reply, pong) = oneshot::channel();
+
+    let answered = async {
+        tx.send(BrokerEvent::Ping { reply }).await.ok()?;
+        pong.await.ok()
+    };
+    tokio::time::timeout(timeout, answered).await.ok()??;
+
+    Some(start.elapsed())
+}
+
+// Like `who`, for how a room's shards are doing
+pub async fn shards(tx: &Sender<BrokerEvent>, timeout: Duration) -> Option<Vec<Shard>> {
+    let (reply, shards) = oneshot::channel();
+
+    tx.try_send(BrokerEvent::Shards { reply }).ok()?;
+
+    tokio::time::timeout(timeout, shards).await.ok()?.ok()
+}
+
+// Holds the room's state for as long as it runs, which a panic gives back
+// to `supervise`
+async fn broker(
+    redis: Pool,
+    state: Arc<Mutex<Supervised>>,
+    fanout: Option<Pool>,
+    receipts: Option<Receipts>,
+    shards: usize,
+) -> io::Result<()> {
+    let mut state = state.lock_owned().await;
+    let Supervised {
+        room,
+        events,
+        users,
+    } = &mut *state;
+
+    // Never sent on without shards, and dropped with them when the broker
+    // stops
+    let (overflowed_tx, mut overflowed) = mpsc::channel(MEMBER_QUEUE_SIZE);
+    let shards = (shards > 1).then(|| Shards::start(room, shards, receipts.clone(), overflowed_tx));
+
+    // Anyone here already is left from before a panic. Their writing tasks
+    // are still going, but the new shards need them, and they may have
+    // missed who came and went.
+    if !users.is_empty() {
+        let mut members: Vec<String> = users.keys().map(|user| format!("+{}", user)).collect();
+        members.sort();
+        let members = Message::members(room, members.join(" "));
+
+        for (user, member) in users.iter() {
+            if let Some(shards) = &shards {
+                shards.add(user, member).await;
+            }
+            if let Err(e) = member.tx.try_send(members.clone()) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    // Members whose writes failed or stalled, reported by their writing task
+    // or found by the heartbeat
+    let (dead_tx, mut dead) = mpsc::channel::<String>(MEMBER_QUEUE_SIZE);
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            Some(user) = overflowed.recv() => {
+                // Their shard has already dropped them
+                if let Some(member) = users.remove(&user) {
+                    drop_behind(&user, member, users, room);
+                }
+                continue;
+            }
+            Some(user) = dead.recv() => match gone(&redis, room, &user, users).await {
+                Some(event) => event,
+                None => continue,
+            },
+            _ = heartbeat.tick() => {
+                // In case their task's report didn't fit
+                for (user, member) in users.iter() {
+                    if member.tx.is_closed() {
+                        let _ = dead_tx.try_send(user.clone());
+                    }
+                }
+                continue;
+            }
+        };
 
-    while let Some(event) = events.recv().await {
         match event {
-            BrokerEvent::JoinRoom { user, stream, msg } => {
+            BrokerEvent::JoinRoom {
+                user,
+                stream,
+                changes,
+                blocked,
+                quiet,
+                msg,
+            } => {
                 // Add user to peers:
                 match users.entry(user.clone()) {
                     Entry::Occupied(..) => (),
                     Entry::Vacant(entry) => {
                         // Each user will have a tx associated with their name and
                         // an rx associated with their tcp connection
-                        let (message_tx, message_rx) = mpsc::channel(100);
-                        entry.insert(message_tx);
+                        let (message_tx, message_rx) = mpsc::channel(MEMBER_QUEUE_SIZE);
+                        let member = entry.insert(Member {
+                            tx: message_tx,
+                            changes,
+                            blocked,
+                            quiet,
+                        });
+
+                        if let Some(shards) = &shards {
+                            shards.add(&user, member).await;
+                        }
+                        stats::record_members(&redis, room, users.len());
 
                         // This task is responsible for writing messages to the connected user.
-                        tokio::spawn(receive_messages(message_rx, stream));
+                        tokio::spawn(receive_messages(
+                            user.clone(),
+                            message_rx,
+                            stream,
+                            dead_tx.clone(),
+                        ));
+
+                        // Give them the whole list to start from
+                        let mut members: Vec<String> =
+                            users.keys().map(|user| format!("+{}", user)).collect();
+                        members.sort();
+                        if let Err(e) = users[&user]
+                            .tx
+                            .try_send(Message::members(room, members.join(" ")))
+                        {
+                            eprintln!("{}", e);
+                        }
 
                         // Send join msg:
-                        send_messages(msg, user, &users).await;
+                        broadcast(&fanout, &receipts, &shards, msg, user.clone(), users, room)
+                            .await;
+                        let delta = Message::members(room, format!("+{}", user));
+                        broadcast(&fanout, &receipts, &shards, delta, user, users, room).await;
                     }
                 };
             }
             BrokerEvent::LeaveRoom { user, msg } => {
                 // Remove user from peers:
                 users.remove(&user);
+                if let Some(shards) = &shards {
+                    shards.remove(&user).await;
+                }
 
                 // Send leave msg
-                send_messages(msg, user, &users).await;
+                broadcast(&fanout, &receipts, &shards, msg, user.clone(), users, room).await;
+                let delta = Message::members(room, format!("-{}", user));
+                broadcast(&fanout, &receipts, &shards, delta, user, users, room).await;
             }
             BrokerEvent::Message { user, msg } => {
-                send_messages(msg, user, &users).await;
+                // Could have been kicked before their connection found out
+                if users.contains_key(&user) {
+                    broadcast(&fanout, &receipts, &shards, msg, user, users, room).await;
+                }
+            }
+            BrokerEvent::Post { msg } => {
+                // Usernames can't be empty, so this reaches everyone
+                broadcast(&fanout, &receipts, &shards, msg, String::new(), users, room).await;
+            }
+            BrokerEvent::Ping { reply } => {
+                let _ = reply.send(());
+            }
+            BrokerEvent::Who { reply } => {
+                let mut members: Vec<String> = users.keys().cloned().collect();
+                members.sort();
+
+                // The requester may have gone away, nothing to do if so
+                let _ = reply.send(members);
+            }
+            BrokerEvent::Typing { user, msg } => {
+                if users.contains_key(&user) {
+                    broadcast(&fanout, &receipts, &shards, msg, user, users, room).await;
+                }
+            }
+            BrokerEvent::Rename { user, to, msg } => {
+                let member = match users.remove(&user) {
+                    Some(member) => member,
+                    None => continue,
+                };
+                let member = users.entry(to.clone()).or_insert(member);
+
+                if let Some(shards) = &shards {
+                    shards.remove(&user).await;
+                    shards.add(&to, member).await;
+                }
+
+                broadcast(&fanout, &receipts, &shards, msg, to.clone(), users, room).await;
+                let delta = Message::members(room, format!("-{} +{}", user, to));
+                broadcast(&fanout, &receipts, &shards, delta, to, users, room).await;
+            }
+            BrokerEvent::Whisper {
+                user,
+                to,
+                msg,
+                reply,
+            } => {
+                let delivered = match (users.contains_key(&user), users.get(&to)) {
+                    (false, _) => false,
+                    // As if they weren't there, so blocking can't be noticed
+                    (true, Some(member)) if hides(member, &msg) => false,
+                    (true, Some(member)) => {
+                        if let Err(e) = member.tx.try_send(msg) {
+                            eprintln!("{}", e);
+                        }
+                        true
+                    }
+                    // They could be in the room on another server
+                    (true, None) => match &fanout {
+                        Some(redis) => {
+                            let remote = Remote::Whisper { to, msg };
+                            pubsub::publish(redis, room, &remote).await.is_ok()
+                        }
+                        None => false,
+                    },
+                };
+
+                // The sender may have gone away, nothing to do if so
+                let _ = reply.send(delivered);
+            }
+            BrokerEvent::Close { msg } => {
+                // Other servers drop the room when they see this
+                if let Some(redis) = &fanout {
+                    let remote = Remote::Close { msg: msg.clone() };
+
+                    if let Err(e) = pubsub::publish(redis, room, &remote).await {
+                        eprintln!("{}", e);
+                    }
+                }
+
+                close_room(msg, users, room);
+                break;
+            }
+            BrokerEvent::RenameRoom { to, msg } => {
+                // Other servers move their broker when they see this
+                if let Some(redis) = &fanout {
+                    let remote = Remote::Rename {
+                        to: to.clone(),
+                        msg: msg.clone(),
+                    };
+
+                    if let Err(e) = pubsub::publish(redis, room, &remote).await {
+                        eprintln!("{}", e);
+                    }
+                }
+
+                rename_room(msg, to, room, users, &shards).await;
             }
+            BrokerEvent::Shards { reply } => {
+                let snapshot = match &shards {
+                    Some(shards) => shards.snapshot(),
+                    None => Vec::new(),
+                };
+
+                // The requester may have gone away, nothing to do if so
+                let _ = reply.send(snapshot);
+            }
+            BrokerEvent::Kick { user, msg } => {
+                // The user could be connected to any server
+                if let Some(redis) = &fanout {
+                    let remote = Remote::Kick {
+                        user: user.clone(),
+                        msg: msg.clone(),
+                    };
+
+                    match pubsub::publish(redis, room, &remote).await {
+                        Ok(()) => continue,
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+
+                kick(msg, user, users, room, &shards).await;
+            }
+            BrokerEvent::Remote(remote) => match remote {
+                Remote::Broadcast { user, msg } => {
+                    send_messages(msg, user, users, room, &receipts, &shards).await
+                }
+                Remote::Kick { user, msg } => kick(msg, user, users, room, &shards).await,
+                Remote::Whisper { to, msg } => {
+                    if let Some(member) = users.get(&to) {
+                        if hides(member, &msg) {
+                            continue;
+                        }
+
+                        if let Err(e) = member.tx.try_send(msg) {
+                            eprintln!("{}", e);
+                        }
+                    }
+                }
+                Remote::Close { msg } => {
+                    close_room(msg, users, room);
+                    break;
+                }
+                Remote::Rename { to, msg } => rename_room(msg, to, room, users, &shards).await,
+            },
         }
     }
 
     Ok(())
 }
 
-async fn send_messages(msg: String, sender: String, users: &HashMap<String, Sender<String>>) {
+// Sends to everyone in the room but `sender`, through Redis if this broker
+// fans out. If publishing fails, at least members on this server get it.
+async fn broadcast(
+    fanout: &Option<Pool>,
+    receipts: &Option<Receipts>,
+    shards: &Option<Shards>,
+    msg: Message,
+    sender: String,
+    users: &mut HashMap<String, Member>,
+    room: &str,
+) {
+    if let Some(redis) = fanout {
+        let remote = Remote::Broadcast {
+            user: sender.clone(),
+            msg: msg.clone(),
+        };
+
+        match pubsub::publish(redis, room, &remote).await {
+            Ok(()) => return,
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    send_messages(msg, sender, users, room, receipts, shards).await;
+}
+
+async fn kick(
+    msg: Message,
+    user: String,
+    users: &mut HashMap<String, Member>,
+    room: &str,
+    shards: &Option<Shards>,
+) {
+    if let Some(member) = users.remove(&user) {
+        // Let them see why they were removed before their
+        // receive task shuts down
+        if let Err(e) = member.tx.try_send(msg.clone()) {
+            eprintln!("{}", e);
+        }
+
+        if let Err(e) = member
+            .changes
+            .try_send(RoomChange::Removed(room.to_owned()))
+        {
+            eprintln!("{}", e);
+        }
+
+        if let Some(shards) = shards {
+            shards.remove(&user).await;
+        }
+    }
+
+    // They're no longer in the map, so everyone else gets it
+    send_messages(msg, user.clone(), users, room, &None, shards).await;
+    send_messages(
+        Message::members(room, format!("-{}", user)),
+        user,
+        users,
+        room,
+        &None,
+        shards,
+    )
+    .await;
+}
+
+// Tells everyone the room is gone and removes them
+fn close_room(msg: Message, users: &mut HashMap<String, Member>, room: &str) {
+    for (_, member) in users.drain() {
+        if let Err(e) = member.tx.try_send(msg.clone()) {
+            eprintln!("{}", e);
+        }
+
+        if let Err(e) = member
+            .changes
+            .try_send(RoomChange::Removed(room.to_owned()))
+        {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+// Has everyone's connection follow the room to its new name, then tells them
+// who renamed it. Members are kept, so nobody has to join again.
+async fn rename_room(
+    msg: Message,
+    to: String,
+    room: &mut String,
+    users: &HashMap<String, Member>,
+    shards: &Option<Shards>,
+) {
+    for member in users.values() {
+        let change = RoomChange::Renamed(room.clone(), to.clone());
+        if let Err(e) = member.changes.try_send(change) {
+            eprintln!("{}", e);
+        }
+
+        if let Err(e) = member.tx.try_send(msg.clone()) {
+            eprintln!("{}", e);
+        }
+    }
+
+    if let Some(shards) = shards {
+        shards.rename(&to).await;
+    }
+
+    *room = to;
+}
+
+// Only chat messages are recorded in `receipts`, and only in rooms small
+// enough to be tracked. Sharded rooms hand it to their shards instead.
+async fn send_messages(
+    msg: Message,
+    sender: String,
+    users: &mut HashMap<String, Member>,
+    room: &str,
+    receipts: &Option<Receipts>,
+    shards: &Option<Shards>,
+) {
+    let track = match (receipts, msg.kind, &msg.id) {
+        (Some(receipts), MessageKind::Chat, Some(_)) => receipts.tracks(users.len()),
+        _ => false,
+    };
+
+    if let Some(shards) = shards {
+        shards.deliver(msg, &sender, track).await;
+        return;
+    }
+
+    let (delivered, overflowed) = send_to(&msg, &sender, users, track);
+
+    if let (true, Some(receipts)) = (track, receipts) {
+        receipts.record(room, delivered, msg.timestamp);
+    }
+
+    for user in overflowed {
+        if let Some(member) = users.remove(&user) {
+            drop_behind(&user, member, users, room);
+        }
+    }
+}
+
+// Queues `msg` for everyone in `users` but `sender`, returning who it was
+// queued for, including the sender when `track` is set since they've seen
+// what they sent, and who had no room left for it
+fn send_to(
+    msg: &Message,
+    sender: &str,
+    users: &HashMap<String, Member>,
+    track: bool,
+) -> (Vec<String>, Vec<String>) {
+    let mut overflowed = Vec::new();
+    let mut delivered = Vec::new();
+
     // Loop over each user in the room
-    for (user, tx) in users {
+    for (user, member) in users.iter() {
         // If they're the sender of the message, skip since they'll see
         // their message twice
-        if user == &sender {
+        if user == sender {
             continue;
         }
 
-        // Send to each user
-        if let Err(e) = tx.send(msg.clone()).await {
-            eprintln!("{}", e);
+        if hides(member, msg) {
+            continue;
+        }
+
+        // Send to each user without waiting, so one slow socket can't
+        // hold up the whole room
+        match member.tx.try_send(msg.clone()) {
+            Ok(()) => delivered.push(user.clone()),
+            Err(TrySendError::Full(_)) => overflowed.push(user.clone()),
+            Err(e) => eprintln!("{}", e),
         };
     }
+
+    if track && users.contains_key(sender) {
+        delivered.push(sender.to_owned());
+    }
+
+    (delivered, overflowed)
+}
+
+// Whether `member` has asked not to get `msg`. Kicks and bans are leaves too,
+// so quiet members don't see them either, though the member list still
+// changes.
+fn hides(member: &Member, msg: &Message) -> bool {
+    let noise = matches!(msg.kind, MessageKind::Join | MessageKind::Leave);
+
+    (noise && member.quiet.load(Ordering::Relaxed)) || block::hides(&member.blocked, msg)
 }
 
-async fn receive_messages(mut messages: Receiver<String>, stream: SharedStream) {
-    // Dropping the Sender should kill this task
-    while let Some(msg) = messages.recv().await {
-        let mut stream = stream.lock().await;
+// Tells a member who fell too far behind, already taken out of `users`,
+// that they're out of the room, and everyone else that they've gone
+fn drop_behind(user: &str, member: Member, users: &HashMap<String, Member>, room: &str) {
+    eprintln!("Removing {} from {}: too far behind", user, room);
 
-        if let Err(e) = stream.write_all(msg.as_bytes()).await {
+    if let Err(e) = member
+        .changes
+        .try_send(RoomChange::Removed(room.to_owned()))
+    {
+        eprintln!("{}", e);
+    }
+
+    // Not worth dropping anyone else over, so no overflow handling
+    let delta = Message::members(room, format!("-{}", user));
+    for member in users.values() {
+        let _ = member.tx.try_send(delta.clone());
+    }
+}
+
+// Turns a member whose connection has stopped taking messages into a leave,
+// as though they'd sent it, after telling their connection they're out. None
+// if they've already left, or joined again since.
+async fn gone(
+    redis: &Pool,
+    room: &str,
+    user: &str,
+    users: &HashMap<String, Member>,
+) -> Option<BrokerEvent> {
+    let member = users.get(user)?;
+    if !member.tx.is_closed() {
+        return None;
+    }
+
+    eprintln!("Removing {} from {}: connection is gone", user, room);
+
+    if let Err(e) = member
+        .changes
+        .try_send(RoomChange::Removed(room.to_owned()))
+    {
+        eprintln!("{}", e);
+    }
+
+    // They were let in, so their leave is saved even if they're a guest now
+    let msg = match room::event(redis, RoomEvent::Leave, room, user, true).await {
+        Ok(msg) => msg,
+        Err(e) => {
             eprintln!("{}", e);
+
+            // Everyone still hears they've gone, it just isn't in the history
+            Message::new(
+                MessageKind::Leave,
+                Some(room),
+                Some(user),
+                get_time_in_ms(),
+                room::gen_leave_msg(user),
+            )
+        }
+    };
+
+    Some(BrokerEvent::LeaveRoom {
+        user: user.to_owned(),
+        msg,
+    })
+}
+
+// Delivers to its share of a room's members for the broker. Ends when the
+// broker drops its Sender.
+async fn shard(
+    mut room: String,
+    mut events: Receiver<ShardEvent>,
+    receipts: Option<Receipts>,
+    overflowed: Sender<String>,
+    stats: Arc<ShardStats>,
+) {
+    let mut users: HashMap<String, Member> = HashMap::new();
+
+    while let Some(event) = events.recv().await {
+        match event {
+            ShardEvent::Add { user, member } => {
+                users.insert(user, member);
+            }
+            ShardEvent::Remove { user } => {
+                users.remove(&user);
+            }
+            ShardEvent::Rename { to } => room = to,
+            ShardEvent::Deliver {
+                msg,
+                sender,
+                track,
+                sent,
+            } => {
+                let (delivered, behind) = send_to(&msg, &sender, &users, track);
+
+                if let (true, Some(receipts)) = (track, &receipts) {
+                    receipts.record(&room, delivered, msg.timestamp);
+                }
+
+                for user in behind {
+                    users.remove(&user);
+
+                    if let Err(e) = overflowed.try_send(user) {
+                        eprintln!("{}", e);
+                    }
+                }
+
+                let micros = sent.elapsed().as_micros() as u64;
+                stats.fanouts.fetch_add(1, Ordering::Relaxed);
+                stats.fanout_micros.fetch_add(micros, Ordering::Relaxed);
+            }
+        }
+
+        stats.members.store(users.len(), Ordering::Relaxed);
+    }
+}
+
+// Hands whatever has queued up for the member since the last batch to their
+// connection's writer in one go, so a busy room costs a write per batch
+// rather than per message. Dropping the Sender ends this task, as does the
+// writer going away after a write fails or times out, which is reported to
+// the broker on `dead`.
+async fn receive_messages(
+    user: String,
+    mut messages: Receiver<Message>,
+    stream: WriterHandle,
+    dead: Sender<String>,
+) {
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+
+    while messages.recv_many(&mut batch, MAX_BATCH).await > 0 {
+        let msgs = std::mem::replace(&mut batch, Vec::with_capacity(MAX_BATCH));
+
+        let e = match stream.write_messages(msgs).await {
+            Ok(()) => continue,
+            Err(e) => e,
         };
+
+        eprintln!("Failed to write to {}: {}", user, e);
+
+        // Closed first, so the broker can tell this member from one who's
+        // joined again under the same name
+        messages.close();
+        if let Err(e) = dead.try_send(user) {
+            eprintln!("{}", e);
+        }
+
+        return;
     }
 }
@@ -0,0 +1,219 @@
+use caseless::Caseless;
+use redis::{AsyncCommands, Script};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::account;
+use crate::command::Command;
+use crate::namespace::NamespaceCommand;
+use crate::pool::Pool;
+use crate::room;
+
+// Names are kept as they were first written, so `Rust` stays `Rust`, but two
+// names that only differ in case or in how an accent was encoded are the same
+// name. Each kind has a hash from the folded name to the one in use, which
+// is what's looked up when someone types a name.
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    User,
+    Room,
+}
+
+impl Kind {
+    fn gen_key(self) -> &'static str {
+        match self {
+            Kind::User => "folded:users",
+            Kind::Room => "folded:rooms",
+        }
+    }
+
+    // Where a name of this kind is kept, to tell if it's still in use
+    fn gen_name_key(self, name: &str) -> String {
+        match self {
+            Kind::User => account::gen_key(name),
+            Kind::Room => room::gen_key(name),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CanonicalError {
+    FailedToSave,
+    FailedToFetch,
+}
+
+impl std::fmt::Display for CanonicalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CanonicalError::FailedToSave => writeln!(f, "Error: Failed to save name"),
+            CanonicalError::FailedToFetch => writeln!(f, "Error: Failed to look up name"),
+        }
+    }
+}
+
+impl std::error::Error for CanonicalError {}
+
+// Canonical caseless matching from the Unicode standard, normalized back to
+// NFC so it's no longer than it has to be
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::canonical::fold;
+///
+/// assert_eq!(fold("Rust"), "rust");
+/// assert_eq!(fold("STRASSE"), fold("straße"));
+/// // "é" as one character and as "e" with a combining accent
+/// assert_eq!(fold("Caf\u{e9}"), fold("cafe\u{301}"));
+/// assert_ne!(fold("rust"), fold("rusty"));
+/// ```
+pub fn fold(name: &str) -> String {
+    name.nfd().default_case_fold().nfc().collect()
+}
+
+// Takes `name` for its kind, false if another spelling of it is in use. A
+// spelling whose room or account has since gone, like a room that expired,
+// doesn't count.
+pub async fn reserve(redis: &Pool, kind: Kind, name: &str) -> Result<bool, CanonicalError> {
+    let script = Script::new(
+        r"
+        local held = redis.call('HGET', KEYS[1], ARGV[1])
+        if held and held ~= ARGV[2] and redis.call('EXISTS', ARGV[3] .. held) == 1 then
+            return 0
+        end
+        redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+        return 1
+        ",
+    );
+
+    let reserved: u8 = script
+        .key(kind.gen_key())
+        .arg(fold(name))
+        .arg(name)
+        .arg(kind.gen_name_key(""))
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            CanonicalError::FailedToSave
+        })?;
+
+    Ok(reserved == 1)
+}
+
+// Only if it's still `name`'s, so a spelling someone else took isn't freed
+pub async fn release(redis: &Pool, kind: Kind, name: &str) -> Result<(), CanonicalError> {
+    let script = Script::new(
+        r"
+        if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+            redis.call('HDEL', KEYS[1], ARGV[1])
+        end
+        ",
+    );
+
+    script
+        .key(kind.gen_key())
+        .arg(fold(name))
+        .arg(name)
+        .invoke_async::<_, ()>(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            CanonicalError::FailedToSave
+        })
+}
+
+// The spelling in use for `name`, or `name` itself if there isn't one. Names
+// that exist exactly as written win, so two rooms from before names were
+// folded that only differ in case can both still be reached.
+pub async fn resolve(redis: &Pool, kind: Kind, name: &str) -> Result<String, CanonicalError> {
+    let script = Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 1 then
+            return ARGV[2]
+        end
+        return redis.call('HGET', KEYS[2], ARGV[1]) or ARGV[2]
+        ",
+    );
+
+    script
+        .key(kind.gen_name_key(name))
+        .key(kind.gen_key())
+        .arg(fold(name))
+        .arg(name)
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            CanonicalError::FailedToFetch
+        })
+}
+
+// Rooms and accounts from before names were folded, run on startup. Ones
+// that are already there are left alone, so this only does anything once.
+pub async fn backfill(redis: &Pool) -> Result<usize, CanonicalError> {
+    let mut conn = redis.get();
+    let mut names = Vec::new();
+
+    for (kind, pattern) in [(Kind::Room, "room:*"), (Kind::User, "user:*")] {
+        let mut iter = conn.scan_match::<_, String>(pattern).await.map_err(|e| {
+            dbg!("{}", e);
+            CanonicalError::FailedToFetch
+        })?;
+
+        while let Some(key) = iter.next_item().await {
+            // Remove `room:` or `user:`
+            names.push((kind, key[5..].to_owned()));
+        }
+    }
+
+    let mut pipe = redis::pipe();
+    for (kind, name) in &names {
+        pipe.hset_nx(kind.gen_key(), fold(name), name);
+    }
+
+    let added: Vec<u8> = pipe.query_async(&mut conn).await.map_err(|e| {
+        dbg!("{}", e);
+        CanonicalError::FailedToSave
+    })?;
+
+    Ok(added.into_iter().filter(|&added| added == 1).count())
+}
+
+// Swaps the names in `command` for the spellings in use, so `>join-room RUST`
+// joins `rust`. Names that are being taken, like the one in `>register`,
+// are reserved where they're created instead.
+pub async fn command(redis: &Pool, command: &mut Command) -> Result<(), CanonicalError> {
+    let (kind, name) = match command {
+        Command::JoinRoom(room, _, _)
+        | Command::Digest(room, _)
+        | Command::Stats(room)
+        | Command::Export(room, _)
+        | Command::Switch(room)
+        | Command::AcceptOwnership(room) => (Kind::Room, room),
+        Command::Login(user, _)
+        | Command::Presence(user)
+        | Command::DirectMessage(user, _)
+        | Command::Whisper(user, _)
+        | Command::KeyExchange(Some(user), _)
+        | Command::TransferOwnership(user)
+        | Command::Kick(user)
+        | Command::Ban(user)
+        | Command::Invite(user)
+        | Command::Approve(user)
+        | Command::Deny(user)
+        | Command::Unmute(user)
+        | Command::Block(Some(user))
+        | Command::Unblock(user)
+        | Command::Grant(user, _, _)
+        | Command::ForgetUser(user)
+        | Command::Report(user, _)
+        | Command::Namespace(_, NamespaceCommand::Allow(user))
+        | Command::Namespace(_, NamespaceCommand::Revoke(user)) => (Kind::User, user),
+        _ => return Ok(()),
+    };
+
+    *name = resolve(redis, kind, name).await?;
+
+    Ok(())
+}
@@ -0,0 +1,695 @@
+use std::collections::VecDeque;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Lines, Stdout};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use unicode_width::UnicodeWidthChar;
+
+use crate::color;
+use crate::command;
+use crate::message::{Message, MessageKind, Protocol};
+use crate::pow::Challenge;
+
+const PROMPT: &str = "> ";
+
+// First wait before reconnecting, doubled after each failed attempt
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Lines typed while disconnected, sent once the connection is back
+const MAX_QUEUED: usize = 100;
+
+// Envelope reference for the client's own `>me`, whose reply isn't shown
+const SESSION_REF: &str = "session";
+
+// How the last line of the server's greeting starts
+const GREETING_END: &str = "Enter \">help\"";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    // Ctrl-U and Ctrl-W
+    KillLine,
+    KillWord,
+    // Ctrl-C and Ctrl-D
+    Interrupt,
+    Eof,
+}
+
+// Turns bytes from a terminal into keys, keeping anything cut off partway
+// through an escape sequence or character for the next read
+#[derive(Debug, Default)]
+pub struct KeyReader {
+    pending: Vec<u8>,
+}
+
+impl KeyReader {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::client::{Key, KeyReader};
+    ///
+    /// let mut reader = KeyReader::default();
+    ///
+    /// assert_eq!(reader.feed(b"h\x1b[D"), vec![Key::Char('h'), Key::Left]);
+    ///
+    /// // Split across reads
+    /// assert_eq!(reader.feed(b"\x1b["), vec![]);
+    /// assert_eq!(reader.feed(b"A\xc3"), vec![Key::Up]);
+    /// assert_eq!(reader.feed(b"\xa9\n"), vec![Key::Char('é'), Key::Enter]);
+    /// ```
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Key> {
+        self.pending.extend_from_slice(bytes);
+
+        let mut keys = Vec::new();
+        let mut used = 0;
+
+        while used < self.pending.len() {
+            let (key, len) = match decode(&self.pending[used..]) {
+                Some(decoded) => decoded,
+                None => break,
+            };
+
+            keys.extend(key);
+            used += len;
+        }
+
+        self.pending.drain(..used);
+
+        keys
+    }
+}
+
+// The key at the start of `bytes` and how many bytes it took, None if it's
+// incomplete. Keys the editor doesn't use come back as Some((None, len)) so
+// they're skipped.
+fn decode(bytes: &[u8]) -> Option<(Option<Key>, usize)> {
+    let key = match bytes[0] {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        0x01 => Key::Home,
+        0x05 => Key::End,
+        0x02 => Key::Left,
+        0x06 => Key::Right,
+        0x15 => Key::KillLine,
+        0x17 => Key::KillWord,
+        0x03 => Key::Interrupt,
+        0x04 => Key::Eof,
+        0x1b => return decode_escape(bytes),
+        b if b < 0x20 => return Some((None, 1)),
+        _ => return decode_char(bytes),
+    };
+
+    Some((Some(key), 1))
+}
+
+// Arrows, Home, End and Delete, as `ESC [ x` or `ESC O x`
+fn decode_escape(bytes: &[u8]) -> Option<(Option<Key>, usize)> {
+    // Longer than anything we understand, so give up on it
+    const MAX_SEQUENCE: usize = 8;
+
+    match bytes.get(1) {
+        Some(b'[' | b'O') => {}
+        Some(_) => return Some((None, 1)),
+        None => return None,
+    }
+
+    let end = match bytes[2..].iter().position(|b| (0x40..=0x7e).contains(b)) {
+        Some(i) => i + 2,
+        None if bytes.len() >= MAX_SEQUENCE => return Some((None, bytes.len())),
+        None => return None,
+    };
+
+    let key = match &bytes[2..=end] {
+        b"A" => Some(Key::Up),
+        b"B" => Some(Key::Down),
+        b"C" => Some(Key::Right),
+        b"D" => Some(Key::Left),
+        b"H" | b"1~" | b"7~" => Some(Key::Home),
+        b"F" | b"4~" | b"8~" => Some(Key::End),
+        b"3~" => Some(Key::Delete),
+        _ => None,
+    };
+
+    Some((key, end + 1))
+}
+
+fn decode_char(bytes: &[u8]) -> Option<(Option<Key>, usize)> {
+    let len = match bytes[0] {
+        b if b < 0x80 => 1,
+        b if b >> 5 == 0b110 => 2,
+        b if b >> 4 == 0b1110 => 3,
+        b if b >> 3 == 0b11110 => 4,
+        _ => return Some((None, 1)),
+    };
+
+    let bytes = bytes.get(..len)?;
+
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Some((s.chars().next().map(Key::Char), len)),
+        Err(_) => Some((None, 1)),
+    }
+}
+
+// What a key did to the line being typed
+#[derive(Debug, PartialEq)]
+pub enum Edit {
+    Changed,
+    Submit(String),
+    Quit,
+}
+
+// The line being typed, with the usual readline keys and a history of what
+// was sent this session
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    line: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    // Where Up and Down have got to in `history`, and the line that was
+    // being typed before
+    browsing: Option<(usize, Vec<char>)>,
+}
+
+impl LineEditor {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::client::{Edit, Key, LineEditor};
+    ///
+    /// let mut editor = LineEditor::default();
+    ///
+    /// for key in [Key::Char('h'), Key::Char('i'), Key::Left, Key::Char('o')] {
+    ///     editor.handle(key);
+    /// }
+    /// assert_eq!(editor.line(), "hoi");
+    ///
+    /// assert_eq!(editor.handle(Key::Enter), Edit::Submit("hoi".into()));
+    /// assert_eq!(editor.line(), "");
+    ///
+    /// editor.handle(Key::Up);
+    /// assert_eq!(editor.line(), "hoi");
+    /// editor.handle(Key::Down);
+    /// assert_eq!(editor.line(), "");
+    ///
+    /// assert_eq!(editor.handle(Key::Eof), Edit::Quit);
+    /// ```
+    pub fn handle(&mut self, key: Key) -> Edit {
+        match key {
+            Key::Char(c) => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Enter => return self.submit(),
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::Up => self.browse_back(),
+            Key::Down => self.browse_forward(),
+            Key::KillLine => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            // Back over any spaces, then the word before them
+            Key::KillWord => {
+                let mut start = self.cursor;
+                while start > 0 && self.line[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                while start > 0 && !self.line[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+
+                self.line.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::Interrupt => return Edit::Quit,
+            // Like a shell, only on an empty line
+            Key::Eof if self.line.is_empty() => return Edit::Quit,
+            Key::Eof => return self.handle(Key::Delete),
+            Key::Backspace | Key::Delete => {}
+        }
+
+        Edit::Changed
+    }
+
+    pub fn line(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    // The part of the line that fits in `width` columns, scrolled to keep
+    // the cursor in view, and the cursor's column within it
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::client::{Key, LineEditor};
+    ///
+    /// let mut editor = LineEditor::default();
+    /// "hello world".chars().for_each(|c| { editor.handle(Key::Char(c)); });
+    ///
+    /// assert_eq!(editor.view(20), ("hello world".to_owned(), 11));
+    /// assert_eq!(editor.view(6), ("world".to_owned(), 5));
+    ///
+    /// editor.handle(Key::Home);
+    /// assert_eq!(editor.view(6), ("hello".to_owned(), 0));
+    /// ```
+    pub fn view(&self, width: usize) -> (String, usize) {
+        // The last column is kept for the cursor
+        let room = width.saturating_sub(1).max(1);
+        let char_width = |c: &char| c.width().unwrap_or(0);
+
+        let mut start = 0;
+        while self.line[start..self.cursor]
+            .iter()
+            .map(char_width)
+            .sum::<usize>()
+            > room
+        {
+            start += 1;
+        }
+
+        let mut used = 0;
+        let visible: String = self.line[start..]
+            .iter()
+            .take_while(|c| {
+                used += char_width(c);
+                used <= room
+            })
+            .collect();
+        let column = self.line[start..self.cursor].iter().map(char_width).sum();
+
+        (visible, column)
+    }
+
+    fn submit(&mut self) -> Edit {
+        let line = self.line();
+        self.line.clear();
+        self.cursor = 0;
+        self.browsing = None;
+
+        if line.is_empty() {
+            return Edit::Changed;
+        }
+
+        if self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+
+        Edit::Submit(line)
+    }
+
+    fn browse_back(&mut self) {
+        let i = match &self.browsing {
+            Some((0, _)) => return,
+            Some((i, _)) => i - 1,
+            None if self.history.is_empty() => return,
+            None => self.history.len() - 1,
+        };
+
+        let typed = match self.browsing.take() {
+            Some((_, typed)) => typed,
+            None => self.line.clone(),
+        };
+        self.browsing = Some((i, typed));
+        self.set_line(self.history[i].chars().collect());
+    }
+
+    fn browse_forward(&mut self) {
+        let (i, typed) = match self.browsing.take() {
+            Some(browsing) => browsing,
+            None => return,
+        };
+
+        match self.history.get(i + 1) {
+            Some(line) => {
+                let line = line.chars().collect();
+                self.browsing = Some((i + 1, typed));
+                self.set_line(line);
+            }
+            None => self.set_line(typed),
+        }
+    }
+
+    fn set_line(&mut self, line: Vec<char>) {
+        self.cursor = line.len();
+        self.line = line;
+    }
+}
+
+// Output goes above the line being typed. Everything printed clears the
+// prompt first and draws it again after, so the two never mix.
+struct Screen {
+    stdout: Stdout,
+    editor: LineEditor,
+    // Without a terminal, like when input is piped in, there's no prompt
+    interactive: bool,
+    color: bool,
+    width: usize,
+}
+
+impl Screen {
+    async fn print(&mut self, text: &str) -> io::Result<()> {
+        let mut out = String::new();
+        if self.interactive {
+            out.push_str("\r\x1b[2K");
+        }
+        out.push_str(text);
+        if !text.ends_with('\n') {
+            out.push('\n');
+        }
+
+        self.stdout.write_all(out.as_bytes()).await?;
+        self.redraw().await
+    }
+
+    async fn redraw(&mut self) -> io::Result<()> {
+        if !self.interactive {
+            return self.stdout.flush().await;
+        }
+
+        let (visible, column) = self.editor.view(self.width.saturating_sub(PROMPT.len()));
+        let mut out = format!("\r\x1b[2K{}{}\r", PROMPT, visible);
+        let column = PROMPT.len() + column;
+        if column > 0 {
+            out.push_str(&format!("\x1b[{}C", column));
+        }
+
+        self.stdout.write_all(out.as_bytes()).await?;
+        self.stdout.flush().await
+    }
+
+    // What was sent, dimmed, with passwords hidden
+    async fn echo(&mut self, line: &str) -> io::Result<()> {
+        if !self.interactive {
+            return Ok(());
+        }
+
+        let line = format!("{}{}", PROMPT, command::redact(line));
+        match self.color {
+            true => self.print(&color::paint(color::DIM, &line)).await,
+            false => self.print(&line).await,
+        }
+    }
+
+    async fn clear(&mut self) -> io::Result<()> {
+        if self.interactive {
+            self.stdout.write_all(b"\r\x1b[2K").await?;
+        }
+
+        self.stdout.flush().await
+    }
+}
+
+// Puts the terminal in raw mode until dropped. `stty` does it, so nothing
+// beyond std is needed, and it fails when stdin isn't a terminal.
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enable() -> Option<Self> {
+        let saved = stty(&["-g"])?;
+        // Keys like Ctrl-C are read as bytes instead of sending signals, so
+        // the terminal is always restored
+        stty(&["-icanon", "-echo", "-isig", "-ixon"])?;
+
+        Some(Self { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+// Columns in the terminal when the client started
+fn terminal_width() -> usize {
+    const DEFAULT_WIDTH: usize = 80;
+
+    stty(&["size"])
+        .and_then(|size| size.split_once(' ')?.1.parse().ok())
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+// Connects to the server at `addr` and runs until the user quits, or input
+// ends and the server has answered everything before it. Dropped
+// connections are retried with a growing delay and picked up with >resume.
+pub async fn run(addr: String, color: bool) -> io::Result<()> {
+    let raw = RawMode::enable();
+    let mut screen = Screen {
+        stdout: io::stdout(),
+        editor: LineEditor::default(),
+        interactive: raw.is_some(),
+        color,
+        width: terminal_width(),
+    };
+
+    let mut stdin = io::stdin();
+    let mut keys = KeyReader::default();
+    let mut buf = [0u8; 1024];
+    let mut input_done = false;
+
+    let mut reader: Option<Lines<BufReader<OwnedReadHalf>>> = None;
+    let mut writer: Option<OwnedWriteHalf> = None;
+    let mut queued: VecDeque<String> = VecDeque::new();
+    let mut session: Option<String> = None;
+    let mut exiting = false;
+    // A new connection's writer, held back until the greeting's over so a
+    // challenge in it is answered before anything else is sent
+    let mut greeting: Option<(OwnedWriteHalf, Option<u64>)> = None;
+
+    let mut delay = RETRY_DELAY;
+    let mut retry_at = Instant::now();
+
+    screen.redraw().await?;
+
+    loop {
+        tokio::select! {
+            n = stdin.read(&mut buf), if !input_done => {
+                let n = n?;
+
+                // Piped input has run out, so the server gets to answer it
+                // before closing the connection
+                if n == 0 {
+                    input_done = true;
+                    exiting = true;
+                    send(&mut writer, &mut queued, ">exit").await;
+                    continue;
+                }
+
+                for key in keys.feed(&buf[..n]) {
+                    match screen.editor.handle(key) {
+                        Edit::Changed => {}
+                        Edit::Submit(line) => {
+                            screen.echo(&line).await?;
+                            exiting = line == ">exit";
+                            send(&mut writer, &mut queued, &line).await;
+                        }
+                        Edit::Quit => return screen.clear().await,
+                    }
+                }
+
+                screen.redraw().await?;
+            }
+            line = next_line(&mut reader) => {
+                if let Some(line) = line {
+                    if let Some((_, answer)) = &mut greeting {
+                        if let Some(challenge) = Challenge::parse(&line) {
+                            *answer = tokio::task::spawn_blocking(move || challenge.solve())
+                                .await
+                                .ok();
+                            continue;
+                        }
+                        if line.starts_with(GREETING_END) {
+                            let (mut write, answer) = greeting.take().unwrap();
+                            if let Err(e) = start(&mut write, answer, session.as_deref(), &mut queued).await {
+                                screen.print(&format!("Couldn't start session: {}", e)).await?;
+                                reader = None;
+                                retry_at = Instant::now() + delay;
+                                continue;
+                            }
+                            writer = Some(write);
+                        }
+                    }
+                    if let Some(text) = render(&line, &mut session, screen.color) {
+                        screen.print(&text).await?;
+                    }
+                    continue;
+                }
+
+                reader = None;
+                writer = None;
+                greeting = None;
+
+                if exiting {
+                    return screen.clear().await;
+                }
+
+                screen.print("Disconnected, reconnecting…").await?;
+                retry_at = Instant::now();
+            }
+            _ = tokio::time::sleep_until(retry_at), if reader.is_none() => {
+                let stream = match connect(&addr).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let secs = delay.as_secs();
+                        let msg = format!("Couldn't connect to {}: {}, retrying in {}s", addr, e, secs);
+                        screen.print(&msg).await?;
+                        retry_at = Instant::now() + delay;
+                        delay = (delay * 2).min(MAX_RETRY_DELAY);
+                        continue;
+                    }
+                };
+                delay = RETRY_DELAY;
+
+                let (read, write) = stream.into_split();
+                reader = Some(BufReader::new(read).lines());
+                greeting = Some((write, None));
+            }
+        }
+    }
+}
+
+async fn connect(addr: &str) -> io::Result<TcpStream> {
+    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(stream) => stream,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+    }
+}
+
+// Answers the greeting's challenge if it had one, switches the connection
+// to JSON, resumes the last one's session if there was one, asks for this
+// one's token and sends anything typed while disconnected
+async fn start(
+    writer: &mut OwnedWriteHalf,
+    answer: Option<u64>,
+    session: Option<&str>,
+    queued: &mut VecDeque<String>,
+) -> io::Result<()> {
+    let mut setup = String::new();
+    if let Some(answer) = answer {
+        setup.push_str(&format!(">pow {}\n", answer));
+    }
+    setup.push_str(">protocol json\n");
+    if let Some(token) = session {
+        setup.push_str(&format!(">resume {}\n", token));
+    }
+    let me = serde_json::json!({ "ref": SESSION_REF, "line": ">me" });
+    setup.push_str(&format!("{}\n", me));
+
+    for line in queued.drain(..) {
+        setup.push_str(&line);
+        setup.push('\n');
+    }
+
+    writer.write_all(setup.as_bytes()).await
+}
+
+// Sends the line, or keeps it for when there's a connection again. A failed
+// write is noticed by the reader.
+async fn send(writer: &mut Option<OwnedWriteHalf>, queued: &mut VecDeque<String>, line: &str) {
+    match writer {
+        Some(writer) => {
+            let _ = writer.write_all(format!("{}\n", line).as_bytes()).await;
+        }
+        None => {
+            if queued.len() == MAX_QUEUED {
+                queued.pop_front();
+            }
+            queued.push_back(line.to_owned());
+        }
+    }
+}
+
+async fn next_line(reader: &mut Option<Lines<BufReader<OwnedReadHalf>>>) -> Option<String> {
+    match reader {
+        Some(reader) => reader.next_line().await.ok().flatten(),
+        None => std::future::pending().await,
+    }
+}
+
+// What to show for a line from the server, None for ones the client deals
+// with itself. Anything that isn't JSON, like the greeting sent before the
+// protocol switches, is shown as it is.
+fn render(line: &str, session: &mut Option<String>, color: bool) -> Option<String> {
+    let msg: Message = match serde_json::from_str(line) {
+        Ok(msg) => msg,
+        Err(_) => return Some(line.to_owned()),
+    };
+
+    // `>me` ends with the session token
+    if msg.reference.as_deref() == Some(SESSION_REF) {
+        if let Some((_, token)) = msg.body.trim_end().rsplit_once("Session: ") {
+            *session = Some(token.to_owned());
+        }
+        return None;
+    }
+
+    if matches!(
+        msg.kind,
+        MessageKind::Members | MessageKind::Expire | MessageKind::Ack
+    ) {
+        return None;
+    }
+
+    let text = match color {
+        true => msg.render_colored(),
+        false => color::strip(&msg.render(Protocol::Text)),
+    };
+
+    // JSON has every message's room, where text only has it for the rooms
+    // you aren't sending to
+    let room = match &msg.room {
+        Some(room) if !matches!(msg.kind, MessageKind::History | MessageKind::Export) => {
+            format!("[{}] ", room)
+        }
+        _ => return Some(text),
+    };
+
+    Some(match color {
+        true => format!("{}{}", color::paint(color::DIM, &room), text),
+        false => format!("{}{}", room, text),
+    })
+}
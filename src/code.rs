@@ -0,0 +1,363 @@
+use redis::RedisError;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::account::AccountError;
+use crate::announce::AnnounceError;
+use crate::archive::ArchiveError;
+use crate::audit::AuditError;
+use crate::block::BlockError;
+use crate::bots::BotError;
+use crate::canonical::CanonicalError;
+use crate::command::ParseError;
+use crate::dm::DmError;
+use crate::federation::FederationError;
+use crate::motd::MotdError;
+use crate::names::NameError;
+use crate::namespace::NamespaceError;
+use crate::paste::PasteError;
+use crate::permissions::PermissionError;
+use crate::plugin::PluginError;
+use crate::presence::PresenceError;
+use crate::pubsub::PubSubError;
+use crate::receipts::ReceiptError;
+use crate::report::ReportError;
+use crate::room::RoomError;
+use crate::search::SearchError;
+use crate::session::SessionError;
+use crate::signing::SigningError;
+use crate::stats::StatsError;
+use crate::validate::ValidationError;
+use crate::webhook::WebhookError;
+
+// What kind of error an `error` message is, for clients to act on without
+// matching its text, which can change. Sent to JSON clients as `code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    // Redis or something else the server needs didn't answer, so trying
+    // again later may work
+    Unavailable,
+    RoomNotFound,
+    UserNotFound,
+    // They exist, but aren't connected and can't be sent to
+    UserOffline,
+    // A message, paste, session or anything else asked for by id
+    NotFound,
+    NameTaken,
+    RateLimited,
+    NotAuthenticated,
+    // Hasn't answered the proof of work from the greeting yet
+    ChallengeRequired,
+    InvalidCredentials,
+    // Needs a role, ownership or an invite they don't have
+    Forbidden,
+    Banned,
+    Muted,
+    NotInRoom,
+    // Not a command, or not the arguments it takes
+    InvalidCommand,
+    // The arguments parsed but aren't allowed, like a name that's too long
+    InvalidArgument,
+    // Rejected by a filter
+    Filtered,
+    // One too many rooms, webhooks or bytes
+    LimitReached,
+    // Turned off on this server
+    Disabled,
+    Internal,
+}
+
+impl ErrorCode {
+    // As it's sent, like `ROOM_NOT_FOUND`
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::code::{Coded, ErrorCode};
+    /// use chatsapp::room::RoomError;
+    ///
+    /// assert_eq!(ErrorCode::RoomNotFound.name(), "ROOM_NOT_FOUND");
+    /// assert_eq!(RoomError::RoomNameTaken.code(), ErrorCode::NameTaken);
+    /// assert_eq!(RoomError::FailedToFetch.code().name(), "UNAVAILABLE");
+    /// ```
+    pub fn name(self) -> String {
+        serde_json::to_value(self)
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+}
+
+// Errors that can be written to a client, which all need a code
+pub trait Coded {
+    fn code(&self) -> ErrorCode;
+}
+
+// The room's broker stopped, or didn't answer
+impl<T> Coded for mpsc::error::SendError<T> {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+}
+
+impl Coded for oneshot::error::RecvError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+}
+
+impl Coded for RedisError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+}
+
+impl Coded for AccountError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            AccountError::FailedToSave | AccountError::FailedToFetch => ErrorCode::Unavailable,
+            AccountError::FailedToHash => ErrorCode::Internal,
+            AccountError::UsernameTaken => ErrorCode::NameTaken,
+            AccountError::InvalidCredentials => ErrorCode::InvalidCredentials,
+        }
+    }
+}
+
+impl Coded for AnnounceError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            AnnounceError::FailedToSave | AnnounceError::FailedToFetch => ErrorCode::Unavailable,
+            AnnounceError::NotFound => ErrorCode::NotFound,
+        }
+    }
+}
+
+impl Coded for ArchiveError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            ArchiveError::FailedToFetch | ArchiveError::FailedToDelete => ErrorCode::Unavailable,
+            ArchiveError::FailedToWrite(_) => ErrorCode::Internal,
+            ArchiveError::RoomNotFound => ErrorCode::RoomNotFound,
+            ArchiveError::InvalidFormat => ErrorCode::InvalidArgument,
+        }
+    }
+}
+
+impl Coded for AuditError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+}
+
+impl Coded for BlockError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            BlockError::FailedToSave | BlockError::FailedToFetch => ErrorCode::Unavailable,
+            BlockError::Yourself => ErrorCode::InvalidArgument,
+            BlockError::NotBlocked => ErrorCode::NotFound,
+        }
+    }
+}
+
+impl Coded for BotError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            BotError::FailedToSave | BotError::FailedToFetch => ErrorCode::Unavailable,
+            BotError::Builtin => ErrorCode::InvalidArgument,
+            BotError::Taken(_) => ErrorCode::NameTaken,
+            BotError::NotClaimed => ErrorCode::NotFound,
+        }
+    }
+}
+
+impl Coded for CanonicalError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+}
+
+impl Coded for ParseError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::InvalidCommand
+    }
+}
+
+impl Coded for DmError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            DmError::FailedToSend | DmError::FailedToDelete | DmError::FailedToFetch => {
+                ErrorCode::Unavailable
+            }
+            DmError::UserNotOnline => ErrorCode::UserOffline,
+        }
+    }
+}
+
+impl Coded for FederationError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+}
+
+impl Coded for MotdError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+}
+
+impl Coded for NameError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            NameError::FailedToSend => ErrorCode::Unavailable,
+            NameError::Taken => ErrorCode::NameTaken,
+        }
+    }
+}
+
+impl Coded for NamespaceError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            NamespaceError::FailedToSave | NamespaceError::FailedToFetch => ErrorCode::Unavailable,
+            NamespaceError::NotAllowed(_) | NamespaceError::NotOwner => ErrorCode::Forbidden,
+            NamespaceError::NotFound => ErrorCode::NotFound,
+        }
+    }
+}
+
+impl Coded for PasteError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            PasteError::FailedToSave | PasteError::FailedToFetch => ErrorCode::Unavailable,
+            PasteError::NotFound => ErrorCode::NotFound,
+            PasteError::TooLarge => ErrorCode::LimitReached,
+        }
+    }
+}
+
+impl Coded for PermissionError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            PermissionError::FailedToSave | PermissionError::FailedToFetch => {
+                ErrorCode::Unavailable
+            }
+            PermissionError::InvalidRole => ErrorCode::InvalidArgument,
+            PermissionError::NotAllowed(_)
+            | PermissionError::Outranked(_)
+            | PermissionError::RoleTooHigh => ErrorCode::Forbidden,
+        }
+    }
+}
+
+impl Coded for PluginError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Internal
+    }
+}
+
+impl Coded for PresenceError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+}
+
+impl Coded for PubSubError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+}
+
+impl Coded for ReceiptError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            ReceiptError::FailedToSave | ReceiptError::FailedToFetch => ErrorCode::Unavailable,
+            ReceiptError::InvalidId => ErrorCode::InvalidArgument,
+        }
+    }
+}
+
+impl Coded for ReportError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            ReportError::FailedToSave | ReportError::FailedToFetch => ErrorCode::Unavailable,
+            ReportError::NotFound => ErrorCode::NotFound,
+            ReportError::Yourself => ErrorCode::InvalidArgument,
+        }
+    }
+}
+
+impl Coded for RoomError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            RoomError::FailedToSend
+            | RoomError::FailedToFetch
+            | RoomError::FailedToCheckRoomExists => ErrorCode::Unavailable,
+            RoomError::RoomNameTaken => ErrorCode::NameTaken,
+            RoomError::NotAuthenticated => ErrorCode::NotAuthenticated,
+            RoomError::IncorrectPassword => ErrorCode::InvalidCredentials,
+            RoomError::Banned => ErrorCode::Banned,
+            RoomError::TooManyRooms | RoomError::TooManyOwned(_) => ErrorCode::LimitReached,
+            RoomError::MessageNotFound | RoomError::NoOffer | RoomError::NoRequest => {
+                ErrorCode::NotFound
+            }
+            RoomError::NotAuthor
+            | RoomError::Signed
+            | RoomError::NotInvited
+            | RoomError::NotOwner => ErrorCode::Forbidden,
+            RoomError::NotInviteOnly | RoomError::NotMuted => ErrorCode::InvalidArgument,
+            RoomError::Muted(_) => ErrorCode::Muted,
+        }
+    }
+}
+
+impl Coded for SearchError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+}
+
+impl Coded for SessionError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            SessionError::FailedToSave | SessionError::FailedToFetch => ErrorCode::Unavailable,
+            SessionError::NotFound => ErrorCode::NotFound,
+        }
+    }
+}
+
+impl Coded for SigningError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            SigningError::FailedToSave | SigningError::FailedToFetch => ErrorCode::Unavailable,
+            SigningError::InvalidKey | SigningError::InvalidSignature => ErrorCode::InvalidArgument,
+            SigningError::NoKey => ErrorCode::NotFound,
+            SigningError::Expired | SigningError::Replayed => ErrorCode::Forbidden,
+        }
+    }
+}
+
+impl Coded for StatsError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+}
+
+impl Coded for ValidationError {
+    fn code(&self) -> ErrorCode {
+        ErrorCode::InvalidArgument
+    }
+}
+
+impl Coded for WebhookError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            WebhookError::FailedToSave | WebhookError::FailedToFetch => ErrorCode::Unavailable,
+            WebhookError::InvalidUrl | WebhookError::UnknownHost => ErrorCode::InvalidArgument,
+            WebhookError::Internal => ErrorCode::Forbidden,
+            WebhookError::TooMany => ErrorCode::LimitReached,
+            WebhookError::NotFound => ErrorCode::NotFound,
+        }
+    }
+}
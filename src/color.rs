@@ -0,0 +1,93 @@
+// ANSI escape codes for clients that turn on `>set color on`
+
+pub const BOLD: &str = "1";
+pub const DIM: &str = "2";
+pub const ITALIC: &str = "3";
+// Swaps the text and background colors
+pub const REVERSE: &str = "7";
+pub const RED: &str = "31";
+pub const GREEN: &str = "32";
+pub const YELLOW: &str = "33";
+pub const CYAN: &str = "36";
+
+// Usernames get one of these, picked from the name so it's stable
+const USER_COLORS: [&str; 6] = ["31", "32", "33", "34", "35", "36"];
+
+const ESC: char = '\x1b';
+
+pub fn paint(code: &str, text: &str) -> String {
+    format!("{}[{}m{}{}[0m", ESC, code, text, ESC)
+}
+
+pub fn user(name: &str) -> String {
+    // FNV-1a, anything that spreads names across the palette will do
+    let hash = name.bytes().fold(0x811c9dc5u32, |h, b| {
+        (h ^ b as u32).wrapping_mul(0x01000193)
+    });
+    let code = USER_COLORS[hash as usize % USER_COLORS.len()];
+
+    paint(&format!("{};{}", BOLD, code), name)
+}
+
+// Highlights every `@name` in the body
+pub fn mentions(body: &str) -> String {
+    body.split(' ')
+        .map(|word| match word.starts_with('@') && word.len() > 1 {
+            true => paint(&format!("{};{}", BOLD, YELLOW), word),
+            false => word.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::color;
+///
+/// let painted = color::paint(color::RED, "oops");
+///
+/// assert_eq!(painted, "\x1b[31moops\x1b[0m");
+/// assert_eq!(color::strip(&painted), "oops");
+/// assert_eq!(color::strip("\x1b]0;title\x07hi"), "hi");
+/// ```
+pub fn strip(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != ESC {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            // CSI, e.g. colors and cursor movement: parameters then a final byte
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC, e.g. setting the window title: ends with BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == ESC && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Anything else is a two character sequence
+            _ => {}
+        }
+    }
+
+    out
+}
@@ -1,25 +1,1115 @@
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::archive::Format;
+use crate::hello::Hello;
+use crate::message::{MessageKind, Protocol};
+use crate::namespace::NamespaceCommand;
+use crate::permissions::Role;
+use crate::retention::{Limit, Setting};
+use crate::room::{Kinds, Options};
+use crate::status::Status;
+use crate::webhook::WebhookCommand;
+
 #[derive(Debug, PartialEq)]
 pub enum Command {
     Help,
-    List,
+    // `true` for the JSON listing meant for clients
+    Commands(bool),
+    // Sorted by latest activity, and only rooms you own
+    // Namespace, then --active and --mine, and which page from 1
+    List(Option<String>, bool, bool, usize),
+    // Keywords to look for in room names and topics
+    Find(String),
     Me,
+    Who,
+    Ping,
+    Unread,
+    Typing,
+    Presence(String),
+    // And whether to zlib what's written
+    SetProtocol(Protocol, bool),
+    SetTimestamps(bool),
+    SetIds(bool),
+    SetColor(bool),
+    SetBell(bool),
+    // Hide joins and leaves
+    SetQuiet(bool),
+    // Offset from UTC in minutes
+    SetTimezone(i32),
+    // Messages replayed on joining a room
+    SetHistory(usize),
+    // Which kinds of them
+    SetHistoryTypes(Kinds),
+    // None when they're back
+    SetStatus(Option<Status>),
     SetUsername(String),
-    CreateRoom(String),
-    JoinRoom(String),
+    Register(String, String),
+    Login(String, String),
+    Resume(String),
+    // None shows your key
+    PublicKey(Option<String>),
+    RemovePublicKey,
+    CreateRoom(String, Option<String>, Options),
+    // Room, password or invite, and whether to replay recent messages
+    JoinRoom(String, Option<String>, bool),
     Message(String),
+    // Count, before when, and which kinds of message
+    History(usize, Option<isize>, Kinds),
+    // Room, and how far back in seconds
+    Digest(String, u64),
+    Stats(String),
+    Export(String, Format),
+    DirectMessage(String, String),
+    Dms,
+    Whisper(String, String),
+    // Who to send it to, or everyone in the room, and the key
+    KeyExchange(Option<String>, String),
     Leave,
+    Switch(String),
+    Topic(String),
+    // None shows the room's language, `off` removes it
+    Lang(Option<String>),
+    // None shows the room's current policy
+    Retention(Option<(Limit, Setting)>),
+    // How long messages last in seconds, 0 to stop them disappearing, or
+    // None to show it
+    Disappearing(Option<u64>),
+    Webhook(WebhookCommand),
+    Reply(String, String),
+    // When it was signed in ms, the base64 signature, and the message
+    Signed(isize, String, String),
+    Paste,
+    Fetch(String),
+    Edit(String, String),
+    Delete(String),
+    // Message id and emoji
+    React(String, String),
+    DeleteRoom,
+    // The room's new name
+    RenameRoom(String),
+    // Who to offer your room to
+    TransferOwnership(String),
+    // The room you were offered
+    AcceptOwnership(String),
+    Kick(String),
+    Ban(String),
+    Invite(String),
+    // Join requests for your invite only room, and deciding them
+    Requests,
+    Approve(String),
+    Deny(String),
+    Unmute(String),
+    // Message id
+    Receipts(String),
+    Namespace(String, NamespaceCommand),
+    // None lists who's blocked
+    Block(Option<String>),
+    Unblock(String),
+    // User, role, and whether it's for every room
+    Grant(String, Role, bool),
+    Announce(String),
+    Broadcast(String),
+    // Who, and why
+    Report(String, Option<String>),
+    Reports,
+    Resolve(String),
+    ForgetUser(String),
+    // None goes back to the configured one
+    SetMotd(Option<String>),
+    // A command for a bot to answer in your room, None lists them
+    Claim(Option<String>),
+    Unclaim(String),
     Invalid,
+    // A known command with arguments that don't fit it
+    Usage(ParseError),
+    // The answer to the proof of work in the greeting
+    Pow(String),
+    // What the client can do, to set everything up in one line
+    Hello(Hello),
     Exit,
 }
 
-const HELP: &str = ">help";
-const EXIT: &str = ">exit";
-const LIST: &str = ">list";
-const ME: &str = ">me";
-const LEAVE: &str = ">leave";
-const SET_USERNAME: &str = ">set-username";
-const CREATE_ROOM: &str = ">create-room";
-const JOIN_ROOM: &str = ">join-room";
+#[derive(Debug, Serialize)]
+pub struct Arg {
+    pub name: &'static str,
+    pub required: bool,
+    // Passwords and tokens, which clients should mask and logs shouldn't keep
+    pub secret: bool,
+    // Takes the rest of the line as typed, spaces, quotes and all
+    pub rest: bool,
+}
+
+// One row of the command table. Parsing, usage errors, `>help` and
+// `>commands --machine` are all driven by this, so adding a command only
+// means adding a row.
+#[derive(Serialize)]
+pub struct Spec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub args: &'static [Arg],
+    pub description: &'static str,
+    // Reads the arguments from what came after the command's name. Anything
+    // left over once it's done is an error.
+    #[serde(skip)]
+    parse: fn(&mut Args) -> Result<Command, ParseError>,
+}
+
+// Why a known command's arguments couldn't be parsed. Each shows the
+// command's usage under it.
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::command::ParseError;
+///
+/// assert_eq!(
+///     ParseError::Missing(">join-room", "room").to_string(),
+///     "Error: >join-room needs room\nUsage: >join-room room [password|invite] [--no-history]\n"
+/// );
+/// ```
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    // The command, and the argument it needs
+    Missing(&'static str, &'static str),
+    // The command, the argument, and what was given for it
+    Invalid(&'static str, &'static str, String),
+    // The command, and the first word it didn't expect
+    Unexpected(&'static str, String),
+    // A quote that wasn't closed, or was followed by more of the word
+    Quote(&'static str),
+}
+
+impl ParseError {
+    fn command(&self) -> &'static str {
+        match self {
+            ParseError::Missing(command, _)
+            | ParseError::Invalid(command, _, _)
+            | ParseError::Unexpected(command, _)
+            | ParseError::Quote(command) => command,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Missing(command, arg) => writeln!(f, "Error: {} needs {}", command, arg)?,
+            ParseError::Invalid(command, arg, value) => writeln!(
+                f,
+                "Error: \"{}\" isn't a valid {} for {}",
+                value, arg, command
+            )?,
+            ParseError::Unexpected(command, word) => {
+                writeln!(f, "Error: {} doesn't take \"{}\"", command, word)?
+            }
+            ParseError::Quote(_) => writeln!(
+                f,
+                "Error: Quotes go around a whole argument and need closing"
+            )?,
+        }
+
+        match find(self.command()) {
+            Some(spec) => writeln!(f, "Usage: {}", usage(spec)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// What's left of a command's line, read an argument at a time. Words are
+// split on spaces, and can be quoted to keep spaces in, like "my password",
+// with \" and \\ inside the quotes. Text at the end of a line is taken as
+// it was typed instead, so quotes in messages are left alone.
+pub struct Args<'a> {
+    command: &'static str,
+    line: &'a str,
+}
+
+impl<'a> Args<'a> {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::command::{Args, ParseError};
+    ///
+    /// let mut args = Args::new(">j", r#"secret "my \"pass\"" "#);
+    ///
+    /// assert_eq!(args.word(), Ok(Some("secret".to_owned())));
+    /// assert_eq!(args.word(), Ok(Some("my \"pass\"".to_owned())));
+    /// assert_eq!(args.word(), Ok(None));
+    ///
+    /// assert_eq!(Args::new(">j", "\"open").word(), Err(ParseError::Quote(">j")));
+    /// assert_eq!(Args::new(">j", "\"a\"b").word(), Err(ParseError::Quote(">j")));
+    ///
+    /// // Quotes that don't open the word are part of it
+    /// let mut args = Args::new(">login", "bob pa\"ss");
+    /// assert_eq!(args.word(), Ok(Some("bob".to_owned())));
+    /// assert_eq!(args.word(), Ok(Some("pa\"ss".to_owned())));
+    /// assert_eq!(Args::new(">msg", "bob \"hi\" there").rest("text"), Ok("bob \"hi\" there".to_owned()));
+    /// ```
+    pub fn new(command: &'static str, line: &'a str) -> Self {
+        Self {
+            command,
+            line: line.trim_start_matches(' '),
+        }
+    }
+
+    // The next word, None at the end of the line
+    pub fn word(&mut self) -> Result<Option<String>, ParseError> {
+        if self.line.is_empty() {
+            return Ok(None);
+        }
+
+        let (word, len) = match split_word(self.line) {
+            Some(split) => split,
+            None => return Err(ParseError::Quote(self.command)),
+        };
+        self.line = self.line[len..].trim_start_matches(' ');
+
+        Ok(Some(word))
+    }
+
+    pub fn required(&mut self, name: &'static str) -> Result<String, ParseError> {
+        self.word()?.ok_or(ParseError::Missing(self.command, name))
+    }
+
+    // The next word as a `T`
+    pub fn parse<T: FromStr>(&mut self, name: &'static str) -> Result<T, ParseError> {
+        let word = self.required(name)?;
+
+        word.parse().map_err(|_| self.invalid(name, word))
+    }
+
+    // Everything left, which has to be something
+    pub fn rest(&mut self, name: &'static str) -> Result<String, ParseError> {
+        if self.line.is_empty() {
+            return Err(ParseError::Missing(self.command, name));
+        }
+
+        Ok(std::mem::take(&mut self.line).to_owned())
+    }
+
+    pub fn invalid(&self, name: &'static str, value: String) -> ParseError {
+        ParseError::Invalid(self.command, name, value)
+    }
+
+    fn unexpected(&self, word: String) -> ParseError {
+        ParseError::Unexpected(self.command, word)
+    }
+
+    fn end(&mut self) -> Result<(), ParseError> {
+        match self.word()? {
+            Some(word) => Err(self.unexpected(word)),
+            None => Ok(()),
+        }
+    }
+}
+
+// The first word of `line`, unquoted, and how many bytes it took up. Only a
+// quote that opens the word starts a quoted one, so a bare word with quotes
+// in it, like a password, is taken as it is. None for a quote that isn't
+// closed, or has more of the word after it.
+fn split_word(line: &str) -> Option<(String, usize)> {
+    if !line.starts_with('"') {
+        let len = line.find(' ').unwrap_or(line.len());
+
+        return Some((line[..len].to_owned(), len));
+    }
+
+    let mut word = String::new();
+    let mut chars = line.char_indices().skip(1);
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, c @ ('"' | '\\'))) => word.push(c),
+                Some((_, c)) => {
+                    word.push('\\');
+                    word.push(c);
+                }
+                None => return None,
+            },
+            '"' => {
+                let len = i + 1;
+
+                return match line[len..].starts_with(' ') || len == line.len() {
+                    true => Some((word, len)),
+                    false => None,
+                };
+            }
+            c => word.push(c),
+        }
+    }
+
+    None
+}
+
+const fn req(name: &'static str) -> Arg {
+    Arg {
+        name,
+        required: true,
+        secret: false,
+        rest: false,
+    }
+}
+
+const fn opt(name: &'static str) -> Arg {
+    Arg {
+        name,
+        required: false,
+        secret: false,
+        rest: false,
+    }
+}
+
+const fn secret(arg: Arg) -> Arg {
+    Arg {
+        secret: true,
+        ..arg
+    }
+}
+
+const fn rest(arg: Arg) -> Arg {
+    Arg { rest: true, ..arg }
+}
+
+// How far back >digest looks without a period, in seconds
+const DEFAULT_DIGEST_PERIOD: u64 = 24 * 60 * 60;
+
+pub static COMMANDS: &[Spec] = &[
+    Spec {
+        name: ">help",
+        aliases: &[">h"],
+        args: &[],
+        description: "Display commands",
+        parse: |_| Ok(Command::Help),
+    },
+    Spec {
+        name: ">commands",
+        aliases: &[],
+        args: &[opt("--machine")],
+        description: "Display commands, as JSON with --machine",
+        parse: |args| match args.word()? {
+            None => Ok(Command::Commands(false)),
+            Some(flag) if flag == "--machine" => Ok(Command::Commands(true)),
+            Some(word) => Err(args.unexpected(word)),
+        },
+    },
+    Spec {
+        name: ">exit",
+        aliases: &[],
+        args: &[],
+        description: "Close connection",
+        parse: |_| Ok(Command::Exit),
+    },
+    Spec {
+        name: ">hello",
+        aliases: &[],
+        args: &[opt("version=n"), opt("caps=a,b")],
+        description: "Say which protocol version and settings (json, compress, color, timestamps, ids) your client wants, best as the first line",
+        parse: |args| {
+            let mut words = Vec::new();
+            while let Some(word) = args.word()? {
+                words.push(word);
+            }
+
+            Hello::parse(&words)
+                .map(Command::Hello)
+                .map_err(|word| args.invalid("version=n", word))
+        },
+    },
+    Spec {
+        name: ">pow",
+        aliases: &[],
+        args: &[req("n")],
+        description: "Answer the challenge in the greeting, if there is one",
+        parse: |args| Ok(Command::Pow(args.required("n")?)),
+    },
+    Spec {
+        name: ">list",
+        aliases: &[],
+        args: &[
+            opt("namespace/"),
+            opt("--active"),
+            opt("--mine"),
+            opt("--page n"),
+        ],
+        description: "List rooms, in one namespace, most recently active first or only ones you own, a page at a time",
+        parse: |args| {
+            let (mut namespace, mut active, mut mine, mut page) = (None, false, false, 1);
+
+            while let Some(word) = args.word()? {
+                match word.as_str() {
+                    "--active" => active = true,
+                    "--mine" => mine = true,
+                    "--page" => match args.parse("n")? {
+                        0 => return Err(args.invalid("n", "0".to_owned())),
+                        n => page = n,
+                    },
+                    _ if namespace.is_none() && !word.starts_with("--") => {
+                        namespace = Some(word.trim_end_matches('/').to_owned())
+                    }
+                    _ => return Err(args.unexpected(word)),
+                }
+            }
+
+            Ok(Command::List(namespace, active, mine, page))
+        },
+    },
+    Spec {
+        name: ">find",
+        aliases: &[],
+        args: &[rest(req("keyword"))],
+        description: "Search room names and topics for rooms with words starting with every keyword",
+        parse: |args| Ok(Command::Find(args.rest("keyword")?)),
+    },
+    Spec {
+        name: ">me",
+        aliases: &[],
+        args: &[],
+        description: "Your user info",
+        parse: |_| Ok(Command::Me),
+    },
+    Spec {
+        name: ">who",
+        aliases: &[],
+        args: &[],
+        description: "List users in your room",
+        parse: |_| Ok(Command::Who),
+    },
+    Spec {
+        name: ">ping",
+        aliases: &[],
+        args: &[],
+        description: "Time a round trip through Redis, your room's broker and back, step by step",
+        parse: |_| Ok(Command::Ping),
+    },
+    Spec {
+        name: ">unread",
+        aliases: &[],
+        args: &[],
+        description: "List rooms with unread messages",
+        parse: |_| Ok(Command::Unread),
+    },
+    Spec {
+        name: ">typing",
+        aliases: &[],
+        args: &[],
+        description: "Tell your room you're typing",
+        parse: |_| Ok(Command::Typing),
+    },
+    Spec {
+        name: ">presence",
+        aliases: &[],
+        args: &[req("user")],
+        description: "Check if a user is online",
+        parse: |args| Ok(Command::Presence(args.required("user")?)),
+    },
+    Spec {
+        name: ">set-username",
+        aliases: &[],
+        args: &[req("name")],
+        description: "Set username",
+        parse: |args| Ok(Command::SetUsername(args.required("name")?)),
+    },
+    Spec {
+        name: ">register",
+        aliases: &[],
+        args: &[req("name"), secret(req("pw"))],
+        description: "Create an account",
+        parse: |args| Ok(Command::Register(args.required("name")?, args.required("pw")?)),
+    },
+    Spec {
+        name: ">login",
+        aliases: &[],
+        args: &[req("name"), secret(req("pw"))],
+        description: "Log in to an account",
+        parse: |args| Ok(Command::Login(args.required("name")?, args.required("pw")?)),
+    },
+    Spec {
+        name: ">resume",
+        aliases: &[],
+        args: &[secret(req("token"))],
+        description: "Pick up a dropped connection's session, token is shown by >me",
+        parse: |args| Ok(Command::Resume(args.required("token")?)),
+    },
+    Spec {
+        name: ">pubkey",
+        aliases: &[],
+        args: &[opt("key|remove")],
+        description: "Show, register or remove the Ed25519 public key your signed messages are checked with",
+        parse: |args| match args.word()? {
+            None => Ok(Command::PublicKey(None)),
+            Some(word) if word == "remove" => Ok(Command::RemovePublicKey),
+            key => Ok(Command::PublicKey(key)),
+        },
+    },
+    Spec {
+        name: ">create-room",
+        aliases: &[],
+        args: &[
+            req("room"),
+            secret(opt("password")),
+            opt("--private"),
+            opt("--encrypted"),
+        ],
+        description:
+            "Create room, --private makes it invite only and --encrypted only takes ciphertext",
+        parse: parse_create_room,
+    },
+    Spec {
+        name: ">join-room",
+        aliases: &[">j"],
+        args: &[
+            req("room"),
+            secret(opt("password|invite")),
+            opt("--no-history"),
+        ],
+        description: "Join room, --no-history skips replaying recent messages",
+        parse: parse_join_room,
+    },
+    Spec {
+        name: ">switch",
+        aliases: &[],
+        args: &[req("room")],
+        description: "Send messages to another joined room",
+        parse: |args| Ok(Command::Switch(args.required("room")?)),
+    },
+    Spec {
+        name: ">leave",
+        aliases: &[">l"],
+        args: &[],
+        description: "Leave the room you're sending to",
+        parse: |_| Ok(Command::Leave),
+    },
+    Spec {
+        name: ">msg",
+        aliases: &[],
+        args: &[req("user"), rest(req("text"))],
+        description: "Send a direct message",
+        parse: |args| Ok(Command::DirectMessage(args.required("user")?, args.rest("text")?)),
+    },
+    Spec {
+        name: ">dms",
+        aliases: &[],
+        args: &[],
+        description: "List your direct message conversations, most recent first, with unread counts",
+        parse: |_| Ok(Command::Dms),
+    },
+    Spec {
+        name: ">whisper",
+        aliases: &[],
+        args: &[req("user"), rest(req("text"))],
+        description: "Send a message only one person in your room sees, not saved",
+        parse: |args| Ok(Command::Whisper(args.required("user")?, args.rest("text")?)),
+    },
+    Spec {
+        name: ">keyx",
+        aliases: &[],
+        args: &[opt("user"), req("key")],
+        description: "Send a base64 key to your encrypted room, or one person in it",
+        parse: |args| {
+            let first = args.required("key")?;
+
+            match args.word()? {
+                Some(key) => Ok(Command::KeyExchange(Some(first), key)),
+                None => Ok(Command::KeyExchange(None, first)),
+            }
+        },
+    },
+    Spec {
+        name: ">protocol",
+        aliases: &[],
+        args: &[req("text|json"), opt("zlib")],
+        description: "Switch output format, and compress JSON with zlib",
+        parse: |args| {
+            let protocol = match args.required("text|json")?.as_str() {
+                "text" => Protocol::Text,
+                "json" => Protocol::Json,
+                other => return Err(args.invalid("text|json", other.to_owned())),
+            };
+
+            // Only clients that parse what they're sent can be expected to
+            // inflate it
+            match args.word()? {
+                None => Ok(Command::SetProtocol(protocol, false)),
+                Some(word) if word == "zlib" && protocol == Protocol::Json => {
+                    Ok(Command::SetProtocol(protocol, true))
+                }
+                Some(word) => Err(args.unexpected(word)),
+            }
+        },
+    },
+    Spec {
+        name: ">set",
+        aliases: &[],
+        args: &[req("time|ids|color|bell|quiet|tz|history|types"), req("value")],
+        description:
+            "Show times, message ids, colors, ring the bell for mentions and DMs or hide joins and leaves (on|off), set your timezone (+05:30, UTC), how many messages joining replays or which types (chat,topic or all)",
+        parse: |args| {
+            let setting = args.required("time|ids|color|bell|quiet|tz|history|types")?;
+            let value = args.required("value")?;
+
+            let on = match value.as_str() {
+                "on" => Some(true),
+                "off" => Some(false),
+                _ => None,
+            };
+
+            match (setting.as_str(), on) {
+                ("time", Some(on)) => Ok(Command::SetTimestamps(on)),
+                ("ids", Some(on)) => Ok(Command::SetIds(on)),
+                ("color", Some(on)) => Ok(Command::SetColor(on)),
+                ("bell", Some(on)) => Ok(Command::SetBell(on)),
+                ("quiet", Some(on)) => Ok(Command::SetQuiet(on)),
+                ("tz", _) => match parse_offset(&value) {
+                    Some(offset) => Ok(Command::SetTimezone(offset)),
+                    None => Err(args.invalid("value", value)),
+                },
+                ("history", _) => match value.parse() {
+                    Ok(n) => Ok(Command::SetHistory(n)),
+                    Err(_) => Err(args.invalid("value", value)),
+                },
+                ("types", _) => match value.parse() {
+                    Ok(kinds) => Ok(Command::SetHistoryTypes(kinds)),
+                    Err(_) => Err(args.invalid("value", value)),
+                },
+                ("time" | "ids" | "color" | "bell" | "quiet", None) => {
+                    Err(args.invalid("value", value))
+                }
+                _ => Err(args.invalid("time|ids|color|bell|quiet|tz|history|types", setting)),
+            }
+        },
+    },
+    Spec {
+        name: ">status",
+        aliases: &[],
+        args: &[req("away|dnd|back"), rest(opt("message"))],
+        description: "Show people in >who that you're away, or not to be disturbed, which silences your bell",
+        parse: |args| {
+            let status = args.required("away|dnd|back")?;
+
+            match status.as_str() {
+                "away" => Ok(Command::SetStatus(Some(Status::Away(args.rest("message").ok())))),
+                "dnd" => Ok(Command::SetStatus(Some(Status::Dnd(args.rest("message").ok())))),
+                "back" => Ok(Command::SetStatus(None)),
+                _ => Err(args.invalid("away|dnd|back", status)),
+            }
+        },
+    },
+    Spec {
+        name: ">history",
+        aliases: &[],
+        args: &[req("n"), opt("before ts"), opt("--no-joins|--types t")],
+        description: "Show n messages older than ts, without joins and leaves or only some types (chat,topic or -join)",
+        parse: parse_history,
+    },
+    Spec {
+        name: ">digest",
+        aliases: &[],
+        args: &[req("room"), opt("period")],
+        description: "Sum up a room's messages over the last period (30m, 12h, 7d), a day by default",
+        parse: |args| {
+            let room = args.required("room")?;
+
+            let period = match args.word()? {
+                Some(period) => match parse_duration(&period) {
+                    Some(secs) => secs,
+                    None => return Err(args.invalid("period", period)),
+                },
+                None => DEFAULT_DIGEST_PERIOD,
+            };
+
+            Ok(Command::Digest(room, period))
+        },
+    },
+    Spec {
+        name: ">stats",
+        aliases: &[],
+        args: &[req("room")],
+        description: "Show how many messages and people a room has had, and the most in it at once",
+        parse: |args| Ok(Command::Stats(args.required("room")?)),
+    },
+    Spec {
+        name: ">export",
+        aliases: &[],
+        args: &[req("room"), opt("json|text")],
+        description: "Download a room's whole history as JSON lines or text, owners and admins only",
+        parse: |args| {
+            let room = args.required("room")?;
+
+            let format = match args.word()? {
+                Some(format) => match format.parse() {
+                    Ok(format) => format,
+                    Err(_) => return Err(args.invalid("json|text", format)),
+                },
+                None => Format::Json,
+            };
+
+            Ok(Command::Export(room, format))
+        },
+    },
+    Spec {
+        name: ">topic",
+        aliases: &[],
+        args: &[rest(req("text"))],
+        description: "Set your room's topic",
+        parse: |args| Ok(Command::Topic(args.rest("text")?)),
+    },
+    Spec {
+        name: ">lang",
+        aliases: &[],
+        args: &[opt("tag|off")],
+        description: "Show or set your room's language, like ar or pt-BR",
+        parse: |args| Ok(Command::Lang(args.word()?)),
+    },
+    Spec {
+        name: ">disappearing",
+        aliases: &[],
+        args: &[opt("duration|off")],
+        description: "Show or set how long messages in your room last before they disappear (like 30m, 12h)",
+        parse: |args| match args.word()? {
+            None => Ok(Command::Disappearing(None)),
+            Some(off) if off == "off" => Ok(Command::Disappearing(Some(0))),
+            Some(duration) => match parse_duration(&duration) {
+                Some(secs) => Ok(Command::Disappearing(Some(secs))),
+                None => Err(args.invalid("duration|off", duration)),
+            },
+        },
+    },
+    Spec {
+        name: ">retention",
+        aliases: &[],
+        args: &[opt("messages|age"), opt("value|off|default")],
+        description: "Show or set how much history your room keeps (age like 30m, 12h, 7d)",
+        parse: |args| {
+            let limit = match args.word()? {
+                Some(limit) => limit,
+                None => return Ok(Command::Retention(None)),
+            };
+
+            let limit = match limit.as_str() {
+                "messages" => Limit::Messages,
+                "age" => Limit::Age,
+                _ => return Err(args.invalid("messages|age", limit)),
+            };
+
+            let value = args.required("value|off|default")?;
+            let setting = match (value.as_str(), limit) {
+                ("off", _) => Some(Setting::Off),
+                ("default", _) => Some(Setting::Default),
+                (_, Limit::Age) => parse_duration(&value).map(Setting::Value),
+                (_, Limit::Messages) => value.parse().ok().filter(|&n| n > 0).map(Setting::Value),
+            };
+
+            match setting {
+                Some(setting) => Ok(Command::Retention(Some((limit, setting)))),
+                None => Err(args.invalid("value|off|default", value)),
+            }
+        },
+    },
+    Spec {
+        name: ">webhook",
+        aliases: &[],
+        args: &[req("add|remove|list|bot|remove-bot"), opt("url|name")],
+        description: "Manage where your room's messages are sent, and bots that can post to it",
+        parse: |args| {
+            let action = args.required("add|remove|list|bot|remove-bot")?;
+
+            let command = match action.as_str() {
+                "list" => WebhookCommand::List,
+                "add" => WebhookCommand::Add(args.required("url|name")?),
+                "remove" => WebhookCommand::Remove(args.required("url|name")?),
+                "bot" => WebhookCommand::Bot(args.required("url|name")?),
+                "remove-bot" => WebhookCommand::RemoveBot(args.required("url|name")?),
+                _ => return Err(args.invalid("add|remove|list|bot|remove-bot", action)),
+            };
+
+            Ok(Command::Webhook(command))
+        },
+    },
+    Spec {
+        name: ">reply",
+        aliases: &[],
+        args: &[req("id"), rest(req("text"))],
+        description: "Reply to a message",
+        parse: |args| Ok(Command::Reply(args.required("id")?, args.rest("text")?)),
+    },
+    Spec {
+        name: ">signed",
+        aliases: &[],
+        args: &[req("ts"), req("signature"), rest(req("text"))],
+        description: "Send a message signed with your key, shown as verified",
+        parse: |args| {
+            Ok(Command::Signed(
+                args.parse("ts")?,
+                args.required("signature")?,
+                args.rest("text")?,
+            ))
+        },
+    },
+    Spec {
+        name: ">paste",
+        aliases: &[],
+        args: &[],
+        description: "Share several lines, end with >end on its own line",
+        parse: |_| Ok(Command::Paste),
+    },
+    Spec {
+        name: ">fetch",
+        aliases: &[],
+        args: &[req("id")],
+        description: "Show a paste",
+        parse: |args| Ok(Command::Fetch(args.required("id")?)),
+    },
+    Spec {
+        name: ">edit",
+        aliases: &[],
+        args: &[req("id"), rest(req("text"))],
+        description: "Change one of your messages",
+        parse: |args| Ok(Command::Edit(args.required("id")?, args.rest("text")?)),
+    },
+    Spec {
+        name: ">delete",
+        aliases: &[],
+        args: &[req("id")],
+        description: "Delete one of your messages",
+        parse: |args| Ok(Command::Delete(args.required("id")?)),
+    },
+    Spec {
+        name: ">react",
+        aliases: &[],
+        args: &[req("id"), req("emoji")],
+        description: "React to a message, or take your reaction back",
+        parse: |args| Ok(Command::React(args.required("id")?, args.required("emoji")?)),
+    },
+    Spec {
+        name: ">delete-room",
+        aliases: &[],
+        args: &[],
+        description: "Delete your room",
+        parse: |_| Ok(Command::DeleteRoom),
+    },
+    Spec {
+        name: ">rename-room",
+        aliases: &[],
+        args: &[req("name")],
+        description: "Rename your room, keeping its history and everyone in it",
+        parse: |args| Ok(Command::RenameRoom(args.required("name")?)),
+    },
+    Spec {
+        name: ">transfer-ownership",
+        aliases: &[],
+        args: &[req("user")],
+        description: "Offer your room to someone, who becomes its owner once they accept",
+        parse: |args| Ok(Command::TransferOwnership(args.required("user")?)),
+    },
+    Spec {
+        name: ">accept-ownership",
+        aliases: &[],
+        args: &[req("room")],
+        description: "Take over a room you've been offered",
+        parse: |args| Ok(Command::AcceptOwnership(args.required("room")?)),
+    },
+    Spec {
+        name: ">kick",
+        aliases: &[],
+        args: &[req("user")],
+        description: "Remove a user from your room",
+        parse: |args| Ok(Command::Kick(args.required("user")?)),
+    },
+    Spec {
+        name: ">ban",
+        aliases: &[],
+        args: &[req("user")],
+        description: "Remove a user and stop them rejoining",
+        parse: |args| Ok(Command::Ban(args.required("user")?)),
+    },
+    Spec {
+        name: ">invite",
+        aliases: &[],
+        args: &[req("user")],
+        description: "Let a user into your invite only room",
+        parse: |args| Ok(Command::Invite(args.required("user")?)),
+    },
+    Spec {
+        name: ">requests",
+        aliases: &[],
+        args: &[],
+        description: "List who has asked to join your invite only room",
+        parse: |_| Ok(Command::Requests),
+    },
+    Spec {
+        name: ">approve",
+        aliases: &[],
+        args: &[req("user")],
+        description: "Let in someone who asked to join your invite only room",
+        parse: |args| Ok(Command::Approve(args.required("user")?)),
+    },
+    Spec {
+        name: ">deny",
+        aliases: &[],
+        args: &[req("user")],
+        description: "Turn down someone who asked to join your invite only room",
+        parse: |args| Ok(Command::Deny(args.required("user")?)),
+    },
+    Spec {
+        name: ">unmute",
+        aliases: &[],
+        args: &[req("user")],
+        description: "Let a user muted for spamming talk in your room again",
+        parse: |args| Ok(Command::Unmute(args.required("user")?)),
+    },
+    Spec {
+        name: ">namespace",
+        aliases: &[],
+        args: &[req("name"), opt("allow|revoke"), opt("user")],
+        description:
+            "Show who can create rooms in a namespace, or as its owner let someone in or out",
+        parse: |args| {
+            let name = args.required("name")?;
+
+            let command = match args.word()? {
+                None => NamespaceCommand::Show,
+                Some(action) if action == "allow" => {
+                    NamespaceCommand::Allow(args.required("user")?)
+                }
+                Some(action) if action == "revoke" => {
+                    NamespaceCommand::Revoke(args.required("user")?)
+                }
+                Some(action) => return Err(args.invalid("allow|revoke", action)),
+            };
+
+            Ok(Command::Namespace(name, command))
+        },
+    },
+    Spec {
+        name: ">receipts",
+        aliases: &[],
+        args: &[req("id")],
+        description: "Show who has seen a message in the current room",
+        parse: |args| Ok(Command::Receipts(args.required("id")?)),
+    },
+    Spec {
+        name: ">block",
+        aliases: &[],
+        args: &[opt("user")],
+        description: "Stop seeing a user's messages, whispers and DMs, or list who you've blocked",
+        parse: |args| Ok(Command::Block(args.word()?)),
+    },
+    Spec {
+        name: ">unblock",
+        aliases: &[],
+        args: &[req("user")],
+        description: "See a blocked user's messages again",
+        parse: |args| Ok(Command::Unblock(args.required("user")?)),
+    },
+    Spec {
+        name: ">grant",
+        aliases: &[],
+        args: &[
+            req("user"),
+            req("admin|moderator|member|guest"),
+            opt("--global"),
+        ],
+        description: "Give a user a role in your room, or every room with --global",
+        parse: |args| {
+            let user = args.required("user")?;
+            let role = args.parse("admin|moderator|member|guest")?;
+
+            match args.word()? {
+                None => Ok(Command::Grant(user, role, false)),
+                Some(flag) if flag == "--global" => Ok(Command::Grant(user, role, true)),
+                Some(word) => Err(args.unexpected(word)),
+            }
+        },
+    },
+    Spec {
+        name: ">announce",
+        aliases: &[],
+        args: &[rest(req("text"))],
+        description: "Send a message to everyone on the server, for global admins",
+        parse: |args| Ok(Command::Announce(args.rest("text")?)),
+    },
+    Spec {
+        name: ">broadcast",
+        aliases: &[],
+        args: &[rest(req("text"))],
+        description: "Send a message to every active room, saved in each one's history, for global admins",
+        parse: |args| Ok(Command::Broadcast(args.rest("text")?)),
+    },
+    Spec {
+        name: ">report",
+        aliases: &[],
+        args: &[req("user"), rest(opt("reason"))],
+        description: "Tell the server's admins about someone, with your room's latest messages",
+        parse: |args| {
+            let user = args.required("user")?;
+
+            Ok(Command::Report(user, args.rest("reason").ok()))
+        },
+    },
+    Spec {
+        name: ">reports",
+        aliases: &[],
+        args: &[],
+        description: "List open reports, for global admins",
+        parse: |_| Ok(Command::Reports),
+    },
+    Spec {
+        name: ">resolve",
+        aliases: &[],
+        args: &[req("id")],
+        description: "Close a report once it's dealt with, for global admins",
+        parse: |args| Ok(Command::Resolve(args.required("id")?)),
+    },
+    Spec {
+        name: ">forget-user",
+        aliases: &[],
+        args: &[req("user")],
+        description: "Erase a user's messages, DMs and account, for global admins",
+        parse: |args| Ok(Command::ForgetUser(args.required("user")?)),
+    },
+    Spec {
+        name: ">set-motd",
+        aliases: &[],
+        args: &[rest(req("text|default"))],
+        description: "Change the message everyone sees when they connect, for global admins",
+        parse: |args| match args.rest("text|default")? {
+            text if text == "default" => Ok(Command::SetMotd(None)),
+            text => Ok(Command::SetMotd(Some(text))),
+        },
+    },
+    Spec {
+        name: ">claim",
+        aliases: &[],
+        args: &[opt("command")],
+        description: "Answer a new command in your room as a bot, or list who answers what",
+        parse: |args| {
+            let name = args.word()?;
+
+            Ok(Command::Claim(name.map(|name| {
+                name.trim_start_matches('>').to_owned()
+            })))
+        },
+    },
+    Spec {
+        name: ">unclaim",
+        aliases: &[],
+        args: &[req("command")],
+        description: "Stop a bot answering a command in your room",
+        parse: |args| {
+            let name = args.required("command")?;
+
+            Ok(Command::Unclaim(name.trim_start_matches('>').to_owned()))
+        },
+    },
+];
 
 impl Command {
     ///
@@ -27,42 +1117,338 @@ impl Command {
     /// # Examples
     ///
     /// ```
-    /// use chatsapp::command::Command;
+    /// use chatsapp::archive::Format;
+    /// use chatsapp::command::{Command, ParseError};
+    /// use chatsapp::message::{MessageKind, Protocol};
+    /// use chatsapp::permissions::Role;
+    /// use chatsapp::retention::{Limit, Setting};
+    /// use chatsapp::room::{Kinds, Options};
+    /// use chatsapp::webhook::WebhookCommand;
     ///
     /// let c1 = Command::parse(">help".into());
     /// let c2 = Command::parse(">set-username bob".into());
     /// let c3 = Command::parse(">not a command".into());
+    /// let c4 = Command::parse(">login bob hunter2".into());
+    /// let c5 = Command::parse(">join-room secret pw".into());
+    /// let c6 = Command::parse(">history 20 before 1674000000000".into());
+    /// let c7 = Command::parse(">set tz -03:30".into());
+    /// let c8 = Command::parse(">j rust".into());
+    /// let c9 = Command::parse(">retention age 7d".into());
+    /// let c10 = Command::parse(">webhook bot ci".into());
+    /// let c11 = Command::parse(">create-room secrets pw --encrypted".into());
+    /// let c12 = Command::parse(">list dev/ --active".into());
+    /// let c13 = Command::parse(">grant bob moderator".into());
+    /// let c14 = Command::parse(">digest rust 12h".into());
+    /// let c15 = Command::parse(">signed 1674002400000 c2ln hello there".into());
+    /// let c16 = Command::parse(">export rust text".into());
+    /// let c17 = Command::parse(">j".into());
+    /// let c18 = Command::parse(">history ten".into());
+    /// let c19 = Command::parse(">login bob \"hunter 2\"".into());
+    /// let c20 = Command::parse(">who is here".into());
+    /// let c21 = Command::parse(">j rust --no-history".into());
+    /// let c22 = Command::parse(">set history 50".into());
+    /// let c23 = Command::parse(">history 20 --no-joins".into());
+    /// let c24 = Command::parse(">protocol json zlib".into());
+    /// let c25 = Command::parse(">history 20 --types chat,topic".into());
     ///
     /// assert_eq!(c1, Command::Help);
     /// assert_eq!(c2, Command::SetUsername("bob".to_owned()));
     /// assert_eq!(c3, Command::Invalid);
+    /// assert_eq!(c4, Command::Login("bob".to_owned(), "hunter2".to_owned()));
+    /// assert_eq!(c5, Command::JoinRoom("secret".to_owned(), Some("pw".to_owned()), true));
+    /// assert_eq!(c6, Command::History(20, Some(1674000000000), Kinds::All));
+    /// assert_eq!(c7, Command::SetTimezone(-210));
+    /// assert_eq!(c8, Command::JoinRoom("rust".to_owned(), None, true));
+    /// assert_eq!(c9, Command::Retention(Some((Limit::Age, Setting::Value(604800)))));
+    /// assert_eq!(c10, Command::Webhook(WebhookCommand::Bot("ci".to_owned())));
+    /// assert_eq!(
+    ///     c11,
+    ///     Command::CreateRoom(
+    ///         "secrets".to_owned(),
+    ///         Some("pw".to_owned()),
+    ///         Options { private: false, encrypted: true }
+    ///     )
+    /// );
+    /// assert_eq!(c12, Command::List(Some("dev".to_owned()), true, false, 1));
+    /// assert_eq!(c13, Command::Grant("bob".to_owned(), Role::Moderator, false));
+    /// assert_eq!(c14, Command::Digest("rust".to_owned(), 43200));
+    /// assert_eq!(
+    ///     c15,
+    ///     Command::Signed(1674002400000, "c2ln".to_owned(), "hello there".to_owned())
+    /// );
+    /// assert_eq!(c16, Command::Export("rust".to_owned(), Format::Text));
+    /// assert_eq!(c17, Command::Usage(ParseError::Missing(">join-room", "room")));
+    /// assert_eq!(
+    ///     c18,
+    ///     Command::Usage(ParseError::Invalid(">history", "n", "ten".to_owned()))
+    /// );
+    /// assert_eq!(c19, Command::Login("bob".to_owned(), "hunter 2".to_owned()));
+    /// assert_eq!(c20, Command::Usage(ParseError::Unexpected(">who", "is".to_owned())));
+    /// assert_eq!(c21, Command::JoinRoom("rust".to_owned(), None, false));
+    /// assert_eq!(c22, Command::SetHistory(50));
+    /// assert_eq!(
+    ///     c23,
+    ///     Command::History(20, None, Kinds::Except(vec![MessageKind::Join, MessageKind::Leave]))
+    /// );
+    /// assert_eq!(c24, Command::SetProtocol(Protocol::Json, true));
+    /// assert_eq!(
+    ///     c25,
+    ///     Command::History(20, None, Kinds::Only(vec![MessageKind::Chat, MessageKind::Topic]))
+    /// );
     /// ```
     pub fn parse(s: String) -> Self {
         if !s.starts_with(">") {
             return Command::Message(s);
         }
 
-        // These commands don't require extra args
-        match s.as_str() {
-            HELP => return Command::Help,
-            EXIT => return Command::Exit,
-            LIST => return Command::List,
-            LEAVE => return Command::Leave,
-            ME => return Command::Me,
-            _ => {}
-        };
+        let (command, rest) = s.split_once(' ').unwrap_or((&s, ""));
 
-        let (command, rest) = match s.split_once(" ") {
-            Some(s) => s,
+        let spec = match find(command) {
+            Some(spec) => spec,
             None => return Command::Invalid,
         };
 
-        match command {
-            // TODO: make sure username is valid
-            SET_USERNAME => Command::SetUsername(rest.into()),
-            CREATE_ROOM => Command::CreateRoom(rest.into()),
-            JOIN_ROOM => Command::JoinRoom(rest.into()),
-            _ => Command::Invalid,
+        let mut args = Args::new(spec.name, rest);
+        match (spec.parse)(&mut args).and_then(|command| args.end().map(|_| command)) {
+            Ok(command) => command,
+            Err(e) => Command::Usage(e),
+        }
+    }
+
+    // What's allowed before the greeting's challenge is answered
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::command::Command;
+    ///
+    /// assert!(Command::parse(">pow 42".into()).unchallenged());
+    /// assert!(Command::parse(">help".into()).unchallenged());
+    /// assert!(Command::parse(">hello caps=json".into()).unchallenged());
+    /// assert!(!Command::parse(">register bob pw".into()).unchallenged());
+    /// assert!(!Command::parse("hello".into()).unchallenged());
+    /// ```
+    pub fn unchallenged(&self) -> bool {
+        matches!(
+            self,
+            Command::Pow(_)
+                | Command::Hello(_)
+                | Command::Help
+                | Command::Commands(_)
+                | Command::Exit
+        )
+    }
+}
+
+// The row for a command by its name or an alias, like `>j`
+pub fn find(command: &str) -> Option<&'static Spec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name == command || spec.aliases.contains(&command))
+}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::command;
+///
+/// assert_eq!(command::redact(">login bob hunter2"), ">login bob ***");
+/// assert_eq!(command::redact(">j secret my pass"), ">j secret ***");
+/// assert_eq!(command::redact(">j secret --no-history pass"), ">j secret ***");
+/// assert_eq!(command::redact(r#">login "bob b" "hunter 2""#), r#">login "bob b" ***"#);
+/// assert_eq!(command::redact(">msg bob hi"), ">msg bob hi");
+/// ```
+pub fn redact(line: &str) -> String {
+    let (command, mut rest) = line.split_once(' ').unwrap_or((line, ""));
+
+    let spec = match find(command) {
+        Some(spec) => spec,
+        None => return line.to_owned(),
+    };
+
+    let mut redacted = command.to_owned();
+    for (i, arg) in spec.args.iter().enumerate() {
+        rest = rest.trim_start_matches(' ');
+        if rest.is_empty() {
+            break;
         }
+
+        // The last argument gets the rest of the line, as does a secret, so
+        // nothing typed after one is kept either
+        let len = match split_word(rest) {
+            Some((_, len)) if i + 1 < spec.args.len() && !arg.secret => len,
+            _ => rest.len(),
+        };
+
+        let (part, remaining) = rest.split_at(len);
+        redacted.push(' ');
+        redacted.push_str(if arg.secret { "***" } else { part });
+        rest = remaining;
     }
+
+    redacted
+}
+
+// What `>help` shows, one aligned line per command
+pub fn help() -> String {
+    let usages: Vec<String> = COMMANDS.iter().map(usage).collect();
+    let width = usages.iter().map(|u| u.len()).max().unwrap_or_default();
+
+    let mut help = String::from("Commands:\n");
+    for (spec, usage) in COMMANDS.iter().zip(&usages) {
+        help.push_str(&format!("{:width$} - {}", usage, spec.description));
+
+        if !spec.aliases.is_empty() {
+            help.push_str(&format!(" (also {})", spec.aliases.join(", ")));
+        }
+
+        help.push('\n');
+    }
+
+    help
+}
+
+// The command table as JSON, so clients can build completion from it
+pub fn machine() -> String {
+    let mut json = serde_json::to_string(COMMANDS).unwrap();
+    json.push('\n');
+
+    json
+}
+
+// Like `>join-room room [password|invite]`
+pub fn usage(spec: &Spec) -> String {
+    let mut usage = spec.name.to_owned();
+
+    for arg in spec.args {
+        if arg.required {
+            usage.push_str(&format!(" {}", arg.name));
+        } else {
+            usage.push_str(&format!(" [{}]", arg.name));
+        }
+    }
+
+    usage
+}
+
+// <room> [password] [--private] [--encrypted]. The password has to come
+// first, since `redact` goes by position.
+fn parse_create_room(args: &mut Args) -> Result<Command, ParseError> {
+    let room = args.required("room")?;
+
+    let mut password = None;
+    let mut options = Options::default();
+    while let Some(word) = args.word()? {
+        match word.as_str() {
+            "--private" => options.private = true,
+            "--encrypted" => options.encrypted = true,
+            _ if password.is_none()
+                && !options.private
+                && !options.encrypted
+                && !word.starts_with("--") =>
+            {
+                password = Some(word)
+            }
+            _ => return Err(args.unexpected(word)),
+        }
+    }
+
+    Ok(Command::CreateRoom(room, password, options))
+}
+
+// The flag can come before or after the password, `redact` hides both
+fn parse_join_room(args: &mut Args) -> Result<Command, ParseError> {
+    let room = args.required("room")?;
+
+    let mut password = None;
+    let mut history = true;
+    while let Some(word) = args.word()? {
+        match word.as_str() {
+            "--no-history" => history = false,
+            _ if password.is_none() => password = Some(word),
+            _ => return Err(args.unexpected(word)),
+        }
+    }
+
+    Ok(Command::JoinRoom(room, password, history))
+}
+
+// `before ts` and `--no-joins` or `--types` can come in any order
+fn parse_history(args: &mut Args) -> Result<Command, ParseError> {
+    let count = args.parse("n")?;
+
+    let mut before = None;
+    let mut kinds = None;
+    while let Some(word) = args.word()? {
+        match word.as_str() {
+            // Short for `--types -join,-leave`
+            "--no-joins" if kinds.is_none() => {
+                kinds = Some(Kinds::Except(vec![MessageKind::Join, MessageKind::Leave]))
+            }
+            "--types" if kinds.is_none() => kinds = Some(args.parse("types")?),
+            "before" if before.is_none() => before = Some(args.parse("ts")?),
+            _ => return Err(args.unexpected(word)),
+        }
+    }
+
+    Ok(Command::History(count, before, kinds.unwrap_or_default()))
+}
+
+// Seconds from `90`, `30m`, `12h` or `7d`
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::command::parse_duration;
+///
+/// assert_eq!(parse_duration("90"), Some(90));
+/// assert_eq!(parse_duration("30m"), Some(1800));
+/// assert_eq!(parse_duration("2w"), None);
+/// ```
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+
+    n.parse::<u64>()
+        .ok()
+        .filter(|&n| n > 0)?
+        .checked_mul(multiplier)
+}
+
+// `UTC`, `+5`, `-08`, or `+05:30`, returned in minutes
+fn parse_offset(s: &str) -> Option<i32> {
+    if s.eq_ignore_ascii_case("utc") {
+        return Some(0);
+    }
+
+    let (sign, s) = match s.split_at_checked(1)? {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => (1, s),
+    };
+
+    let (hours, mins) = match s.split_once(':') {
+        Some((h, m)) => (h.parse::<i32>().ok()?, m.parse::<i32>().ok()?),
+        None => (s.parse::<i32>().ok()?, 0),
+    };
+
+    if !(0..=14).contains(&hours) || !(0..60).contains(&mins) {
+        return None;
+    }
+
+    Some(sign * (hours * 60 + mins))
 }
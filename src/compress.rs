@@ -0,0 +1,73 @@
+use flate2::{Compress, Compression, FlushCompress, Status};
+use tokio::io;
+
+// One zlib stream over everything written to a connection once it's asked
+// for. Each write is flushed, so clients can inflate what they've got
+// without waiting for more, while repeated names and JSON keys still only
+// cost a few bytes after the first time.
+pub struct Deflater {
+    compress: Compress,
+}
+
+impl Default for Deflater {
+    fn default() -> Self {
+        Self {
+            compress: Compress::new(Compression::default(), true),
+        }
+    }
+}
+
+impl Deflater {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::compress::Deflater;
+    /// use flate2::{Decompress, FlushDecompress};
+    ///
+    /// let mut deflater = Deflater::default();
+    /// let line = r#"{"type":"chat","room":"rust","user":"alice","body":"hi"}"#.repeat(20);
+    /// let sent = deflater.write(line.as_bytes()).unwrap();
+    /// assert!(sent.len() < line.len() / 4);
+    ///
+    /// // Everything written so far can be read without waiting for the end
+    /// let mut inflate = Decompress::new(true);
+    /// let mut read = Vec::with_capacity(line.len() * 2);
+    /// inflate.decompress_vec(&sent, &mut read, FlushDecompress::Sync).unwrap();
+    /// assert_eq!(read, line.as_bytes());
+    ///
+    /// let end = deflater.finish().unwrap();
+    /// inflate.decompress_vec(&end, &mut read, FlushDecompress::Finish).unwrap();
+    /// assert_eq!(read.len(), line.len());
+    /// ```
+    pub fn write(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        self.run(input, FlushCompress::Sync)
+    }
+
+    // Ends the stream, so what's written after it is plain again
+    pub fn finish(&mut self) -> io::Result<Vec<u8>> {
+        self.run(&[], FlushCompress::Finish)
+    }
+
+    fn run(&mut self, input: &[u8], flush: FlushCompress) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(input.len() / 2 + 64);
+        let start = self.compress.total_in();
+
+        loop {
+            let read = (self.compress.total_in() - start) as usize;
+            let status = self
+                .compress
+                .compress_vec(&input[read..], &mut out, flush)
+                .map_err(io::Error::other)?;
+            let read = (self.compress.total_in() - start) as usize;
+
+            // Space left over means the flush had all it needed
+            if status == Status::StreamEnd || (read == input.len() && out.len() < out.capacity()) {
+                return Ok(out);
+            }
+
+            out.reserve(out.capacity());
+        }
+    }
+}
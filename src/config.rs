@@ -0,0 +1,410 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+
+use crate::ratelimit::RateLimit;
+use crate::retention::Policy;
+
+const DEFAULT_PATH: &str = "chatsapp.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    FailedToRead(std::io::Error),
+    FailedToParse(toml::de::Error),
+    InvalidEnv(&'static str),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::FailedToRead(e) => writeln!(f, "Error: Failed to read config: {}", e),
+            ConfigError::FailedToParse(e) => writeln!(f, "Error: Failed to parse config: {}", e),
+            ConfigError::InvalidEnv(var) => writeln!(f, "Error: Invalid value for {}", var),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub listen_addr: String,
+    pub redis_url: String,
+    // Messages replayed when joining a room
+    pub history_size: usize,
+    // None means there's no limit
+    pub max_rooms: Option<usize>,
+    // Rooms one person can own at once, unlimited if unset
+    pub max_rooms_per_user: Option<usize>,
+    pub rate_limit: RateLimit,
+    // File of words for the wordlist filter to redact
+    pub wordlist: Option<String>,
+    // How long a room can go without anyone in it before its broker stops
+    pub broker_idle_secs: u64,
+    // Tasks each room's members are split over for delivery. 1 has the
+    // broker send to everyone itself.
+    pub broker_shards: usize,
+    // Once a room's broker stops, delete the room after this long. Rooms
+    // are kept forever if unset.
+    pub empty_room_ttl_secs: Option<u64>,
+    // Where to serve Prometheus metrics, disabled if unset
+    pub metrics_addr: Option<String>,
+    // Share rooms with other servers using the same Redis through Pub/Sub
+    pub pubsub: bool,
+    // How long a >paste can be fetched for
+    pub paste_ttl_secs: u64,
+    // Where to serve the admin console, disabled if unset. Only connections
+    // from this machine are accepted.
+    pub admin_addr: Option<String>,
+    // How long an >invite token can be used for
+    pub invite_ttl_secs: u64,
+    // How long a dropped connection can be picked up with >resume
+    pub session_ttl_secs: u64,
+    // History kept per room unless the owner overrides it
+    pub retention: Policy,
+    // How often rooms are trimmed to their retention policy
+    pub retention_interval_secs: u64,
+    // How long after an announcement goes out people who connect still see it
+    pub announcement_window_secs: u64,
+    // Where to serve the HTTP API, disabled if unset
+    pub api_addr: Option<String>,
+    // Room messages kept in memory while Redis is unreachable. Once it's
+    // full, sending fails until Redis is back.
+    pub outbox_capacity: usize,
+    // How long someone caught spamming a room is muted in it, 0 turns spam
+    // detection off
+    pub spam_mute_secs: u64,
+    // Rooms with at most this many people in them record who's seen each
+    // message, for >receipts. 0 turns them off.
+    pub receipts_max_members: usize,
+    // Open connections allowed at once, unlimited if unset
+    pub max_connections: Option<usize>,
+    // Open connections allowed from one IP address, unlimited if unset
+    pub max_connections_per_ip: Option<usize>,
+    // Experimental. Where other servers connect to share `fed/` rooms,
+    // disabled if unset.
+    pub federation_addr: Option<String>,
+    // Federation addresses of the servers to send this one's messages to
+    pub federation_peers: Vec<String>,
+    // Shown after users' names on other servers, as `name@server`
+    pub federation_name: String,
+    // Peers have to send this to connect, and none are accepted without it
+    pub federation_key: Option<String>,
+    // Where the admin console's `archive` writes rooms before deleting them
+    pub archive_dir: String,
+    // WASM plugins are loaded from every `.wasm` file here on startup,
+    // none if unset
+    pub plugin_dir: Option<String>,
+    // Shown to everyone who connects, above how to get help, until an admin
+    // replaces it with >set-motd
+    pub motd: String,
+    // How long a DM to someone who's offline waits for them to log in
+    pub dm_queue_ttl_secs: u64,
+    // Leading zero bits new connections have to find a hash with before
+    // they can do anything, 0 turns the challenge off
+    pub pow_difficulty: u32,
+    // Most bytes written to a connection at once. Longer output, like a big
+    // history replay, goes out in frames of about this size.
+    pub max_write_bytes: usize,
+    // Clear someone's away status once they send a message
+    pub clear_away_on_activity: bool,
+    // Make a room that doesn't exist when someone joins it, owned by them,
+    // rather than saying it wasn't found
+    pub auto_create_rooms: bool,
+    // Hosts, by name or address, webhooks can be sent to even though they're
+    // on this machine or a private network. Any others are refused.
+    pub webhook_allowlist: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: "0.0.0.0:8000".into(),
+            redis_url: "redis://:redis@127.0.0.1/".into(),
+            history_size: 10,
+            max_rooms: None,
+            max_rooms_per_user: None,
+            rate_limit: RateLimit::default(),
+            wordlist: None,
+            broker_idle_secs: 300,
+            broker_shards: 1,
+            empty_room_ttl_secs: None,
+            metrics_addr: None,
+            pubsub: false,
+            paste_ttl_secs: 86400,
+            admin_addr: None,
+            invite_ttl_secs: 86400,
+            session_ttl_secs: 300,
+            retention: Policy::default(),
+            retention_interval_secs: 60,
+            announcement_window_secs: 86400,
+            api_addr: None,
+            outbox_capacity: 10000,
+            spam_mute_secs: 300,
+            receipts_max_members: 20,
+            max_connections: None,
+            max_connections_per_ip: None,
+            federation_addr: None,
+            federation_peers: Vec::new(),
+            federation_name: "chatsapp".into(),
+            federation_key: None,
+            archive_dir: "archive".into(),
+            plugin_dir: None,
+            motd: "Welcome to ChatsApp!".into(),
+            dm_queue_ttl_secs: 604800,
+            pow_difficulty: 0,
+            max_write_bytes: 65536,
+            clear_away_on_activity: false,
+            auto_create_rooms: false,
+            webhook_allowlist: Vec::new(),
+        }
+    }
+}
+
+// The config the server runs with, which a reload swaps for a new one while
+// it's running. Readers take a snapshot with `get`, so each sees the old
+// config or the new one, never a mix.
+#[derive(Debug)]
+pub struct LiveConfig {
+    current: RwLock<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    pub fn set(&self, config: Config) {
+        *self.current.write().unwrap() = Arc::new(config);
+    }
+}
+
+impl Config {
+    // `self` with the settings from `new` that can change without a restart:
+    // the rate limit, MOTD, default retention policy, wordlist, spam muting
+    // and proof of work. Everything else, like addresses and Redis, is only read on
+    // startup, so it's kept as it was.
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::config::Config;
+    ///
+    /// let running = Config::default();
+    /// let new = Config::parse("
+    ///     motd = \"Back soon\"
+    ///     listen_addr = \"0.0.0.0:9000\"
+    ///     [rate_limit]
+    ///     capacity = 3.0
+    /// ").unwrap();
+    ///
+    /// let reloaded = running.with_reloadable(new);
+    /// assert_eq!(reloaded.motd, "Back soon");
+    /// assert_eq!(reloaded.rate_limit.capacity, 3.0);
+    /// assert_eq!(reloaded.listen_addr, running.listen_addr);
+    /// ```
+    pub fn with_reloadable(&self, new: Config) -> Config {
+        Config {
+            rate_limit: new.rate_limit,
+            motd: new.motd,
+            retention: new.retention,
+            wordlist: new.wordlist,
+            spam_mute_secs: new.spam_mute_secs,
+            pow_difficulty: new.pow_difficulty,
+            clear_away_on_activity: new.clear_away_on_activity,
+            ..self.clone()
+        }
+    }
+
+    // Reads the file named by `CHATSAPP_CONFIG` (or `chatsapp.toml` if it
+    // exists), then applies any `CHATSAPP_*` environment variables on top.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match std::env::var("CHATSAPP_CONFIG") {
+            Ok(path) => Self::from_file(path)?,
+            Err(_) if Path::new(DEFAULT_PATH).exists() => Self::from_file(DEFAULT_PATH)?,
+            Err(_) => Self::default(),
+        };
+
+        config.apply_env()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(ConfigError::FailedToRead)?;
+
+        Self::parse(&contents)
+    }
+
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::config::Config;
+    ///
+    /// let config = Config::parse("
+    /// listen_addr = \"127.0.0.1:9000\"
+    ///
+    /// [rate_limit]
+    /// capacity = 5.0
+    /// ").unwrap();
+    ///
+    /// assert_eq!(config.listen_addr, "127.0.0.1:9000");
+    /// assert_eq!(config.rate_limit.capacity, 5.0);
+    /// assert_eq!(config.history_size, 10);
+    /// ```
+    pub fn parse(s: &str) -> Result<Self, ConfigError> {
+        toml::from_str(s).map_err(ConfigError::FailedToParse)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(v) = env("CHATSAPP_LISTEN_ADDR")? {
+            self.listen_addr = v;
+        }
+        if let Some(v) = env("CHATSAPP_REDIS_URL")? {
+            self.redis_url = v;
+        }
+        if let Some(v) = env("CHATSAPP_HISTORY_SIZE")? {
+            self.history_size = v;
+        }
+        if let Some(v) = env("CHATSAPP_MAX_ROOMS")? {
+            self.max_rooms = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_MAX_ROOMS_PER_USER")? {
+            self.max_rooms_per_user = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_RATE_CAPACITY")? {
+            self.rate_limit.capacity = v;
+        }
+        if let Some(v) = env("CHATSAPP_RATE_REFILL")? {
+            self.rate_limit.refill_per_sec = v;
+        }
+        if let Some(v) = env("CHATSAPP_RATE_MAX_WARNINGS")? {
+            self.rate_limit.max_warnings = v;
+        }
+        if let Some(v) = env("CHATSAPP_WORDLIST")? {
+            self.wordlist = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_BROKER_IDLE_SECS")? {
+            self.broker_idle_secs = v;
+        }
+        if let Some(v) = env("CHATSAPP_BROKER_SHARDS")? {
+            self.broker_shards = v;
+        }
+        if let Some(v) = env("CHATSAPP_EMPTY_ROOM_TTL_SECS")? {
+            self.empty_room_ttl_secs = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_METRICS_ADDR")? {
+            self.metrics_addr = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_PUBSUB")? {
+            self.pubsub = v;
+        }
+        if let Some(v) = env("CHATSAPP_PASTE_TTL_SECS")? {
+            self.paste_ttl_secs = v;
+        }
+        if let Some(v) = env("CHATSAPP_ADMIN_ADDR")? {
+            self.admin_addr = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_INVITE_TTL_SECS")? {
+            self.invite_ttl_secs = v;
+        }
+        if let Some(v) = env("CHATSAPP_SESSION_TTL_SECS")? {
+            self.session_ttl_secs = v;
+        }
+        if let Some(v) = env("CHATSAPP_RETENTION_MAX_MESSAGES")? {
+            self.retention.max_messages = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_RETENTION_MAX_AGE_SECS")? {
+            self.retention.max_age_secs = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_RETENTION_INTERVAL_SECS")? {
+            self.retention_interval_secs = v;
+        }
+        if let Some(v) = env("CHATSAPP_ANNOUNCEMENT_WINDOW_SECS")? {
+            self.announcement_window_secs = v;
+        }
+        if let Some(v) = env("CHATSAPP_API_ADDR")? {
+            self.api_addr = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_OUTBOX_CAPACITY")? {
+            self.outbox_capacity = v;
+        }
+        if let Some(v) = env("CHATSAPP_SPAM_MUTE_SECS")? {
+            self.spam_mute_secs = v;
+        }
+        if let Some(v) = env("CHATSAPP_RECEIPTS_MAX_MEMBERS")? {
+            self.receipts_max_members = v;
+        }
+        if let Some(v) = env("CHATSAPP_MAX_CONNECTIONS")? {
+            self.max_connections = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_MAX_CONNECTIONS_PER_IP")? {
+            self.max_connections_per_ip = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_FEDERATION_ADDR")? {
+            self.federation_addr = Some(v);
+        }
+        // Comma separated
+        if let Some(v) = env::<String>("CHATSAPP_FEDERATION_PEERS")? {
+            self.federation_peers = v.split(',').map(|peer| peer.trim().to_owned()).collect();
+        }
+        if let Some(v) = env("CHATSAPP_FEDERATION_NAME")? {
+            self.federation_name = v;
+        }
+        if let Some(v) = env("CHATSAPP_FEDERATION_KEY")? {
+            self.federation_key = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_ARCHIVE_DIR")? {
+            self.archive_dir = v;
+        }
+        if let Some(v) = env("CHATSAPP_PLUGIN_DIR")? {
+            self.plugin_dir = Some(v);
+        }
+        if let Some(v) = env("CHATSAPP_MOTD")? {
+            self.motd = v;
+        }
+        if let Some(v) = env("CHATSAPP_DM_QUEUE_TTL_SECS")? {
+            self.dm_queue_ttl_secs = v;
+        }
+        if let Some(v) = env("CHATSAPP_POW_DIFFICULTY")? {
+            self.pow_difficulty = v;
+        }
+        if let Some(v) = env("CHATSAPP_MAX_WRITE_BYTES")? {
+            self.max_write_bytes = v;
+        }
+        if let Some(v) = env("CHATSAPP_CLEAR_AWAY_ON_ACTIVITY")? {
+            self.clear_away_on_activity = v;
+        }
+        if let Some(v) = env("CHATSAPP_AUTO_CREATE_ROOMS")? {
+            self.auto_create_rooms = v;
+        }
+        // Comma separated
+        if let Some(v) = env::<String>("CHATSAPP_WEBHOOK_ALLOWLIST")? {
+            self.webhook_allowlist = v.split(',').map(|host| host.trim().to_owned()).collect();
+        }
+
+        Ok(())
+    }
+}
+
+fn env<T: FromStr>(var: &'static str) -> Result<Option<T>, ConfigError> {
+    match std::env::var(var) {
+        Ok(v) => v
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::InvalidEnv(var)),
+        Err(_) => Ok(None),
+    }
+}
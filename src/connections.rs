@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::RwLock;
+
+use crate::writer::WriterHandle;
+
+// Every open connection, logged in or not, so the admin console can see
+// and act on them
+pub type ConnectionMap = Arc<RwLock<HashMap<u64, Connection>>>;
+
+pub struct Connection {
+    pub addr: String,
+    pub username: Option<String>,
+    pub stream: WriterHandle,
+    // Sending a reason here makes the connection write it and hang up
+    pub disconnect: Sender<String>,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub fn new_connection_map() -> ConnectionMap {
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub async fn register(conns: &ConnectionMap, id: u64, conn: Connection) {
+    conns.write().await.insert(id, conn);
+}
+
+pub async fn unregister(conns: &ConnectionMap, id: u64) {
+    conns.write().await.remove(&id);
+}
+
+pub async fn set_username(conns: &ConnectionMap, id: u64, username: Option<String>) {
+    if let Some(conn) = conns.write().await.get_mut(&id) {
+        conn.username = username;
+    }
+}
+
+// Asks every connection using `username` to close, returning how many there were
+pub async fn disconnect(conns: &ConnectionMap, username: &str, reason: &str) -> usize {
+    let conns = conns.read().await;
+    let mut count = 0;
+
+    for conn in conns.values() {
+        if conn.username.as_deref() != Some(username) {
+            continue;
+        }
+
+        // The connection may already be on its way out
+        if conn.disconnect.try_send(reason.to_owned()).is_ok() {
+            count += 1;
+        }
+    }
+
+    count
+}
+
+// How long a refused connection gets to read why before it's dropped
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq)]
+pub enum Refusal {
+    Full,
+    TooManyFromAddr,
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refusal::Full => writeln!(f, "Sorry, the server is full right now, try again later"),
+            Refusal::TooManyFromAddr => writeln!(
+                f,
+                "Sorry, there are too many connections from your address, close one and try again"
+            ),
+        }
+    }
+}
+
+// Caps open connections overall and from each address. It's checked as
+// soon as a connection is accepted, before it costs anything else.
+pub struct Limiter {
+    max_total: Option<usize>,
+    max_per_addr: Option<usize>,
+    open: Mutex<Open>,
+}
+
+#[derive(Default)]
+struct Open {
+    total: usize,
+    by_addr: HashMap<IpAddr, usize>,
+}
+
+// A connection's place under the limits, given back when it's dropped
+pub struct Slot {
+    limiter: Arc<Limiter>,
+    addr: IpAddr,
+}
+
+impl Limiter {
+    // None means there's no limit
+    pub fn new(max_total: Option<usize>, max_per_addr: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            max_total,
+            max_per_addr,
+            open: Mutex::new(Open::default()),
+        })
+    }
+
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::net::IpAddr;
+    ///
+    /// use chatsapp::connections::{Limiter, Refusal};
+    ///
+    /// let limiter = Limiter::new(Some(3), Some(2));
+    /// let a: IpAddr = "10.0.0.1".parse().unwrap();
+    /// let b: IpAddr = "10.0.0.2".parse().unwrap();
+    ///
+    /// let first = limiter.acquire(a).unwrap();
+    /// let _second = limiter.acquire(a).unwrap();
+    /// assert_eq!(limiter.acquire(a).err(), Some(Refusal::TooManyFromAddr));
+    ///
+    /// let _third = limiter.acquire(b).unwrap();
+    /// assert_eq!(limiter.acquire(b).err(), Some(Refusal::Full));
+    ///
+    /// // Closing a connection makes room for another
+    /// drop(first);
+    /// assert!(limiter.acquire(a).is_ok());
+    /// ```
+    pub fn acquire(self: &Arc<Self>, addr: IpAddr) -> Result<Slot, Refusal> {
+        let mut open = self.open.lock().unwrap();
+
+        if self.max_total.is_some_and(|max| open.total >= max) {
+            return Err(Refusal::Full);
+        }
+
+        let from_addr = open.by_addr.entry(addr).or_default();
+        if self.max_per_addr.is_some_and(|max| *from_addr >= max) {
+            return Err(Refusal::TooManyFromAddr);
+        }
+
+        *from_addr += 1;
+        open.total += 1;
+
+        Ok(Slot {
+            limiter: Arc::clone(self),
+            addr,
+        })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        open.total -= 1;
+
+        // Forget addresses with nothing open, so the map doesn't grow forever
+        if let Some(count) = open.by_addr.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                open.by_addr.remove(&self.addr);
+            }
+        }
+    }
+}
+
+// Tells a connection why it was turned away and hangs up, without waiting
+// long on clients that don't read
+pub async fn refuse(mut stream: TcpStream, refusal: Refusal) {
+    let reason = refusal.to_string();
+    let write = stream.write_all(reason.as_bytes());
+
+    let _ = tokio::time::timeout(REFUSAL_TIMEOUT, write).await;
+}
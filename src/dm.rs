@@ -0,0 +1,337 @@
+use std::time::Duration;
+use std::{collections::HashMap, sync::Arc};
+
+use redis::AsyncCommands;
+use tokio::sync::RwLock;
+
+use crate::id;
+use crate::message::{Message, MessageKind, Protocol};
+use crate::pool::Pool;
+use crate::room::get_time_in_ms;
+use crate::writer::WriterHandle;
+
+// <Username, Stream for the User>
+pub type UserMap = Arc<RwLock<HashMap<String, WriterHandle>>>;
+
+// How much of the last message >dms shows
+const PREVIEW_CHARS: usize = 50;
+
+#[derive(Debug)]
+pub enum DmError {
+    FailedToSend,
+    FailedToDelete,
+    FailedToFetch,
+    UserNotOnline,
+}
+
+impl std::fmt::Display for DmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DmError::FailedToSend => writeln!(f, "Error: Failed to send"),
+            DmError::FailedToDelete => writeln!(f, "Error: Failed to delete messages"),
+            DmError::FailedToFetch => writeln!(f, "Error: Failed to fetch messages"),
+            DmError::UserNotOnline => writeln!(f, "Error: User is not online"),
+        }
+    }
+}
+
+impl std::error::Error for DmError {}
+
+// One line of >dms
+#[derive(Debug, PartialEq)]
+pub struct Conversation {
+    // Who it's with
+    pub with: String,
+    // When the last message was sent, in ms
+    pub at: isize,
+    // What they've sent since you last sent them something
+    pub unread: usize,
+    pub preview: String,
+}
+
+pub fn new_user_map() -> UserMap {
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+// Persists the message to the conversation between both users and
+// returns the message that should be written to the recipient. Both users'
+// indexes of their conversations are updated with it, and sending to
+// someone counts as having read what they sent you.
+pub async fn event(redis: &Pool, from: &str, to: &str, message: &str) -> Result<Message, DmError> {
+    let mut conn = redis.get();
+
+    let key = gen_key(from, to);
+    let score = get_time_in_ms();
+    let msg = Message::new(MessageKind::Dm, None, Some(from), score, message.into())
+        .with_id(id::gen(score));
+    let preview = preview(from, message);
+
+    redis::pipe()
+        .atomic()
+        .zadd(key, gen_member(&msg), score)
+        .ignore()
+        .zadd(gen_index_key(from), to, score)
+        .ignore()
+        .zadd(gen_index_key(to), from, score)
+        .ignore()
+        .hset(gen_last_key(from), to, &preview)
+        .ignore()
+        .hset(gen_last_key(to), from, &preview)
+        .ignore()
+        .hincr(gen_unread_key(to), from, 1)
+        .ignore()
+        .hdel(gen_unread_key(from), to)
+        .ignore()
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            DmError::FailedToSend
+        })?;
+
+    Ok(msg)
+}
+
+// Everyone `username` has DMed or been DMed by, most recent first.
+// Conversations from before the index was kept show up once either side
+// sends something.
+pub async fn conversations(redis: &Pool, username: &str) -> Result<Vec<Conversation>, DmError> {
+    let mut conn = redis.get();
+
+    let index: Vec<(String, isize)> = conn
+        .zrevrange_withscores(gen_index_key(username), 0, -1)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            DmError::FailedToFetch
+        })?;
+    if index.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let with: Vec<&str> = index.iter().map(|(with, _)| with.as_str()).collect();
+    let (unread, previews): (Vec<Option<usize>>, Vec<Option<String>>) = redis::pipe()
+        .cmd("HMGET")
+        .arg(gen_unread_key(username))
+        .arg(&with)
+        .cmd("HMGET")
+        .arg(gen_last_key(username))
+        .arg(&with)
+        .query_async(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            DmError::FailedToFetch
+        })?;
+
+    Ok(index
+        .into_iter()
+        .zip(unread.into_iter().zip(previews))
+        .map(|((with, at), (unread, preview))| Conversation {
+            with,
+            at,
+            unread: unread.unwrap_or_default(),
+            preview: preview.unwrap_or_default(),
+        })
+        .collect())
+}
+
+// Who sent the last message and how it starts
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::dm::preview;
+///
+/// assert_eq!(preview("alice", "hi"), "alice: hi");
+/// assert_eq!(
+///     preview("alice", &"a".repeat(60)),
+///     format!("alice: {}…", "a".repeat(43))
+/// );
+/// ```
+pub fn preview(from: &str, message: &str) -> String {
+    let line = format!("{}: {}", from, message);
+
+    if line.chars().count() <= PREVIEW_CHARS {
+        return line;
+    }
+
+    let mut preview: String = line.chars().take(PREVIEW_CHARS).collect();
+    preview.push('…');
+
+    preview
+}
+
+// Holds `msg` for `to` until they next log in, see `take_queued`. Anything
+// older than `ttl` is dropped, and the whole queue expires `ttl` after the
+// last message so nobody's queue outlives them never logging in again.
+pub async fn queue(redis: &Pool, to: &str, msg: &Message, ttl: Duration) -> Result<(), DmError> {
+    let mut conn = redis.get();
+    let key = gen_queue_key(to);
+    let ttl = ttl.as_millis() as isize;
+
+    redis::pipe()
+        .atomic()
+        .zadd(&key, serde_json::to_string(msg).unwrap(), msg.timestamp)
+        .ignore()
+        .zrembyscore(&key, "-inf", get_time_in_ms() - ttl)
+        .ignore()
+        .pexpire(&key, ttl as usize)
+        .ignore()
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            DmError::FailedToSend
+        })
+}
+
+// Empties `username`'s queue, returning what was sent to them in the last
+// `ttl`, oldest first
+pub async fn take_queued(
+    redis: &Pool,
+    username: &str,
+    ttl: Duration,
+) -> Result<Vec<Message>, DmError> {
+    let mut conn = redis.get();
+    let key = gen_queue_key(username);
+    let since = get_time_in_ms() - ttl.as_millis() as isize;
+
+    let (queued,): (Vec<String>,) = redis::pipe()
+        .atomic()
+        .zrangebyscore(&key, since, "+inf")
+        .del(&key)
+        .ignore()
+        .query_async(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            DmError::FailedToFetch
+        })?;
+
+    Ok(queued
+        .iter()
+        .filter_map(|msg| serde_json::from_str(msg).ok())
+        .collect())
+}
+
+// Deletes every conversation `username` was in, returning how many. The other
+// side's messages go with them, since each conversation is one key.
+pub async fn forget(redis: &Pool, username: &str) -> Result<usize, DmError> {
+    let mut conn = redis.get();
+    let mut keys = Vec::new();
+
+    // Either name can come first, see `gen_key`
+    for pattern in [format!("dm:{}:*", username), format!("dm:*:{}", username)] {
+        let mut iter = conn.scan_match::<_, String>(pattern).await.map_err(|e| {
+            dbg!("{}", e);
+            DmError::FailedToDelete
+        })?;
+
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+    }
+
+    // Along with anything still waiting for them, their index and their
+    // place in everyone else's
+    let mut pipe = redis::pipe();
+    pipe.del(&[
+        gen_queue_key(username),
+        gen_index_key(username),
+        gen_last_key(username),
+        gen_unread_key(username),
+    ])
+    .ignore();
+    for key in &keys {
+        let other = key
+            .splitn(3, ':')
+            .skip(1)
+            .find(|name| *name != username)
+            .unwrap_or_default();
+        pipe.zrem(gen_index_key(other), username)
+            .ignore()
+            .hdel(gen_last_key(other), username)
+            .ignore()
+            .hdel(gen_unread_key(other), username)
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+        dbg!("{}", e);
+        DmError::FailedToDelete
+    })?;
+
+    if keys.is_empty() {
+        return Ok(0);
+    }
+
+    conn.del::<_, usize>(&keys).await.map_err(|e| {
+        dbg!("{}", e);
+        DmError::FailedToDelete
+    })
+}
+
+pub async fn get_stream(users: &UserMap, username: &str) -> Result<WriterHandle, DmError> {
+    match users.read().await.get(username) {
+        Some(stream) => Ok(stream.clone()),
+        None => Err(DmError::UserNotOnline),
+    }
+}
+
+pub async fn register(users: &UserMap, username: String, stream: WriterHandle) {
+    users.write().await.insert(username, stream);
+}
+
+// Only removes the entry if it belongs to this connection, since the same
+// account may have logged in again somewhere else. Returns whether it did,
+// so anything else tied to the login is only cleared up by its owner too.
+pub async fn unregister(users: &UserMap, username: &str, stream: &WriterHandle) -> bool {
+    let mut users = users.write().await;
+
+    match users.get(username) {
+        Some(current) if current.same(stream) => {
+            users.remove(username);
+            true
+        }
+        _ => false,
+    }
+}
+
+// Sorted set members are unique, so without the id sending the same text
+// twice would only move the first one
+fn gen_member(msg: &Message) -> String {
+    format!(
+        "{} {}",
+        msg.id.as_deref().unwrap_or_default(),
+        msg.render(Protocol::Text)
+    )
+}
+
+// Both users share one conversation key regardless of who sent first
+fn gen_key(a: &str, b: &str) -> String {
+    if a < b {
+        format!("dm:{}:{}", a, b)
+    } else {
+        format!("dm:{}:{}", b, a)
+    }
+}
+
+fn gen_queue_key(username: &str) -> String {
+    format!("dmqueue:{}", username)
+}
+
+// Who `username` has conversations with, scored by their last message
+fn gen_index_key(username: &str) -> String {
+    format!("dms:{}", username)
+}
+
+// The preview of each of their conversations' last message
+fn gen_last_key(username: &str) -> String {
+    format!("dmlast:{}", username)
+}
+
+// How many messages they haven't answered in each conversation
+fn gen_unread_key(username: &str) -> String {
+    format!("dmunread:{}", username)
+}
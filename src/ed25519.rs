@@ -0,0 +1,66 @@
+// Ed25519 signatures (RFC 8032), for messages signed by clients, backed by
+// `ed25519-dalek`. Keys and signatures are plain byte arrays so the rest of
+// the server doesn't need its types.
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SECRET_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::ed25519;
+///
+/// let secret = [7; 32];
+/// let public = ed25519::public_key(&secret);
+/// let signature = ed25519::sign(&secret, b"hello");
+///
+/// assert!(ed25519::verify(&public, b"hello", &signature));
+/// assert!(!ed25519::verify(&public, b"hell0", &signature));
+/// assert!(!ed25519::verify(&ed25519::public_key(&[8; 32]), b"hello", &signature));
+/// ```
+pub fn sign(secret: &[u8; SECRET_KEY_LEN], msg: &[u8]) -> [u8; SIGNATURE_LEN] {
+    SigningKey::from_bytes(secret).sign(msg).to_bytes()
+}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::ed25519;
+///
+/// let hex = |s: &str| -> Vec<u8> {
+///     (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+/// };
+/// // RFC 8032 section 7.1, test 1
+/// let secret = hex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+/// let public = hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+/// let signature = hex(concat!(
+///     "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555",
+///     "fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"
+/// ));
+///
+/// let secret: [u8; 32] = secret.try_into().unwrap();
+/// assert_eq!(ed25519::public_key(&secret).to_vec(), public);
+/// assert_eq!(ed25519::sign(&secret, b"").to_vec(), signature);
+/// ```
+pub fn public_key(secret: &[u8; SECRET_KEY_LEN]) -> [u8; PUBLIC_KEY_LEN] {
+    SigningKey::from_bytes(secret).verifying_key().to_bytes()
+}
+
+// Whether `signature` is `public`'s for `msg`. Strict verification refuses
+// signatures with an `s` that isn't reduced and weak keys, so each message
+// has only one.
+pub fn verify(public: &[u8; PUBLIC_KEY_LEN], msg: &[u8], signature: &[u8; SIGNATURE_LEN]) -> bool {
+    let key = match VerifyingKey::from_bytes(public) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+
+    key.verify_strict(msg, &Signature::from_bytes(signature))
+        .is_ok()
+}
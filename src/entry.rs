@@ -0,0 +1,197 @@
+use redis::streams::StreamId;
+
+use crate::message::{Message, MessageKind, Protocol, VERIFIED_MARK};
+
+// What a forgotten user's messages say instead
+pub const DELETED: &str = "[deleted]";
+
+// One message in a room's history, as it was saved. Entries have the
+// message's kind in `type` and the message itself as JSON in `msg`, and are
+// rendered when they're read, so how messages look can change without
+// rewriting history. Rooms from before that only have the text they were
+// shown as, in `text`.
+#[derive(Debug, Clone)]
+pub enum Entry {
+    Typed(Message),
+    Text(String),
+}
+
+impl Entry {
+    // What to save for `msg`, as stream fields
+    pub fn fields(msg: &Message) -> [(&'static str, String); 2] {
+        let mut msg = msg.clone();
+        // Only meant for whoever sent it, and the id is the entry's own
+        msg.reference = None;
+        msg.id = None;
+
+        [
+            ("type", msg.kind.name()),
+            ("msg", serde_json::to_string(&msg).unwrap()),
+        ]
+    }
+
+    // Entries that are neither are read as empty text
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::entry::Entry;
+    /// use chatsapp::message::{Message, MessageKind};
+    ///
+    /// let msg = Message::new(MessageKind::Chat, Some("rust"), Some("bob"), 1, "hi".into());
+    /// let [_, (_, json)] = Entry::fields(&msg);
+    /// let entry = Entry::parse(Some(json), None);
+    ///
+    /// assert_eq!(entry.kind(), Some(MessageKind::Chat));
+    /// assert_eq!(entry.author(), Some("bob"));
+    /// assert_eq!(entry.text(), "bob: hi\n");
+    ///
+    /// let old = Entry::parse(None, Some("bob✓: hi\n".to_owned()));
+    ///
+    /// assert_eq!(old.kind(), None);
+    /// assert_eq!(old.author(), Some("bob"));
+    /// assert!(old.is_signed());
+    /// assert_eq!(Entry::parse(None, Some("bob has joined the room\n".into())).author(), None);
+    /// ```
+    pub fn parse(msg: Option<String>, text: Option<String>) -> Self {
+        if let Some(msg) = msg.and_then(|msg| serde_json::from_str(&msg).ok()) {
+            return Entry::Typed(msg);
+        }
+
+        Entry::Text(text.unwrap_or_default())
+    }
+
+    pub fn from_stream(entry: &StreamId) -> Self {
+        Self::parse(entry.get("msg"), entry.get("text"))
+    }
+
+    // None for entries from before types were saved
+    pub fn kind(&self) -> Option<MessageKind> {
+        match self {
+            Entry::Typed(msg) => Some(msg.kind),
+            Entry::Text(_) => None,
+        }
+    }
+
+    // As text clients are shown it
+    pub fn text(&self) -> String {
+        match self {
+            Entry::Typed(msg) => msg.render(Protocol::Text),
+            Entry::Text(text) => text.clone(),
+        }
+    }
+
+    // Who sent it, if it's a chat message
+    pub fn author(&self) -> Option<&str> {
+        match self {
+            Entry::Typed(msg) if msg.kind == MessageKind::Chat => msg.user.as_deref(),
+            Entry::Typed(_) => None,
+            Entry::Text(text) => text_author(text),
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        match self {
+            Entry::Typed(msg) => msg.verified,
+            Entry::Text(text) => match chat_line(text).split_once(": ") {
+                Some((author, _)) => author.ends_with(VERIFIED_MARK),
+                None => false,
+            },
+        }
+    }
+
+    // What to save instead once `username` is forgotten: their messages say
+    // DELETED, and so do quotes of them in other people's replies. None if
+    // there's nothing of theirs in it. `timestamp` is the entry's, for older
+    // entries that don't have one.
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::entry::{Entry, DELETED};
+    /// use chatsapp::message::{Message, MessageKind};
+    ///
+    /// let own = Entry::parse(None, Some("bob✓: my address is...\n".to_owned()));
+    /// let redacted = own.redact("rust", 1, "bob").unwrap();
+    /// assert_eq!((redacted.user.as_deref(), redacted.body.as_str()), (Some("bob"), DELETED));
+    /// assert!(!redacted.verified);
+    ///
+    /// let reply = Message::new(MessageKind::Chat, Some("rust"), Some("alice"), 2, "hi".into())
+    ///     .with_reply(Some("1-0".to_owned()), "bob: my address is...".to_owned());
+    /// let [_, (_, json)] = Entry::fields(&reply);
+    /// let redacted = Entry::parse(Some(json), None).redact("rust", 2, "bob").unwrap();
+    /// assert_eq!(redacted.quote.as_deref(), Some("bob: [deleted]"));
+    /// assert_eq!(redacted.body, "hi");
+    ///
+    /// // Already done, and nothing to do with them
+    /// assert!(Entry::Typed(own.redact("rust", 1, "bob").unwrap()).redact("rust", 1, "bob").is_none());
+    /// assert!(Entry::parse(None, Some("bobby: hi\n".to_owned())).redact("rust", 3, "bob").is_none());
+    /// ```
+    pub fn redact(&self, room: &str, timestamp: isize, username: &str) -> Option<Message> {
+        if self.author() == Some(username) {
+            let mut msg = match self {
+                Entry::Typed(msg) if msg.body == DELETED => return None,
+                Entry::Typed(msg) => msg.clone(),
+                Entry::Text(_) => Message::new(
+                    MessageKind::Chat,
+                    Some(room),
+                    Some(username),
+                    timestamp,
+                    String::new(),
+                ),
+            };
+            msg.body = DELETED.to_owned();
+            // The signature was for what they wrote
+            msg.verified = false;
+
+            return Some(msg);
+        }
+
+        // Quotes are "user: text", or "user✓: text" if it was signed
+        let msg = match self {
+            Entry::Typed(msg) => msg,
+            Entry::Text(_) => return None,
+        };
+        let quoted = msg.quote.as_deref()?.strip_prefix(username)?;
+        let quoted = quoted.strip_prefix(VERIFIED_MARK).unwrap_or(quoted);
+
+        match quoted.strip_prefix(": ") {
+            Some(text) if text != DELETED => {
+                let mut msg = msg.clone();
+                msg.quote = Some(format!("{}: {}", username, DELETED));
+                Some(msg)
+            }
+            _ => None,
+        }
+    }
+
+    // What a reply was replying to, as it's shown above it
+    pub fn quote(&self) -> Option<&str> {
+        match self {
+            Entry::Typed(msg) => msg.quote.as_deref(),
+            Entry::Text(text) => text.strip_prefix("> ")?.lines().next(),
+        }
+    }
+}
+
+// Replies are shown with the quote on the line above
+pub(crate) fn chat_line(text: &str) -> &str {
+    text.trim_end_matches('\n')
+        .lines()
+        .last()
+        .unwrap_or_default()
+}
+
+// Who sent a chat message, from the "user: body" it was saved as. Notices
+// either have no ": " or spaces before it, like "alice set the topic to: x".
+fn text_author(text: &str) -> Option<&str> {
+    let (author, _) = chat_line(text).split_once(": ")?;
+    let author = author.strip_suffix(VERIFIED_MARK).unwrap_or(author);
+
+    match author.is_empty() || author.contains(char::is_whitespace) {
+        true => None,
+        false => Some(author),
+    }
+}
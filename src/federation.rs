@@ -0,0 +1,485 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, error::RecvError, Sender};
+
+use crate::broker::{self, BrokerEvent, RoomMap};
+use crate::config::Config;
+use crate::filter::Filters;
+use crate::message::{Message, MessageKind};
+use crate::permissions::{self, Action};
+use crate::pool::Pool;
+use crate::receipts::Receipts;
+use crate::room::{self, RoomEvent};
+use crate::validate;
+
+// Only rooms named like this are shared with peers
+pub const ROOM_PREFIX: &str = "fed/";
+
+// Where `room::event` publishes local messages for the peering task
+const CHANNEL: &str = "federation";
+
+// How long a message id is remembered, so gossip that comes back around is
+// dropped rather than delivered twice
+const SEEN_TTL_SECS: usize = 86400;
+
+// Events a slow peer link can fall behind by before it skips ahead
+const PEER_QUEUE_SIZE: usize = 1024;
+
+// How long to wait before reconnecting to a peer or resubscribing
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// The longest line a peer can send, with room for the longest message
+// escaped as JSON
+const MAX_LINE: usize = 32 * 1024;
+
+// One chat message, as it travels between servers, one per line as JSON.
+// On the local channel `id` and `user` are this server's own; before going
+// to a peer they're qualified with `federation_name`, as `<server>/<id>`
+// and `<name>@<server>`, so they mean the same thing everywhere.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub id: String,
+    pub room: String,
+    pub user: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum FederationError {
+    FailedToPublish,
+    FailedToFetch,
+}
+
+impl std::fmt::Display for FederationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FederationError::FailedToPublish => writeln!(f, "Error: Failed to publish event"),
+            FederationError::FailedToFetch => writeln!(f, "Error: Failed to check event"),
+        }
+    }
+}
+
+impl std::error::Error for FederationError {}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::federation::is_federated;
+///
+/// assert!(is_federated("fed/rust"));
+/// assert!(!is_federated("rust"));
+/// assert!(!is_federated("fed"));
+/// ```
+pub fn is_federated(room: &str) -> bool {
+    room.starts_with(ROOM_PREFIX)
+}
+
+// Hands a chat message sent on this server in a federated room to whichever
+// server is peering, in the background. Messages from peers have `@` in
+// their sender's name and are passed on by the peering task itself.
+pub fn notify(redis: &Pool, room: &str, msg: &Message) {
+    if !is_federated(room) || msg.kind != MessageKind::Chat {
+        return;
+    }
+
+    let (id, user) = match (&msg.id, &msg.user) {
+        (Some(id), Some(user)) if !user.contains('@') => (id.clone(), user.clone()),
+        _ => return,
+    };
+
+    let event = Event {
+        id,
+        room: room.to_owned(),
+        user,
+        body: msg.body.clone(),
+    };
+    let redis = redis.clone();
+
+    tokio::spawn(async move {
+        let payload = serde_json::to_string(&event).unwrap();
+
+        let published: redis::RedisResult<()> = redis.get().publish(CHANNEL, payload).await;
+        if let Err(e) = published {
+            dbg!("{}", e);
+            eprintln!("{}", FederationError::FailedToPublish);
+        }
+    });
+}
+
+// Starts peering: local messages are sent to every address in
+// `federation_peers`, and if `federation_addr` is set, peers can connect
+// there to send theirs. Whatever arrives from one peer is passed on to the
+// others, so servers that aren't peered directly still hear each other.
+pub fn spawn(
+    client: Client,
+    redis: Pool,
+    rooms: RoomMap,
+    filters: Arc<Filters>,
+    config: Arc<Config>,
+) {
+    let (peers, _) = broadcast::channel(PEER_QUEUE_SIZE);
+
+    for addr in &config.federation_peers {
+        tokio::spawn(connect(addr.clone(), config.clone(), peers.subscribe()));
+    }
+
+    tokio::spawn(subscribe(
+        client,
+        redis.clone(),
+        config.clone(),
+        peers.clone(),
+    ));
+
+    // Without a key anyone who can reach the port could post as any peer
+    match (config.federation_addr.clone(), &config.federation_key) {
+        (Some(addr), Some(_)) => {
+            tokio::spawn(serve(addr, redis, rooms, filters, config, peers));
+        }
+        (Some(addr), None) => {
+            eprintln!(
+                "Not accepting federation peers on {} without federation_key",
+                addr
+            )
+        }
+        (None, _) => {}
+    }
+}
+
+// Sends events to one peer, reconnecting whenever the link drops
+async fn connect(addr: String, config: Arc<Config>, mut events: broadcast::Receiver<String>) {
+    loop {
+        if let Err(e) = send_to_peer(&addr, &config, &mut events).await {
+            eprintln!("Federation peer {}: {}", addr, e);
+        }
+
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+async fn send_to_peer(
+    addr: &str,
+    config: &Config,
+    events: &mut broadcast::Receiver<String>,
+) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+
+    // The first line is the shared key, which peers won't accept empty
+    let key = config.federation_key.as_deref().unwrap_or_default();
+    stream.write_all(format!("{}\n", key).as_bytes()).await?;
+
+    loop {
+        match events.recv().await {
+            Ok(line) => stream.write_all(line.as_bytes()).await?,
+            Err(RecvError::Lagged(n)) => eprintln!("Federation peer {} missed {} events", addr, n),
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+// Qualifies this server's messages and queues them for every peer. Pub/Sub
+// needs a dedicated connection, like `pubsub::spawn_subscriber`.
+async fn subscribe(client: Client, redis: Pool, config: Arc<Config>, peers: Sender<String>) {
+    loop {
+        if let Err(e) = forward_local(&client, &redis, &config, &peers).await {
+            eprintln!("{}", e);
+        }
+
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
+
+async fn forward_local(
+    client: &Client,
+    redis: &Pool,
+    config: &Config,
+    peers: &Sender<String>,
+) -> redis::RedisResult<()> {
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+    pubsub.subscribe(CHANNEL).await?;
+
+    let mut messages = pubsub.on_message();
+
+    while let Some(msg) = messages.next().await {
+        let payload: String = msg.get_payload()?;
+        let mut event: Event = match serde_json::from_str(&payload) {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("Ignoring federation event: {}", e);
+                continue;
+            }
+        };
+
+        event.id = format!("{}/{}", config.federation_name, event.id);
+        event.user = format!("{}@{}", event.user, config.federation_name);
+
+        // Several servers sharing this Redis may all be peering
+        match first_sighting(redis, &event.id).await {
+            Ok(true) => send(peers, &event),
+            Ok(false) => {}
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    Ok(())
+}
+
+// Accepts peers and delivers what they send to this server's rooms
+async fn serve(
+    addr: String,
+    redis: Pool,
+    rooms: RoomMap,
+    filters: Arc<Filters>,
+    config: Arc<Config>,
+    peers: Sender<String>,
+) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to start federation on {}: {}", addr, e);
+            return;
+        }
+    };
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+
+        let redis = redis.clone();
+        let rooms = Arc::clone(&rooms);
+        let filters = Arc::clone(&filters);
+        let config = Arc::clone(&config);
+        let peers = peers.clone();
+
+        tokio::spawn(async move {
+            let peer_link = receive_from_peer(stream, &redis, &rooms, &filters, &config, &peers);
+            if let Err(e) = peer_link.await {
+                eprintln!("Federation peer {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn receive_from_peer(
+    stream: TcpStream,
+    redis: &Pool,
+    rooms: &RoomMap,
+    filters: &Arc<Filters>,
+    config: &Config,
+    peers: &Sender<String>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+
+    let key = read_line(&mut reader).await?.unwrap_or_default();
+    if !keys_match(&key, config.federation_key.as_deref().unwrap_or_default()) {
+        eprintln!("Federation peer sent the wrong key");
+        return Ok(());
+    }
+
+    while let Some(line) = read_line(&mut reader).await? {
+        let event: Event = match serde_json::from_str(&line) {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("Ignoring federation event: {}", e);
+                continue;
+            }
+        };
+
+        if !is_valid(&event) {
+            eprintln!("Ignoring federation event {}", event.id);
+            continue;
+        }
+
+        match first_sighting(redis, &event.id).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        }
+
+        deliver(redis, rooms, filters, config, &event).await;
+        send(peers, &event);
+    }
+
+    Ok(())
+}
+
+// Like `http::read_line`, a peer can't make a line grow without end
+async fn read_line(reader: &mut BufReader<TcpStream>) -> std::io::Result<Option<String>> {
+    let mut line = String::new();
+
+    let n = (&mut *reader)
+        .take(MAX_LINE as u64)
+        .read_line(&mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "line too long",
+        ));
+    }
+
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_owned()))
+}
+
+// Compares digests of the two in full, so how long it takes doesn't give
+// away how much of the key, or its length, a peer got right
+fn keys_match(sent: &str, key: &str) -> bool {
+    let (sent, key) = (Sha256::digest(sent), Sha256::digest(key));
+
+    sent.iter()
+        .zip(key.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+// Saves the message to the room's history here and hands it to its broker,
+// after the same checks `api::send` makes. Rooms have to be created on each
+// server, and ones that haven't been are skipped.
+async fn deliver(
+    redis: &Pool,
+    rooms: &RoomMap,
+    filters: &Arc<Filters>,
+    config: &Config,
+    event: &Event,
+) {
+    let body = match check(redis, filters, event).await {
+        Ok(body) => body,
+        Err(reason) => {
+            return eprintln!(
+                "Not delivering federation event {}: {}",
+                event.id,
+                reason.trim_end()
+            )
+        }
+    };
+
+    let fanout = config.pubsub.then(|| redis.clone());
+    let receipts = Receipts::new(redis.clone(), config.receipts_max_members);
+
+    let tx = match broker::get_or_spawn(
+        redis,
+        &event.room,
+        rooms,
+        fanout,
+        receipts,
+        config.broker_shards,
+    )
+    .await
+    {
+        Ok(Some(tx)) => tx,
+        Ok(None) => return,
+        Err(e) => return eprintln!("{}", e),
+    };
+
+    let chat = RoomEvent::Chat(body);
+    let msg = match room::event(redis, chat, &event.room, &event.user, true).await {
+        Ok(msg) => msg,
+        Err(e) => return eprintln!("{}", e),
+    };
+
+    if let Err(e) = tx.send(BrokerEvent::Post { msg }).await {
+        eprintln!("{}", e);
+    }
+}
+
+// Bans, mutes, invites and roles here apply to peers' users too, and the
+// body goes through this server's filters. Returns the body to save.
+async fn check(redis: &Pool, filters: &Arc<Filters>, event: &Event) -> Result<String, String> {
+    let (room, user) = (event.room.as_str(), event.user.as_str());
+
+    room::check_banned(redis, room, user)
+        .await
+        .map_err(|e| e.to_string())?;
+    room::check_invited(redis, room, user, None)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Filters can't read ciphertext, so encrypted rooms only check that's
+    // what it is
+    let body = match room::is_encrypted(redis, room)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        true => {
+            validate::ciphertext(&event.body).map_err(|e| e.to_string())?;
+            event.body.clone()
+        }
+        false => {
+            let body = filters.run(user, room, event.body.clone()).await?;
+            let lang = room::lang(redis, room).await.map_err(|e| e.to_string())?;
+            filters.transform(lang.as_deref(), room, body)
+        }
+    };
+
+    room::check_muted(redis, room, user)
+        .await
+        .map_err(|e| e.to_string())?;
+    permissions::check(redis, Some(room), user, true, Action::Send)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(body)
+}
+
+// Peers are trusted with their own users' names, but not with anything that
+// couldn't have been sent here
+fn is_valid(event: &Event) -> bool {
+    let (name, server) = match event.user.split_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+
+    is_federated(&event.room)
+        && validate::room_name(&event.room).is_ok()
+        && validate::username(name).is_ok()
+        && validate::username(server).is_ok()
+        && validate::message(&event.body).is_ok()
+}
+
+// Records the id, returning whether it was new
+async fn first_sighting(redis: &Pool, id: &str) -> Result<bool, FederationError> {
+    let set: Option<String> = redis::cmd("SET")
+        .arg(gen_seen_key(id))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(SEEN_TTL_SECS)
+        .query_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            FederationError::FailedToFetch
+        })?;
+
+    Ok(set.is_some())
+}
+
+fn send(peers: &Sender<String>, event: &Event) {
+    let line = format!("{}\n", serde_json::to_string(event).unwrap());
+
+    // Fails only when there are no peers to send to
+    let _ = peers.send(line);
+}
+
+fn gen_seen_key(id: &str) -> String {
+    format!("fed-seen:{}", id)
+}
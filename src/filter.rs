@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use crate::lang::LangHook;
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Allow,
+    // Send this instead of what the user wrote
+    Transform(String),
+    // Don't send anything, and tell the user why
+    Reject(String),
+}
+
+// Runs before a chat message is persisted or broadcast
+pub trait MessageFilter: Send + Sync {
+    fn filter(&self, user: &str, room: &str, msg: &str) -> Action;
+}
+
+#[derive(Default)]
+pub struct Filters {
+    filters: Vec<Box<dyn MessageFilter>>,
+    hooks: Vec<Box<dyn LangHook>>,
+}
+
+impl Filters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, filter: impl MessageFilter + 'static) {
+        self.filters.push(Box::new(filter));
+    }
+
+    // Each filter sees the output of the one before it. Returns the message
+    // to send, or the reason it was rejected.
+    pub fn apply(&self, user: &str, room: &str, mut msg: String) -> Result<String, String> {
+        for filter in &self.filters {
+            match filter.filter(user, room, &msg) {
+                Action::Allow => {}
+                Action::Transform(new) => msg = new,
+                Action::Reject(reason) => return Err(reason),
+            }
+        }
+
+        Ok(msg)
+    }
+
+    // `apply` on tokio's blocking threads, which is how connections run it.
+    // Plugins are among the filters, and their WASM can run for a while and
+    // waits on each plugin's lock, so it mustn't hold up an async task.
+    pub async fn run(
+        self: &Arc<Self>,
+        user: &str,
+        room: &str,
+        msg: String,
+    ) -> Result<String, String> {
+        let filters = Arc::clone(self);
+        let (user, room) = (user.to_owned(), room.to_owned());
+
+        tokio::task::spawn_blocking(move || filters.apply(&user, &room, msg))
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Filters failed: {}", e);
+                Err("Your message couldn't be checked, try again".to_owned())
+            })
+    }
+
+    pub fn add_hook(&mut self, hook: impl LangHook + 'static) {
+        self.hooks.push(Box::new(hook));
+    }
+
+    // Runs the language hooks on a message that made it through `apply`,
+    // in a room with the language `lang`
+    pub fn transform(&self, lang: Option<&str>, room: &str, mut msg: String) -> String {
+        let lang = match lang {
+            Some(lang) => lang,
+            None => return msg,
+        };
+
+        for hook in &self.hooks {
+            if let Some(new) = hook.transform(lang, room, &msg) {
+                msg = new;
+            }
+        }
+
+        msg
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WordlistMode {
+    Reject,
+    Redact,
+}
+
+pub struct WordlistFilter {
+    words: HashSet<String>,
+    mode: WordlistMode,
+}
+
+impl WordlistFilter {
+    pub fn new<I, S>(words: I, mode: WordlistMode) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let words = words
+            .into_iter()
+            .map(|w| w.as_ref().trim().to_lowercase())
+            .filter(|w| !w.is_empty())
+            .collect();
+
+        Self { words, mode }
+    }
+
+    // One word per line, blank lines and lines starting with `#` are ignored
+    pub fn from_file(path: impl AsRef<Path>, mode: WordlistMode) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let words = contents.lines().filter(|l| !l.starts_with('#'));
+
+        Ok(Self::new(words, mode))
+    }
+
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::filter::{WordlistFilter, WordlistMode};
+    ///
+    /// let filter = WordlistFilter::new(["darn"], WordlistMode::Redact);
+    ///
+    /// assert_eq!(filter.redact("Darn it, darnit"), "**** it, darnit");
+    /// ```
+    pub fn redact(&self, msg: &str) -> String {
+        let mut out = String::with_capacity(msg.len());
+        let mut word = String::new();
+
+        for c in msg.chars().chain(std::iter::once(' ')) {
+            if c.is_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+
+            if self.words.contains(&word.to_lowercase()) {
+                out.extend(std::iter::repeat_n('*', word.chars().count()));
+            } else {
+                out.push_str(&word);
+            }
+            word.clear();
+
+            out.push(c);
+        }
+
+        // Drop the space used to flush the last word
+        out.pop();
+
+        out
+    }
+}
+
+impl MessageFilter for WordlistFilter {
+    fn filter(&self, _user: &str, _room: &str, msg: &str) -> Action {
+        let redacted = self.redact(msg);
+
+        if redacted == msg {
+            return Action::Allow;
+        }
+
+        match self.mode {
+            WordlistMode::Reject => Action::Reject("That message contains blocked words".into()),
+            WordlistMode::Redact => Action::Transform(redacted),
+        }
+    }
+}
+
+// The configured wordlist, which a config reload can replace or remove.
+// Clones share it, so one can be added to `Filters` and another kept for
+// reloading. Lets everything through while there isn't one.
+#[derive(Clone, Default)]
+pub struct SharedWordlist {
+    filter: Arc<RwLock<Option<WordlistFilter>>>,
+}
+
+impl SharedWordlist {
+    pub fn set(&self, filter: Option<WordlistFilter>) {
+        *self.filter.write().unwrap() = filter;
+    }
+}
+
+impl MessageFilter for SharedWordlist {
+    fn filter(&self, user: &str, room: &str, msg: &str) -> Action {
+        match &*self.filter.read().unwrap() {
+            Some(filter) => filter.filter(user, room, msg),
+            None => Action::Allow,
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::str::FromStr;
+
+// The protocol this server speaks. It goes up when something changes in a
+// way older clients wouldn't expect, and clients that said hello with an
+// older version, or didn't say hello, keep getting what they did before.
+pub const VERSION: u32 = 1;
+
+// What a client can ask for in its hello, each one a setting it would
+// otherwise change with >protocol or >set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Capability {
+    Json,
+    // zlib, which only JSON clients get
+    Compress,
+    Color,
+    Timestamps,
+    Ids,
+}
+
+impl FromStr for Capability {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Capability::Json),
+            "compress" => Ok(Capability::Compress),
+            "color" => Ok(Capability::Color),
+            "timestamps" => Ok(Capability::Timestamps),
+            "ids" => Ok(Capability::Ids),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Capability::Json => "json",
+            Capability::Compress => "compress",
+            Capability::Color => "color",
+            Capability::Timestamps => "timestamps",
+            Capability::Ids => "ids",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Hello {
+    pub version: u32,
+    // Sorted, without repeats
+    pub caps: Vec<Capability>,
+}
+
+impl Hello {
+    // From the words after >hello, like `version=1 caps=json,color`. Keys
+    // and capabilities this server doesn't know are left out rather than
+    // refused, so newer clients can still talk to it. A missing version is
+    // 1. Errors with the word that didn't parse.
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::hello::{Capability, Hello};
+    ///
+    /// let hello = Hello::parse(&["version=1", "caps=json,compress,emoji"]).unwrap();
+    /// assert_eq!(hello.version, 1);
+    /// assert_eq!(hello.caps, [Capability::Json, Capability::Compress]);
+    ///
+    /// let hello = Hello::parse(&["caps=ids,color,ids", "lang=en"]).unwrap();
+    /// assert_eq!(hello.version, 1);
+    /// assert_eq!(hello.caps, [Capability::Color, Capability::Ids]);
+    ///
+    /// assert_eq!(Hello::parse(&["version=one"]), Err("version=one".to_owned()));
+    /// assert_eq!(Hello::parse(&["version=0"]), Err("version=0".to_owned()));
+    /// ```
+    pub fn parse<S: AsRef<str>>(words: &[S]) -> Result<Self, String> {
+        let mut hello = Hello {
+            version: 1,
+            caps: Vec::new(),
+        };
+
+        for word in words {
+            let word = word.as_ref();
+            match word.split_once('=') {
+                Some(("version", version)) => {
+                    hello.version = match version.parse() {
+                        Ok(version) if version > 0 => version,
+                        _ => return Err(word.to_owned()),
+                    };
+                }
+                Some(("caps", caps)) => {
+                    hello.caps.extend(
+                        caps.split(',')
+                            .filter_map(|cap| cap.parse::<Capability>().ok()),
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        hello.caps.sort();
+        hello.caps.dedup();
+
+        Ok(hello)
+    }
+
+    // What the server will do for the client: the older of the two
+    // versions, and the capabilities that work together
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::hello::{Capability, Hello};
+    ///
+    /// let hello = Hello::parse(&["version=7", "caps=compress,color"]).unwrap();
+    /// let agreed = hello.agree();
+    ///
+    /// assert_eq!(agreed.version, 1);
+    /// assert_eq!(agreed.caps, [Capability::Color]);
+    /// assert_eq!(agreed.to_string(), "Hello version=1 caps=color\n");
+    /// ```
+    pub fn agree(mut self) -> Self {
+        self.version = self.version.min(VERSION);
+
+        if !self.has(Capability::Json) {
+            self.caps.retain(|cap| *cap != Capability::Compress);
+        }
+
+        self
+    }
+
+    pub fn has(&self, cap: Capability) -> bool {
+        self.caps.contains(&cap)
+    }
+}
+
+// The server's answer, in the same form the client said it
+impl std::fmt::Display for Hello {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let caps: Vec<String> = self.caps.iter().map(Capability::to_string).collect();
+
+        writeln!(f, "Hello version={} caps={}", self.version, caps.join(","))
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use base64ct::{Base64, Encoding};
+use serde::Serialize;
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::{timeout, Duration};
+
+// Just enough HTTP/1.1 for the API and webhooks: one request per connection,
+// bodies sized by Content-Length and no chunked encoding or TLS.
+
+// Largest request body accepted, in bytes
+pub const MAX_BODY: usize = 64 * 1024;
+
+// Longest request line or header
+const MAX_LINE: usize = 8 * 1024;
+
+// Most headers a request can have, and their total size in bytes
+const MAX_HEADERS: usize = 64;
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+
+// How long a client gets to send its whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long a webhook gets to respond
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    // Names are lowercased
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    // Path split on `/`, without the empty first segment
+    pub fn segments(&self) -> Vec<&str> {
+        self.path.trim_matches('/').split('/').collect()
+    }
+
+    // Username and password from an `Authorization: Basic` header
+    pub fn basic_auth(&self) -> Option<(String, String)> {
+        let encoded = self.header("authorization")?.strip_prefix("Basic ")?;
+        let decoded = Base64::decode_vec(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+
+        Some((username.to_owned(), password.to_owned()))
+    }
+
+    // Token from an `Authorization: Bearer` header
+    pub fn bearer(&self) -> Option<&str> {
+        self.header("authorization")?
+            .strip_prefix("Bearer ")
+            .map(str::trim)
+    }
+}
+
+// Host with port, and path of an `http://` url
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::http::parse_url;
+///
+/// assert_eq!(
+///     parse_url("http://example.com/hook"),
+///     Some(("example.com:80".to_owned(), "/hook".to_owned()))
+/// );
+/// assert_eq!(
+///     parse_url("http://localhost:8080"),
+///     Some(("localhost:8080".to_owned(), "/".to_owned()))
+/// );
+/// assert_eq!(
+///     parse_url("http://[::1]/hook"),
+///     Some(("[::1]:80".to_owned(), "/hook".to_owned()))
+/// );
+/// assert_eq!(parse_url("https://example.com/hook"), None);
+/// assert_eq!(parse_url("http:///hook"), None);
+/// ```
+pub fn parse_url(url: &str) -> Option<(String, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+
+    if host.is_empty() || host.contains(char::is_whitespace) || path.contains(char::is_whitespace) {
+        return None;
+    }
+
+    // The colons inside an IPv6 address's brackets aren't a port
+    let has_port = host
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.contains(']'));
+    let host = match has_port {
+        true => host.to_owned(),
+        false => format!("{}:80", host),
+    };
+
+    Some((host, path.to_owned()))
+}
+
+// Whether `ip` is this machine or a network it's on: loopback, private,
+// link-local and unspecified addresses, which urls from users shouldn't reach
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::http::is_internal;
+///
+/// assert!(is_internal("127.0.0.1".parse().unwrap()));
+/// assert!(is_internal("10.1.2.3".parse().unwrap()));
+/// assert!(is_internal("192.168.0.1".parse().unwrap()));
+/// assert!(is_internal("169.254.169.254".parse().unwrap()));
+/// assert!(is_internal("0.0.0.0".parse().unwrap()));
+/// assert!(is_internal("::1".parse().unwrap()));
+/// assert!(is_internal("fd00::1".parse().unwrap()));
+/// assert!(is_internal("fe80::1".parse().unwrap()));
+/// assert!(is_internal("::ffff:127.0.0.1".parse().unwrap()));
+/// assert!(!is_internal("93.184.216.34".parse().unwrap()));
+/// assert!(!is_internal("2606:2800:220:1::".parse().unwrap()));
+/// ```
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+
+                // Unique local (fc00::/7) and link-local (fe80::/10)
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+// Looks up `host`, with its port, refusing it with `PermissionDenied` if any
+// of its addresses are internal. Hosts in `allowed`, by name or address, can
+// be internal. Connecting to the addresses returned, rather than looking the
+// name up again, means it can't resolve somewhere else in between.
+pub async fn resolve_external(host: &str, allowed: &[String]) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = lookup_host(host).await?.collect();
+    let name = host
+        .rsplit_once(':')
+        .map_or(host, |(name, _)| name)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+
+    let permitted = |addr: &SocketAddr| {
+        !is_internal(addr.ip())
+            || allowed.iter().any(|allowed| {
+                allowed.eq_ignore_ascii_case(name) || allowed.parse() == Ok(addr.ip())
+            })
+    };
+
+    if addrs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no addresses"));
+    }
+    if !addrs.iter().all(permitted) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "internal address",
+        ));
+    }
+
+    Ok(addrs)
+}
+
+// POSTs `body` as JSON and returns the response status. The host is checked
+// with `resolve_external` again here, since what it resolves to can change
+// after the url was added.
+pub async fn post_json(url: &str, body: &str, allowed: &[String]) -> io::Result<u16> {
+    let (host, path) = parse_url(url).ok_or_else(|| invalid("not an http:// url"))?;
+
+    timeout(CLIENT_TIMEOUT, async {
+        let addrs = resolve_external(&host, allowed).await?;
+        let mut stream = TcpStream::connect(&addrs[..]).await?;
+
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path,
+            host,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut reader = BufReader::new(&mut stream);
+        let status_line = read_line(&mut reader)
+            .await?
+            .ok_or_else(|| invalid("no response"))?;
+
+        status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| invalid("malformed status line"))
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out"))?
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: u16, value: &impl Serialize) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_string(value).unwrap(),
+        }
+    }
+
+    // Errors are JSON too, so clients only need to handle one format. The
+    // chat errors this gets passed are written for the terminal, so their
+    // prefix and newline are dropped.
+    pub fn error(status: u16, message: &str) -> Self {
+        let message = message.trim_end().trim_start_matches("Error: ");
+
+        Self::json(status, &serde_json::json!({ "error": message }))
+    }
+}
+
+// Reads one request. Returns None if the connection closed before sending one.
+// The whole request has to arrive within REQUEST_TIMEOUT, so a client can't
+// keep a task busy by sending a byte at a time.
+pub async fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    timeout(REQUEST_TIMEOUT, parse_request(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out"))?
+}
+
+async fn parse_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    let mut reader = BufReader::new(stream);
+
+    let request_line = match read_line(&mut reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let mut parts = request_line.split(' ');
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target),
+        _ => return Err(invalid("malformed request line")),
+    };
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+
+    let mut headers = HashMap::new();
+    let (mut count, mut size) = (0, 0);
+    while let Some(line) = read_line(&mut reader).await? {
+        if line.is_empty() {
+            break;
+        }
+
+        count += 1;
+        size += line.len();
+        if count > MAX_HEADERS || size > MAX_HEADER_BYTES {
+            return Err(invalid("too many headers"));
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+        }
+    }
+
+    let length: usize = match headers.get("content-length") {
+        Some(length) => length.parse().map_err(|_| invalid("bad content-length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Err(invalid("body too large"));
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    let body = String::from_utf8(body).map_err(|_| invalid("body isn't utf-8"))?;
+
+    Ok(Some(Request {
+        method,
+        path: path.to_owned(),
+        query,
+        headers,
+        body,
+    }))
+}
+
+pub async fn write_response(stream: &mut TcpStream, response: &Response) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await
+}
+
+async fn read_line(reader: &mut BufReader<&mut TcpStream>) -> io::Result<Option<String>> {
+    let mut line = String::new();
+
+    let n = (&mut *reader)
+        .take(MAX_LINE as u64)
+        .read_line(&mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(invalid("line too long"));
+    }
+
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_owned()))
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
@@ -0,0 +1,51 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+
+// Crockford's base32, which is what ULIDs use
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+pub const LEN: usize = 26;
+
+// Generates a ULID: 48 bits of milliseconds followed by 80 random bits, so
+// IDs sort by time and the time can be read back out of them.
+pub fn gen(timestamp: isize) -> String {
+    let random = ((OsRng.next_u64() as u128) << 16) | (OsRng.next_u32() as u128 & 0xffff);
+    let value = ((timestamp as u128) << 80) | random;
+
+    (0..LEN)
+        .rev()
+        .map(|i| ALPHABET[(value >> (i * 5)) as usize & 31] as char)
+        .collect()
+}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::id;
+///
+/// let ulid = id::gen(1674000000000);
+///
+/// assert_eq!(ulid.len(), 26);
+/// assert_eq!(id::timestamp(&ulid), Some(1674000000000));
+/// assert_eq!(id::timestamp("not an id"), None);
+/// ```
+pub fn timestamp(id: &str) -> Option<isize> {
+    if id.len() != LEN {
+        return None;
+    }
+
+    let mut value: u128 = 0;
+    for (i, c) in id.bytes().enumerate() {
+        let digit = ALPHABET.iter().position(|&a| a == c.to_ascii_uppercase())?;
+
+        // 26 characters hold 130 bits, so the first one only has 3 to give
+        if i == 0 && digit > 7 {
+            return None;
+        }
+
+        value = (value << 5) | digit as u128;
+    }
+
+    Some((value >> 80) as isize)
+}
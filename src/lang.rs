@@ -0,0 +1,78 @@
+// Scripts written right to left, as the script subtag of a language tag
+const RTL_SCRIPTS: &[&str] = &[
+    "arab", "hebr", "syrc", "thaa", "nkoo", "adlm", "rohg", "mand",
+];
+
+// Languages usually written right to left when the tag doesn't say which
+// script, like `ar` but not `az`
+const RTL_LANGUAGES: &[&str] = &[
+    "ar", "arc", "ckb", "dv", "fa", "he", "iw", "ks", "ps", "sd", "ug", "ur", "yi",
+];
+
+// Runs on chat messages in rooms with a language, after the filters, with
+// the room's tag. Somewhere to change how a message reads for the language
+// it's in, like marking its direction or transliterating it. Returns None
+// to leave it as it is.
+pub trait LangHook: Send + Sync {
+    fn transform(&self, lang: &str, room: &str, msg: &str) -> Option<String>;
+}
+
+// Whether `lang` is written right to left, from its script if it names one
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::lang::is_rtl;
+///
+/// assert!(is_rtl("ar"));
+/// assert!(is_rtl("he-IL"));
+/// assert!(is_rtl("az-Arab"));
+/// assert!(!is_rtl("en"));
+/// assert!(!is_rtl("ar-Latn"));
+/// ```
+pub fn is_rtl(lang: &str) -> bool {
+    let lang = lang.to_ascii_lowercase();
+    let mut subtags = lang.split('-');
+    let language = subtags.next().unwrap_or_default();
+
+    // Scripts are the only subtags of four letters
+    let is_script =
+        |subtag: &&str| subtag.len() == 4 && subtag.chars().all(|c| c.is_ascii_alphabetic());
+
+    match subtags.find(is_script) {
+        Some(script) => RTL_SCRIPTS.contains(&script),
+        None => RTL_LANGUAGES.contains(&language),
+    }
+}
+
+// Isolates messages in right to left rooms, so a terminal lays them out on
+// their own instead of mixing them up with the name and time around them
+pub struct DirectionMarks;
+
+// Right-to-left isolate, ended by pop directional isolate
+const RLI: char = '\u{2067}';
+const PDI: char = '\u{2069}';
+
+impl LangHook for DirectionMarks {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::lang::{DirectionMarks, LangHook};
+    ///
+    /// let marked = DirectionMarks.transform("he", "room", "שלום").unwrap();
+    ///
+    /// assert_eq!(marked, "\u{2067}שלום\u{2069}");
+    /// assert_eq!(DirectionMarks.transform("he", "room", &marked), None);
+    /// assert_eq!(DirectionMarks.transform("en", "room", "hello"), None);
+    /// ```
+    fn transform(&self, lang: &str, _room: &str, msg: &str) -> Option<String> {
+        if !is_rtl(lang) || msg.starts_with(RLI) {
+            return None;
+        }
+
+        Some(format!("{}{}{}", RLI, msg, PDI))
+    }
+}
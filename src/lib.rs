@@ -1,4 +1,57 @@
+pub mod account;
+pub mod admin;
+pub mod announce;
+pub mod api;
 pub mod app;
+pub mod archive;
+pub mod audit;
+pub mod block;
+pub mod bots;
 pub mod broker;
+pub mod canonical;
+pub mod client;
+pub mod code;
+pub mod color;
 pub mod command;
+pub mod compress;
+pub mod config;
+pub mod connections;
+pub mod dm;
+pub mod ed25519;
+pub mod entry;
+pub mod federation;
+pub mod filter;
+pub mod hello;
+pub mod http;
+pub mod id;
+pub mod lang;
+pub mod markup;
+pub mod message;
+pub mod metrics;
+pub mod motd;
+pub mod names;
+pub mod namespace;
+pub mod outbox;
+pub mod paste;
+pub mod permissions;
+pub mod plugin;
+pub mod pool;
+pub mod pow;
+pub mod presence;
+pub mod pubsub;
+pub mod ratelimit;
+pub mod receipts;
+pub mod reload;
+pub mod report;
+pub mod retention;
 pub mod room;
+pub mod search;
+pub mod session;
+pub mod signing;
+pub mod spam;
+pub mod stats;
+pub mod status;
+pub mod testing;
+pub mod validate;
+pub mod webhook;
+pub mod writer;
@@ -1,32 +1,200 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use chatsapp::{app::App, broker};
+use chatsapp::{
+    admin, announce, api,
+    app::{App, Shared},
+    broker, canonical,
+    config::{Config, LiveConfig},
+    connections::{self, Limiter},
+    dm, federation,
+    filter::{Filters, SharedWordlist, WordlistFilter, WordlistMode},
+    lang::DirectionMarks,
+    metrics::{self, Metrics},
+    outbox,
+    plugin::Plugins,
+    pool::Pool,
+    pubsub,
+    ratelimit::Failures,
+    reload::{self, Reloader},
+    retention, search,
+};
 use redis::Client as RedisClient;
 use tokio::{io, net::TcpListener};
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:8000").await?;
+    let live = match Config::load() {
+        Ok(c) => Arc::new(LiveConfig::new(c)),
+        Err(e) => panic!("{}", e),
+    };
+    // What's only read on startup comes from here, anything a reload can
+    // change is read from `live` when it's used
+    let config = live.get();
+
+    let listener = TcpListener::bind(&config.listen_addr).await?;
 
-    let redis = RedisClient::open("redis://:redis@127.0.0.1/").unwrap();
-    let redis = Arc::new(redis);
+    let client = RedisClient::open(config.redis_url.as_str()).unwrap();
+    let redis = match Pool::new(client.clone(), config.outbox_capacity).await {
+        Ok(p) => p.with_webhook_allowlist(config.webhook_allowlist.clone()),
+        Err(e) => panic!("{}", e),
+    };
+    redis.spawn_health_check(Duration::from_secs(30));
 
-    let rooms = match broker::bootstrap_rooms(&redis).await {
+    let empty_room_ttl = config.empty_room_ttl_secs.map(Duration::from_secs);
+    let rooms = match broker::bootstrap_rooms(&redis, empty_room_ttl).await {
         Ok(r) => r,
         Err(e) => panic!("{}", e),
     };
 
+    match canonical::backfill(&redis).await {
+        Ok(0) => {}
+        Ok(n) => eprintln!(
+            "Indexed {} room and user names for case-insensitive lookup",
+            n
+        ),
+        Err(e) => panic!("{}", e),
+    }
+
+    match search::backfill(&redis).await {
+        Ok(0) => {}
+        Ok(n) => eprintln!("Indexed {} rooms for >find", n),
+        Err(e) => panic!("{}", e),
+    }
+
+    if config.pubsub {
+        pubsub::spawn_subscriber(client.clone(), Arc::clone(&rooms));
+    }
+
+    broker::spawn_gc(
+        redis.clone(),
+        Arc::clone(&rooms),
+        Duration::from_secs(config.broker_idle_secs),
+        empty_room_ttl,
+    );
+
+    retention::spawn(
+        redis.clone(),
+        Arc::clone(&live),
+        Arc::clone(&rooms),
+        Duration::from_secs(config.retention_interval_secs),
+    );
+
+    let metrics = Arc::new(Metrics::new());
+    if let Some(addr) = &config.metrics_addr {
+        tokio::spawn(metrics::serve(
+            addr.clone(),
+            Arc::clone(&metrics),
+            redis.clone(),
+            Arc::clone(&rooms),
+        ));
+    }
+
+    let users = dm::new_user_map();
+
+    let mut filters = Filters::new();
+    let wordlist = SharedWordlist::default();
+    if let Some(path) = &config.wordlist {
+        wordlist.set(Some(WordlistFilter::from_file(path, WordlistMode::Redact)?));
+    }
+    filters.add(wordlist.clone());
+
+    let reloader = Reloader::new(Arc::clone(&live), wordlist);
+    reload::spawn_on_hangup(reloader.clone());
+
+    let plugins = match &config.plugin_dir {
+        Some(dir) => match Plugins::load(dir) {
+            Ok(plugins) => plugins,
+            Err(e) => panic!("{}", e),
+        },
+        None => Plugins::default(),
+    };
+    for name in plugins.names() {
+        eprintln!("Loaded plugin {}", name);
+    }
+    // Plugins see messages after the wordlist has
+    filters.add(plugins.clone());
+    filters.add_hook(DirectionMarks);
+    let filters = Arc::new(filters);
+
+    if config.federation_addr.is_some() || !config.federation_peers.is_empty() {
+        federation::spawn(
+            client,
+            redis.clone(),
+            Arc::clone(&rooms),
+            Arc::clone(&filters),
+            Arc::clone(&config),
+        );
+    }
+
+    let conns = connections::new_connection_map();
+    outbox::spawn_flusher(redis.clone(), Arc::clone(&conns));
+    announce::spawn_scheduler(
+        redis.clone(),
+        Arc::clone(&conns),
+        Duration::from_secs(config.announcement_window_secs),
+    );
+    if let Some(addr) = &config.admin_addr {
+        tokio::spawn(admin::serve(
+            addr.clone(),
+            Arc::clone(&conns),
+            Arc::clone(&rooms),
+            redis.clone(),
+            config.archive_dir.clone(),
+            reloader,
+        ));
+    }
+
+    if let Some(addr) = &config.api_addr {
+        let ctx = api::Context {
+            redis: redis.clone(),
+            rooms: Arc::clone(&rooms),
+            filters: Arc::clone(&filters),
+            config: Arc::clone(&config),
+            failed_logins: Arc::new(Failures::new(api::LOGIN_FAILURES)),
+        };
+
+        tokio::spawn(api::serve(addr.clone(), ctx));
+    }
+
+    let shared = Shared {
+        redis,
+        users,
+        filters,
+        config: live,
+        metrics,
+        conns,
+        plugins,
+    };
+
+    let limiter = Limiter::new(config.max_connections, config.max_connections_per_ip);
+
     loop {
-        let redis = Arc::clone(&redis);
+        let shared = shared.clone();
         let rooms = Arc::clone(&rooms);
 
         let (stream, addr) = listener.accept().await?;
+
+        let slot = match limiter.acquire(addr.ip()) {
+            Ok(slot) => slot,
+            Err(refusal) => {
+                shared.metrics.refused();
+                tokio::spawn(connections::refuse(stream, refusal));
+                continue;
+            }
+        };
+
         tokio::spawn(async move {
-            let app = App::new(stream, addr, redis);
+            // Held until the connection closes
+            let _slot = slot;
+            let metrics = Arc::clone(&shared.metrics);
+            let app = App::new(stream, addr, shared);
 
+            metrics.connected();
             if let Err(e) = app.run(rooms).await {
                 eprintln!("{}", e)
             };
+            metrics.disconnected();
         });
     }
 }
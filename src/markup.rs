@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+
+use crate::color;
+
+// Light formatting people can put in what they write: `*bold*`, `_italic_`
+// and `` `code` ``. Text clients with color on see it styled, JSON clients
+// get where it is with the message, and everyone else sees it as typed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Style {
+    Bold,
+    Italic,
+    Code,
+}
+
+impl Style {
+    fn from_delimiter(c: char) -> Option<Self> {
+        match c {
+            '*' => Some(Style::Bold),
+            '_' => Some(Style::Italic),
+            '`' => Some(Style::Code),
+            _ => None,
+        }
+    }
+
+    fn ansi(self) -> &'static str {
+        match self {
+            Style::Bold => color::BOLD,
+            Style::Italic => color::ITALIC,
+            Style::Code => color::REVERSE,
+        }
+    }
+}
+
+// Where a style applies in a message body, in characters rather than bytes,
+// from its opening delimiter up to and including its closing one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub style: Style,
+    pub start: usize,
+    pub end: usize,
+}
+
+// A delimiter opens a span at the start of a word and closes it at the end
+// of one, so `snake_case_names` and `2*3*4` are left alone. Spans don't nest,
+// and nothing inside code is styled.
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::markup::{self, Span, Style};
+///
+/// assert_eq!(
+///     markup::parse("a *big* `x_y`"),
+///     vec![
+///         Span { style: Style::Bold, start: 2, end: 7 },
+///         Span { style: Style::Code, start: 8, end: 13 },
+///     ]
+/// );
+/// assert_eq!(markup::parse("é _oui_")[0].start, 2);
+/// assert!(markup::parse("snake_case_name 2*3*4 ** * not *").is_empty());
+/// ```
+pub fn parse(body: &str) -> Vec<Span> {
+    let chars: Vec<char> = body.chars().collect();
+    let mut spans = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let style = match Style::from_delimiter(chars[i]) {
+            Some(style) if opens(&chars, i) => style,
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+
+        match (i + 2..chars.len()).find(|&j| chars[j] == chars[i] && closes(&chars, j)) {
+            Some(close) => {
+                spans.push(Span {
+                    style,
+                    start: i,
+                    end: close + 1,
+                });
+                i = close + 1;
+            }
+            None => i += 1,
+        }
+    }
+
+    spans
+}
+
+fn opens(chars: &[char], i: usize) -> bool {
+    let after = chars.get(i + 1).is_some_and(|c| !c.is_whitespace());
+
+    (i == 0 || !chars[i - 1].is_alphanumeric()) && after
+}
+
+fn closes(chars: &[char], i: usize) -> bool {
+    let after = chars.get(i + 1).is_none_or(|c| !c.is_alphanumeric());
+
+    !chars[i - 1].is_whitespace() && after
+}
+
+// The body with its delimiters swapped for ANSI styles
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::markup;
+///
+/// assert_eq!(markup::to_ansi("so *very* nice"), "so \x1b[1mvery\x1b[0m nice");
+/// assert_eq!(markup::to_ansi("a_b"), "a_b");
+/// ```
+pub fn to_ansi(body: &str) -> String {
+    let chars: Vec<char> = body.chars().collect();
+    let mut out = String::with_capacity(body.len());
+    let mut at = 0;
+
+    for span in parse(body) {
+        out.extend(&chars[at..span.start]);
+
+        let inner: String = chars[span.start + 1..span.end - 1].iter().collect();
+        out.push_str(&color::paint(span.style.ansi(), &inner));
+
+        at = span.end;
+    }
+    out.extend(&chars[at..]);
+
+    out
+}
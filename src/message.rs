@@ -0,0 +1,581 @@
+use serde::{Deserialize, Serialize};
+use unicode_width::UnicodeWidthStr;
+
+use crate::code::ErrorCode;
+use crate::color;
+use crate::markup::{self, Span};
+use crate::room::get_time_in_ms;
+
+// Shown after the name on chat messages whose signature was checked
+pub const VERIFIED_MARK: char = '✓';
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    Chat,
+    Join,
+    Leave,
+    Dm,
+    // Sent to one member of a room and not saved
+    Whisper,
+    // A claimed command, sent to the bot that answers it and not saved
+    Command,
+    // A key for an encrypted room, to everyone in it or one member, not saved
+    #[serde(rename = "keyx")]
+    KeyExchange,
+    Typing,
+    Topic,
+    // Someone in the room changed their name, `user` is the new one
+    Rename,
+    // `user` renamed the room, `room` is its new name and the body its old one
+    RoomRename,
+    Mention,
+    History,
+    Edit,
+    Delete,
+    // Someone reacted to the message with `id`, the body is the emoji and
+    // how many people have reacted with it now, like `👍 3`
+    Reaction,
+    // Who joined or left as `+alice` or `-bob`, space separated. Only JSON
+    // clients get these, for keeping a member list up to date.
+    Members,
+    // Ids of messages that disappeared from a room with disappearing
+    // messages on, space separated. Also only for JSON clients.
+    Expire,
+    System,
+    Error,
+    // Confirms a message sent in an `Envelope` was saved and delivered
+    Ack,
+    // One line of a room's archive from >export, as it would be in the file
+    Export,
+}
+
+impl MessageKind {
+    // What it's called in JSON, like `chat` or `keyx`
+    pub fn name(self) -> String {
+        serde_json::to_value(self)
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+}
+
+// From its name in JSON
+impl std::str::FromStr for MessageKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_owned())).map_err(|_| ())
+    }
+}
+
+// Everything the server writes to a client goes through this, so it can be
+// rendered as plain text or as a JSON object depending on the connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    #[serde(rename = "type")]
+    pub kind: MessageKind,
+    pub room: Option<String>,
+    pub user: Option<String>,
+    pub timestamp: isize,
+    pub body: String,
+    // Set on messages persisted to a room, see `id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    // Id of the message this replies to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    // Shortened text of that message, shown above the reply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote: Option<String>,
+    // Copied from the `Envelope` this is a reply to
+    #[serde(rename = "ref", default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    // Signed with the sender's registered key, see `signing`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub verified: bool,
+    // What went wrong, on errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    // The room's language tag when it was sent, on chat messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+}
+
+// How a message goes to JSON clients, with where its body is styled. Worked
+// out as it's sent rather than kept on every `Message`.
+#[derive(Serialize)]
+struct Styled<'a> {
+    #[serde(flatten)]
+    msg: &'a Message,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    markup: Vec<Span>,
+}
+
+// A line from a JSON client wrapped with a reference of its choosing, which
+// everything written back while handling it carries as `ref`
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Envelope {
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub line: String,
+}
+
+impl Envelope {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::message::Envelope;
+    ///
+    /// let envelope = Envelope::parse(r#"{"ref":"42","line":"hello"}"#).unwrap();
+    ///
+    /// assert_eq!(envelope.reference, "42");
+    /// assert_eq!(envelope.line, "hello");
+    ///
+    /// // Anything else is a plain line
+    /// assert_eq!(Envelope::parse("hello"), None);
+    /// assert_eq!(Envelope::parse("{not json}"), None);
+    /// ```
+    pub fn parse(line: &str) -> Option<Self> {
+        if !line.starts_with('{') {
+            return None;
+        }
+
+        serde_json::from_str(line).ok()
+    }
+}
+
+impl Message {
+    pub fn new(
+        kind: MessageKind,
+        room: Option<&str>,
+        user: Option<&str>,
+        timestamp: isize,
+        body: String,
+    ) -> Self {
+        Self {
+            kind,
+            room: room.map(String::from),
+            user: user.map(String::from),
+            timestamp,
+            body,
+            id: None,
+            reply_to: None,
+            quote: None,
+            reference: None,
+            verified: false,
+            code: None,
+            lang: None,
+        }
+    }
+
+    pub fn with_id(mut self, id: String) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn verified(mut self) -> Self {
+        self.verified = true;
+        self
+    }
+
+    pub fn with_reply(mut self, reply_to: Option<String>, quote: String) -> Self {
+        self.reply_to = reply_to;
+        self.quote = Some(quote);
+        self
+    }
+
+    pub fn system(body: &str) -> Self {
+        Self::new(
+            MessageKind::System,
+            None,
+            None,
+            get_time_in_ms(),
+            body.into(),
+        )
+    }
+
+    pub fn members(room: &str, delta: String) -> Self {
+        Self::new(
+            MessageKind::Members,
+            Some(room),
+            None,
+            get_time_in_ms(),
+            delta,
+        )
+    }
+
+    pub fn expire(room: &str, ids: &[String]) -> Self {
+        Self::new(
+            MessageKind::Expire,
+            Some(room),
+            None,
+            get_time_in_ms(),
+            ids.join(" "),
+        )
+    }
+
+    // For a message sent in an envelope, so its id and time are known
+    pub fn ack(msg: &Message) -> Self {
+        let mut ack = Self::new(
+            MessageKind::Ack,
+            msg.room.as_deref(),
+            None,
+            msg.timestamp,
+            "Sent\n".into(),
+        );
+        ack.id = msg.id.clone();
+
+        ack
+    }
+
+    pub fn with_reference(mut self, reference: Option<String>) -> Self {
+        self.reference = reference;
+        self
+    }
+
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::code::ErrorCode;
+    /// use chatsapp::message::{Message, Protocol};
+    ///
+    /// let msg = Message::error(ErrorCode::NotInRoom, "You're not currently in a room.\n");
+    ///
+    /// assert_eq!(msg.render(Protocol::Text), "You're not currently in a room.\n");
+    /// assert!(msg.render(Protocol::Json).contains(r#""code":"NOT_IN_ROOM""#));
+    /// ```
+    pub fn error(code: ErrorCode, body: &str) -> Self {
+        let mut msg = Self::new(
+            MessageKind::Error,
+            None,
+            None,
+            get_time_in_ms(),
+            body.into(),
+        );
+        msg.code = Some(code);
+
+        msg
+    }
+
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::message::{Message, MessageKind, Protocol};
+    ///
+    /// let msg = Message::new(MessageKind::Chat, Some("rust"), Some("bob"), 1, "hi".into());
+    ///
+    /// assert_eq!(msg.render(Protocol::Text), "bob: hi\n");
+    ///
+    /// let reply = msg.clone().with_reply(None, "alice: hello".into());
+    /// assert_eq!(reply.render(Protocol::Text), "> alice: hello\nbob: hi\n");
+    /// assert_eq!(msg.clone().verified().render(Protocol::Text), "bob✓: hi\n");
+    /// assert_eq!(
+    ///     msg.render(Protocol::Json),
+    ///     "{\"type\":\"chat\",\"room\":\"rust\",\"user\":\"bob\",\"timestamp\":1,\"body\":\"hi\"}\n"
+    /// );
+    ///
+    /// let styled = Message::new(MessageKind::Chat, None, Some("bob"), 1, "*hi*".into());
+    /// assert!(styled
+    ///     .render(Protocol::Json)
+    ///     .contains(r#""markup":[{"style":"bold","start":0,"end":4}]"#));
+    /// ```
+    pub fn render(&self, protocol: Protocol) -> String {
+        match protocol {
+            Protocol::Text => self.to_text(),
+            Protocol::Json => self.to_json(),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let user = self.user.as_deref().unwrap_or_default();
+        let id = self.id.as_deref().unwrap_or_default();
+
+        match self.kind {
+            MessageKind::Chat => {
+                let author = format!("{}{}", user, self.mark());
+
+                match &self.quote {
+                    Some(quote) => format!("> {}\n{}: {}\n", quote, author, self.body),
+                    None => format!("{}: {}\n", author, self.body),
+                }
+            }
+            MessageKind::Dm => format!("[dm] {}: {}\n", user, self.body),
+            MessageKind::Whisper => format!("[whisper] {}: {}\n", user, self.body),
+            MessageKind::Command => format!("[command] {}: {}\n", user, self.body),
+            MessageKind::KeyExchange => format!("[keyx] {}: {}\n", user, self.body),
+            MessageKind::Typing => format!("{} is typing…\n", user),
+            MessageKind::Mention => format!("{} mentioned you: {}\n", user, self.body),
+            MessageKind::Edit => format!("{} edited {}: {}\n", user, id, self.body),
+            MessageKind::Delete => format!("{} deleted {}\n", user, id),
+            MessageKind::Reaction => format!("{} reacted to {}: {}\n", user, id, self.body),
+            MessageKind::RoomRename => format!(
+                "{} renamed {} to {}\n",
+                user,
+                self.body,
+                self.room.as_deref().unwrap_or_default()
+            ),
+            MessageKind::Join
+            | MessageKind::Leave
+            | MessageKind::Topic
+            | MessageKind::Rename
+            | MessageKind::Members
+            | MessageKind::Expire => format!("{}\n", self.body),
+            // These are already formatted for the terminal
+            MessageKind::History
+            | MessageKind::System
+            | MessageKind::Error
+            | MessageKind::Ack
+            | MessageKind::Export => self.body.clone(),
+        }
+    }
+
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::message::{Message, MessageKind};
+    ///
+    /// let msg = Message::new(MessageKind::Chat, None, Some("bob"), 1, "hi \x1b[2Jall".into());
+    ///
+    /// // Escapes sent by users are dropped so only ours reach the terminal
+    /// assert!(msg.render_colored().ends_with(": hi all\n"));
+    /// ```
+    pub fn render_colored(&self) -> String {
+        let user = color::user(self.user.as_deref().unwrap_or_default());
+        let id = self.id.as_deref().unwrap_or_default();
+        let body = match has_markup(self.kind) {
+            true => markup::to_ansi(&color::strip(&self.body)),
+            false => color::strip(&self.body),
+        };
+        let body = color::mentions(&body);
+
+        match self.kind {
+            MessageKind::Chat => {
+                let author = match self.verified {
+                    true => format!("{}{}", user, color::paint(color::GREEN, &self.mark())),
+                    false => user,
+                };
+
+                match &self.quote {
+                    Some(quote) => {
+                        let quote = color::paint(color::DIM, &format!("> {}", color::strip(quote)));
+                        format!("{}\n{}: {}\n", quote, author, body)
+                    }
+                    None => format!("{}: {}\n", author, body),
+                }
+            }
+            MessageKind::Dm => {
+                format!("{} {}: {}\n", color::paint(color::CYAN, "[dm]"), user, body)
+            }
+            MessageKind::Whisper => {
+                let tag = color::paint(color::CYAN, "[whisper]");
+                format!("{} {}: {}\n", tag, user, body)
+            }
+            MessageKind::Command => {
+                let tag = color::paint(color::DIM, "[command]");
+                format!("{} {}: {}\n", tag, user, body)
+            }
+            MessageKind::KeyExchange => {
+                let tag = color::paint(color::DIM, "[keyx]");
+                format!("{} {}: {}\n", tag, user, body)
+            }
+            MessageKind::Typing => {
+                let typing = format!("{} is typing…", self.user.as_deref().unwrap_or_default());
+                format!("{}\n", color::paint(color::DIM, &typing))
+            }
+            MessageKind::Mention => {
+                let notice = color::paint(
+                    &format!("{};{}", color::BOLD, color::YELLOW),
+                    "mentioned you:",
+                );
+                format!("{} {} {}\n", user, notice, body)
+            }
+            MessageKind::Edit => format!("{} edited {}: {}\n", user, id, body),
+            MessageKind::Delete => format!("{} deleted {}\n", user, id),
+            MessageKind::Reaction => format!("{} reacted to {}: {}\n", user, id, body),
+            MessageKind::RoomRename => {
+                let room = color::strip(self.room.as_deref().unwrap_or_default());
+                let notice = format!("renamed {} to {}", color::strip(&self.body), room);
+                format!("{} {}\n", user, color::paint(color::DIM, &notice))
+            }
+            // The rest are a single colour, without the trailing newline so
+            // the reset lands on the same line
+            MessageKind::Join
+            | MessageKind::Leave
+            | MessageKind::Topic
+            | MessageKind::Rename
+            | MessageKind::Members
+            | MessageKind::Expire => {
+                format!("{}\n", color::paint(color::DIM, &color::strip(&self.body)))
+            }
+            MessageKind::System | MessageKind::Ack => paint_lines(color::CYAN, &self.body),
+            MessageKind::Error => paint_lines(color::RED, &self.body),
+            MessageKind::History | MessageKind::Export => color::strip(&self.body),
+        }
+    }
+
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::message::Message;
+    ///
+    /// let mentions = Message::mentions("@alice have you seen @bob's message? cc @alice");
+    ///
+    /// assert_eq!(mentions, vec!["alice", "bob"]);
+    /// ```
+    pub fn mentions(body: &str) -> Vec<&str> {
+        let mut mentions = Vec::new();
+
+        for word in body.split_whitespace() {
+            let name = match word.strip_prefix('@') {
+                Some(name) => name,
+                None => continue,
+            };
+
+            // Stop at punctuation, so "@bob's" and "@bob," both mention bob
+            let end = name
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                .unwrap_or(name.len());
+            let name = &name[..end];
+
+            if !name.is_empty() && !mentions.contains(&name) {
+                mentions.push(name);
+            }
+        }
+
+        mentions
+    }
+
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::message::Message;
+    ///
+    /// // 2023-01-18 00:40:00 UTC
+    /// assert_eq!(Message::format_time(1674002400000, 0), "00:40");
+    /// assert_eq!(Message::format_time(1674002400000, -60), "23:40");
+    /// ```
+    pub fn format_time(timestamp: isize, offset_mins: i32) -> String {
+        let secs = timestamp as i64 / 1000 + offset_mins as i64 * 60;
+        let of_day = secs.rem_euclid(24 * 60 * 60);
+
+        format!("{:02}:{:02}", of_day / 3600, of_day % 3600 / 60)
+    }
+
+    fn mark(&self) -> String {
+        match self.verified {
+            true => VERIFIED_MARK.to_string(),
+            false => String::new(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let mut msg = self.clone();
+        msg.body = msg.body.trim_end_matches('\n').to_owned();
+        let markup = match has_markup(msg.kind) {
+            true => markup::parse(&msg.body),
+            false => Vec::new(),
+        };
+
+        let mut json = serde_json::to_string(&Styled { msg: &msg, markup }).unwrap();
+        json.push('\n');
+
+        json
+    }
+}
+
+// What people write themselves, where `*bold*` and the like are styled
+fn has_markup(kind: MessageKind) -> bool {
+    matches!(
+        kind,
+        MessageKind::Chat
+            | MessageKind::Dm
+            | MessageKind::Whisper
+            | MessageKind::Mention
+            | MessageKind::Edit
+    )
+}
+
+fn paint_lines(code: &str, body: &str) -> String {
+    let body = color::strip(body);
+
+    match body.strip_suffix('\n') {
+        Some(body) => format!("{}\n", color::paint(code, body)),
+        None => color::paint(code, &body),
+    }
+}
+
+// Messages are one line, so people write `\n` for a line break and `\\` for
+// a backslash. Text clients get real line breaks, with the lines after the
+// first lined up under where the message starts, after the first `: `.
+// JSON clients get the body as it was sent.
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::message;
+///
+/// assert_eq!(message::break_lines("bob: one\\ntwo\n"), "bob: one\n     two\n");
+/// // Wide characters take two columns, combining ones none
+/// assert_eq!(message::break_lines("李雷: 一\\n二\n"), "李雷: 一\n      二\n");
+/// assert_eq!(message::break_lines("e\u{301}: a\\nb\n"), "e\u{301}: a\n   b\n");
+/// assert_eq!(message::break_lines("bob: C:\\\\new\n"), "bob: C:\\new\n");
+/// ```
+pub fn break_lines(text: &str) -> String {
+    if !text.contains('\\') {
+        return text.to_owned();
+    }
+
+    let mut out = String::with_capacity(text.len());
+
+    for line in text.split_inclusive('\n') {
+        let indent = line
+            .find(": ")
+            .map(|i| display_width(&line[..i + 2]))
+            .unwrap_or_default();
+
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('\\', Some('n')) => {
+                    chars.next();
+                    out.push('\n');
+                    out.extend(std::iter::repeat_n(' ', indent));
+                }
+                ('\\', Some('\\')) => {
+                    chars.next();
+                    out.push('\\');
+                }
+                _ => out.push(c),
+            }
+        }
+    }
+
+    out
+}
+
+// Columns `text` takes up in a terminal, so CJK counts twice, combining
+// accents not at all, and colors not at all
+pub fn display_width(text: &str) -> usize {
+    color::strip(text).width()
+}
@@ -0,0 +1,277 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::broker::{self, RoomMap};
+use crate::pool::Pool;
+
+#[derive(Default)]
+pub struct Metrics {
+    connections: AtomicI64,
+    refused: AtomicU64,
+    messages: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn disconnected(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_sent(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Renders everything in the Prometheus text format. Room stats are
+    // gathered from the brokers at scrape time rather than tracked.
+    pub async fn render(&self, redis: &Pool, rooms: &RoomMap) -> String {
+        let mut out = String::new();
+
+        gauge(
+            &mut out,
+            "chatsapp_connections",
+            "Open client connections",
+            self.connections.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "chatsapp_connections_refused_total",
+            "Connections turned away by max_connections or max_connections_per_ip",
+            self.refused.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "chatsapp_messages_total",
+            "Chat messages sent, use rate() for messages per second",
+            self.messages.load(Ordering::Relaxed),
+        );
+
+        let latency = redis_latency(redis).await;
+        gauge(
+            &mut out,
+            "chatsapp_redis_up",
+            "Whether Redis answered a PING",
+            latency.is_some() as u8,
+        );
+        if let Some(latency) = latency {
+            gauge(
+                &mut out,
+                "chatsapp_redis_latency_seconds",
+                "Round trip time of a PING to Redis",
+                latency.as_secs_f64(),
+            );
+        }
+
+        gauge(
+            &mut out,
+            "chatsapp_outbox_messages",
+            "Room messages waiting for Redis to come back",
+            redis.outbox().len(),
+        );
+
+        let rooms = broker::snapshot(rooms).await;
+
+        header(
+            &mut out,
+            "chatsapp_rooms",
+            "Rooms with a running broker",
+            "gauge",
+        );
+        writeln!(out, "chatsapp_rooms {}", rooms.len()).unwrap();
+
+        header(
+            &mut out,
+            "chatsapp_broker_queue_depth",
+            "Events waiting to be handled by a room's broker",
+            "gauge",
+        );
+        for (room, tx) in &rooms {
+            let depth = tx.max_capacity() - tx.capacity();
+            writeln!(
+                out,
+                "chatsapp_broker_queue_depth{{room=\"{}\"}} {}",
+                escape(room),
+                depth
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "chatsapp_room_members",
+            "Users currently in a room",
+            "gauge",
+        );
+        for (room, tx) in &rooms {
+            // Skip busy brokers rather than holding up the scrape
+            if let Some(members) = broker::who(tx, broker::WHO_TIMEOUT).await {
+                writeln!(
+                    out,
+                    "chatsapp_room_members{{room=\"{}\"}} {}",
+                    escape(room),
+                    members.len()
+                )
+                .unwrap();
+            }
+        }
+
+        // Only sharded rooms have any
+        let mut shards = Vec::new();
+        for (room, tx) in &rooms {
+            if let Some(stats) = broker::shards(tx, broker::WHO_TIMEOUT).await {
+                shards.extend(stats.into_iter().enumerate().map(|(i, s)| (room, i, s)));
+            }
+        }
+
+        header(
+            &mut out,
+            "chatsapp_shard_members",
+            "Users a room's delivery shard sends to",
+            "gauge",
+        );
+        for (room, i, shard) in &shards {
+            writeln!(
+                out,
+                "chatsapp_shard_members{{room=\"{}\",shard=\"{}\"}} {}",
+                escape(room),
+                i,
+                shard.members
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "chatsapp_shard_fanout_seconds",
+            "Time from a broker handing a message to a shard to it being queued for the shard's members",
+            "summary",
+        );
+        for (room, i, shard) in &shards {
+            let labels = format!("room=\"{}\",shard=\"{}\"", escape(room), i);
+
+            writeln!(
+                out,
+                "chatsapp_shard_fanout_seconds_sum{{{}}} {}",
+                labels, shard.fanout_seconds
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "chatsapp_shard_fanout_seconds_count{{{}}} {}",
+                labels, shard.fanouts
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+// Serves `GET /metrics` on `addr`. This is deliberately tiny, it only needs
+// to answer Prometheus.
+pub async fn serve(addr: String, metrics: Arc<Metrics>, redis: Pool, rooms: RoomMap) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to start metrics server on {}: {}", addr, e);
+            return;
+        }
+    };
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+
+        let metrics = Arc::clone(&metrics);
+        let redis = redis.clone();
+        let rooms = Arc::clone(&rooms);
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_scrape(stream, &metrics, &redis, &rooms).await {
+                eprintln!("{}", e);
+            }
+        });
+    }
+}
+
+async fn handle_scrape(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+    redis: &Pool,
+    rooms: &RoomMap,
+) -> io::Result<()> {
+    // The request line is all we care about
+    let mut buf = [0; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+
+    let response = if request.starts_with("GET /metrics ") {
+        let body = metrics.render(redis, rooms).await;
+
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+async fn redis_latency(redis: &Pool) -> Option<Duration> {
+    let mut conn = redis.get();
+    let start = Instant::now();
+
+    redis::cmd("PING")
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .ok()?;
+
+    Some(start.elapsed())
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, help, "gauge");
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, help, "counter");
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+// Label values need `\`, `"` and newlines escaped
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
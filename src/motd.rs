@@ -0,0 +1,48 @@
+use redis::AsyncCommands;
+
+use crate::pool::Pool;
+
+// Set by >set-motd, overriding the `motd` in the config until it's reset
+const KEY: &str = "motd";
+
+#[derive(Debug)]
+pub enum MotdError {
+    FailedToSave,
+    FailedToFetch,
+}
+
+impl std::fmt::Display for MotdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MotdError::FailedToSave => writeln!(f, "Error: Failed to save message of the day"),
+            MotdError::FailedToFetch => writeln!(f, "Error: Failed to fetch message of the day"),
+        }
+    }
+}
+
+impl std::error::Error for MotdError {}
+
+// None if it hasn't been set, so the config's is used
+pub async fn get(redis: &Pool) -> Result<Option<String>, MotdError> {
+    let mut conn = redis.get();
+
+    conn.get(KEY).await.map_err(|e| {
+        dbg!("{}", e);
+        MotdError::FailedToFetch
+    })
+}
+
+// Shared by every server using the same Redis. None goes back to the config's.
+pub async fn set(redis: &Pool, text: Option<&str>) -> Result<(), MotdError> {
+    let mut conn = redis.get();
+
+    let res = match text {
+        Some(text) => conn.set::<_, _, ()>(KEY, text).await,
+        None => conn.del::<_, ()>(KEY).await,
+    };
+
+    res.map_err(|e| {
+        dbg!("{}", e);
+        MotdError::FailedToSave
+    })
+}
@@ -0,0 +1,115 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use redis::Script;
+
+use crate::canonical;
+use crate::pool::Pool;
+use crate::presence;
+
+// Claims expire like presence keys, so names held by a server that died come
+// free again without anyone cleaning up.
+pub const TTL_SECS: usize = presence::TTL_SECS;
+
+#[derive(Debug)]
+pub enum NameError {
+    FailedToSend,
+    Taken,
+}
+
+impl std::fmt::Display for NameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NameError::FailedToSend => writeln!(f, "Error: Failed to claim username"),
+            NameError::Taken => writeln!(f, "Error: Username is in use"),
+        }
+    }
+}
+
+impl std::error::Error for NameError {}
+
+// Identifies a connection across servers, stored as the value of its claims
+pub fn gen_owner() -> String {
+    format!("{:016x}", OsRng.next_u64())
+}
+
+// SET NX so only one connection can hold a name at a time
+pub async fn claim(redis: &Pool, username: &str, owner: &str) -> Result<(), NameError> {
+    let mut conn = redis.get();
+
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(gen_key(username))
+        .arg(owner)
+        .arg("NX")
+        .arg("EX")
+        .arg(TTL_SECS)
+        .query_async(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            NameError::FailedToSend
+        })?;
+
+    if claimed.is_none() {
+        Err(NameError::Taken)?;
+    }
+
+    Ok(())
+}
+
+// Only extends the claim if it still belongs to `owner`
+pub async fn refresh(redis: &Pool, username: &str, owner: &str) -> Result<(), NameError> {
+    let mut conn = redis.get();
+
+    let script = Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            return redis.call('EXPIRE', KEYS[1], ARGV[2])
+        end
+        return 0
+        ",
+    );
+
+    script
+        .key(gen_key(username))
+        .arg(owner)
+        .arg(TTL_SECS)
+        .invoke_async::<_, ()>(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            NameError::FailedToSend
+        })?;
+
+    Ok(())
+}
+
+// Only deletes the claim if it still belongs to `owner`, so a connection
+// that lost its claim can't free a name someone else has since taken
+pub async fn release(redis: &Pool, username: &str, owner: &str) -> Result<(), NameError> {
+    let mut conn = redis.get();
+
+    let script = Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            return redis.call('DEL', KEYS[1])
+        end
+        return 0
+        ",
+    );
+
+    script
+        .key(gen_key(username))
+        .arg(owner)
+        .invoke_async::<_, ()>(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            NameError::FailedToSend
+        })?;
+
+    Ok(())
+}
+
+// Folded, so a guest can't pass as `alice` by calling themselves `Alice`
+fn gen_key(username: &str) -> String {
+    format!("name:{}", canonical::fold(username))
+}
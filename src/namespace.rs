@@ -0,0 +1,177 @@
+use redis::{AsyncCommands, Script};
+
+use crate::pool::Pool;
+
+// What can be done with a namespace through >namespace
+#[derive(Debug, PartialEq)]
+pub enum NamespaceCommand {
+    Show,
+    Allow(String),
+    Revoke(String),
+}
+
+#[derive(Debug)]
+pub enum NamespaceError {
+    FailedToSave,
+    FailedToFetch,
+    NotAllowed(String),
+    NotOwner,
+    NotFound,
+}
+
+impl std::fmt::Display for NamespaceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NamespaceError::FailedToSave => writeln!(f, "Error: Failed to save namespace"),
+            NamespaceError::FailedToFetch => writeln!(f, "Error: Failed to fetch namespace"),
+            NamespaceError::NotAllowed(owner) => writeln!(
+                f,
+                "Error: Only people {} allows can create rooms in that namespace",
+                owner
+            ),
+            NamespaceError::NotOwner => writeln!(f, "Error: You don't own that namespace"),
+            NamespaceError::NotFound => writeln!(f, "Error: Nobody has created rooms there yet"),
+        }
+    }
+}
+
+impl std::error::Error for NamespaceError {}
+
+// Whoever creates the first room in a namespace owns it, and owners decide
+// who else can create rooms in it. Nested names like `dev/rust/async` all
+// belong to the top level one, so there's a single list per namespace.
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::namespace;
+///
+/// assert_eq!(namespace::of("dev/rust"), Some("dev"));
+/// assert_eq!(namespace::of("dev/rust/async"), Some("dev"));
+/// assert_eq!(namespace::of("rust"), None);
+/// ```
+pub fn of(room: &str) -> Option<&str> {
+    room.split_once('/').map(|(namespace, _)| namespace)
+}
+
+// Checks `username` can create rooms in `namespace`, making them its owner
+// if nobody does yet
+pub async fn check_create(
+    redis: &Pool,
+    namespace: &str,
+    username: &str,
+) -> Result<(), NamespaceError> {
+    let script = Script::new(
+        r"
+        local owner = redis.call('HGET', KEYS[1], 'owner')
+        if not owner then
+            redis.call('HSET', KEYS[1], 'owner', ARGV[1])
+            return ''
+        end
+        if owner == ARGV[1] or redis.call('SISMEMBER', KEYS[2], ARGV[1]) == 1 then
+            return ''
+        end
+        return owner
+        ",
+    );
+
+    let owner: String = script
+        .key(gen_key(namespace))
+        .key(gen_creators_key(namespace))
+        .arg(username)
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            NamespaceError::FailedToSave
+        })?;
+
+    match owner.is_empty() {
+        true => Ok(()),
+        false => Err(NamespaceError::NotAllowed(owner)),
+    }
+}
+
+// The namespace's owner and who else can create rooms in it
+pub async fn info(redis: &Pool, namespace: &str) -> Result<(String, Vec<String>), NamespaceError> {
+    let mut conn = redis.get();
+
+    let (owner, mut creators): (Option<String>, Vec<String>) = redis::pipe()
+        .hget(gen_key(namespace), "owner")
+        .smembers(gen_creators_key(namespace))
+        .query_async(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            NamespaceError::FailedToFetch
+        })?;
+    creators.sort();
+
+    match owner {
+        Some(owner) => Ok((owner, creators)),
+        None => Err(NamespaceError::NotFound),
+    }
+}
+
+pub async fn allow(
+    redis: &Pool,
+    namespace: &str,
+    owner: &str,
+    username: &str,
+) -> Result<(), NamespaceError> {
+    check_owner(redis, namespace, owner).await?;
+
+    redis
+        .get()
+        .sadd::<_, _, ()>(gen_creators_key(namespace), username)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            NamespaceError::FailedToSave
+        })
+}
+
+// Rooms they've already created there are still theirs
+pub async fn revoke(
+    redis: &Pool,
+    namespace: &str,
+    owner: &str,
+    username: &str,
+) -> Result<(), NamespaceError> {
+    check_owner(redis, namespace, owner).await?;
+
+    redis
+        .get()
+        .srem::<_, _, ()>(gen_creators_key(namespace), username)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            NamespaceError::FailedToSave
+        })
+}
+
+async fn check_owner(redis: &Pool, namespace: &str, username: &str) -> Result<(), NamespaceError> {
+    let owner: Option<String> = redis
+        .get()
+        .hget(gen_key(namespace), "owner")
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            NamespaceError::FailedToFetch
+        })?;
+
+    match owner {
+        Some(owner) if owner == username => Ok(()),
+        Some(_) => Err(NamespaceError::NotOwner),
+        None => Err(NamespaceError::NotFound),
+    }
+}
+
+fn gen_key(namespace: &str) -> String {
+    format!("namespace:{}", namespace)
+}
+
+fn gen_creators_key(namespace: &str) -> String {
+    format!("namespace-creators:{}", namespace)
+}
@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use redis::{RedisError, Script};
+
+use crate::announce;
+use crate::connections::ConnectionMap;
+use crate::entry::Entry;
+use crate::message::Message;
+use crate::pool::Pool;
+use crate::room;
+
+// How often the flusher tries to save what's buffered
+const TICK: Duration = Duration::from_secs(1);
+
+// Most messages saved in one transaction
+const BATCH: usize = 100;
+
+// Room messages that couldn't be saved because Redis was unreachable, kept
+// in the order they were sent until it's back. Every server has its own.
+pub struct Outbox {
+    // Only ever locked briefly, never across an await
+    pending: Mutex<VecDeque<Pending>>,
+    capacity: usize,
+}
+
+#[derive(Clone)]
+struct Pending {
+    room: String,
+    // When it was sent, in ms, which its id is given when it can be
+    sent: isize,
+    // The entry's fields, see `Entry::fields`
+    fields: [(&'static str, String); 2],
+}
+
+impl Outbox {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            pending: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    // Returns false if the buffer is full, in which case the message is lost
+    pub fn push(&self, room: &str, msg: &Message) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+
+        if pending.len() >= self.capacity {
+            return false;
+        }
+
+        pending.push_back(Pending {
+            room: room.to_owned(),
+            sent: msg.timestamp,
+            fields: Entry::fields(msg),
+        });
+
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn peek(&self, n: usize) -> Vec<Pending> {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .take(n)
+            .cloned()
+            .collect()
+    }
+
+    fn pop(&self, n: usize) {
+        self.pending.lock().unwrap().drain(..n);
+    }
+}
+
+// Errors that mean Redis couldn't be reached, rather than it refusing the
+// command. Only these are worth buffering for.
+pub fn is_outage(e: &RedisError) -> bool {
+    e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout()
+}
+
+// Saves what's buffered once Redis is back, oldest first. Everyone connected
+// to this server is told when messages start being buffered and when
+// they've been saved.
+pub fn spawn_flusher(redis: Pool, conns: ConnectionMap) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(TICK);
+        let mut degraded = false;
+        let mut saved = 0;
+
+        loop {
+            ticker.tick().await;
+
+            if redis.outbox().is_empty() {
+                continue;
+            }
+
+            if !degraded {
+                degraded = true;
+
+                let msg = Message::system(
+                    "Messages can't be saved right now. They're still being delivered, and will be saved when the database is back\n",
+                );
+                announce::deliver(&conns, &msg).await;
+            }
+
+            loop {
+                let batch = redis.outbox().peek(BATCH);
+                if batch.is_empty() {
+                    break;
+                }
+
+                if let Err(e) = flush(&redis, &batch).await {
+                    eprintln!("Failed to save buffered messages: {}", e);
+                    break;
+                }
+
+                redis.outbox().pop(batch.len());
+                saved += batch.len();
+            }
+
+            if redis.outbox().is_empty() {
+                degraded = false;
+
+                let msg = Message::system(&format!(
+                    "The database is back, {} buffered messages were saved\n",
+                    saved
+                ));
+                announce::deliver(&conns, &msg).await;
+                saved = 0;
+            }
+        }
+    });
+}
+
+// One script per batch, so losing the connection partway can't leave it half
+// saved and then saved again. Rooms deleted in the meantime are skipped
+// rather than recreated. Each message keeps the time it was sent as its id's,
+// unless something newer was saved to the room since, when it has to take
+// the current time to stay in order.
+async fn flush(redis: &Pool, batch: &[Pending]) -> Result<(), RedisError> {
+    let script = Script::new(
+        r"
+        for i, key in ipairs(KEYS) do
+            if redis.call('EXISTS', key) == 1 then
+                local info = redis.call('XINFO', 'STREAM', key)
+                local last = 0
+                for j = 1, #info, 2 do
+                    if info[j] == 'last-generated-id' then
+                        last = tonumber(string.match(info[j + 1], '^(%d+)'))
+                    end
+                end
+
+                local sent = ARGV[i * 5 - 4]
+                local id = '*'
+                if tonumber(sent) > last then
+                    id = sent .. '-0'
+                end
+
+                redis.call('XADD', key, id, unpack(ARGV, i * 5 - 3, i * 5))
+            end
+        end
+        return 0
+        ",
+    );
+
+    let mut invocation = script.prepare_invoke();
+    for pending in batch {
+        invocation
+            .key(room::gen_key(&pending.room))
+            .arg(pending.sent);
+
+        for (field, value) in &pending.fields {
+            invocation.arg(field).arg(value);
+        }
+    }
+
+    invocation.invoke_async(&mut redis.get()).await
+}
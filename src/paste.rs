@@ -0,0 +1,67 @@
+use redis::AsyncCommands;
+
+use crate::id;
+use crate::pool::Pool;
+use crate::room::get_time_in_ms;
+
+// Ends a paste when sent on its own line
+pub const SENTINEL: &str = ">end";
+
+// Largest paste a client can send, in bytes
+pub const MAX_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum PasteError {
+    FailedToSave,
+    FailedToFetch,
+    NotFound,
+    TooLarge,
+}
+
+impl std::fmt::Display for PasteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasteError::FailedToSave => writeln!(f, "Error: Failed to save paste"),
+            PasteError::FailedToFetch => writeln!(f, "Error: Failed to fetch paste"),
+            PasteError::NotFound => {
+                writeln!(f, "Error: No paste with that id, it may have expired")
+            }
+            PasteError::TooLarge => {
+                writeln!(f, "Error: Pastes can't be larger than {} bytes", MAX_SIZE)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PasteError {}
+
+// Stores the paste until it expires, returning the id to fetch it with
+pub async fn save(redis: &Pool, content: &str, ttl_secs: usize) -> Result<String, PasteError> {
+    let mut conn = redis.get();
+
+    let id = id::gen(get_time_in_ms());
+
+    conn.set_ex::<_, _, ()>(gen_key(&id), content, ttl_secs)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            PasteError::FailedToSave
+        })?;
+
+    Ok(id)
+}
+
+pub async fn fetch(redis: &Pool, id: &str) -> Result<String, PasteError> {
+    let mut conn = redis.get();
+
+    let content: Option<String> = conn.get(gen_key(id)).await.map_err(|e| {
+        dbg!("{}", e);
+        PasteError::FailedToFetch
+    })?;
+
+    content.ok_or(PasteError::NotFound)
+}
+
+fn gen_key(id: &str) -> String {
+    format!("paste:{}", id)
+}
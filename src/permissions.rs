@@ -0,0 +1,274 @@
+use std::str::FromStr;
+
+use crate::pool::Pool;
+use crate::room;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Guest,
+    Member,
+    Moderator,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = PermissionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "guest" => Ok(Role::Guest),
+            "member" => Ok(Role::Member),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(PermissionError::InvalidRole),
+        }
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Guest => write!(f, "guest"),
+            Role::Member => write!(f, "member"),
+            Role::Moderator => write!(f, "moderator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+// Things that need more than being in the room
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Send,
+    Topic,
+    Lang,
+    Kick,
+    Ban,
+    Unmute,
+    Invite,
+    Retention,
+    Webhook,
+    DeleteRoom,
+    RenameRoom,
+    Grant,
+    Announce,
+    Broadcast,
+    ForgetUser,
+    SetMotd,
+    Reports,
+    Claim,
+    Export,
+}
+
+impl Action {
+    // The least role that can do it
+    pub fn required(self) -> Role {
+        match self {
+            Action::Send => Role::Member,
+            Action::Topic
+            | Action::Lang
+            | Action::Kick
+            | Action::Ban
+            | Action::Unmute
+            | Action::Claim => Role::Moderator,
+            Action::Invite
+            | Action::Retention
+            | Action::Webhook
+            | Action::DeleteRoom
+            | Action::RenameRoom
+            | Action::Grant
+            | Action::Announce
+            | Action::Broadcast
+            | Action::ForgetUser
+            | Action::SetMotd
+            | Action::Reports
+            | Action::Export => Role::Admin,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PermissionError {
+    FailedToSave,
+    FailedToFetch,
+    InvalidRole,
+    NotAllowed(Role),
+    Outranked(String),
+    RoleTooHigh,
+}
+
+impl std::fmt::Display for PermissionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PermissionError::FailedToSave => writeln!(f, "Error: Failed to save role"),
+            PermissionError::FailedToFetch => writeln!(f, "Error: Failed to fetch roles"),
+            PermissionError::InvalidRole => {
+                writeln!(f, "Error: Roles are admin, moderator, member or guest")
+            }
+            PermissionError::NotAllowed(Role::Member) => {
+                writeln!(f, "Error: You can only read in this room")
+            }
+            PermissionError::NotAllowed(role) => {
+                writeln!(f, "Error: That needs the {} role or higher", role)
+            }
+            PermissionError::Outranked(user) => {
+                writeln!(f, "Error: {} has the same role as you or higher", user)
+            }
+            PermissionError::RoleTooHigh => {
+                writeln!(f, "Error: You can only give roles below your own")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PermissionError {}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::permissions::{self, Action, Role};
+///
+/// assert!(permissions::allows(Role::Moderator, Action::Kick));
+/// assert!(!permissions::allows(Role::Moderator, Action::DeleteRoom));
+/// assert!(!permissions::allows(Role::Guest, Action::Send));
+/// assert!("owner".parse::<Role>().is_err());
+/// ```
+pub fn allows(role: Role, action: Action) -> bool {
+    role >= action.required()
+}
+
+// What `username` can do in `room`, or across the server without one. The
+// higher of their global and room roles counts, owners are admins of their
+// own rooms, and anyone without a role is a member. Guests who haven't
+// logged in are only ever guests.
+pub async fn role(
+    redis: &Pool,
+    room: Option<&str>,
+    username: &str,
+    authenticated: bool,
+) -> Result<Role, PermissionError> {
+    if !authenticated {
+        return Ok(Role::Guest);
+    }
+
+    let mut pipe = redis::pipe();
+    pipe.hget(gen_key(None), username);
+    if let Some(room) = room {
+        pipe.hget(gen_key(Some(room)), username)
+            .hget(room::gen_meta_key(room), "owner");
+    }
+
+    let values: Vec<Option<String>> = pipe.query_async(&mut redis.get()).await.map_err(|e| {
+        dbg!("{}", e);
+        PermissionError::FailedToFetch
+    })?;
+
+    let global = parse_role(values.first());
+    let local = parse_role(values.get(1));
+    let owner = values.get(2).cloned().flatten();
+
+    if owner.as_deref() == Some(username) {
+        return Ok(Role::Admin);
+    }
+
+    // Demoting someone anywhere takes precedence, so a room can make a
+    // member a guest and the server can make anyone one
+    Ok(match (global, local) {
+        (Some(Role::Guest), _) | (_, Some(Role::Guest)) => Role::Guest,
+        (global, local) => global.max(local).unwrap_or(Role::Member),
+    })
+}
+
+// Fails with the role that's needed if `username` can't do `action` in
+// `room`. Without a room only global roles count, like for announcements.
+pub async fn check(
+    redis: &Pool,
+    room: Option<&str>,
+    username: &str,
+    authenticated: bool,
+    action: Action,
+) -> Result<(), PermissionError> {
+    let role = role(redis, room, username, authenticated).await?;
+
+    match allows(role, action) {
+        true => Ok(()),
+        false => Err(PermissionError::NotAllowed(action.required())),
+    }
+}
+
+// Moderators can't kick or ban each other, or whoever made them one
+pub async fn check_outranks(
+    redis: &Pool,
+    room: &str,
+    username: &str,
+    target: &str,
+) -> Result<(), PermissionError> {
+    let own = role(redis, Some(room), username, true).await?;
+    let theirs = role(redis, Some(room), target, true).await?;
+
+    match own > theirs {
+        true => Ok(()),
+        false => Err(PermissionError::Outranked(target.to_owned())),
+    }
+}
+
+// Like kicks, grants need a higher role than the target already has, and
+// than the one they're given. Admins can't demote each other or make more
+// admins that way, those come from the console.
+pub async fn check_grant(
+    redis: &Pool,
+    room: Option<&str>,
+    username: &str,
+    target: &str,
+    granted: Role,
+) -> Result<(), PermissionError> {
+    let own = role(redis, room, username, true).await?;
+    if own <= granted {
+        return Err(PermissionError::RoleTooHigh);
+    }
+
+    let theirs = role(redis, room, target, true).await?;
+    match own > theirs {
+        true => Ok(()),
+        false => Err(PermissionError::Outranked(target.to_owned())),
+    }
+}
+
+// Gives `username` a role in `room`, or everywhere without one. Members
+// aren't stored, since that's what everyone is by default.
+pub async fn grant(
+    redis: &Pool,
+    room: Option<&str>,
+    username: &str,
+    role: Role,
+) -> Result<(), PermissionError> {
+    let key = gen_key(room);
+    let mut cmd = match role {
+        Role::Member => redis::cmd("HDEL"),
+        _ => redis::cmd("HSET"),
+    };
+    cmd.arg(key).arg(username);
+    if role != Role::Member {
+        cmd.arg(role.to_string());
+    }
+
+    cmd.query_async::<_, ()>(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            PermissionError::FailedToSave
+        })
+}
+
+fn parse_role(value: Option<&Option<String>>) -> Option<Role> {
+    value?.as_deref()?.parse().ok()
+}
+
+pub(crate) fn gen_key(room: Option<&str>) -> String {
+    match room {
+        Some(room) => format!("roles:{}", room),
+        None => "roles".to_owned(),
+    }
+}
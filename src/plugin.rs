@@ -0,0 +1,378 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use wasmi::{
+    Config, Engine, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+};
+
+use crate::filter::{Action, MessageFilter};
+
+// Roughly how many instructions a plugin can run per hook call, so one stuck
+// in a loop can't hold up the connection calling it
+const FUEL_PER_CALL: u64 = 10_000_000;
+
+// How big a plugin's memory can grow
+const MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+// Longest response read back from a hook
+const MAX_RESPONSE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum PluginError {
+    FailedToRead(std::io::Error),
+    // The plugin, and what was wrong with it
+    InvalidModule(String, String),
+    // The plugin, and why the call didn't return a response
+    FailedToCall(String, String),
+}
+
+impl std::fmt::Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PluginError::FailedToRead(e) => writeln!(f, "Error: Failed to read plugins: {}", e),
+            PluginError::InvalidModule(name, e) => {
+                writeln!(f, "Error: Plugin {} can't be loaded: {}", name, e)
+            }
+            PluginError::FailedToCall(name, e) => {
+                writeln!(f, "Error: Plugin {} failed: {}", name, e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PluginError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hook {
+    // Before a chat message is saved, can replace or reject it
+    Message,
+    // After someone joins a room
+    Join,
+    // A command the server doesn't know, before it goes to a room's bots
+    Command,
+}
+
+impl Hook {
+    fn export(self) -> &'static str {
+        match self {
+            Hook::Message => "on_message",
+            Hook::Join => "on_join",
+            Hook::Command => "on_command",
+        }
+    }
+}
+
+// What a hook is given, as JSON
+#[derive(Serialize)]
+struct Event<'a> {
+    user: &'a str,
+    room: Option<&'a str>,
+    // The message, or the whole command line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+}
+
+// What a hook answers with, as JSON. Anything left out is ignored.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct Response {
+    // Sent to the user alone
+    pub reply: Option<String>,
+    // Said in the room, under the plugin's name
+    pub say: Option<String>,
+    // From on_message, sent instead of what the user wrote
+    pub text: Option<String>,
+    // From on_message, the message isn't sent and the user is told this
+    pub reject: Option<String>,
+}
+
+// Given the event's pointer and length, returns the response's
+type HookFunc = TypedFunc<(i32, i32), i64>;
+
+struct Instance {
+    store: Store<StoreLimits>,
+    memory: Memory,
+    // Gives the plugin `len` bytes of its memory to copy an event into
+    alloc: TypedFunc<i32, i32>,
+    hooks: Vec<(Hook, HookFunc)>,
+}
+
+// A WASM module loaded from `plugin_dir`. It has to export its `memory` and
+// `alloc(len) -> ptr`, plus any of `on_message`, `on_join` and `on_command`.
+// Hooks are called with a pointer to and length of the event's JSON, and
+// return the pointer and length of their response as `ptr << 32 | len`, or
+// 0 for nothing to say. Modules can't import anything.
+pub struct Plugin {
+    pub name: String,
+    // Calls run one at a time, so plugins can keep state between them. Only
+    // locked on blocking threads, see `Plugins`.
+    instance: Mutex<Instance>,
+}
+
+impl Plugin {
+    pub fn new(name: &str, wasm: &[u8]) -> Result<Self, PluginError> {
+        let invalid = |e: wasmi::Error| PluginError::InvalidModule(name.to_owned(), e.to_string());
+
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(invalid)?;
+
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build();
+        let mut store = Store::new(&engine, limits);
+        store.limiter(|limits| limits);
+
+        // Nothing is linked in, so plugins can only see what they're sent
+        let instance = Linker::<StoreLimits>::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(invalid)?;
+
+        let memory = match instance.get_memory(&store, "memory") {
+            Some(memory) => memory,
+            None => {
+                return Err(PluginError::InvalidModule(
+                    name.to_owned(),
+                    "no memory export".to_owned(),
+                ))
+            }
+        };
+        let alloc = instance.get_typed_func(&store, "alloc").map_err(invalid)?;
+
+        let hooks = [Hook::Message, Hook::Join, Hook::Command]
+            .into_iter()
+            .filter_map(|hook| {
+                let func = instance.get_typed_func(&store, hook.export()).ok()?;
+                Some((hook, func))
+            })
+            .collect();
+
+        Ok(Self {
+            name: name.to_owned(),
+            instance: Mutex::new(Instance {
+                store,
+                memory,
+                alloc,
+                hooks,
+            }),
+        })
+    }
+
+    // None if the plugin doesn't have the hook, or had nothing to say
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::plugin::{Hook, Plugin, Response};
+    ///
+    /// let wasm = wat::parse_str(r#"
+    ///     (module
+    ///         (memory (export "memory") 1)
+    ///         (data (i32.const 0) "{\"reply\":\"pong\"}")
+    ///         (func (export "alloc") (param i32) (result i32) i32.const 1024)
+    ///         (func (export "on_command") (param i32 i32) (result i64) i64.const 16)
+    ///         (func (export "on_join") (param i32 i32) (result i64) (loop (br 0)) i64.const 0))
+    /// "#).unwrap();
+    /// let plugin = Plugin::new("ping", &wasm).unwrap();
+    ///
+    /// let pong = Response { reply: Some("pong".to_owned()), ..Default::default() };
+    /// assert_eq!(plugin.call(Hook::Command, "bob", None, Some(">ping")).unwrap(), Some(pong));
+    ///
+    /// // Runs out of fuel rather than hanging
+    /// assert!(plugin.call(Hook::Join, "bob", Some("rust"), None).is_err());
+    /// assert_eq!(plugin.call(Hook::Message, "bob", Some("rust"), Some("hi")).unwrap(), None);
+    /// ```
+    pub fn call(
+        &self,
+        hook: Hook,
+        user: &str,
+        room: Option<&str>,
+        text: Option<&str>,
+    ) -> Result<Option<Response>, PluginError> {
+        let failed = |e: String| PluginError::FailedToCall(self.name.clone(), e);
+
+        let mut instance = self.instance.lock().unwrap();
+        let Instance {
+            store,
+            memory,
+            alloc,
+            hooks,
+        } = &mut *instance;
+
+        let func = match hooks.iter().find(|(h, _)| *h == hook) {
+            Some((_, func)) => *func,
+            None => return Ok(None),
+        };
+
+        // Topped back up rather than added to, so nothing carries over
+        let left = store.consume_fuel(0).map_err(|e| failed(e.to_string()))?;
+        store
+            .add_fuel(FUEL_PER_CALL - left)
+            .map_err(|e| failed(e.to_string()))?;
+
+        let event = serde_json::to_vec(&Event { user, room, text }).unwrap();
+        let len = event.len() as i32;
+
+        let ptr = alloc
+            .call(&mut *store, len)
+            .map_err(|e| failed(e.to_string()))?;
+        memory
+            .write(&mut *store, ptr as u32 as usize, &event)
+            .map_err(|e| failed(e.to_string()))?;
+
+        let packed = func
+            .call(&mut *store, (ptr, len))
+            .map_err(|e| failed(e.to_string()))?;
+        if packed == 0 {
+            return Ok(None);
+        }
+
+        let (ptr, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+        if len > MAX_RESPONSE {
+            return Err(failed(format!("response is over {} bytes", MAX_RESPONSE)));
+        }
+
+        let mut response = vec![0; len];
+        memory
+            .read(&*store, ptr, &mut response)
+            .map_err(|e| failed(e.to_string()))?;
+
+        serde_json::from_slice(&response)
+            .map(Some)
+            .map_err(|e| failed(e.to_string()))
+    }
+}
+
+// Every loaded plugin, asked in order of their file names. Cheap to clone.
+// Hooks are called on tokio's blocking threads, so a slow plugin, or one
+// waiting for another call to finish, doesn't stall the async tasks.
+#[derive(Clone, Default)]
+pub struct Plugins {
+    plugins: Arc<Vec<Plugin>>,
+}
+
+impl Plugins {
+    pub fn new(plugins: Vec<Plugin>) -> Self {
+        Self {
+            plugins: Arc::new(plugins),
+        }
+    }
+
+    // Every `.wasm` file in `dir`, named after the file without it
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self, PluginError> {
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir).map_err(PluginError::FailedToRead)? {
+            let path = entry.map_err(PluginError::FailedToRead)?.path();
+
+            if path.extension().is_some_and(|ext| ext == "wasm") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut plugins = Vec::new();
+        for path in paths {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            let wasm = std::fs::read(&path).map_err(PluginError::FailedToRead)?;
+
+            plugins.push(Plugin::new(&name, &wasm)?);
+        }
+
+        Ok(Self::new(plugins))
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.plugins.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    // What every plugin had to say about `user` joining `room`
+    pub async fn on_join(&self, user: &str, room: &str) -> Vec<(String, Response)> {
+        let plugins = Arc::clone(&self.plugins);
+        let (user, room) = (user.to_owned(), room.to_owned());
+
+        blocking(Vec::new(), move || {
+            plugins
+                .iter()
+                .filter_map(|plugin| {
+                    let response = ask(plugin, Hook::Join, &user, Some(&room), None)?;
+                    Some((plugin.name.clone(), response))
+                })
+                .collect()
+        })
+        .await
+    }
+
+    // The first plugin to answer the command, and what it said
+    pub async fn on_command(
+        &self,
+        user: &str,
+        room: Option<&str>,
+        line: &str,
+    ) -> Option<(String, Response)> {
+        let plugins = Arc::clone(&self.plugins);
+        let (user, room, line) = (user.to_owned(), room.map(str::to_owned), line.to_owned());
+
+        blocking(None, move || {
+            plugins.iter().find_map(|plugin| {
+                let response = ask(plugin, Hook::Command, &user, room.as_deref(), Some(&line))?;
+                Some((plugin.name.clone(), response))
+            })
+        })
+        .await
+    }
+}
+
+// Runs `f` on a blocking thread, giving `fallback` if it panicked
+async fn blocking<T: Send + 'static>(fallback: T, f: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|e| {
+        eprintln!("Plugins failed: {}", e);
+        fallback
+    })
+}
+
+// Each plugin's on_message sees what the one before it sent on, like filters.
+// `Filters::run` calls it on a blocking thread.
+impl MessageFilter for Plugins {
+    fn filter(&self, user: &str, room: &str, msg: &str) -> Action {
+        let mut changed = None;
+
+        for plugin in self.plugins.iter() {
+            let text = changed.as_deref().unwrap_or(msg);
+
+            match ask(plugin, Hook::Message, user, Some(room), Some(text)) {
+                Some(Response {
+                    reject: Some(reason),
+                    ..
+                }) => return Action::Reject(reason),
+                Some(Response {
+                    text: Some(text), ..
+                }) => changed = Some(text),
+                _ => {}
+            }
+        }
+
+        match changed {
+            Some(text) => Action::Transform(text),
+            None => Action::Allow,
+        }
+    }
+}
+
+// A plugin that fails is logged and treated as having nothing to say, so a
+// broken one can't stop people chatting
+fn ask(
+    plugin: &Plugin,
+    hook: Hook,
+    user: &str,
+    room: Option<&str>,
+    text: Option<&str>,
+) -> Option<Response> {
+    match plugin.call(hook, user, room, text) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("{}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use redis::aio::ConnectionManager;
+use redis::{Client, RedisResult};
+
+use crate::outbox::Outbox;
+
+// A single multiplexed connection shared by every task. Cloning it is cheap,
+// and it reconnects by itself when a command fails because Redis went away.
+#[derive(Clone)]
+pub struct Pool {
+    conn: ConnectionManager,
+    // Room messages waiting for Redis to come back, holding at most
+    // `outbox_capacity`
+    outbox: Arc<Outbox>,
+    // Internal hosts webhooks can still be sent to, from `webhook_allowlist`
+    webhook_allowlist: Arc<Vec<String>>,
+}
+
+impl Pool {
+    pub async fn new(client: Client, outbox_capacity: usize) -> RedisResult<Self> {
+        let conn = ConnectionManager::new(client).await?;
+        let outbox = Arc::new(Outbox::new(outbox_capacity));
+
+        Ok(Self {
+            conn,
+            outbox,
+            webhook_allowlist: Arc::default(),
+        })
+    }
+
+    pub fn with_webhook_allowlist(mut self, hosts: Vec<String>) -> Self {
+        self.webhook_allowlist = Arc::new(hosts);
+        self
+    }
+
+    pub fn get(&self) -> ConnectionManager {
+        self.conn.clone()
+    }
+
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
+    pub fn webhook_allowlist(&self) -> &[String] {
+        &self.webhook_allowlist
+    }
+
+    // Times a write, for >ping. The key is the connection's and expires
+    // straight after.
+    pub async fn ping(&self, id: u64) -> RedisResult<Duration> {
+        let start = Instant::now();
+
+        redis::cmd("SET")
+            .arg(format!("ping:{}", id))
+            .arg(1)
+            .arg("PX")
+            .arg(1000)
+            .query_async::<_, ()>(&mut self.get())
+            .await?;
+
+        Ok(start.elapsed())
+    }
+
+    // Pings Redis on an interval, so a dropped connection gets noticed (and
+    // replaced) before a user's command runs into it.
+    pub fn spawn_health_check(&self, interval: Duration) {
+        let mut conn = self.get();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+
+                if let Err(e) = redis::cmd("PING").query_async::<_, ()>(&mut conn).await {
+                    eprintln!("Redis health check failed: {}", e);
+                }
+            }
+        });
+    }
+}
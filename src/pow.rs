@@ -0,0 +1,101 @@
+use sha2::{Digest, Sha256};
+
+use crate::session;
+
+// Starts the greeting line with the challenge, which clients look for
+const PREFIX: &str = "Challenge: ";
+
+// A proof of work asked of new connections before anything else, so each
+// one costs whoever opens it some CPU time. Finding an `n` where the SHA-256
+// of `<nonce>:<n>` starts with `difficulty` zero bits takes about
+// 2^difficulty hashes, while checking it takes one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Challenge {
+    pub nonce: String,
+    pub difficulty: u32,
+}
+
+impl Challenge {
+    pub fn new(difficulty: u32) -> Self {
+        Self {
+            nonce: session::gen_token(),
+            difficulty,
+        }
+    }
+
+    // For the greeting. The first part is what clients parse.
+    pub fn describe(&self) -> String {
+        format!(
+            "{}{} {} - send >pow n, where the SHA-256 of \"{}:n\" starts with {} zero bits\n",
+            PREFIX, self.nonce, self.difficulty, self.nonce, self.difficulty
+        )
+    }
+
+    // The challenge in a greeting line, for clients
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::pow::Challenge;
+    ///
+    /// let challenge = Challenge::new(8);
+    ///
+    /// assert_eq!(Challenge::parse(&challenge.describe()), Some(challenge));
+    /// assert_eq!(Challenge::parse("Welcome!"), None);
+    /// ```
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut words = line.strip_prefix(PREFIX)?.split(' ');
+        let nonce = words.next()?.to_owned();
+        let difficulty = words.next()?.parse().ok()?;
+
+        Some(Self { nonce, difficulty })
+    }
+
+    pub fn check(&self, answer: &str) -> bool {
+        let hash = Sha256::new()
+            .chain_update(self.nonce.as_bytes())
+            .chain_update(b":")
+            .chain_update(answer.as_bytes())
+            .finalize();
+
+        leading_zeros(&hash) >= self.difficulty
+    }
+
+    // Tries every `n` from 0 until one works
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::pow::Challenge;
+    ///
+    /// let challenge = Challenge::new(8);
+    /// let answer = challenge.solve().to_string();
+    ///
+    /// assert!(challenge.check(&answer));
+    /// assert!(Challenge::new(0).check("anything"));
+    ///
+    /// // The SHA-256 of "abc:181" is 003613d5..., ten zero bits
+    /// let known = Challenge { nonce: "abc".into(), difficulty: 8 };
+    /// assert_eq!(known.solve(), 181);
+    /// assert!(Challenge { difficulty: 10, ..known.clone() }.check("181"));
+    /// assert!(!Challenge { difficulty: 11, ..known }.check("181"));
+    /// ```
+    pub fn solve(&self) -> u64 {
+        (0..).find(|n| self.check(&n.to_string())).unwrap()
+    }
+}
+
+fn leading_zeros(hash: &[u8]) -> u32 {
+    let mut zeros = 0;
+
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    zeros
+}
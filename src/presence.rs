@@ -0,0 +1,64 @@
+use redis::AsyncCommands;
+
+use crate::pool::Pool;
+
+// Presence keys expire on their own, so a server that dies without cleaning
+// up doesn't leave people marked as online forever.
+pub const TTL_SECS: usize = 60;
+
+#[derive(Debug)]
+pub enum PresenceError {
+    FailedToSend,
+    FailedToFetch,
+}
+
+impl std::fmt::Display for PresenceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresenceError::FailedToSend => writeln!(f, "Error: Failed to update presence"),
+            PresenceError::FailedToFetch => writeln!(f, "Error: Failed to fetch presence"),
+        }
+    }
+}
+
+impl std::error::Error for PresenceError {}
+
+// Marks the user as online, or refreshes the TTL if they already are
+pub async fn set_online(redis: &Pool, username: &str) -> Result<(), PresenceError> {
+    let mut conn = redis.get();
+
+    conn.set_ex::<_, _, ()>(gen_key(username), "online", TTL_SECS)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            PresenceError::FailedToSend
+        })?;
+
+    Ok(())
+}
+
+pub async fn set_offline(redis: &Pool, username: &str) -> Result<(), PresenceError> {
+    let mut conn = redis.get();
+
+    conn.del::<_, ()>(gen_key(username)).await.map_err(|e| {
+        dbg!("{}", e);
+        PresenceError::FailedToSend
+    })?;
+
+    Ok(())
+}
+
+pub async fn is_online(redis: &Pool, username: &str) -> Result<bool, PresenceError> {
+    let mut conn = redis.get();
+
+    let exists: u8 = conn.exists(gen_key(username)).await.map_err(|e| {
+        dbg!("{}", e);
+        PresenceError::FailedToFetch
+    })?;
+
+    Ok(exists == 1)
+}
+
+pub(crate) fn gen_key(username: &str) -> String {
+    format!("presence:{}", username)
+}
@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use redis::{AsyncCommands, Client};
+use serde::{Deserialize, Serialize};
+
+use crate::broker::{BrokerEvent, RoomMap};
+use crate::message::Message;
+use crate::pool::Pool;
+
+const CHANNEL_PREFIX: &str = "chat:";
+
+// How long to wait before resubscribing after losing the connection
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// What a broker publishes when other servers need to act on an event. Every
+// server, including the one that published it, delivers these to the
+// members connected to it.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Remote {
+    // Send to everyone in the room except `user`
+    Broadcast { user: String, msg: Message },
+    Kick { user: String, msg: Message },
+    // Send only to `to`, if they're connected to this server
+    Whisper { to: String, msg: Message },
+    Close { msg: Message },
+    // The room is now called `to`
+    Rename { to: String, msg: Message },
+}
+
+#[derive(Debug)]
+pub enum PubSubError {
+    FailedToPublish,
+}
+
+impl std::fmt::Display for PubSubError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PubSubError::FailedToPublish => writeln!(f, "Error: Failed to publish event"),
+        }
+    }
+}
+
+impl std::error::Error for PubSubError {}
+
+pub async fn publish(redis: &Pool, room: &str, remote: &Remote) -> Result<(), PubSubError> {
+    let mut conn = redis.get();
+
+    let payload = serde_json::to_string(remote).unwrap();
+
+    conn.publish::<_, _, ()>(gen_channel(room), payload)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            PubSubError::FailedToPublish
+        })?;
+
+    Ok(())
+}
+
+// Subscribes to every room's channel and hands events to this server's
+// brokers. Pub/Sub needs a dedicated connection, so this doesn't use the
+// pool, and it resubscribes if that connection drops.
+pub fn spawn_subscriber(client: Client, rooms_map: RoomMap) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = subscribe(&client, &rooms_map).await {
+                eprintln!("{}", e);
+            }
+
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    });
+}
+
+async fn subscribe(client: &Client, rooms_map: &RoomMap) -> redis::RedisResult<()> {
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+    pubsub.psubscribe(format!("{}*", CHANNEL_PREFIX)).await?;
+
+    let mut messages = pubsub.on_message();
+
+    while let Some(msg) = messages.next().await {
+        let room = match msg.get_channel_name().strip_prefix(CHANNEL_PREFIX) {
+            Some(room) => room.to_owned(),
+            None => continue,
+        };
+
+        let payload: String = msg.get_payload()?;
+        let remote: Remote = match serde_json::from_str(&payload) {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("Ignoring event for {}: {}", room, e);
+                continue;
+            }
+        };
+
+        // Like a local >delete-room, the broker has to come out of the map
+        // first so nobody can join it while it closes
+        let tx = match &remote {
+            Remote::Close { .. } => rooms_map.write().await.remove(&room),
+            // Moved under the new name before anyone can look for it there
+            Remote::Rename { to, .. } => {
+                let mut map = rooms_map.write().await;
+                let tx = map.remove(&room);
+                if let Some(tx) = &tx {
+                    map.insert(to.clone(), tx.clone());
+                }
+                tx
+            }
+            _ => rooms_map.read().await.get(&room).cloned(),
+        };
+
+        // No broker means nobody on this server is in the room
+        if let Some(tx) = tx {
+            if let Err(e) = tx.send(BrokerEvent::Remote(remote)).await {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn gen_channel(room: &str) -> String {
+    format!("{}{}", CHANNEL_PREFIX, room)
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    // Maximum burst of lines a client can send at once
+    pub capacity: f64,
+    // Tokens regained per second
+    pub refill_per_sec: f64,
+    // Warnings allowed before the client gets disconnected
+    pub max_warnings: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            capacity: 10.0,
+            refill_per_sec: 2.0,
+            max_warnings: 5,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Warn,
+    Disconnect,
+}
+
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    warnings: u32,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.capacity,
+            warnings: 0,
+            last: Instant::now(),
+        }
+    }
+
+    // Keeps the tokens left and warnings given, so a reload can't be used to
+    // get a full bucket back
+    pub fn set_limit(&mut self, limit: RateLimit) {
+        if self.limit != limit {
+            self.tokens = self.tokens.min(limit.capacity);
+            self.limit = limit;
+        }
+    }
+
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::ratelimit::{RateLimit, TokenBucket, Verdict};
+    ///
+    /// let mut bucket = TokenBucket::new(RateLimit {
+    ///     capacity: 2.0,
+    ///     refill_per_sec: 0.001,
+    ///     max_warnings: 1,
+    /// });
+    ///
+    /// assert_eq!(bucket.check(), Verdict::Allow);
+    /// assert_eq!(bucket.check(), Verdict::Allow);
+    /// assert_eq!(bucket.check(), Verdict::Warn);
+    /// assert_eq!(bucket.check(), Verdict::Disconnect);
+    /// ```
+    pub fn check(&mut self) -> Verdict {
+        self.refill();
+
+        // Staying quiet long enough to refill the bucket forgives past abuse
+        if self.tokens >= self.limit.capacity {
+            self.warnings = 0;
+        }
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Verdict::Allow;
+        }
+
+        self.warnings += 1;
+
+        if self.warnings > self.limit.max_warnings {
+            Verdict::Disconnect
+        } else {
+            Verdict::Warn
+        }
+    }
+
+    // Whether `check` would allow one now, without using it up
+    pub fn has_token(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+
+        self.tokens = (self.tokens + elapsed * self.limit.refill_per_sec).min(self.limit.capacity);
+    }
+}
+
+// A bucket per key, for things that only cost a token when they fail, like
+// logins per address and per user. Full buckets are the same as none, so
+// they're dropped once there are enough to matter.
+pub struct Failures {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl Failures {
+    const PRUNE_AT: usize = 1024;
+
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::ratelimit::{Failures, RateLimit};
+    ///
+    /// let failures = Failures::new(RateLimit {
+    ///     capacity: 2.0,
+    ///     refill_per_sec: 0.001,
+    ///     max_warnings: 0,
+    /// });
+    ///
+    /// failures.record("ip:127.0.0.1");
+    /// assert!(failures.allows("ip:127.0.0.1"));
+    /// failures.record("ip:127.0.0.1");
+    /// assert!(!failures.allows("ip:127.0.0.1"));
+    /// assert!(failures.allows("user:alice"));
+    /// ```
+    pub fn allows(&self, key: &str) -> bool {
+        match self.buckets.lock().unwrap().get_mut(key) {
+            Some(bucket) => bucket.has_token(),
+            None => true,
+        }
+    }
+
+    pub fn record(&self, key: &str) {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= Self::PRUNE_AT {
+            let capacity = self.limit.capacity;
+            buckets.retain(|_, bucket| {
+                bucket.refill();
+                bucket.tokens < capacity
+            });
+        }
+
+        buckets
+            .entry(key.to_owned())
+            .or_insert_with(|| TokenBucket::new(self.limit))
+            .check();
+    }
+}
@@ -0,0 +1,104 @@
+use redis::AsyncCommands;
+
+use crate::pool::Pool;
+use crate::room;
+
+#[derive(Debug)]
+pub enum ReceiptError {
+    FailedToSave,
+    FailedToFetch,
+    InvalidId,
+}
+
+impl std::fmt::Display for ReceiptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReceiptError::FailedToSave => writeln!(f, "Error: Failed to save receipts"),
+            ReceiptError::FailedToFetch => writeln!(f, "Error: Failed to fetch receipts"),
+            ReceiptError::InvalidId => writeln!(f, "Error: That isn't a message id"),
+        }
+    }
+}
+
+impl std::error::Error for ReceiptError {}
+
+// Records who's been sent what in rooms with at most `max_members` people in
+// them. Each member only has the timestamp of the latest message they were
+// sent, in `receipts:<room>`, since anything before it came to them too,
+// live or in the history replayed when they joined.
+#[derive(Clone)]
+pub struct Receipts {
+    redis: Pool,
+    max_members: usize,
+}
+
+impl Receipts {
+    // None when they're turned off
+    pub fn new(redis: Pool, max_members: usize) -> Option<Self> {
+        (max_members > 0).then_some(Self { redis, max_members })
+    }
+
+    // Bigger rooms would mean a write per message for too many people
+    pub fn tracks(&self, members: usize) -> bool {
+        members <= self.max_members
+    }
+
+    // Spawned, so a broker never waits on Redis to deliver the next message
+    pub fn record(&self, room: &str, users: Vec<String>, timestamp: isize) {
+        if users.is_empty() {
+            return;
+        }
+
+        let redis = self.redis.clone();
+        let key = gen_key(room);
+
+        tokio::spawn(async move {
+            if let Err(e) = save(&redis, key, users, timestamp).await {
+                eprintln!("{}", e);
+            }
+        });
+    }
+}
+
+// Everyone who's been sent the message with `id` in `room`
+pub async fn seen_by(redis: &Pool, room: &str, id: &str) -> Result<Vec<String>, ReceiptError> {
+    let timestamp = room::id_timestamp(id).ok_or(ReceiptError::InvalidId)?;
+
+    let mut users: Vec<String> = redis
+        .get()
+        .zrangebyscore(gen_key(room), timestamp, "+inf")
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            ReceiptError::FailedToFetch
+        })?;
+    users.sort();
+
+    Ok(users)
+}
+
+// GT keeps members' timestamps from going back, if messages from other
+// servers arrive out of order
+async fn save(
+    redis: &Pool,
+    key: String,
+    users: Vec<String>,
+    timestamp: isize,
+) -> Result<(), ReceiptError> {
+    let mut cmd = redis::cmd("ZADD");
+    cmd.arg(key).arg("GT");
+    for user in users {
+        cmd.arg(timestamp).arg(user);
+    }
+
+    cmd.query_async::<_, ()>(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            ReceiptError::FailedToSave
+        })
+}
+
+pub(crate) fn gen_key(room: &str) -> String {
+    format!("receipts:{}", room)
+}
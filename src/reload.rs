@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::{Config, ConfigError, LiveConfig};
+use crate::filter::{SharedWordlist, WordlistFilter, WordlistMode};
+
+#[derive(Debug)]
+pub enum ReloadError {
+    Config(ConfigError),
+    Wordlist(std::io::Error),
+}
+
+impl std::fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReloadError::Config(e) => write!(f, "{}", e),
+            ReloadError::Wordlist(e) => writeln!(f, "Error: Failed to read wordlist: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {}
+
+// What a reload swaps out. Connections read the config again for each
+// message or command, so nobody has to reconnect to see a change.
+#[derive(Clone)]
+pub struct Reloader {
+    config: Arc<LiveConfig>,
+    wordlist: SharedWordlist,
+}
+
+impl Reloader {
+    pub fn new(config: Arc<LiveConfig>, wordlist: SharedWordlist) -> Self {
+        Self { config, wordlist }
+    }
+
+    // Rereads the config the way `Config::load` does on startup and applies
+    // what can change while running, see `Config::with_reloadable`. Nothing
+    // changes unless all of it loads, so a typo leaves the running config.
+    pub fn reload(&self) -> Result<(), ReloadError> {
+        let new = Config::load().map_err(ReloadError::Config)?;
+        let config = self.config.get().with_reloadable(new);
+
+        let wordlist = match &config.wordlist {
+            Some(path) => Some(
+                WordlistFilter::from_file(path, WordlistMode::Redact)
+                    .map_err(ReloadError::Wordlist)?,
+            ),
+            None => None,
+        };
+
+        self.wordlist.set(wordlist);
+        self.config.set(config);
+
+        Ok(())
+    }
+}
+
+// Reloads whenever the process gets SIGHUP, like `kill -HUP <pid>`
+pub fn spawn_on_hangup(reloader: Reloader) {
+    tokio::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                eprintln!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+
+        while hangups.recv().await.is_some() {
+            match reloader.reload() {
+                Ok(()) => eprintln!("Reloaded config"),
+                Err(e) => eprint!("{}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,160 @@
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::id;
+use crate::pool::Pool;
+use crate::room::get_time_in_ms;
+
+// Sorted set of open reports, scored by when they were made, oldest first
+// so they're dealt with in order
+const KEY: &str = "reports";
+
+// How many of the room's latest messages are saved with a report
+pub const CONTEXT: usize = 10;
+
+#[derive(Debug)]
+pub enum ReportError {
+    FailedToSave,
+    FailedToFetch,
+    NotFound,
+    Yourself,
+}
+
+impl std::fmt::Display for ReportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReportError::FailedToSave => writeln!(f, "Error: Failed to save report"),
+            ReportError::FailedToFetch => writeln!(f, "Error: Failed to fetch reports"),
+            ReportError::NotFound => writeln!(f, "Error: No open report with that id"),
+            ReportError::Yourself => writeln!(f, "Error: You can't report yourself"),
+        }
+    }
+}
+
+impl std::error::Error for ReportError {}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Report {
+    pub id: String,
+    pub reporter: String,
+    pub target: String,
+    // Where the reporter was when they made it
+    pub room: Option<String>,
+    pub reason: Option<String>,
+    // The room's latest messages at the time, as text clients saw them
+    pub context: Vec<String>,
+}
+
+impl Report {
+    // A line for the report, then its context indented under it
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::report::Report;
+    ///
+    /// let report = Report {
+    ///     id: "01GQ0P6KB0".into(),
+    ///     reporter: "alice".into(),
+    ///     target: "bob".into(),
+    ///     room: Some("rust".into()),
+    ///     reason: Some("spamming links".into()),
+    ///     context: vec!["bob: buy now\n".into()],
+    /// };
+    ///
+    /// assert_eq!(
+    ///     report.describe(),
+    ///     "01GQ0P6KB0 alice reported bob in rust: spamming links\n  bob: buy now\n"
+    /// );
+    /// ```
+    pub fn describe(&self) -> String {
+        let mut out = format!("{} {} reported {}", self.id, self.reporter, self.target);
+
+        if let Some(room) = &self.room {
+            out.push_str(&format!(" in {}", room));
+        }
+        if let Some(reason) = &self.reason {
+            out.push_str(&format!(": {}", reason));
+        }
+        out.push('\n');
+
+        for line in &self.context {
+            out.push_str("  ");
+            out.push_str(line);
+        }
+
+        out
+    }
+}
+
+// Adds a report to the queue, returning its id
+pub async fn file(
+    redis: &Pool,
+    reporter: &str,
+    target: &str,
+    room: Option<&str>,
+    reason: Option<&str>,
+    context: Vec<String>,
+) -> Result<String, ReportError> {
+    if reporter == target {
+        return Err(ReportError::Yourself);
+    }
+
+    let at = get_time_in_ms();
+    let report = Report {
+        id: id::gen(at),
+        reporter: reporter.to_owned(),
+        target: target.to_owned(),
+        room: room.map(str::to_owned),
+        reason: reason.map(str::to_owned),
+        context,
+    };
+    let member = serde_json::to_string(&report).unwrap();
+
+    redis
+        .get()
+        .zadd::<_, _, _, ()>(KEY, member, at)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            ReportError::FailedToSave
+        })?;
+
+    Ok(report.id)
+}
+
+// Every report that hasn't been resolved, oldest first
+pub async fn open(redis: &Pool) -> Result<Vec<Report>, ReportError> {
+    let members: Vec<String> = redis.get().zrange(KEY, 0, -1).await.map_err(|e| {
+        dbg!("{}", e);
+        ReportError::FailedToFetch
+    })?;
+
+    Ok(members
+        .iter()
+        .filter_map(|member| serde_json::from_str(member).ok())
+        .collect())
+}
+
+// Takes a report out of the queue once it's been dealt with, returning it.
+// Who resolved it is in the audit log with the command.
+pub async fn resolve(redis: &Pool, id: &str) -> Result<Report, ReportError> {
+    let report = open(redis)
+        .await?
+        .into_iter()
+        .find(|report| report.id.eq_ignore_ascii_case(id))
+        .ok_or(ReportError::NotFound)?;
+
+    let member = serde_json::to_string(&report).unwrap();
+    redis
+        .get()
+        .zrem::<_, _, ()>(KEY, member)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            ReportError::FailedToSave
+        })?;
+
+    Ok(report)
+}
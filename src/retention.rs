@@ -0,0 +1,175 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::broker::{BrokerEvent, RoomMap};
+use crate::config::{Config, LiveConfig};
+use crate::message::Message;
+use crate::pool::Pool;
+use crate::pubsub::{self, Remote};
+use crate::room::{self, get_time_in_ms, RoomError};
+
+// How much history a room keeps. The server's default comes from config and
+// owners can override either limit for their room with >retention.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    pub max_messages: Option<u64>,
+    pub max_age_secs: Option<u64>,
+}
+
+impl Policy {
+    // Room overrides are stored as a number, where 0 means no limit
+    pub fn with_overrides(self, messages: Option<u64>, age_secs: Option<u64>) -> Self {
+        let or_default = |value: Option<u64>, default| match value {
+            Some(0) => None,
+            Some(value) => Some(value),
+            None => default,
+        };
+
+        Self {
+            max_messages: or_default(messages, self.max_messages),
+            max_age_secs: or_default(age_secs, self.max_age_secs),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let messages = match self.max_messages {
+            Some(n) => format!("at most {} messages", n),
+            None => "any number of messages".to_owned(),
+        };
+        let age = match self.max_age_secs {
+            Some(secs) => format!("for {}", describe_secs(secs)),
+            None => "forever".to_owned(),
+        };
+
+        format!("This room keeps {} {}\n", messages, age)
+    }
+}
+
+// For >disappearing, and when joining a room where it's on
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::retention::describe_disappearing;
+///
+/// assert_eq!(
+///     describe_disappearing(Some(1800)),
+///     "Messages in this room disappear after 30 minutes\n"
+/// );
+/// assert_eq!(describe_disappearing(None), "Messages in this room don't disappear\n");
+/// ```
+pub fn describe_disappearing(secs: Option<u64>) -> String {
+    match secs {
+        Some(secs) => format!(
+            "Messages in this room disappear after {}\n",
+            describe_secs(secs)
+        ),
+        None => "Messages in this room don't disappear\n".to_owned(),
+    }
+}
+
+fn describe_secs(secs: u64) -> String {
+    let (n, unit) = match secs {
+        s if s % 86400 == 0 => (s / 86400, "day"),
+        s if s % 3600 == 0 => (s / 3600, "hour"),
+        s if s % 60 == 0 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+
+    match n {
+        1 => format!("1 {}", unit),
+        n => format!("{} {}s", n, unit),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Messages,
+    Age,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Setting {
+    Value(u64),
+    // No limit, whatever the server default is
+    Off,
+    // Go back to the server default
+    Default,
+}
+
+// Every `interval`, trims every room to its policy, with the server default
+// as it is in `config` at the time, and removes messages that have
+// disappeared. Members of `rooms` are told which ones did.
+pub fn spawn(redis: Pool, config: Arc<LiveConfig>, rooms: RoomMap, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            // Read each time, since a reload can change it
+            if let Err(e) = trim_all(&redis, &config.get(), &rooms).await {
+                eprintln!("{}", e);
+            }
+        }
+    });
+}
+
+pub async fn trim_all(redis: &Pool, config: &Config, rooms: &RoomMap) -> Result<(), RoomError> {
+    for key in room::list(redis).await? {
+        // Remove `room:`
+        let room = &key[5..];
+
+        if let Some(secs) = room::disappearing(redis, room).await? {
+            let min_time = get_time_in_ms() - secs as isize * 1000;
+            let ids = room::disappear(redis, room, min_time).await?;
+
+            if !ids.is_empty() {
+                notify(redis, config, rooms, room, Message::expire(room, &ids)).await;
+            }
+        }
+
+        let (messages, age_secs) = room::retention(redis, room).await?;
+        let policy = config.retention.with_overrides(messages, age_secs);
+
+        if policy == Policy::default() {
+            continue;
+        }
+
+        let min_time = policy
+            .max_age_secs
+            .map(|secs| get_time_in_ms() - secs as isize * 1000);
+
+        room::trim(redis, room, policy.max_messages, min_time).await?;
+    }
+
+    Ok(())
+}
+
+// Through the room's broker on this server, or if there isn't one, straight
+// to the others through Redis, since members could be connected there
+async fn notify(redis: &Pool, config: &Config, rooms: &RoomMap, room: &str, msg: Message) {
+    let tx = rooms.read().await.get(room).cloned();
+
+    if let Some(tx) = tx {
+        if let Err(e) = tx.send(BrokerEvent::Post { msg }).await {
+            eprintln!("{}", e);
+        }
+        return;
+    }
+
+    if config.pubsub {
+        let remote = Remote::Broadcast {
+            user: String::new(),
+            msg,
+        };
+
+        if let Err(e) = pubsub::publish(redis, room, &remote).await {
+            eprintln!("{}", e);
+        }
+    }
+}
@@ -1,43 +1,216 @@
+use std::collections::HashMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use redis::{AsyncCommands, Client};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use redis::streams::StreamRangeReply;
+use redis::{AsyncCommands, AsyncIter, Script};
+
+use crate::account::{hash_password, verify_password};
+use crate::bots;
+use crate::canonical::{self, Kind};
+use crate::entry::{self, Entry};
+use crate::federation;
+use crate::message::{Message, MessageKind};
+use crate::outbox;
+use crate::permissions;
+use crate::pool::Pool;
+use crate::receipts;
+use crate::retention::{Limit, Setting};
+use crate::search;
+use crate::stats;
+use crate::webhook;
 
 pub enum RoomEvent {
     Chat(String),
+    // Chat whose signature has been checked, see `signing`
+    Signed(String),
+    // Id of the message being replied to, and the reply
+    Reply(String, String),
     Join,
     Leave,
+    Kick(String),
+    Ban(String),
+    // Someone who asked to join a private room was let in
+    Approve(String),
+    Topic(String),
+    // Their previous name
+    Rename(String),
+    // The room's previous name
+    RoomRename(String),
+    // Sent by an admin to every active room
+    Broadcast(String),
+}
+
+// Set by flags on >create-room
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Options {
+    // Only let in the owner and invited users
+    pub private: bool,
+    // Only store and relay ciphertext, see `validate::ciphertext`
+    pub encrypted: bool,
+}
+
+// Which kinds of message history is read for, from `>history --types` or
+// `>set types`. Written as `all`, a list to keep like `chat,topic`, or a list
+// to leave out like `-join,-leave`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum Kinds {
+    #[default]
+    All,
+    Only(Vec<MessageKind>),
+    Except(Vec<MessageKind>),
+}
+
+impl Kinds {
+    // Entries saved before kinds were can't be told apart, so they're kept
+    pub fn keeps(&self, kind: Option<MessageKind>) -> bool {
+        match (self, kind) {
+            (_, None) | (Kinds::All, _) => true,
+            (Kinds::Only(kinds), Some(kind)) => kinds.contains(&kind),
+            (Kinds::Except(kinds), Some(kind)) => !kinds.contains(&kind),
+        }
+    }
+}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::message::MessageKind;
+/// use chatsapp::room::Kinds;
+///
+/// let chat: Kinds = "chat,topic".parse().unwrap();
+/// assert_eq!(chat, Kinds::Only(vec![MessageKind::Chat, MessageKind::Topic]));
+/// assert!(!chat.keeps(Some(MessageKind::Join)));
+/// assert!(chat.keeps(None));
+///
+/// let quiet: Kinds = "-join,-leave".parse().unwrap();
+/// assert!(!quiet.keeps(Some(MessageKind::Leave)));
+/// assert_eq!(quiet.to_string(), "-join,-leave");
+///
+/// assert!("chat,-join".parse::<Kinds>().is_err());
+/// assert!("chats".parse::<Kinds>().is_err());
+/// ```
+impl std::str::FromStr for Kinds {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "all" {
+            return Ok(Kinds::All);
+        }
+
+        let except = s.starts_with('-');
+        let mut kinds = Vec::new();
+        for name in s.split(',') {
+            let name = match (except, name.strip_prefix('-')) {
+                (true, Some(name)) => name,
+                (false, None) => name,
+                // Either every kind is left out or none are
+                _ => return Err(()),
+            };
+            kinds.push(name.parse()?);
+        }
+
+        match except {
+            true => Ok(Kinds::Except(kinds)),
+            false => Ok(Kinds::Only(kinds)),
+        }
+    }
+}
+
+impl std::fmt::Display for Kinds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (prefix, kinds) = match self {
+            Kinds::All => return write!(f, "all"),
+            Kinds::Only(kinds) => ("", kinds),
+            Kinds::Except(kinds) => ("-", kinds),
+        };
+        let names: Vec<String> = kinds
+            .iter()
+            .map(|kind| format!("{}{}", prefix, kind.name()))
+            .collect();
+
+        write!(f, "{}", names.join(","))
+    }
 }
 
 #[derive(Debug)]
 pub enum RoomError {
-    FailedToConnect,
     FailedToSend,
     FailedToFetch,
     FailedToCheckRoomExists,
     RoomNameTaken,
+    NotAuthenticated,
+    IncorrectPassword,
+    Banned,
+    TooManyRooms,
+    // The most one person can own
+    TooManyOwned(usize),
+    MessageNotFound,
+    NotAuthor,
+    Signed,
+    NotInvited,
+    NotInviteOnly,
+    // Seconds until they can talk again
+    Muted(u64),
+    NotMuted,
+    NotOwner,
+    NoOffer,
+    NoRequest,
 }
 
 impl std::fmt::Display for RoomError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RoomError::FailedToConnect => write!(f, "Error: Failed to connect\n"),
-            RoomError::FailedToSend => write!(f, "Error: Failed to send\n"),
-            RoomError::FailedToFetch => write!(f, "Error: Failed to fetch\n"),
+            RoomError::FailedToSend => writeln!(f, "Error: Failed to send"),
+            RoomError::FailedToFetch => writeln!(f, "Error: Failed to fetch"),
             RoomError::FailedToCheckRoomExists => {
-                write!(f, "Error: Failed to check if room exists\n")
+                writeln!(f, "Error: Failed to check if room exists")
+            }
+            RoomError::RoomNameTaken => writeln!(f, "Error: Room name taken"),
+            RoomError::NotAuthenticated => writeln!(f, "Error: You need to log in first"),
+            RoomError::IncorrectPassword => writeln!(f, "Error: Incorrect room password"),
+            RoomError::Banned => writeln!(f, "Error: You are banned from this room"),
+            RoomError::TooManyRooms => writeln!(f, "Error: The server has reached its room limit"),
+            RoomError::TooManyOwned(max) => writeln!(
+                f,
+                "Error: You can own at most {} rooms, delete or transfer one first",
+                max
+            ),
+            RoomError::MessageNotFound => writeln!(f, "Error: No message with that id"),
+            RoomError::NotAuthor => writeln!(f, "Error: You can only change your own messages"),
+            RoomError::Signed => writeln!(f, "Error: Signed messages can't be edited"),
+            RoomError::NotInvited => {
+                writeln!(
+                    f,
+                    "Error: This room is invite only, ask the owner for an invite"
+                )
             }
-            RoomError::RoomNameTaken => write!(f, "Error: Room name taken\n"),
+            RoomError::NotInviteOnly => writeln!(f, "Error: Anyone can join this room"),
+            RoomError::Muted(secs) => writeln!(
+                f,
+                "Error: You're muted in this room for another {} seconds",
+                secs
+            ),
+            RoomError::NotMuted => writeln!(f, "Error: That user isn't muted"),
+            RoomError::NotOwner => writeln!(f, "Error: Only the room's owner can do that"),
+            RoomError::NoOffer => writeln!(f, "Error: Nobody has offered you that room"),
+            RoomError::NoRequest => writeln!(f, "Error: That user hasn't asked to join"),
         }
     }
 }
 
 impl std::error::Error for RoomError {}
 
-pub async fn new(redis: &Client, room: &str) -> Result<(), RoomError> {
-    let mut conn = redis.get_async_connection().await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToConnect
-    })?;
+pub async fn new(
+    redis: &Pool,
+    room: &str,
+    owner: &str,
+    password: Option<&str>,
+    options: Options,
+) -> Result<(), RoomError> {
+    let mut conn = redis.get();
 
     let key = gen_key(room);
 
@@ -50,126 +223,2012 @@ pub async fn new(redis: &Client, room: &str) -> Result<(), RoomError> {
         Err(RoomError::RoomNameTaken)?;
     }
 
-    // Key, member, score
-    conn.zadd(key, "Start of chat\n", 0).await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToSend
-    })?;
+    // `Rust` is taken if `rust` is
+    let reserved = canonical::reserve(redis, Kind::Room, room)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToCheckRoomExists
+        })?;
+    if !reserved {
+        Err(RoomError::RoomNameTaken)?;
+    }
+
+    conn.hset::<_, _, _, ()>(gen_meta_key(room), "owner", owner)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    if let Some(password) = password {
+        let hash = hash_password(password).await.map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+        conn.hset::<_, _, _, ()>(gen_meta_key(room), "password", hash)
+            .await
+            .map_err(|e| {
+                dbg!("{}", e);
+                RoomError::FailedToSend
+            })?;
+    }
+
+    if options.private {
+        conn.hset::<_, _, _, ()>(gen_meta_key(room), "private", 1)
+            .await
+            .map_err(|e| {
+                dbg!("{}", e);
+                RoomError::FailedToSend
+            })?;
+    }
+
+    if options.encrypted {
+        conn.hset::<_, _, _, ()>(gen_meta_key(room), "encrypted", 1)
+            .await
+            .map_err(|e| {
+                dbg!("{}", e);
+                RoomError::FailedToSend
+            })?;
+    }
+
+    let start = Message::new(
+        MessageKind::System,
+        Some(room),
+        None,
+        0,
+        "Start of chat\n".to_owned(),
+    );
+
+    // 0-0 isn't a valid stream id, so this is the lowest there is
+    conn.xadd::<_, _, _, _, ()>(key, "0-1", &Entry::fields(&start))
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    // Only >find needs it, so the room is still made if this fails
+    if let Err(e) = search::index(redis, room, None).await {
+        eprintln!("{}", e);
+    }
 
     Ok(())
 }
 
-pub async fn list(redis: &Client) -> Result<Vec<String>, RoomError> {
-    let mut conn = redis.get_async_connection().await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToConnect
-    })?;
+pub async fn check_password(
+    redis: &Pool,
+    room: &str,
+    password: Option<&str>,
+) -> Result<(), RoomError> {
+    let mut conn = redis.get();
 
-    let rooms: Vec<String> = conn.keys("room*").await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToFetch
-    })?;
+    let hash: Option<String> = conn
+        .hget(gen_meta_key(room), "password")
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })?;
 
-    Ok(rooms)
+    match (hash, password) {
+        (None, _) => Ok(()),
+        (Some(hash), Some(password)) if verify_password(password, &hash).await => Ok(()),
+        _ => Err(RoomError::IncorrectPassword),
+    }
 }
 
-pub async fn event(
-    redis: &Client,
-    event: RoomEvent,
+pub async fn is_encrypted(redis: &Pool, room: &str) -> Result<bool, RoomError> {
+    redis
+        .get()
+        .hexists(gen_meta_key(room), "encrypted")
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })
+}
+
+// Stops `username` sending to the room for `secs`
+pub async fn mute(redis: &Pool, room: &str, username: &str, secs: u64) -> Result<(), RoomError> {
+    redis
+        .get()
+        .set_ex::<_, _, ()>(gen_mute_key(room, username), 1, secs as usize)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })
+}
+
+pub async fn unmute(redis: &Pool, room: &str, username: &str) -> Result<(), RoomError> {
+    let removed: u8 = redis
+        .get()
+        .del(gen_mute_key(room, username))
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    match removed {
+        0 => Err(RoomError::NotMuted),
+        _ => Ok(()),
+    }
+}
+
+// Fails with how long is left if `username` is muted in the room
+pub async fn check_muted(redis: &Pool, room: &str, username: &str) -> Result<(), RoomError> {
+    // -2 if there's no mute
+    let ttl: i64 = redis
+        .get()
+        .ttl(gen_mute_key(room, username))
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })?;
+
+    match ttl {
+        ttl if ttl > 0 => Err(RoomError::Muted(ttl as u64)),
+        _ => Ok(()),
+    }
+}
+
+// Remembers that `new` was called `old` when they were in the room, or
+// whatever they were first called if they've changed names before
+pub async fn set_alias(redis: &Pool, room: &str, old: &str, new: &str) -> Result<(), RoomError> {
+    let script = Script::new(
+        r"
+        local original = redis.call('HGET', KEYS[1], ARGV[1]) or ARGV[1]
+        redis.call('HDEL', KEYS[1], ARGV[1])
+        if original ~= ARGV[2] then
+            redis.call('HSET', KEYS[1], ARGV[2], original)
+        end
+        return 0
+        ",
+    );
+
+    script
+        .key(gen_aliases_key(room))
+        .arg(old)
+        .arg(new)
+        .invoke_async::<_, ()>(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })
+}
+
+// Once they've left, the name they had isn't needed to tell who they are
+pub async fn remove_alias(redis: &Pool, room: &str, username: &str) -> Result<(), RoomError> {
+    redis
+        .get()
+        .hdel::<_, _, ()>(gen_aliases_key(room), username)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })
+}
+
+// What members who changed name were called when they joined, by current name
+pub async fn aliases(redis: &Pool, room: &str) -> Result<HashMap<String, String>, RoomError> {
+    redis
+        .get()
+        .hgetall(gen_aliases_key(room))
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })
+}
+
+// Creates a single use token that lets `username` into a private room
+pub async fn invite(
+    redis: &Pool,
     room: &str,
     username: &str,
+    ttl_secs: usize,
 ) -> Result<String, RoomError> {
-    let mut conn = redis.get_async_connection().await.map_err(|e| {
+    let mut conn = redis.get();
+
+    let private: bool = conn
+        .hexists(gen_meta_key(room), "private")
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })?;
+
+    if !private {
+        Err(RoomError::NotInviteOnly)?;
+    }
+
+    let token = format!("{:016x}{:016x}", OsRng.next_u64(), OsRng.next_u64());
+
+    conn.set_ex::<_, _, ()>(gen_invite_key(room, &token), username, ttl_secs)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    Ok(token)
+}
+
+// Private rooms let in their owner, anyone who has used an invite before,
+// and anyone with a valid invite token, which is used up
+pub async fn check_invited(
+    redis: &Pool,
+    room: &str,
+    username: &str,
+    token: Option<&str>,
+) -> Result<(), RoomError> {
+    let mut conn = redis.get();
+
+    let (private, owner): (Option<String>, Option<String>) = redis::cmd("HMGET")
+        .arg(gen_meta_key(room))
+        .arg("private")
+        .arg("owner")
+        .query_async(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })?;
+
+    if private.is_none() || owner.as_deref() == Some(username) {
+        return Ok(());
+    }
+
+    let member: bool = conn
+        .sismember(gen_members_key(room), username)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })?;
+
+    if member {
+        return Ok(());
+    }
+
+    let token = token.ok_or(RoomError::NotInvited)?;
+
+    // Checking and using the token in one step keeps it single use
+    let script = Script::new(
+        r"
+        if redis.call('GET', KEYS[1]) == ARGV[1] then
+            redis.call('DEL', KEYS[1])
+            return redis.call('SADD', KEYS[2], ARGV[1])
+        end
+        return -1
+        ",
+    );
+
+    let added: isize = script
+        .key(gen_invite_key(room, token))
+        .key(gen_members_key(room))
+        .arg(username)
+        .invoke_async(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    if added == -1 {
+        Err(RoomError::NotInvited)?;
+    }
+
+    Ok(())
+}
+
+// Asks to be let into a private room, for its owner to decide. False if
+// they'd already asked.
+pub async fn request(redis: &Pool, room: &str, username: &str) -> Result<bool, RoomError> {
+    let added: usize = redis::cmd("ZADD")
+        .arg(gen_requests_key(room))
+        .arg("NX")
+        .arg(get_time_in_ms())
+        .arg(username)
+        .query_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    Ok(added == 1)
+}
+
+// Who's waiting to be let in and since when, oldest first
+pub async fn requests(redis: &Pool, room: &str) -> Result<Vec<(String, isize)>, RoomError> {
+    redis
+        .get()
+        .zrange_withscores(gen_requests_key(room), 0, -1)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })
+}
+
+// Takes the request out, letting them in like a used invite if `approve`
+pub async fn decide(
+    redis: &Pool,
+    room: &str,
+    username: &str,
+    approve: bool,
+) -> Result<(), RoomError> {
+    let script = Script::new(
+        r"
+        if redis.call('ZREM', KEYS[1], ARGV[1]) == 0 then
+            return -1
+        end
+        if ARGV[2] == '1' then
+            redis.call('SADD', KEYS[2], ARGV[1])
+        end
+        return 1
+        ",
+    );
+
+    let decided: isize = script
+        .key(gen_requests_key(room))
+        .key(gen_members_key(room))
+        .arg(username)
+        .arg(approve as u8)
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    if decided == -1 {
+        Err(RoomError::NoRequest)?;
+    }
+
+    Ok(())
+}
+
+pub async fn exists(redis: &Pool, room: &str) -> Result<bool, RoomError> {
+    let mut conn = redis.get();
+
+    let exists: u8 = conn.exists(gen_key(room)).await.map_err(|e| {
         dbg!("{}", e);
-        RoomError::FailedToConnect
+        RoomError::FailedToCheckRoomExists
     })?;
 
-    let key = gen_key(room);
-    let score = get_time_in_ms();
+    Ok(exists == 1)
+}
 
-    let msg = match event {
-        RoomEvent::Chat(message) => {
-            let chat = gen_chat(username, &message);
+pub async fn delete(redis: &Pool, room: &str) -> Result<(), RoomError> {
+    let mut conn = redis.get();
 
-            conn.zadd(key, &chat, score).await.map_err(|e| {
-                dbg!("{}", e);
-                RoomError::FailedToSend
-            })?;
+    conn.del::<_, ()>(&gen_all_keys(room)[..])
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
 
-            chat
-        }
-        RoomEvent::Join => {
-            let join = gen_join_msg(username);
+    if let Err(e) = search::remove(redis, room).await {
+        eprintln!("{}", e);
+    }
 
-            conn.zadd(key, &join, score).await.map_err(|e| {
-                dbg!("{}", e);
-                RoomError::FailedToSend
-            })?;
+    canonical::release(redis, Kind::Room, room)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })
+}
 
-            join
-        }
-        RoomEvent::Leave => {
-            let leave = gen_leave_msg(username);
+// Offers the room to `to`, kept in its meta hash until they accept. Only the
+// owner can, and a new offer replaces the last.
+pub async fn offer(redis: &Pool, room: &str, owner: &str, to: &str) -> Result<(), RoomError> {
+    let script = Script::new(
+        r"
+        if redis.call('HGET', KEYS[1], 'owner') ~= ARGV[1] then
+            return 0
+        end
+        redis.call('HSET', KEYS[1], 'offer', ARGV[2])
+        return 1
+        ",
+    );
+
+    let offered: u8 = script
+        .key(gen_meta_key(room))
+        .arg(owner)
+        .arg(to)
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    if offered == 0 {
+        Err(RoomError::NotOwner)?;
+    }
+
+    Ok(())
+}
+
+// Makes `username` the owner if the room was offered to them, returning who
+// owned it before
+pub async fn accept(redis: &Pool, room: &str, username: &str) -> Result<String, RoomError> {
+    let script = Script::new(
+        r"
+        if redis.call('HGET', KEYS[1], 'offer') ~= ARGV[1] then
+            return false
+        end
+        local owner = redis.call('HGET', KEYS[1], 'owner')
+        redis.call('HSET', KEYS[1], 'owner', ARGV[1])
+        redis.call('HDEL', KEYS[1], 'offer')
+        return owner
+        ",
+    );
+
+    let owner: Option<String> = script
+        .key(gen_meta_key(room))
+        .arg(username)
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    owner.ok_or(RoomError::NoOffer)
+}
 
-            conn.zadd(key, &leave, score).await.map_err(|e| {
+// Moves every key of the room's to `to` in one step, so it's never half
+// renamed. Anything already under `to` that isn't a room, like roles left
+// from one that expired, is cleared first. Mutes and invites have a key
+// each, so they're found with a SCAN beforehand and passed in with the rest.
+// Changing only the case, like `rust` to `Rust`, is fine even though `Rust`
+// would otherwise be taken.
+pub async fn rename(redis: &Pool, room: &str, to: &str) -> Result<(), RoomError> {
+    let recased = canonical::fold(room) == canonical::fold(to);
+    if !recased {
+        let reserved = canonical::reserve(redis, Kind::Room, to)
+            .await
+            .map_err(|e| {
                 dbg!("{}", e);
                 RoomError::FailedToSend
             })?;
+        if !reserved {
+            Err(RoomError::RoomNameTaken)?;
+        }
+    }
 
-            leave
+    let mut pairs: Vec<(String, String)> = gen_all_keys(room)
+        .into_iter()
+        .zip(gen_all_keys(to))
+        .collect();
+    let mut leftover = Vec::new();
+    for (from, moved) in [
+        (gen_mute_key(room, ""), gen_mute_key(to, "")),
+        (gen_invite_key(room, ""), gen_invite_key(to, "")),
+    ] {
+        for key in scan_prefix(redis, &from).await? {
+            let moved = format!("{}{}", moved, &key[from.len()..]);
+            pairs.push((key, moved));
         }
-    };
+    }
+    for prefix in [gen_mute_key(to, ""), gen_invite_key(to, "")] {
+        leftover.extend(scan_prefix(redis, &prefix).await?);
+    }
 
-    Ok(msg)
-}
+    // KEYS are ARGV[1] pairs to move, then what's left under `to` to clear.
+    // Mutes and invites that expired since the SCAN are skipped like any
+    // other key that's gone.
+    let script = Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[2]) == 1 then
+            return 0
+        end
+        local pairs = tonumber(ARGV[1])
+        for i = pairs * 2 + 1, #KEYS do
+            redis.call('DEL', KEYS[i])
+        end
+        for i = 1, pairs * 2, 2 do
+            if redis.call('EXISTS', KEYS[i]) == 1 then
+                redis.call('RENAME', KEYS[i], KEYS[i + 1])
+            else
+                redis.call('DEL', KEYS[i + 1])
+            end
+        end
+        return 1
+        ",
+    );
 
-pub async fn recent_msgs(redis: &Client, room: &str) -> Result<Vec<String>, RoomError> {
-    let mut conn = redis.get_async_connection().await.map_err(|e| {
-        dbg!("{}", e);
-        RoomError::FailedToConnect
-    })?;
+    let mut invocation = script.prepare_invoke();
+    invocation.arg(pairs.len());
+    for (from, to) in &pairs {
+        invocation.key(from).key(to);
+    }
+    for key in &leftover {
+        invocation.key(key);
+    }
 
-    let key = gen_key(room);
+    let renamed: u8 = invocation
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
 
-    let mut offset: u128 = conn.zcount(&key, 0, "inf").await.map_err(|e| {
-        dbg!(e);
-        RoomError::FailedToFetch
-    })?;
+    if renamed == 0 {
+        if !recased {
+            if let Err(e) = canonical::release(redis, Kind::Room, to).await {
+                eprintln!("{}", e);
+            }
+        }
+        Err(RoomError::RoomNameTaken)?;
+    }
 
-    if offset < 10 {
-        offset = 0
-    } else {
-        offset -= 10
+    // The topic came along, so it's found by that as well as its new name
+    let topic = topic(redis, to).await?;
+    let reindexed = match search::remove(redis, room).await {
+        Ok(()) => search::index(redis, to, topic.as_deref()).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = reindexed {
+        eprintln!("{}", e);
     }
 
-    let msgs: Vec<String> = conn
-        .zrangebyscore_limit(key, 0, "inf", offset as isize, 10)
+    let moved = match recased {
+        true => canonical::reserve(redis, Kind::Room, to).await.map(|_| ()),
+        false => canonical::release(redis, Kind::Room, room).await,
+    };
+    moved.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToSend
+    })
+}
+
+// Every key starting with `prefix`, walked with a cursor so Redis isn't held
+// up. Room names can't have `:` or pattern characters, see
+// `validate::namespace_name`, so a room's prefix only matches its own keys.
+async fn scan_prefix(redis: &Pool, prefix: &str) -> Result<Vec<String>, RoomError> {
+    let mut conn = redis.get();
+    let mut iter = conn
+        .scan_match::<_, String>(format!("{}*", prefix))
         .await
         .map_err(|e| {
-            dbg!(e);
+            dbg!("{}", e);
             RoomError::FailedToFetch
         })?;
 
-    Ok(msgs)
-}
+    let mut keys = Vec::new();
+    while let Some(key) = iter.next_item().await {
+        keys.push(key);
+    }
+    // SCAN can return a key more than once
+    keys.sort();
+    keys.dedup();
 
-fn gen_key(name: &str) -> String {
-    format!("room:{}", name)
+    Ok(keys)
 }
 
-fn gen_chat(username: &str, message: &str) -> String {
-    format!("{}: {}\n", username, message)
-}
+// Used for rooms nobody has been in for a while
+pub async fn expire(redis: &Pool, room: &str, secs: usize) -> Result<(), RoomError> {
+    let mut conn = redis.get();
+    let mut pipe = redis::pipe();
 
-fn gen_join_msg(username: &str) -> String {
-    format!("{} has joined the room\n", username)
+    for key in gen_all_keys(room) {
+        pipe.expire(key, secs).ignore();
+    }
+
+    pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToSend
+    })?;
+
+    Ok(())
+}
+
+// Undoes `expire`
+pub async fn persist(redis: &Pool, room: &str) -> Result<(), RoomError> {
+    let mut conn = redis.get();
+    let mut pipe = redis::pipe();
+
+    for key in gen_all_keys(room) {
+        pipe.persist(key).ignore();
+    }
+
+    pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToSend
+    })?;
+
+    Ok(())
+}
+
+pub async fn ban(redis: &Pool, room: &str, username: &str) -> Result<(), RoomError> {
+    let mut conn = redis.get();
+
+    conn.sadd::<_, _, ()>(gen_bans_key(room), username)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    Ok(())
+}
+
+pub async fn check_banned(redis: &Pool, room: &str, username: &str) -> Result<(), RoomError> {
+    let mut conn = redis.get();
+
+    let banned: bool = conn
+        .sismember(gen_bans_key(room), username)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })?;
+
+    if banned {
+        Err(RoomError::Banned)?;
+    }
+
+    Ok(())
+}
+
+// Every room's key, `room:<name>`. SCAN goes through the keyspace a bit at a
+// time, so unlike KEYS it doesn't stop Redis serving everyone else while it
+// looks, however many keys there are.
+pub async fn list(redis: &Pool) -> Result<Vec<String>, RoomError> {
+    // Keys looked at per SCAN, not rooms returned
+    const COUNT: usize = 1000;
+
+    let mut conn = redis.get();
+    let mut rooms = Vec::new();
+
+    let mut scan = redis::cmd("SCAN");
+    scan.cursor_arg(0)
+        .arg("MATCH")
+        .arg(gen_key("*"))
+        .arg("COUNT")
+        .arg(COUNT);
+
+    let mut iter: AsyncIter<String> = scan.iter_async(&mut conn).await.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToFetch
+    })?;
+
+    while let Some(room) = iter.next_item().await {
+        rooms.push(room);
+    }
+
+    // SCAN can return a key more than once
+    rooms.sort_unstable();
+    rooms.dedup();
+
+    Ok(rooms)
+}
+
+// Everything up to now counts as read, used when a user joins or leaves a room
+pub async fn mark_read(redis: &Pool, room: &str, username: &str) -> Result<(), RoomError> {
+    let mut conn = redis.get();
+
+    conn.hset::<_, _, _, ()>(gen_last_read_key(username), room, get_time_in_ms())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    Ok(())
+}
+
+// Most unread messages counted in a room. Counting walks the stream, so a
+// busy room someone hasn't been in for months stops here, shown as "999+".
+pub const MAX_UNREAD: usize = 999;
+
+// Returns each room the user has been in with the number of messages
+// since they were last there, up to one more than `MAX_UNREAD`.
+pub async fn unread(redis: &Pool, username: &str) -> Result<Vec<(String, usize)>, RoomError> {
+    let mut conn = redis.get();
+
+    let last_read: Vec<(String, isize)> =
+        conn.hgetall(gen_last_read_key(username))
+            .await
+            .map_err(|e| {
+                dbg!("{}", e);
+                RoomError::FailedToFetch
+            })?;
+
+    if last_read.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // Streams can't count a range, so it's done next to the data
+    let script = Script::new(
+        r"
+        local counts = {}
+        for i, key in ipairs(KEYS) do
+            counts[i] = #redis.call('XRANGE', key, ARGV[i + 1], '+', 'COUNT', ARGV[1])
+        end
+        return counts
+        ",
+    );
+
+    let mut invocation = script.prepare_invoke();
+    invocation.arg(MAX_UNREAD + 1);
+    for (room, ts) in &last_read {
+        invocation.key(gen_key(room)).arg(ts + 1);
+    }
+
+    let counts: Vec<usize> = invocation.invoke_async(&mut conn).await.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToFetch
+    })?;
+
+    Ok(last_read
+        .into_iter()
+        .map(|(room, _)| room)
+        .zip(counts)
+        .collect())
+}
+
+// `count` from `unread`, with the ones over `MAX_UNREAD` shown as "999+"
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::room::{describe_unread, MAX_UNREAD};
+///
+/// assert_eq!(describe_unread(12), "12");
+/// assert_eq!(describe_unread(MAX_UNREAD), "999");
+/// assert_eq!(describe_unread(MAX_UNREAD + 1), "999+");
+/// ```
+pub fn describe_unread(count: usize) -> String {
+    match count > MAX_UNREAD {
+        true => format!("{}+", MAX_UNREAD),
+        false => count.to_string(),
+    }
+}
+
+// The room's own retention limits, see `retention::Policy::with_overrides`
+pub async fn retention(redis: &Pool, room: &str) -> Result<(Option<u64>, Option<u64>), RoomError> {
+    let mut conn = redis.get();
+
+    let limits: (Option<u64>, Option<u64>) = conn
+        .hget(gen_meta_key(room), &["retention_messages", "retention_age"])
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })?;
+
+    Ok(limits)
+}
+
+pub async fn set_retention(
+    redis: &Pool,
+    room: &str,
+    limit: Limit,
+    setting: Setting,
+) -> Result<(), RoomError> {
+    let mut conn = redis.get();
+
+    let field = match limit {
+        Limit::Messages => "retention_messages",
+        Limit::Age => "retention_age",
+    };
+
+    let res = match setting {
+        Setting::Value(value) => conn.hset(gen_meta_key(room), field, value).await,
+        Setting::Off => conn.hset(gen_meta_key(room), field, 0).await,
+        Setting::Default => conn.hdel(gen_meta_key(room), field).await,
+    };
+
+    res.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToSend
+    })
+}
+
+// Drops all but the latest `max_messages` and anything older than
+// `min_time`, returning how many messages were removed. MINID needs Redis 6.2.
+pub async fn trim(
+    redis: &Pool,
+    room: &str,
+    max_messages: Option<u64>,
+    min_time: Option<isize>,
+) -> Result<usize, RoomError> {
+    let script = Script::new(
+        r"
+        local trimmed = 0
+        if ARGV[1] ~= '' then
+            trimmed = trimmed + redis.call('XTRIM', KEYS[1], 'MAXLEN', ARGV[1])
+        end
+        if ARGV[2] ~= '' then
+            trimmed = trimmed + redis.call('XTRIM', KEYS[1], 'MINID', ARGV[2])
+        end
+
+        -- Edits and reactions of trimmed messages aren't needed anymore
+        if trimmed > 0 then
+            for i = 2, 3 do
+                for _, id in ipairs(redis.call('HKEYS', KEYS[i])) do
+                    if #redis.call('XRANGE', KEYS[1], id, id) == 0 then
+                        redis.call('HDEL', KEYS[i], id)
+                    end
+                end
+            end
+            for _, reactor in ipairs(redis.call('SMEMBERS', KEYS[4])) do
+                local id = string.match(reactor, '^(%S+)')
+                if #redis.call('XRANGE', KEYS[1], id, id) == 0 then
+                    redis.call('SREM', KEYS[4], reactor)
+                end
+            end
+        end
+
+        return trimmed
+        ",
+    );
+
+    let trimmed: usize = script
+        .key(gen_key(room))
+        .key(gen_edits_key(room))
+        .key(gen_reactions_key(room))
+        .key(gen_reactors_key(room))
+        .arg(max_messages.map(|n| n.to_string()).unwrap_or_default())
+        .arg(min_time.map(|t| t.to_string()).unwrap_or_default())
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    Ok(trimmed)
+}
+
+// Removes every message sent before `min_time`, returning their ids. Unlike
+// `trim`, which can't say what it removed, these have to be known so clients
+// can be told to drop them.
+pub async fn disappear(
+    redis: &Pool,
+    room: &str,
+    min_time: isize,
+) -> Result<Vec<String>, RoomError> {
+    let script = Script::new(
+        r"
+        local ids = {}
+        for _, entry in ipairs(redis.call('XRANGE', KEYS[1], '-', ARGV[1])) do
+            local id = entry[1]
+            table.insert(ids, id)
+            redis.call('XDEL', KEYS[1], id)
+            redis.call('HDEL', KEYS[2], id)
+            redis.call('HDEL', KEYS[3], id)
+        end
+
+        if #ids > 0 then
+            for _, reactor in ipairs(redis.call('SMEMBERS', KEYS[4])) do
+                local id = string.match(reactor, '^(%S+)')
+                if #redis.call('XRANGE', KEYS[1], id, id) == 0 then
+                    redis.call('SREM', KEYS[4], reactor)
+                end
+            end
+        end
+
+        return ids
+        ",
+    );
+
+    script
+        .key(gen_key(room))
+        .key(gen_edits_key(room))
+        .key(gen_reactions_key(room))
+        .key(gen_reactors_key(room))
+        // Inclusive, so everything up to the millisecond before
+        .arg(min_time - 1)
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })
+}
+
+// How long messages last in the room, None if they don't disappear
+pub async fn disappearing(redis: &Pool, room: &str) -> Result<Option<u64>, RoomError> {
+    let secs = meta_fields(redis, &[room], "disappear_secs")
+        .await?
+        .pop()
+        .flatten();
+
+    Ok(secs.and_then(|secs| secs.parse().ok()))
+}
+
+// 0 stops messages disappearing
+pub async fn set_disappearing(redis: &Pool, room: &str, secs: u64) -> Result<(), RoomError> {
+    let mut conn = redis.get();
+
+    let res = match secs {
+        0 => conn.hdel(gen_meta_key(room), "disappear_secs").await,
+        secs => conn.hset(gen_meta_key(room), "disappear_secs", secs).await,
+    };
+
+    res.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToSend
+    })
+}
+
+pub async fn set_topic(redis: &Pool, room: &str, topic: &str) -> Result<(), RoomError> {
+    let mut conn = redis.get();
+
+    conn.hset::<_, _, _, ()>(gen_meta_key(room), "topic", topic)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    if let Err(e) = search::index(redis, room, Some(topic)).await {
+        eprintln!("{}", e);
+    }
+
+    Ok(())
+}
+
+pub async fn topic(redis: &Pool, room: &str) -> Result<Option<String>, RoomError> {
+    Ok(topics(redis, &[room]).await?.pop().flatten())
+}
+
+// The room's language as a tag like `ar` or `pt-BR`, None to remove it
+pub async fn set_lang(redis: &Pool, room: &str, lang: Option<&str>) -> Result<(), RoomError> {
+    let mut conn = redis.get();
+
+    let res = match lang {
+        Some(lang) => conn.hset(gen_meta_key(room), "lang", lang).await,
+        None => conn.hdel(gen_meta_key(room), "lang").await,
+    };
+
+    res.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToSend
+    })
+}
+
+pub async fn lang(redis: &Pool, room: &str) -> Result<Option<String>, RoomError> {
+    Ok(meta_fields(redis, &[room], "lang").await?.pop().flatten())
+}
+
+// Looks up the topic of each room in a single round trip
+pub async fn topics(redis: &Pool, rooms: &[&str]) -> Result<Vec<Option<String>>, RoomError> {
+    meta_fields(redis, rooms, "topic").await
+}
+
+pub async fn owners(redis: &Pool, rooms: &[&str]) -> Result<Vec<Option<String>>, RoomError> {
+    meta_fields(redis, rooms, "owner").await
+}
+
+async fn meta_fields(
+    redis: &Pool,
+    rooms: &[&str],
+    field: &str,
+) -> Result<Vec<Option<String>>, RoomError> {
+    if rooms.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut conn = redis.get();
+    let mut pipe = redis::pipe();
+
+    for room in rooms {
+        pipe.hget(gen_meta_key(room), field);
+    }
+
+    let values: Vec<Option<String>> = pipe.query_async(&mut conn).await.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToFetch
+    })?;
+
+    Ok(values)
+}
+
+// When each room last had a message, join or leave, from the id of its
+// latest entry. None for rooms that have been trimmed empty.
+pub async fn last_activity(redis: &Pool, rooms: &[&str]) -> Result<Vec<Option<isize>>, RoomError> {
+    if rooms.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut conn = redis.get();
+    let mut pipe = redis::pipe();
+
+    for room in rooms {
+        pipe.xrevrange_count(gen_key(room), "+", "-", 1);
+    }
+
+    let replies: Vec<StreamRangeReply> = pipe.query_async(&mut conn).await.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToFetch
+    })?;
+
+    Ok(replies
+        .iter()
+        .map(|reply| reply.ids.first().and_then(|entry| id_timestamp(&entry.id)))
+        .collect())
+}
+
+pub async fn event(
+    redis: &Pool,
+    event: RoomEvent,
+    room: &str,
+    username: &str,
+    authenticated: bool,
+) -> Result<Message, RoomError> {
+    // Only account holders can write to a room's history. Anyone already in
+    // the room was logged in to join, so their leaving or changing name is
+    // recorded even if they're a guest by now.
+    if !authenticated && !matches!(event, RoomEvent::Leave | RoomEvent::Rename(_)) {
+        Err(RoomError::NotAuthenticated)?;
+    }
+
+    let mut conn = redis.get();
+
+    let key = gen_key(room);
+    // Replaced by the time in the stream id once it's added
+    let score = get_time_in_ms();
+
+    let mut msg = match event {
+        RoomEvent::Chat(message) => Message::new(
+            MessageKind::Chat,
+            Some(room),
+            Some(username),
+            score,
+            message,
+        ),
+        RoomEvent::Signed(message) => Message::new(
+            MessageKind::Chat,
+            Some(room),
+            Some(username),
+            score,
+            message,
+        )
+        .verified(),
+        RoomEvent::Reply(parent, message) => {
+            let entry = find(redis, room, &parent).await?;
+            let quote = gen_quote(&entry.text());
+
+            Message::new(
+                MessageKind::Chat,
+                Some(room),
+                Some(username),
+                score,
+                message,
+            )
+            .with_reply(Some(parent), quote)
+        }
+        RoomEvent::Join => {
+            let join = gen_join_msg(username);
+            Message::new(MessageKind::Join, Some(room), Some(username), score, join)
+        }
+        RoomEvent::Leave => {
+            let leave = gen_leave_msg(username);
+            Message::new(MessageKind::Leave, Some(room), Some(username), score, leave)
+        }
+        // The message is about the user being removed rather than the owner
+        RoomEvent::Kick(target) => {
+            let kick = gen_kick_msg(&target, username);
+            Message::new(MessageKind::Leave, Some(room), Some(&target), score, kick)
+        }
+        RoomEvent::Ban(target) => {
+            let ban = gen_ban_msg(&target, username);
+            Message::new(MessageKind::Leave, Some(room), Some(&target), score, ban)
+        }
+        RoomEvent::Approve(target) => {
+            let approve = gen_approve_msg(&target, username);
+            Message::new(
+                MessageKind::System,
+                Some(room),
+                Some(&target),
+                score,
+                approve,
+            )
+        }
+        RoomEvent::Topic(topic) => {
+            let topic = gen_topic_msg(username, &topic);
+            Message::new(MessageKind::Topic, Some(room), Some(username), score, topic)
+        }
+        RoomEvent::Broadcast(text) => {
+            let broadcast = gen_broadcast_msg(username, &text);
+            Message::new(
+                MessageKind::System,
+                Some(room),
+                Some(username),
+                score,
+                broadcast,
+            )
+        }
+        RoomEvent::RoomRename(old) => Message::new(
+            MessageKind::RoomRename,
+            Some(room),
+            Some(username),
+            score,
+            old,
+        ),
+        RoomEvent::Rename(old) => {
+            let rename = gen_rename_msg(&old, username);
+            Message::new(
+                MessageKind::Rename,
+                Some(room),
+                Some(username),
+                score,
+                rename,
+            )
+        }
+    };
+
+    // Chat is tagged with the language it was sent in. Not having it isn't
+    // worth losing the message over, especially if Redis is down and it
+    // could be buffered.
+    if msg.kind == MessageKind::Chat {
+        msg.lang = lang(redis, room).await.unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            None
+        });
+    }
+
+    let id: String = match conn.xadd(key, "*", &Entry::fields(&msg)).await {
+        Ok(id) => id,
+        // Still delivered, but without an id until it's saved
+        Err(e) if outbox::is_outage(&e) && redis.outbox().push(room, &msg) => {
+            eprintln!("Buffering message for {}: {}", room, e);
+            return Ok(msg);
+        }
+        Err(e) => {
+            dbg!("{}", e);
+            return Err(RoomError::FailedToSend);
+        }
+    };
+
+    let mut msg = msg.with_id(id);
+    msg.timestamp = msg.id.as_deref().and_then(id_timestamp).unwrap_or(score);
+
+    if msg.kind == MessageKind::Chat {
+        if let Err(e) = stats::record_message(redis, room, username).await {
+            eprintln!("{}", e);
+        }
+    }
+
+    webhook::notify(redis, room, &msg);
+    federation::notify(redis, room, &msg);
+
+    Ok(msg)
+}
+
+// Replaces the text of one of `username`'s chat messages. Stream entries
+// can't be changed in place, so the new message goes in the room's edits
+// hash and is used instead of the original whenever it's read.
+pub async fn edit(
+    redis: &Pool,
+    room: &str,
+    id: &str,
+    username: &str,
+    body: String,
+) -> Result<Message, RoomError> {
+    let entry = find_own(redis, room, id, username).await?;
+    let timestamp = id_timestamp(id).ok_or(RoomError::MessageNotFound)?;
+
+    // The signature was for what was sent
+    if entry.is_signed() {
+        Err(RoomError::Signed)?;
+    }
+
+    let mut edited = Message::new(
+        MessageKind::Chat,
+        Some(room),
+        Some(username),
+        timestamp,
+        body,
+    )
+    .with_id(id.to_owned());
+
+    // Replies keep what they were replying to
+    if let Some(quote) = entry.quote() {
+        edited = edited.with_reply(None, quote.to_owned());
+    }
+    let [_, (_, saved)] = Entry::fields(&edited);
+
+    // Only if it wasn't deleted since we looked
+    let script = Script::new(
+        r"
+        if #redis.call('XRANGE', KEYS[1], ARGV[1], ARGV[1]) == 0 then
+            return -1
+        end
+        return redis.call('HSET', KEYS[2], ARGV[1], ARGV[2])
+        ",
+    );
+
+    let added: isize = script
+        .key(gen_key(room))
+        .key(gen_edits_key(room))
+        .arg(id)
+        .arg(saved)
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    if added == -1 {
+        Err(RoomError::MessageNotFound)?;
+    }
+
+    Ok(Message::new(
+        MessageKind::Edit,
+        Some(room),
+        Some(username),
+        get_time_in_ms(),
+        edited.body,
+    )
+    .with_id(id.to_owned()))
+}
+
+// Adds `username`'s `emoji` to a message, or takes it back if they'd already
+// reacted with it. Each message's counts are kept together as one field, so
+// history can fetch them like edits.
+pub async fn react(
+    redis: &Pool,
+    room: &str,
+    id: &str,
+    username: &str,
+    emoji: &str,
+) -> Result<Message, RoomError> {
+    id_timestamp(id).ok_or(RoomError::MessageNotFound)?;
+
+    let script = Script::new(
+        r"
+        if #redis.call('XRANGE', KEYS[1], ARGV[1], ARGV[1]) == 0 then
+            return -1
+        end
+
+        local reactor = ARGV[1] .. ' ' .. ARGV[2] .. ' ' .. ARGV[3]
+        local delta = 1
+        if redis.call('SADD', KEYS[3], reactor) == 0 then
+            redis.call('SREM', KEYS[3], reactor)
+            delta = -1
+        end
+
+        local counts, count, found = {}, 0, false
+        local current = redis.call('HGET', KEYS[2], ARGV[1]) or ''
+        for emoji, n in string.gmatch(current, '(%S+) (%d+)') do
+            n = tonumber(n)
+            if emoji == ARGV[2] then
+                n, count, found = n + delta, n + delta, true
+            end
+            if n > 0 then
+                table.insert(counts, emoji .. ' ' .. n)
+            end
+        end
+        if not found and delta == 1 then
+            table.insert(counts, ARGV[2] .. ' 1')
+            count = 1
+        end
+
+        if #counts == 0 then
+            redis.call('HDEL', KEYS[2], ARGV[1])
+        else
+            redis.call('HSET', KEYS[2], ARGV[1], table.concat(counts, ' '))
+        end
+        return count
+        ",
+    );
+
+    let count: isize = script
+        .key(gen_key(room))
+        .key(gen_reactions_key(room))
+        .key(gen_reactors_key(room))
+        .arg(id)
+        .arg(emoji)
+        .arg(username)
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    if count == -1 {
+        Err(RoomError::MessageNotFound)?;
+    }
+
+    Ok(Message::new(
+        MessageKind::Reaction,
+        Some(room),
+        Some(username),
+        get_time_in_ms(),
+        format!("{} {}", emoji, count),
+    )
+    .with_id(id.to_owned()))
+}
+
+// Removes one of `username`'s chat messages
+pub async fn delete_msg(
+    redis: &Pool,
+    room: &str,
+    id: &str,
+    username: &str,
+) -> Result<Message, RoomError> {
+    find_own(redis, room, id, username).await?;
+
+    let (removed, _): (u8, u8) = redis::pipe()
+        .atomic()
+        .xdel(gen_key(room), &[id])
+        .hdel(gen_edits_key(room), id)
+        .hdel(gen_reactions_key(room), id)
+        .ignore()
+        .query_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    if removed == 0 {
+        Err(RoomError::MessageNotFound)?;
+    }
+
+    Ok(Message::new(
+        MessageKind::Delete,
+        Some(room),
+        Some(username),
+        get_time_in_ms(),
+        String::new(),
+    )
+    .with_id(id.to_owned()))
+}
+
+// Looks up a message by id, as it is now
+async fn find(redis: &Pool, room: &str, id: &str) -> Result<Entry, RoomError> {
+    // Anything else would be an error from XRANGE rather than a miss
+    id_timestamp(id).ok_or(RoomError::MessageNotFound)?;
+
+    let mut conn = redis.get();
+
+    let reply: StreamRangeReply = conn.xrange(gen_key(room), id, id).await.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToFetch
+    })?;
+
+    let mut entries = read_entries(&mut conn, room, reply).await?;
+
+    entries
+        .pop()
+        .map(|(_, entry)| entry)
+        .ok_or(RoomError::MessageNotFound)
+}
+
+// Like `find`, but only if `username` sent the message
+async fn find_own(redis: &Pool, room: &str, id: &str, username: &str) -> Result<Entry, RoomError> {
+    let entry = find(redis, room, id).await?;
+
+    if entry.author() != Some(username) {
+        Err(RoomError::NotAuthor)?;
+    }
+
+    Ok(entry)
+}
+
+pub async fn recent_msgs(
+    redis: &Pool,
+    room: &str,
+    count: usize,
+    kinds: &Kinds,
+) -> Result<Vec<Message>, RoomError> {
+    history(redis, room, count, None, kinds).await
+}
+
+// Fetches up to `count` messages older than `before` (or the latest if
+// `before` is None), oldest first. Messages of a kind `kinds` doesn't keep
+// are left out and don't count towards `count`.
+pub async fn history(
+    redis: &Pool,
+    room: &str,
+    count: usize,
+    before: Option<isize>,
+    kinds: &Kinds,
+) -> Result<Vec<Message>, RoomError> {
+    let mut conn = redis.get();
+
+    // A bare time as the end covers every id in that millisecond, so one
+    // less keeps paging from repeating the oldest message
+    let mut end = match before {
+        Some(before) => (before - 1).to_string(),
+        None => "+".to_owned(),
+    };
+    let mut msgs = Vec::new();
+
+    while msgs.len() < count {
+        let reply: StreamRangeReply = conn
+            .xrevrange_count(gen_key(room), &end, "-", count - msgs.len())
+            .await
+            .map_err(|e| {
+                dbg!(e);
+                RoomError::FailedToFetch
+            })?;
+
+        // `(` ends the next page before the last id rather than on it
+        let last = match reply.ids.last() {
+            Some(entry) => format!("({}", entry.id),
+            None => break,
+        };
+        let full = reply.ids.len() == count - msgs.len();
+
+        for (id, entry) in read_entries(&mut conn, room, reply).await? {
+            if kinds.keeps(entry.kind()) {
+                msgs.push((id, entry));
+            }
+        }
+
+        // Without anything to skip the first page is all there is
+        if !full || *kinds == Kinds::All {
+            break;
+        }
+        end = last;
+    }
+    msgs.reverse();
+
+    add_reactions(&mut conn, room, msgs).await
+}
+
+// Fetches up to `count` messages newer than `after`, oldest first
+pub async fn since(
+    redis: &Pool,
+    room: &str,
+    after: isize,
+    count: usize,
+) -> Result<Vec<Message>, RoomError> {
+    let mut conn = redis.get();
+
+    let reply: StreamRangeReply = conn
+        .xrange_count(gen_key(room), after + 1, "+", count)
+        .await
+        .map_err(|e| {
+            dbg!(e);
+            RoomError::FailedToFetch
+        })?;
+
+    let msgs = read_entries(&mut conn, room, reply).await?;
+
+    add_reactions(&mut conn, room, msgs).await
+}
+
+// What a room has been up to, for >digest
+#[derive(Debug, Default, PartialEq)]
+pub struct Digest {
+    pub messages: usize,
+    // Who sent the most, busiest first
+    pub authors: Vec<(String, usize)>,
+    pub first: Option<isize>,
+    pub last: Option<isize>,
+}
+
+// Fetches up to `count` entries after the one with id `after`, or from the
+// start, oldest first. These are ids and text as shown to text clients, with
+// edits applied, for reading a whole room a page at a time.
+pub async fn entries(
+    redis: &Pool,
+    room: &str,
+    after: Option<&str>,
+    count: usize,
+) -> Result<Vec<(String, String)>, RoomError> {
+    let mut conn = redis.get();
+
+    // `(` starts after the id rather than on it
+    let start = match after {
+        Some(id) => format!("({}", id),
+        None => "-".to_owned(),
+    };
+
+    let reply: StreamRangeReply = conn
+        .xrange_count(gen_key(room), start, "+", count)
+        .await
+        .map_err(|e| {
+            dbg!(e);
+            RoomError::FailedToFetch
+        })?;
+
+    let entries = read_entries(&mut conn, room, reply).await?;
+
+    Ok(entries
+        .into_iter()
+        .map(|(id, entry)| (id, entry.text()))
+        .collect())
+}
+
+// Summarises chat messages newer than `after`, going by the times in their
+// ids. Joins, topics and other notices aren't counted.
+pub async fn digest(redis: &Pool, room: &str, after: isize) -> Result<Digest, RoomError> {
+    const PAGE: usize = 500;
+
+    let mut conn = redis.get();
+    let mut digest = Digest::default();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut start = after.to_string();
+
+    loop {
+        let reply: StreamRangeReply = conn
+            .xrange_count(gen_key(room), &start, "+", PAGE)
+            .await
+            .map_err(|e| {
+                dbg!(e);
+                RoomError::FailedToFetch
+            })?;
+
+        for stream_entry in &reply.ids {
+            let entry = Entry::from_stream(stream_entry);
+            let author = match entry.author() {
+                Some(author) => author,
+                None => continue,
+            };
+
+            let timestamp = id_timestamp(&stream_entry.id);
+            digest.messages += 1;
+            digest.first = digest.first.or(timestamp);
+            digest.last = timestamp.or(digest.last);
+            *counts.entry(author.to_owned()).or_default() += 1;
+        }
+
+        // `(` starts the next page after the last id rather than on it
+        match reply.ids.last() {
+            Some(entry) if reply.ids.len() == PAGE => start = format!("({}", entry.id),
+            _ => break,
+        }
+    }
+
+    digest.authors = counts.into_iter().collect();
+    digest
+        .authors
+        .sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+
+    Ok(digest)
+}
+
+// Converts a room that still keeps its messages in a sorted set into a
+// stream, keeping their order. Returns whether there was anything to do.
+pub async fn migrate(redis: &Pool, room: &str) -> Result<bool, RoomError> {
+    // Entries get the old score as their time. Members may start with the
+    // ULID they were given before streams, which the stream id replaces.
+    let script = Script::new(
+        r"
+        if redis.call('TYPE', KEYS[1]).ok ~= 'zset' then
+            return 0
+        end
+
+        local members = redis.call('ZRANGE', KEYS[1], 0, -1, 'WITHSCORES')
+        redis.call('DEL', KEYS[2])
+
+        local last, seq = -1, 0
+        for i = 1, #members, 2 do
+            local text, score = members[i], tonumber(members[i + 1])
+
+            if score == last then
+                seq = seq + 1
+            else
+                last, seq = score, 0
+            end
+
+            local ulid, rest = string.match(text, '^(%w+) (.*)$')
+            if ulid and #ulid == 26 then
+                text = rest
+            end
+
+            local id = string.format('%.0f-%d', score, score == 0 and seq + 1 or seq)
+            redis.call('XADD', KEYS[2], id, 'text', text)
+        end
+
+        redis.call('RENAME', KEYS[2], KEYS[1])
+        return 1
+        ",
+    );
+
+    let migrated: u8 = script
+        .key(gen_key(room))
+        .key(format!("migrate:{}", room))
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToSend
+        })?;
+
+    Ok(migrated == 1)
+}
+
+// Replaces everything `username` said in the room, and quotes of it, with
+// `entry::DELETED`, returning how many entries changed. Stream entries can't
+// be rewritten, so like `migrate` the whole stream is copied to a new one
+// with the same ids in a Lua script, swapping in the redacted entries, and
+// takes its place. Their edits go too, along with their role in the room
+// and their place among its participants in `stats`.
+pub async fn redact(redis: &Pool, room: &str, username: &str) -> Result<usize, RoomError> {
+    const PAGE: usize = 500;
+
+    let mut conn = redis.get();
+    let mut redacted = Vec::new();
+    let mut edits = redis::pipe();
+    edits.atomic();
+    let mut start = "-".to_owned();
+
+    loop {
+        let reply: StreamRangeReply = conn
+            .xrange_count(gen_key(room), &start, "+", PAGE)
+            .await
+            .map_err(|e| {
+                dbg!(e);
+                RoomError::FailedToFetch
+            })?;
+
+        if reply.ids.is_empty() {
+            break;
+        }
+
+        let ids: Vec<&str> = reply.ids.iter().map(|entry| entry.id.as_str()).collect();
+        let page_edits: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(gen_edits_key(room))
+            .arg(&ids)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| {
+                dbg!("{}", e);
+                RoomError::FailedToFetch
+            })?;
+
+        for (stream_entry, edit) in reply.ids.iter().zip(page_edits) {
+            let id = &stream_entry.id;
+            let timestamp = id_timestamp(id).unwrap_or_default();
+            let entry = Entry::from_stream(stream_entry);
+
+            if let Some(msg) = entry.redact(room, timestamp, username) {
+                redacted.push((id.clone(), Entry::fields(&msg)));
+            }
+
+            // Edits have the whole message again, quote included
+            let edit = match edit {
+                Some(edit) => Entry::parse(Some(edit.clone()), Some(edit)),
+                None => continue,
+            };
+            if entry.author() == Some(username) {
+                edits.hdel(gen_edits_key(room), id).ignore();
+            } else if let Some(msg) = edit.redact(room, timestamp, username) {
+                let [_, (_, saved)] = Entry::fields(&msg);
+                edits.hset(gen_edits_key(room), id, saved).ignore();
+            }
+        }
+
+        // `(` starts the next page after the last id rather than on it
+        match reply.ids.last() {
+            Some(entry) if reply.ids.len() == PAGE => start = format!("({}", entry.id),
+            _ => break,
+        }
+    }
+
+    edits
+        .hdel(permissions::gen_key(Some(room)), username)
+        .ignore();
+    edits
+        .hdel(stats::gen_participants_key(room), username)
+        .ignore();
+    edits.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToSend
+    })?;
+
+    if redacted.is_empty() {
+        return Ok(0);
+    }
+
+    // Entries deleted since they were read are left out rather than put back
+    let script = Script::new(
+        r"
+        local redacted = {}
+        for i = 1, #ARGV, 5 do
+            redacted[ARGV[i]] = {unpack(ARGV, i + 1, i + 4)}
+        end
+
+        local ttl = redis.call('PTTL', KEYS[1])
+        redis.call('DEL', KEYS[2])
+
+        local count = 0
+        for _, entry in ipairs(redis.call('XRANGE', KEYS[1], '-', '+')) do
+            local id, fields = entry[1], entry[2]
+            if redacted[id] then
+                fields = redacted[id]
+                count = count + 1
+            end
+            redis.call('XADD', KEYS[2], id, unpack(fields))
+        end
+
+        if redis.call('EXISTS', KEYS[2]) == 0 then
+            return 0
+        end
+        redis.call('RENAME', KEYS[2], KEYS[1])
+        if ttl > 0 then
+            redis.call('PEXPIRE', KEYS[1], ttl)
+        end
+        return count
+        ",
+    );
+
+    let mut invocation = script.prepare_invoke();
+    invocation
+        .key(gen_key(room))
+        .key(format!("redact:{}", room));
+    for (id, fields) in &redacted {
+        invocation.arg(id);
+        for (field, value) in fields {
+            invocation.arg(field).arg(value);
+        }
+    }
+
+    invocation.invoke_async(&mut conn).await.map_err(|e| {
+        dbg!("{}", e);
+        RoomError::FailedToSend
+    })
+}
+
+// Reads each entry, swapping in any edits
+async fn read_entries(
+    conn: &mut redis::aio::ConnectionManager,
+    room: &str,
+    reply: StreamRangeReply,
+) -> Result<Vec<(String, Entry)>, RoomError> {
+    if reply.ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let ids: Vec<&str> = reply.ids.iter().map(|entry| entry.id.as_str()).collect();
+
+    // HMGET always returns a list, even for one field
+    let edits: Vec<Option<String>> = redis::cmd("HMGET")
+        .arg(gen_edits_key(room))
+        .arg(&ids)
+        .query_async(conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })?;
+
+    Ok(reply
+        .ids
+        .iter()
+        .zip(edits)
+        .map(|(stream_entry, edit)| {
+            // Edits from before kinds were saved are the text they're shown as
+            let entry = match edit {
+                Some(edit) => Entry::parse(Some(edit.clone()), Some(edit)),
+                None => Entry::from_stream(stream_entry),
+            };
+            (stream_entry.id.clone(), entry)
+        })
+        .collect())
+}
+
+// Turns entries into history, with each message's reactions on a line under
+// it. They're rendered here rather than when they're saved, so how messages
+// look can change without rewriting history.
+async fn add_reactions(
+    conn: &mut redis::aio::ConnectionManager,
+    room: &str,
+    entries: Vec<(String, Entry)>,
+) -> Result<Vec<Message>, RoomError> {
+    if entries.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let ids: Vec<&str> = entries.iter().map(|(id, _)| id.as_str()).collect();
+
+    let reactions: Vec<Option<String>> = redis::cmd("HMGET")
+        .arg(gen_reactions_key(room))
+        .arg(&ids)
+        .query_async(conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            RoomError::FailedToFetch
+        })?;
+
+    Ok(entries
+        .into_iter()
+        .zip(reactions)
+        .map(|((id, entry), reactions)| {
+            let mut text = entry.text();
+            if let Some(reactions) = reactions {
+                text.push_str(&format!("  {}\n", reactions));
+            }
+            history_msg(room, id, text)
+        })
+        .collect())
+}
+
+// Written above the messages replayed on joining, so it's clear where they
+// start and how many there are. `what` says which they are, like `earlier`.
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::room;
+///
+/// assert_eq!(room::separator(20, "earlier"), "--- 20 earlier messages ---\n");
+/// assert_eq!(room::separator(1, "missed"), "--- 1 missed message ---\n");
+/// ```
+pub fn separator(count: usize, what: &str) -> String {
+    let plural = if count == 1 { "" } else { "s" };
+
+    format!("--- {} {} message{} ---\n", count, what, plural)
+}
+
+fn history_msg(room: &str, id: String, text: String) -> Message {
+    let timestamp = id_timestamp(&id).unwrap_or_default();
+
+    Message::new(MessageKind::History, Some(room), None, timestamp, text).with_id(id)
+}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::room::id_timestamp;
+///
+/// assert_eq!(id_timestamp("1674000000000-3"), Some(1674000000000));
+/// assert_eq!(id_timestamp("1674000000000"), None);
+/// ```
+pub fn id_timestamp(id: &str) -> Option<isize> {
+    let (ms, seq) = id.split_once('-')?;
+    seq.parse::<u64>().ok()?;
+
+    ms.parse().ok()
+}
+
+pub(crate) fn gen_key(name: &str) -> String {
+    format!("room:{}", name)
+}
+
+// Replies show the start of the message they're replying to
+fn gen_quote(text: &str) -> String {
+    const MAX_CHARS: usize = 50;
+
+    let line = entry::chat_line(text);
+
+    if line.chars().count() <= MAX_CHARS {
+        return line.to_owned();
+    }
+
+    let mut quote: String = line.chars().take(MAX_CHARS).collect();
+    quote.push('…');
+
+    quote
+}
+
+// Room settings live in a separate hash so they don't show up in `keys("room*")`
+pub(crate) fn gen_meta_key(name: &str) -> String {
+    format!("meta:{}", name)
+}
+
+// Every key that belongs to the room itself
+fn gen_all_keys(name: &str) -> [String; 16] {
+    [
+        gen_key(name),
+        gen_meta_key(name),
+        gen_bans_key(name),
+        gen_members_key(name),
+        gen_requests_key(name),
+        gen_edits_key(name),
+        gen_aliases_key(name),
+        gen_reactions_key(name),
+        gen_reactors_key(name),
+        webhook::gen_urls_key(name),
+        webhook::gen_bots_key(name),
+        receipts::gen_key(name),
+        permissions::gen_key(Some(name)),
+        bots::gen_key(name),
+        stats::gen_key(name),
+        stats::gen_participants_key(name),
+    ]
+}
+
+pub(crate) fn gen_last_read_key(username: &str) -> String {
+    format!("lastread:{}", username)
+}
+
+fn gen_bans_key(name: &str) -> String {
+    format!("bans:{}", name)
+}
+
+// <Message id, edited text>
+fn gen_edits_key(name: &str) -> String {
+    format!("edits:{}", name)
+}
+
+// Users let into a private room
+fn gen_members_key(name: &str) -> String {
+    format!("members:{}", name)
+}
+
+// <Name they joined as, current name>
+fn gen_aliases_key(name: &str) -> String {
+    format!("aliases:{}", name)
+}
+
+// <Message id, reactions like "👍 3 🎉 1">
+fn gen_reactions_key(name: &str) -> String {
+    format!("reactions:{}", name)
+}
+
+// "<id> <emoji> <user>" for everyone who's reacted, so nobody counts twice
+fn gen_reactors_key(name: &str) -> String {
+    format!("reactors:{}", name)
+}
+
+// Mutes expire on their own too, and `rename` finds them by their prefix
+fn gen_mute_key(name: &str, username: &str) -> String {
+    format!("mute:{}:{}", name, username)
+}
+
+// Join requests for a private room, scored by when they were made
+fn gen_requests_key(name: &str) -> String {
+    format!("requests:{}", name)
+}
+
+// Invites expire on their own, so they aren't in `gen_all_keys`. `rename`
+// finds them by their prefix.
+fn gen_invite_key(name: &str, token: &str) -> String {
+    format!("invite:{}:{}", name, token)
+}
+
+fn gen_join_msg(username: &str) -> String {
+    format!("{} has joined the room", username)
+}
+
+pub(crate) fn gen_leave_msg(username: &str) -> String {
+    format!("{} has left the room", username)
+}
+
+fn gen_kick_msg(username: &str, by: &str) -> String {
+    format!("{} was kicked from the room by {}", username, by)
+}
+
+fn gen_topic_msg(username: &str, topic: &str) -> String {
+    format!("{} set the topic to: {}", username, topic)
+}
+
+fn gen_ban_msg(username: &str, by: &str) -> String {
+    format!("{} was banned from the room by {}", username, by)
+}
+
+fn gen_approve_msg(username: &str, by: &str) -> String {
+    format!("{} was let in by {}", username, by)
+}
+
+fn gen_broadcast_msg(username: &str, text: &str) -> String {
+    format!("Broadcast from {}: {}\n", username, text)
 }
 
-fn gen_leave_msg(username: &str) -> String {
-    format!("{} has left the room\n", username)
+fn gen_rename_msg(old: &str, new: &str) -> String {
+    format!("{} is now known as {}", old, new)
 }
 
-fn get_time_in_ms() -> isize {
+pub(crate) fn get_time_in_ms() -> isize {
     let start = SystemTime::now();
     let since_epoch = start.duration_since(UNIX_EPOCH).unwrap();
 
@@ -0,0 +1,172 @@
+use std::collections::BTreeSet;
+
+use redis::{AsyncCommands, Script};
+
+use crate::canonical;
+use crate::pool::Pool;
+use crate::room;
+
+// Most rooms one >find shows
+pub const MAX_RESULTS: usize = 50;
+
+// The words in room names and topics are kept in one sorted set, as
+// `<word> <room>` members that all score 0, so ZRANGEBYLEX can find every
+// word starting with what was typed without looking at the rest. Each room
+// also has a set of its own members, to take them out again when the topic
+// changes or the room goes.
+const INDEX_KEY: &str = "search";
+
+#[derive(Debug)]
+pub enum SearchError {
+    FailedToSave,
+    FailedToFetch,
+}
+
+impl std::fmt::Display for SearchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SearchError::FailedToSave => writeln!(f, "Error: Failed to index room"),
+            SearchError::FailedToFetch => writeln!(f, "Error: Failed to search rooms"),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+// What a room is found by, folded like names so case doesn't matter. Words
+// are split on anything that isn't a letter or a digit, which includes the
+// `/` in namespaces and the `-` in `rust-beginners`.
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::search::words;
+///
+/// assert_eq!(words("dev/Rust-async"), ["async", "dev", "rust"]);
+/// assert_eq!(words("All about Rust, and rust"), ["about", "all", "and", "rust"]);
+/// assert!(words(" -- ").is_empty());
+/// ```
+pub fn words(text: &str) -> Vec<String> {
+    let folded = canonical::fold(text);
+    let words: BTreeSet<&str> = folded
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+
+    words.into_iter().map(str::to_owned).collect()
+}
+
+// Replaces whatever the room was indexed under with its name and `topic`
+pub async fn index(redis: &Pool, room: &str, topic: Option<&str>) -> Result<(), SearchError> {
+    let mut words = words(room);
+    words.extend(topic.map(self::words).unwrap_or_default());
+
+    let members: Vec<String> = words
+        .iter()
+        .map(|word| format!("{} {}", word, room))
+        .collect();
+
+    replace(redis, room, &members).await
+}
+
+// Takes the room out of the index, once it's deleted or renamed
+pub async fn remove(redis: &Pool, room: &str) -> Result<(), SearchError> {
+    replace(redis, room, &[]).await
+}
+
+async fn replace(redis: &Pool, room: &str, members: &[String]) -> Result<(), SearchError> {
+    let script = Script::new(
+        r"
+        local old = redis.call('SMEMBERS', KEYS[2])
+        if #old > 0 then
+            redis.call('ZREM', KEYS[1], unpack(old))
+        end
+        redis.call('DEL', KEYS[2])
+        for _, member in ipairs(ARGV) do
+            redis.call('ZADD', KEYS[1], 0, member)
+            redis.call('SADD', KEYS[2], member)
+        end
+        ",
+    );
+
+    script
+        .key(INDEX_KEY)
+        .key(gen_key(room))
+        .arg(members)
+        .invoke_async::<_, ()>(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            SearchError::FailedToSave
+        })
+}
+
+// Rooms with a word starting with each word of `keywords`, sorted by name.
+// Rooms that have expired since they were indexed can still be in it, so
+// callers check they exist.
+pub async fn find(redis: &Pool, keywords: &str) -> Result<Vec<String>, SearchError> {
+    let keywords = words(keywords);
+    if keywords.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // `[` includes the bound, and nothing sorts after 0xff
+    let mut pipe = redis::pipe();
+    for keyword in &keywords {
+        let min = [b"[", keyword.as_bytes()].concat();
+        let max = [b"[", keyword.as_bytes(), &[0xff]].concat();
+        pipe.zrangebylex(INDEX_KEY, min, max);
+    }
+
+    let matches: Vec<Vec<String>> = pipe.query_async(&mut redis.get()).await.map_err(|e| {
+        dbg!("{}", e);
+        SearchError::FailedToFetch
+    })?;
+
+    let mut rooms = matches.into_iter().map(|members| {
+        members
+            .into_iter()
+            .filter_map(|member| Some(member.split_once(' ')?.1.to_owned()))
+            .collect::<BTreeSet<String>>()
+    });
+
+    let first = rooms.next().unwrap_or_default();
+    let found = rooms.fold(first, |found, rooms| &found & &rooms);
+
+    Ok(found.into_iter().collect())
+}
+
+// Rooms from before the index, or from when it was lost, are indexed on
+// startup if it's missing. Returns how many were.
+pub async fn backfill(redis: &Pool) -> Result<usize, SearchError> {
+    let exists: bool = redis.get().exists(INDEX_KEY).await.map_err(|e| {
+        dbg!("{}", e);
+        SearchError::FailedToFetch
+    })?;
+    if exists {
+        return Ok(0);
+    }
+
+    let rooms = room::list(redis).await.map_err(|e| {
+        dbg!("{}", e);
+        SearchError::FailedToFetch
+    })?;
+    // Remove `room:`
+    let names: Vec<&str> = rooms.iter().map(|room| &room[5..]).collect();
+
+    let topics = room::topics(redis, &names).await.map_err(|e| {
+        dbg!("{}", e);
+        SearchError::FailedToFetch
+    })?;
+
+    for (room, topic) in names.iter().zip(&topics) {
+        index(redis, room, topic.as_deref()).await?;
+    }
+
+    Ok(names.len())
+}
+
+fn gen_key(room: &str) -> String {
+    format!("search:{}", room)
+}
@@ -0,0 +1,97 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::pool::Pool;
+
+#[derive(Debug)]
+pub enum SessionError {
+    FailedToSave,
+    FailedToFetch,
+    NotFound,
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::FailedToSave => writeln!(f, "Error: Failed to save session"),
+            SessionError::FailedToFetch => writeln!(f, "Error: Failed to fetch session"),
+            SessionError::NotFound => {
+                writeln!(f, "Error: No session with that token, it may have expired")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+// What a dropped connection was doing, so a reconnect can pick up from there
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub username: String,
+    pub authenticated: bool,
+    pub rooms: Vec<String>,
+    pub active: Option<String>,
+    // Score of the moment they left their rooms, messages after it were missed
+    pub last_seen: isize,
+}
+
+// Random 128-bit token in hex, handed to the client by >me
+pub fn gen_token() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Keeps the session for `ttl_secs`, the grace period for reconnecting
+pub async fn save(
+    redis: &Pool,
+    token: &str,
+    session: &Session,
+    ttl_secs: usize,
+) -> Result<(), SessionError> {
+    let mut conn = redis.get();
+
+    let value = serde_json::to_string(session).map_err(|e| {
+        dbg!("{}", e);
+        SessionError::FailedToSave
+    })?;
+
+    conn.set_ex::<_, _, ()>(gen_key(token), value, ttl_secs)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            SessionError::FailedToSave
+        })?;
+
+    Ok(())
+}
+
+// Fetches and deletes the session, so a token can't resume two connections
+pub async fn take(redis: &Pool, token: &str) -> Result<Session, SessionError> {
+    let mut conn = redis.get();
+
+    let key = gen_key(token);
+    let (value, _): (Option<String>, u8) = redis::pipe()
+        .atomic()
+        .get(&key)
+        .del(&key)
+        .query_async(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            SessionError::FailedToFetch
+        })?;
+
+    let value = value.ok_or(SessionError::NotFound)?;
+
+    serde_json::from_str(&value).map_err(|e| {
+        dbg!("{}", e);
+        SessionError::FailedToFetch
+    })
+}
+
+fn gen_key(token: &str) -> String {
+    format!("session:{}", token)
+}
@@ -0,0 +1,179 @@
+use base64ct::{Base64, Encoding};
+use redis::AsyncCommands;
+
+use crate::account;
+use crate::ed25519::{self, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+use crate::pool::Pool;
+use crate::room::get_time_in_ms;
+
+// How far a signed message's time can be from ours, either way, in ms
+pub const MAX_CLOCK_SKEW_MS: isize = 5 * 60 * 1000;
+
+// Signatures are remembered for longer than they're accepted, so one can't
+// be sent twice
+const SEEN_TTL_SECS: usize = 600;
+
+// Kept next to the password in the account's hash
+const FIELD: &str = "pubkey";
+
+#[derive(Debug, PartialEq)]
+pub enum SigningError {
+    FailedToSave,
+    FailedToFetch,
+    InvalidKey,
+    NoKey,
+    InvalidSignature,
+    Expired,
+    Replayed,
+}
+
+impl std::fmt::Display for SigningError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SigningError::FailedToSave => writeln!(f, "Error: Failed to save key"),
+            SigningError::FailedToFetch => writeln!(f, "Error: Failed to fetch key"),
+            SigningError::InvalidKey => {
+                writeln!(
+                    f,
+                    "Error: Keys are 32 bytes of Ed25519 public key in base64"
+                )
+            }
+            SigningError::NoKey => {
+                writeln!(
+                    f,
+                    "Error: Register a public key with >pubkey before signing"
+                )
+            }
+            SigningError::InvalidSignature => writeln!(f, "Error: Signature doesn't match"),
+            SigningError::Expired => writeln!(
+                f,
+                "Error: Signed messages have to be sent within {} minutes of signing",
+                MAX_CLOCK_SKEW_MS / 60000
+            ),
+            SigningError::Replayed => writeln!(f, "Error: That message has already been sent"),
+        }
+    }
+}
+
+impl std::error::Error for SigningError {}
+
+// What a client signs for a message to a room, so a signature can't be
+// moved to another room or sent again later
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::signing;
+///
+/// assert_eq!(signing::payload("rust", 1674002400000, "hi"), "rust\n1674002400000\nhi");
+/// ```
+pub fn payload(room: &str, timestamp: isize, text: &str) -> String {
+    format!("{}\n{}\n{}", room, timestamp, text)
+}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::signing::{self, SigningError};
+///
+/// let key = signing::parse_key("11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=").unwrap();
+/// assert_eq!(key[0], 0xd7);
+///
+/// assert_eq!(signing::parse_key("c2VjcmV0IQ=="), Err(SigningError::InvalidKey));
+/// assert_eq!(signing::parse_key("not base64"), Err(SigningError::InvalidKey));
+/// ```
+pub fn parse_key(key: &str) -> Result<[u8; PUBLIC_KEY_LEN], SigningError> {
+    decode(key).ok_or(SigningError::InvalidKey)
+}
+
+pub async fn set_key(redis: &Pool, username: &str, key: &str) -> Result<(), SigningError> {
+    parse_key(key)?;
+
+    redis
+        .get()
+        .hset::<_, _, _, ()>(account::gen_key(username), FIELD, key)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            SigningError::FailedToSave
+        })
+}
+
+pub async fn remove_key(redis: &Pool, username: &str) -> Result<(), SigningError> {
+    redis
+        .get()
+        .hdel::<_, _, ()>(account::gen_key(username), FIELD)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            SigningError::FailedToSave
+        })
+}
+
+// The key as it was registered, in base64
+pub async fn key(redis: &Pool, username: &str) -> Result<Option<String>, SigningError> {
+    redis
+        .get()
+        .hget(account::gen_key(username), FIELD)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            SigningError::FailedToFetch
+        })
+}
+
+// Checks `signature` is the user's for `text` in `room` at `timestamp`, and
+// that it hasn't been used before
+pub async fn verify(
+    redis: &Pool,
+    username: &str,
+    room: &str,
+    timestamp: isize,
+    signature: &str,
+    text: &str,
+) -> Result<(), SigningError> {
+    if (get_time_in_ms() - timestamp).abs() > MAX_CLOCK_SKEW_MS {
+        Err(SigningError::Expired)?;
+    }
+
+    let key = match key(redis, username).await? {
+        Some(key) => parse_key(&key)?,
+        None => Err(SigningError::NoKey)?,
+    };
+
+    let sig: [u8; SIGNATURE_LEN] = decode(signature).ok_or(SigningError::InvalidSignature)?;
+    let payload = payload(room, timestamp, text);
+
+    if !ed25519::verify(&key, payload.as_bytes(), &sig) {
+        Err(SigningError::InvalidSignature)?;
+    }
+
+    let first: Option<String> = redis::cmd("SET")
+        .arg(gen_seen_key(signature))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(SEEN_TTL_SECS)
+        .query_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            SigningError::FailedToFetch
+        })?;
+
+    match first {
+        Some(_) => Ok(()),
+        None => Err(SigningError::Replayed),
+    }
+}
+
+fn decode<const N: usize>(text: &str) -> Option<[u8; N]> {
+    Base64::decode_vec(text).ok()?.try_into().ok()
+}
+
+fn gen_seen_key(signature: &str) -> String {
+    format!("sig-seen:{}", signature)
+}
@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+
+// Sending the same message this many times in a row is spam
+const MAX_REPEATS: usize = 3;
+
+// As is shouting in this many messages in a row
+const MAX_SHOUTS: usize = 3;
+
+// Or sending this many messages within `BURST_WINDOW_MS`
+const BURST_COUNT: usize = 6;
+const BURST_WINDOW_MS: isize = 5000;
+
+// Messages with fewer letters than this can't be shouting, so `OK` or `LOL`
+// are fine
+const MIN_SHOUT_LETTERS: usize = 8;
+
+// Remembers enough of someone's recent messages in one room to tell whether
+// they're spamming it
+#[derive(Debug, Default)]
+pub struct Detector {
+    // Time and body of their latest messages, newest last
+    recent: VecDeque<(isize, String)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Reason {
+    Repeats,
+    Shouting,
+    Burst,
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::Repeats => write!(f, "sending the same message over and over"),
+            Reason::Shouting => write!(f, "writing in capitals"),
+            Reason::Burst => write!(f, "sending too many messages at once"),
+        }
+    }
+}
+
+impl Detector {
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::spam::{Detector, Reason};
+    ///
+    /// let mut detector = Detector::default();
+    ///
+    /// assert_eq!(detector.check("buy now", 0), None);
+    /// assert_eq!(detector.check("buy now", 10_000), None);
+    /// assert_eq!(detector.check("buy now", 20_000), Some(Reason::Repeats));
+    ///
+    /// let mut detector = Detector::default();
+    ///
+    /// assert_eq!(detector.check("WHY IS NOBODY HERE", 0), None);
+    /// assert_eq!(detector.check("HELLO, ANYONE THERE?", 10_000), None);
+    /// assert_eq!(detector.check("ANSWER ME PLEASE", 20_000), Some(Reason::Shouting));
+    /// ```
+    pub fn check(&mut self, body: &str, now: isize) -> Option<Reason> {
+        self.recent.push_back((now, body.to_owned()));
+
+        let keep = MAX_REPEATS.max(MAX_SHOUTS).max(BURST_COUNT);
+        if self.recent.len() > keep {
+            self.recent.pop_front();
+        }
+
+        let latest = |n: usize| self.recent.iter().rev().take(n);
+
+        if self.recent.len() >= MAX_REPEATS && latest(MAX_REPEATS).all(|(_, b)| b == body) {
+            return self.flag(Reason::Repeats);
+        }
+
+        if self.recent.len() >= MAX_SHOUTS && latest(MAX_SHOUTS).all(|(_, b)| is_shouting(b)) {
+            return self.flag(Reason::Shouting);
+        }
+
+        if self.recent.len() >= BURST_COUNT
+            && latest(BURST_COUNT).all(|(at, _)| now - at < BURST_WINDOW_MS)
+        {
+            return self.flag(Reason::Burst);
+        }
+
+        None
+    }
+
+    // Starts over, so they aren't muted again for what they sent before
+    fn flag(&mut self, reason: Reason) -> Option<Reason> {
+        self.recent.clear();
+
+        Some(reason)
+    }
+}
+
+// Mostly capitals, ignoring anything that isn't a letter
+fn is_shouting(body: &str) -> bool {
+    let letters = body.chars().filter(|c| c.is_alphabetic());
+    let (upper, total) = letters.fold((0, 0), |(upper, total), c| {
+        (upper + c.is_uppercase() as usize, total + 1)
+    });
+
+    total >= MIN_SHOUT_LETTERS && upper * 5 >= total * 4
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use redis::Script;
+
+use crate::pool::Pool;
+
+#[derive(Debug)]
+pub enum StatsError {
+    FailedToSave,
+    FailedToFetch,
+}
+
+impl std::fmt::Display for StatsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatsError::FailedToSave => writeln!(f, "Error: Failed to save room stats"),
+            StatsError::FailedToFetch => writeln!(f, "Error: Failed to fetch room stats"),
+        }
+    }
+}
+
+impl std::error::Error for StatsError {}
+
+// Counted from when the room was first used with stats, not from the start
+// of its history
+#[derive(Debug)]
+pub struct RoomStats {
+    // Chat messages sent
+    pub messages: u64,
+    // Everyone who's sent at least one
+    pub participants: u64,
+    // Most people in the room at once on one server
+    pub peak_members: u64,
+}
+
+// Counts a chat message, and its sender as a participant
+pub async fn record_message(redis: &Pool, room: &str, username: &str) -> Result<(), StatsError> {
+    let mut conn = redis.get();
+
+    redis::pipe()
+        .hincr(gen_key(room), "messages", 1)
+        .ignore()
+        .hincr(gen_participants_key(room), username, 1)
+        .ignore()
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            StatsError::FailedToSave
+        })
+}
+
+// Raises the room's peak if `members` beats it. Spawned, so a broker never
+// waits on Redis to let the next person in.
+pub fn record_members(redis: &Pool, room: &str, members: usize) {
+    let redis = redis.clone();
+    let key = gen_key(room);
+
+    tokio::spawn(async move {
+        if let Err(e) = save_peak(&redis, key, members).await {
+            eprintln!("{}", e);
+        }
+    });
+}
+
+async fn save_peak(redis: &Pool, key: String, members: usize) -> Result<(), StatsError> {
+    let script = Script::new(
+        r"
+        local peak = tonumber(redis.call('HGET', KEYS[1], 'peak') or '0')
+        if tonumber(ARGV[1]) > peak then
+            redis.call('HSET', KEYS[1], 'peak', ARGV[1])
+        end
+        ",
+    );
+
+    script
+        .key(key)
+        .arg(members)
+        .invoke_async(&mut redis.get())
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            StatsError::FailedToSave
+        })
+}
+
+pub async fn get(redis: &Pool, room: &str) -> Result<RoomStats, StatsError> {
+    let mut conn = redis.get();
+
+    let (counters, participants): (HashMap<String, u64>, u64) = redis::pipe()
+        .hgetall(gen_key(room))
+        .hlen(gen_participants_key(room))
+        .query_async(&mut conn)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            StatsError::FailedToFetch
+        })?;
+
+    Ok(RoomStats {
+        messages: counters.get("messages").copied().unwrap_or_default(),
+        participants,
+        peak_members: counters.get("peak").copied().unwrap_or_default(),
+    })
+}
+
+// <Counter, value>, alongside the room's `meta:<room>`
+pub(crate) fn gen_key(room: &str) -> String {
+    format!("stats:{}", room)
+}
+
+// <Username, messages they've sent>, so each person only counts once
+pub(crate) fn gen_participants_key(room: &str) -> String {
+    format!("participants:{}", room)
+}
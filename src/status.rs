@@ -0,0 +1,91 @@
+use std::str::FromStr;
+
+// What someone has said they're up to with >status, shown next to their
+// name in >who. Saved with their account like their other settings, as its
+// Display form.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Away(Option<String>),
+    // Do not disturb, which also keeps their bell from ringing
+    Dnd(Option<String>),
+}
+
+impl Status {
+    pub fn message(&self) -> Option<&str> {
+        match self {
+            Status::Away(message) | Status::Dnd(message) => message.as_deref(),
+        }
+    }
+
+    pub fn is_dnd(&self) -> bool {
+        matches!(self, Status::Dnd(_))
+    }
+
+    // How >who shows it
+    ///
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use chatsapp::status::Status;
+    ///
+    /// assert_eq!(Status::Away(Some("lunch".into())).describe(), "away: lunch");
+    /// assert_eq!(Status::Dnd(None).describe(), "do not disturb");
+    /// ```
+    pub fn describe(&self) -> String {
+        let name = match self {
+            Status::Away(_) => "away",
+            Status::Dnd(_) => "do not disturb",
+        };
+
+        match self.message() {
+            Some(message) => format!("{}: {}", name, message),
+            None => name.to_owned(),
+        }
+    }
+}
+
+// As it's saved, like `away lunch`
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Status::Away(_) => "away",
+            Status::Dnd(_) => "dnd",
+        };
+
+        match self.message() {
+            Some(message) => write!(f, "{} {}", name, message),
+            None => write!(f, "{}", name),
+        }
+    }
+}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::status::Status;
+///
+/// let status = Status::Dnd(Some("in a meeting".into()));
+///
+/// assert_eq!(status.to_string().parse(), Ok(status));
+/// assert_eq!("away".parse(), Ok(Status::Away(None)));
+/// assert!("busy".parse::<Status>().is_err());
+/// ```
+impl FromStr for Status {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, message) = match s.split_once(' ') {
+            Some((name, message)) => (name, Some(message.to_owned())),
+            None => (s, None),
+        };
+
+        match name {
+            "away" => Ok(Status::Away(message)),
+            "dnd" => Ok(Status::Dnd(message)),
+            _ => Err(()),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use redis::Client as RedisClient;
+use tokio::io::{
+    self, AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, Lines, ReadHalf, WriteHalf,
+};
+
+use crate::app::{App, Shared};
+use crate::broker::{self, RoomMap};
+use crate::config::{Config, LiveConfig};
+use crate::connections;
+use crate::dm;
+use crate::filter::Filters;
+use crate::lang::DirectionMarks;
+use crate::metrics::Metrics;
+use crate::plugin::Plugins;
+use crate::pool::Pool;
+use crate::retention;
+
+// How long a client waits for a line before deciding nothing's coming
+const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Bytes either side can write before the other reads
+const PIPE_SIZE: usize = 64 * 1024;
+
+// Runs the server in-process for end-to-end tests, with clients connected
+// over in-memory pipes instead of sockets. Rooms and accounts still live in
+// Redis, at the config's `redis_url`, so tests should use names of their own.
+pub struct TestServer {
+    shared: Shared,
+    rooms: RoomMap,
+}
+
+impl TestServer {
+    // None if Redis can't be reached, so tests can be skipped without it
+    pub async fn start(config: Config) -> Option<Self> {
+        let client = RedisClient::open(config.redis_url.as_str()).ok()?;
+        let pool = Pool::new(client, config.outbox_capacity);
+        let redis = match tokio::time::timeout(RECV_TIMEOUT, pool).await {
+            Ok(Ok(redis)) => redis.with_webhook_allowlist(config.webhook_allowlist.clone()),
+            _ => return None,
+        };
+
+        let rooms = broker::bootstrap_rooms(&redis, None).await.ok()?;
+
+        let plugins = match &config.plugin_dir {
+            Some(dir) => Plugins::load(dir).unwrap(),
+            None => Plugins::default(),
+        };
+        let mut filters = Filters::new();
+        filters.add(plugins.clone());
+        filters.add_hook(DirectionMarks);
+
+        let shared = Shared {
+            redis,
+            users: dm::new_user_map(),
+            filters: Arc::new(filters),
+            config: Arc::new(LiveConfig::new(config)),
+            metrics: Arc::new(Metrics::new()),
+            conns: connections::new_connection_map(),
+            plugins,
+        };
+
+        Some(Self { shared, rooms })
+    }
+
+    // For setting up what there's no command for, like global roles
+    pub fn redis(&self) -> &Pool {
+        &self.shared.redis
+    }
+
+    // Applies `config` as a reload would, to every connection
+    pub fn reload(&self, config: Config) {
+        let running = self.shared.config.get();
+        self.shared.config.set(running.with_reloadable(config));
+    }
+
+    // Trims rooms and removes messages that have disappeared, as the
+    // retention task does every `retention_interval_secs`
+    pub async fn trim(&self) {
+        let config = self.shared.config.get();
+
+        retention::trim_all(&self.shared.redis, &config, &self.rooms)
+            .await
+            .unwrap();
+    }
+
+    // A new connection, as if someone had just connected with `nc`
+    pub fn connect(&self) -> TestClient {
+        let (client, server) = io::duplex(PIPE_SIZE);
+        let (server_reader, server_writer) = io::split(server);
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let app = App::from_halves(
+            Box::new(server_reader),
+            Box::new(server_writer),
+            addr,
+            self.shared.clone(),
+        );
+
+        let rooms = Arc::clone(&self.rooms);
+        tokio::spawn(async move {
+            if let Err(e) = app.run(rooms).await {
+                eprintln!("{}", e);
+            }
+        });
+
+        let (reader, writer) = io::split(client);
+
+        TestClient {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        }
+    }
+}
+
+// The other end of a connection, sending commands and reading back what a
+// text client would see
+pub struct TestClient {
+    lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+    writer: WriteHalf<DuplexStream>,
+}
+
+impl TestClient {
+    pub async fn send(&mut self, line: &str) -> io::Result<()> {
+        self.writer
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+    }
+
+    // For lines that aren't valid UTF-8, newline included
+    pub async fn send_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes).await
+    }
+
+    // None once the server hangs up or goes quiet for `RECV_TIMEOUT`
+    pub async fn recv(&mut self) -> Option<String> {
+        match tokio::time::timeout(RECV_TIMEOUT, self.lines.next_line()).await {
+            Ok(Ok(line)) => line,
+            _ => None,
+        }
+    }
+
+    // Reads until a line containing `text`, panicking with everything read
+    // on the way if it never comes
+    pub async fn expect(&mut self, text: &str) -> String {
+        let mut seen = Vec::new();
+
+        while let Some(line) = self.recv().await {
+            if line.contains(text) {
+                return line;
+            }
+            seen.push(line);
+        }
+
+        panic!("Expected a line containing {:?}, got {:?}", text, seen);
+    }
+
+    // Reads until the server goes quiet, panicking if any line contains `text`
+    pub async fn expect_none(&mut self, text: &str) {
+        while let Some(line) = self.recv().await {
+            assert!(!line.contains(text), "Didn't expect {:?}", line);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use base64ct::{Base64, Encoding};
+
+use crate::command::Command;
+use crate::webhook::WebhookCommand;
+
+// Longest chat message, DM, topic or edit, in characters
+pub const MAX_MESSAGE_LEN: usize = 2000;
+
+pub const MAX_USERNAME_LEN: usize = 32;
+
+pub const MAX_ROOM_LEN: usize = 64;
+
+// Long enough for emoji built from several code points, like flags
+pub const MAX_REACTION_LEN: usize = 8;
+
+// Long enough for any tag in common use, like `zh-Hant-HK`
+pub const MAX_LANG_LEN: usize = 35;
+
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    MessageTooLong,
+    UsernameTooLong,
+    UsernameInvalid,
+    RoomNameTooLong,
+    RoomNameInvalid,
+    CommandNameInvalid,
+    ReactionInvalid,
+    LangInvalid,
+    NotCiphertext,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::MessageTooLong => writeln!(
+                f,
+                "Error: Messages can't be longer than {} characters, try >paste",
+                MAX_MESSAGE_LEN
+            ),
+            ValidationError::UsernameTooLong => writeln!(
+                f,
+                "Error: Usernames can't be longer than {} characters",
+                MAX_USERNAME_LEN
+            ),
+            ValidationError::UsernameInvalid => writeln!(
+                f,
+                "Error: Usernames can only contain letters, numbers, _ and -"
+            ),
+            ValidationError::RoomNameTooLong => writeln!(
+                f,
+                "Error: Room names can't be longer than {} characters",
+                MAX_ROOM_LEN
+            ),
+            ValidationError::RoomNameInvalid => writeln!(
+                f,
+                "Error: Room names can only contain letters, numbers, _, - and ., with / between namespaces"
+            ),
+            ValidationError::CommandNameInvalid => writeln!(
+                f,
+                "Error: Bot commands are one word of up to {} letters, numbers, _ or -",
+                MAX_USERNAME_LEN
+            ),
+            ValidationError::ReactionInvalid => writeln!(
+                f,
+                "Error: Reactions are a single emoji or word of up to {} characters",
+                MAX_REACTION_LEN
+            ),
+            ValidationError::LangInvalid => writeln!(
+                f,
+                "Error: Languages are tags like en, pt-BR or sr-Latn"
+            ),
+            ValidationError::NotCiphertext => writeln!(
+                f,
+                "Error: This room is encrypted, send base64 ciphertext from an encrypting client"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+// Checks the user supplied parts of a command before it's handled
+pub fn command(command: &Command) -> Result<(), ValidationError> {
+    match command {
+        // Only names being created are checked, so accounts and rooms made
+        // before these rules can still be used
+        Command::SetUsername(name)
+        | Command::Register(name, _)
+        | Command::Webhook(WebhookCommand::Bot(name)) => username(name),
+        Command::CreateRoom(room, _, _)
+        | Command::RenameRoom(room)
+        | Command::List(Some(room), _, _, _) => room_name(room),
+        Command::Namespace(namespace, _) => namespace_name(namespace),
+        Command::Claim(Some(name)) => command_name(name),
+        Command::React(_, emoji) => reaction(emoji),
+        Command::Lang(Some(tag)) if tag != "off" => lang(tag),
+        Command::Message(text)
+        | Command::Topic(text)
+        | Command::Reply(_, text)
+        | Command::Signed(_, _, text)
+        | Command::Edit(_, text)
+        | Command::DirectMessage(_, text)
+        | Command::Whisper(_, text)
+        | Command::Announce(text)
+        | Command::Broadcast(text)
+        | Command::Report(_, Some(text))
+        | Command::SetMotd(Some(text)) => message(text),
+        Command::SetStatus(Some(status)) => status.message().map_or(Ok(()), message),
+        Command::KeyExchange(_, key) => message(key).and_then(|()| ciphertext(key)),
+        _ => Ok(()),
+    }
+}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::validate::{self, ValidationError};
+///
+/// assert_eq!(validate::username("alice_99"), Ok(()));
+/// assert_eq!(validate::username("al:ce"), Err(ValidationError::UsernameInvalid));
+/// assert_eq!(validate::username(&"a".repeat(33)), Err(ValidationError::UsernameTooLong));
+/// ```
+pub fn username(name: &str) -> Result<(), ValidationError> {
+    if name.chars().count() > MAX_USERNAME_LEN {
+        return Err(ValidationError::UsernameTooLong);
+    }
+
+    // Same characters @mentions stop at, so every name can be mentioned
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(ValidationError::UsernameInvalid);
+    }
+
+    Ok(())
+}
+
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::validate::{self, ValidationError};
+///
+/// assert_eq!(validate::room_name("rust-lang.beginners"), Ok(()));
+/// assert_eq!(validate::room_name("dev/rust"), Ok(()));
+/// assert_eq!(validate::room_name("room*"), Err(ValidationError::RoomNameInvalid));
+/// assert_eq!(validate::room_name("a:b"), Err(ValidationError::RoomNameInvalid));
+/// assert_eq!(validate::room_name("dev//rust"), Err(ValidationError::RoomNameInvalid));
+/// assert_eq!(validate::room_name("dev/"), Err(ValidationError::RoomNameInvalid));
+/// ```
+pub fn room_name(room: &str) -> Result<(), ValidationError> {
+    if room.chars().count() > MAX_ROOM_LEN {
+        return Err(ValidationError::RoomNameTooLong);
+    }
+
+    // `/` only separates namespaces, and can't be part of one
+    if !room.split('/').all(|part| namespace_name(part).is_ok()) {
+        return Err(ValidationError::RoomNameInvalid);
+    }
+
+    Ok(())
+}
+
+// A namespace, or one part of a room name. Room names end up in Redis keys
+// and patterns, so `*`, `?`, `[` and `:` in particular have to be kept out.
+pub fn namespace_name(name: &str) -> Result<(), ValidationError> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.');
+    if name.is_empty() || !name.chars().all(allowed) {
+        return Err(ValidationError::RoomNameInvalid);
+    }
+
+    Ok(())
+}
+
+// A command a bot claims, without the `>`. Usernames' rules keep it to one
+// word that can't be mistaken for anything else on the line.
+pub fn command_name(name: &str) -> Result<(), ValidationError> {
+    username(name).map_err(|_| ValidationError::CommandNameInvalid)
+}
+
+// Reactions are stored space separated, and shown in a line under messages
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::validate::{self, ValidationError};
+///
+/// assert_eq!(validate::reaction("👍"), Ok(()));
+/// assert_eq!(validate::reaction("🇳🇿"), Ok(()));
+/// assert_eq!(validate::reaction("+1 me"), Err(ValidationError::ReactionInvalid));
+/// assert_eq!(validate::reaction("\x1b[2J"), Err(ValidationError::ReactionInvalid));
+/// ```
+pub fn reaction(emoji: &str) -> Result<(), ValidationError> {
+    let count = emoji.chars().count();
+    let allowed = |c: char| !c.is_whitespace() && !c.is_control();
+
+    if count == 0 || count > MAX_REACTION_LEN || !emoji.chars().all(allowed) {
+        return Err(ValidationError::ReactionInvalid);
+    }
+
+    Ok(())
+}
+
+// A BCP 47 language tag, checked for its shape rather than against the
+// registry: a language of 2 to 8 letters, then subtags of up to 8 letters
+// or digits like a script or region
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::validate::{self, ValidationError};
+///
+/// assert_eq!(validate::lang("ar"), Ok(()));
+/// assert_eq!(validate::lang("sr-Latn-RS"), Ok(()));
+/// assert_eq!(validate::lang("es-419"), Ok(()));
+/// assert_eq!(validate::lang("e"), Err(ValidationError::LangInvalid));
+/// assert_eq!(validate::lang("en--us"), Err(ValidationError::LangInvalid));
+/// assert_eq!(validate::lang("en_US"), Err(ValidationError::LangInvalid));
+/// ```
+pub fn lang(tag: &str) -> Result<(), ValidationError> {
+    let mut subtags = tag.split('-');
+    let language = subtags.next().unwrap_or_default();
+
+    let valid = (2..=8).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+
+    if tag.len() > MAX_LANG_LEN || !valid {
+        return Err(ValidationError::LangInvalid);
+    }
+
+    Ok(())
+}
+
+pub fn message(text: &str) -> Result<(), ValidationError> {
+    if text.chars().count() > MAX_MESSAGE_LEN {
+        return Err(ValidationError::MessageTooLong);
+    }
+
+    Ok(())
+}
+
+// Encrypted rooms only take padded base64, which the server stores and
+// relays without being able to read. Newlines in what was encrypted can't
+// break up the line either.
+///
+///
+/// # Examples
+///
+/// ```
+/// use chatsapp::validate::{self, ValidationError};
+///
+/// assert_eq!(validate::ciphertext("c2VjcmV0IQ=="), Ok(()));
+/// assert_eq!(validate::ciphertext("hello there"), Err(ValidationError::NotCiphertext));
+/// assert_eq!(validate::ciphertext(""), Err(ValidationError::NotCiphertext));
+/// ```
+pub fn ciphertext(text: &str) -> Result<(), ValidationError> {
+    match Base64::decode_vec(text) {
+        Ok(bytes) if !bytes.is_empty() => Ok(()),
+        _ => Err(ValidationError::NotCiphertext),
+    }
+}
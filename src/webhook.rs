@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use redis::AsyncCommands;
+
+use crate::http;
+use crate::message::{Message, Protocol};
+use crate::pool::Pool;
+use crate::session;
+
+// Most outgoing webhooks a room can have
+pub const MAX_URLS: usize = 5;
+
+#[derive(Debug)]
+pub enum WebhookError {
+    FailedToSave,
+    FailedToFetch,
+    InvalidUrl,
+    UnknownHost,
+    // Resolves to an internal address that isn't allowlisted
+    Internal,
+    TooMany,
+    NotFound,
+}
+
+impl std::fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebhookError::FailedToSave => writeln!(f, "Error: Failed to save webhook"),
+            WebhookError::FailedToFetch => writeln!(f, "Error: Failed to fetch webhooks"),
+            WebhookError::InvalidUrl => {
+                writeln!(
+                    f,
+                    "Error: Webhooks need an http:// url, https isn't supported"
+                )
+            }
+            WebhookError::UnknownHost => writeln!(f, "Error: Couldn't find the webhook's host"),
+            WebhookError::Internal => {
+                writeln!(f, "Error: Webhooks can't be sent to internal addresses")
+            }
+            WebhookError::TooMany => {
+                writeln!(f, "Error: Rooms can't have more than {} webhooks", MAX_URLS)
+            }
+            WebhookError::NotFound => writeln!(f, "Error: No webhook or bot by that name"),
+        }
+    }
+}
+
+impl std::error::Error for WebhookError {}
+
+#[derive(Debug, PartialEq)]
+pub enum WebhookCommand {
+    Add(String),
+    Remove(String),
+    List,
+    // Creates a token external services can post as this bot with
+    Bot(String),
+    RemoveBot(String),
+}
+
+pub async fn add(redis: &Pool, room: &str, url: &str) -> Result<(), WebhookError> {
+    let host = match http::parse_url(url) {
+        Some((host, _)) => host,
+        None => Err(WebhookError::InvalidUrl)?,
+    };
+
+    match http::resolve_external(&host, redis.webhook_allowlist()).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Err(WebhookError::Internal)?,
+        Err(_) => Err(WebhookError::UnknownHost)?,
+    }
+
+    let mut conn = redis.get();
+
+    let count: usize = conn.scard(gen_urls_key(room)).await.map_err(|e| {
+        dbg!("{}", e);
+        WebhookError::FailedToFetch
+    })?;
+
+    if count >= MAX_URLS {
+        Err(WebhookError::TooMany)?;
+    }
+
+    conn.sadd::<_, _, ()>(gen_urls_key(room), url)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            WebhookError::FailedToSave
+        })?;
+
+    Ok(())
+}
+
+pub async fn remove(redis: &Pool, room: &str, url: &str) -> Result<(), WebhookError> {
+    let removed: u8 = redis
+        .get()
+        .srem(gen_urls_key(room), url)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            WebhookError::FailedToSave
+        })?;
+
+    match removed {
+        0 => Err(WebhookError::NotFound),
+        _ => Ok(()),
+    }
+}
+
+pub async fn urls(redis: &Pool, room: &str) -> Result<Vec<String>, WebhookError> {
+    redis.get().smembers(gen_urls_key(room)).await.map_err(|e| {
+        dbg!("{}", e);
+        WebhookError::FailedToFetch
+    })
+}
+
+// <Token, Bot name>
+pub async fn bots(redis: &Pool, room: &str) -> Result<HashMap<String, String>, WebhookError> {
+    redis.get().hgetall(gen_bots_key(room)).await.map_err(|e| {
+        dbg!("{}", e);
+        WebhookError::FailedToFetch
+    })
+}
+
+// Returns the token the bot posts with. Adding a bot that exists gives it
+// another token.
+pub async fn add_bot(redis: &Pool, room: &str, name: &str) -> Result<String, WebhookError> {
+    let token = session::gen_token();
+
+    redis
+        .get()
+        .hset::<_, _, _, ()>(gen_bots_key(room), &token, name)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            WebhookError::FailedToSave
+        })?;
+
+    Ok(token)
+}
+
+// Revokes every token for the bot
+pub async fn remove_bot(redis: &Pool, room: &str, name: &str) -> Result<(), WebhookError> {
+    let tokens: Vec<String> = bots(redis, room)
+        .await?
+        .into_iter()
+        .filter(|(_, bot)| bot == name)
+        .map(|(token, _)| token)
+        .collect();
+
+    if tokens.is_empty() {
+        Err(WebhookError::NotFound)?;
+    }
+
+    redis
+        .get()
+        .hdel::<_, _, ()>(gen_bots_key(room), tokens)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            WebhookError::FailedToSave
+        })?;
+
+    Ok(())
+}
+
+// The bot a token belongs to, if any
+pub async fn bot(redis: &Pool, room: &str, token: &str) -> Result<Option<String>, WebhookError> {
+    redis
+        .get()
+        .hget(gen_bots_key(room), token)
+        .await
+        .map_err(|e| {
+            dbg!("{}", e);
+            WebhookError::FailedToFetch
+        })
+}
+
+// Bots post under `name[bot]`, which no user can be called since usernames
+// can't contain brackets
+pub fn bot_username(name: &str) -> String {
+    format!("{}[bot]", name)
+}
+
+// POSTs the message to each of the room's webhooks in the background, in the
+// same JSON as the JSON protocol. Failures are only logged.
+pub fn notify(redis: &Pool, room: &str, msg: &Message) {
+    let redis = redis.clone();
+    let room = room.to_owned();
+    let body = msg.render(Protocol::Json);
+
+    tokio::spawn(async move {
+        let urls = match urls(&redis, &room).await {
+            Ok(urls) => urls,
+            Err(e) => return eprintln!("{}", e),
+        };
+
+        for url in urls {
+            let body = body.clone();
+            let redis = redis.clone();
+
+            tokio::spawn(async move {
+                match http::post_json(&url, &body, redis.webhook_allowlist()).await {
+                    Ok(status) if (200..300).contains(&status) => {}
+                    Ok(status) => eprintln!("Webhook {} returned {}", url, status),
+                    Err(e) => eprintln!("Webhook {} failed: {}", url, e),
+                }
+            });
+        }
+    });
+}
+
+pub(crate) fn gen_urls_key(room: &str) -> String {
+    format!("webhooks:{}", room)
+}
+
+pub(crate) fn gen_bots_key(room: &str) -> String {
+    format!("bots:{}", room)
+}
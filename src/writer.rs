@@ -0,0 +1,402 @@
+use std::time::Duration;
+
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::color;
+use crate::compress::Deflater;
+use crate::message::{self, Message, MessageKind, Protocol};
+
+const BEL: char = '\x07';
+
+// Requests a connection can have waiting before whoever's sending one waits
+// for the writer to catch up
+const QUEUE_SIZE: usize = 256;
+
+// Most requests taken off the queue at once. Messages queued one after
+// another go out in one write.
+const MAX_BATCH: usize = 64;
+
+// How long writing one frame can take before the connection is treated as
+// gone, so a socket nobody reads from can't hold up the rooms sending to it
+// forever
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where a connection's output goes, a socket or, in tests, an in-memory pipe
+pub type WriteStream = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+#[derive(Debug)]
+enum Request {
+    Write(Vec<Message>),
+    Protocol(Protocol),
+    ActiveRoom(Option<String>),
+    Timestamps(bool),
+    Timezone(i32),
+    Ids(bool),
+    Color(bool),
+    Bell(bool),
+    Dnd(bool),
+    Compression(bool),
+    // Answered once everything queued before it has been written
+    Flush(oneshot::Sender<()>),
+}
+
+// How everything else writes to a connection. Each connection's write half
+// is owned by one task running its `Writer`, and the connection itself and
+// the brokers of the rooms it's in queue messages and setting changes for it
+// here, so nothing waits on anyone else's write and whole messages go out in
+// the order they were queued. Clones all feed the same task, which ends once
+// they're all dropped or a write fails, after which sending returns an error.
+#[derive(Debug, Clone)]
+pub struct WriterHandle {
+    tx: mpsc::Sender<Request>,
+}
+
+impl WriterHandle {
+    // Starts the task that owns `stream`, which writes at most
+    // `max_write_bytes` at a time
+    pub fn spawn(stream: WriteStream, max_write_bytes: usize) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(Writer::new(stream, max_write_bytes).run(rx));
+
+        Self { tx }
+    }
+
+    pub async fn write_message(&self, msg: Message) -> io::Result<()> {
+        self.write_messages(vec![msg]).await
+    }
+
+    pub async fn write_messages(&self, msgs: Vec<Message>) -> io::Result<()> {
+        self.send(Request::Write(msgs)).await
+    }
+
+    pub async fn set_protocol(&self, protocol: Protocol) -> io::Result<()> {
+        self.send(Request::Protocol(protocol)).await
+    }
+
+    pub async fn set_active_room(&self, room: Option<String>) -> io::Result<()> {
+        self.send(Request::ActiveRoom(room)).await
+    }
+
+    pub async fn set_timestamps(&self, on: bool) -> io::Result<()> {
+        self.send(Request::Timestamps(on)).await
+    }
+
+    pub async fn set_timezone(&self, offset_mins: i32) -> io::Result<()> {
+        self.send(Request::Timezone(offset_mins)).await
+    }
+
+    pub async fn set_ids(&self, on: bool) -> io::Result<()> {
+        self.send(Request::Ids(on)).await
+    }
+
+    pub async fn set_color(&self, on: bool) -> io::Result<()> {
+        self.send(Request::Color(on)).await
+    }
+
+    pub async fn set_bell(&self, on: bool) -> io::Result<()> {
+        self.send(Request::Bell(on)).await
+    }
+
+    // Keeps the bell quiet while they've asked not to be disturbed, without
+    // changing whether it's on
+    pub async fn set_dnd(&self, on: bool) -> io::Result<()> {
+        self.send(Request::Dnd(on)).await
+    }
+
+    // Turning it off ends the zlib stream, so the client sees where plain
+    // text starts again
+    pub async fn set_compression(&self, on: bool) -> io::Result<()> {
+        self.send(Request::Compression(on)).await
+    }
+
+    // Waits for everything queued so far to be written
+    pub async fn flush(&self) -> io::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::Flush(tx)).await?;
+
+        rx.await.map_err(|_| closed())
+    }
+
+    // Whether both feed the same connection
+    pub fn same(&self, other: &WriterHandle) -> bool {
+        self.tx.same_channel(&other.tx)
+    }
+
+    async fn send(&self, request: Request) -> io::Result<()> {
+        self.tx.send(request).await.map_err(|_| closed())
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed")
+}
+
+// Write half of a connection, which knows how that client wants messages rendered
+struct Writer {
+    stream: WriteStream,
+    // Rendered lines are written and flushed in frames of at most this many
+    // bytes, before rendering any more. A line longer than it goes out on
+    // its own, since lines are never split.
+    max_frame: usize,
+    protocol: Protocol,
+    // Text clients get messages from other rooms prefixed with `[room]`
+    active_room: Option<String>,
+    // Prefix text messages with `[HH:MM]` in the client's timezone
+    timestamps: bool,
+    tz_offset_mins: i32,
+    // Prefix text messages with their id, for >edit and >delete
+    ids: bool,
+    // ANSI colors for text clients, otherwise escapes are stripped
+    color: bool,
+    // Ring the terminal's bell for mentions and DMs
+    bell: bool,
+    // Their status is do not disturb, so the bell doesn't ring either way
+    dnd: bool,
+    // Set once a JSON client asks for zlib, everything after is deflated
+    deflater: Option<Deflater>,
+}
+
+impl Writer {
+    fn new(stream: WriteStream, max_frame: usize) -> Self {
+        Self {
+            stream,
+            max_frame: max_frame.max(1),
+            protocol: Protocol::Text,
+            active_room: None,
+            timestamps: false,
+            tz_offset_mins: 0,
+            ids: false,
+            color: false,
+            bell: false,
+            dnd: false,
+            deflater: None,
+        }
+    }
+
+    // Handles requests until every handle is dropped or a write fails or
+    // times out, then drops the stream, which closes a socket's write half
+    async fn run(mut self, mut requests: mpsc::Receiver<Request>) {
+        let mut batch = Vec::with_capacity(MAX_BATCH);
+        let mut pending = Vec::new();
+
+        while requests.recv_many(&mut batch, MAX_BATCH).await > 0 {
+            for request in batch.drain(..) {
+                if let Request::Write(msgs) = request {
+                    pending.extend(msgs);
+                    continue;
+                }
+
+                // Written first, since settings only apply to what comes after
+                let res = match self.write_pending(&mut pending).await {
+                    Ok(()) => self.apply(request).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    eprintln!("{}", e);
+                    return;
+                }
+            }
+
+            if let Err(e) = self.write_pending(&mut pending).await {
+                eprintln!("{}", e);
+                return;
+            }
+        }
+    }
+
+    async fn apply(&mut self, request: Request) -> io::Result<()> {
+        match request {
+            Request::Write(msgs) => return self.write_messages(&msgs).await,
+            Request::Protocol(protocol) => self.protocol = protocol,
+            Request::ActiveRoom(room) => self.active_room = room,
+            Request::Timestamps(on) => self.timestamps = on,
+            Request::Timezone(offset_mins) => self.tz_offset_mins = offset_mins,
+            Request::Ids(on) => self.ids = on,
+            Request::Color(on) => self.color = on,
+            Request::Bell(on) => self.bell = on,
+            Request::Dnd(on) => self.dnd = on,
+            Request::Compression(on) => return self.set_compression(on).await,
+            // They may have stopped waiting
+            Request::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn write_pending(&mut self, pending: &mut Vec<Message>) -> io::Result<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let res = self.write_messages(pending).await;
+        pending.clear();
+
+        res
+    }
+
+    async fn set_compression(&mut self, on: bool) -> io::Result<()> {
+        match (on, self.deflater.take()) {
+            (true, None) => self.deflater = Some(Deflater::default()),
+            (false, Some(mut deflater)) => {
+                let end = deflater.finish()?;
+                self.stream.write_all(&end).await?;
+            }
+            (_, deflater) => self.deflater = deflater,
+        }
+
+        Ok(())
+    }
+
+    // Renders them into frames of up to `max_frame` bytes, so messages go
+    // out together rather than costing a write each, but a long history
+    // replay isn't built up in memory and written all at once. Yields after
+    // each full frame, so one connection's backlog doesn't keep the other
+    // tasks on its thread waiting.
+    async fn write_messages(&mut self, msgs: &[Message]) -> io::Result<()> {
+        let mut frame = String::new();
+
+        for msg in msgs {
+            let out = match self.render(msg) {
+                Some(out) => out,
+                None => continue,
+            };
+
+            // Sent before the line would take it over, so the only frames
+            // bigger than `max_frame` are single lines that are themselves
+            if !frame.is_empty() && frame.len() + out.len() > self.max_frame {
+                self.write_frame(&frame).await?;
+                frame.clear();
+                tokio::task::yield_now().await;
+            }
+            frame.push_str(&out);
+
+            if frame.len() >= self.max_frame {
+                self.write_frame(&frame).await?;
+                frame.clear();
+                tokio::task::yield_now().await;
+            }
+        }
+
+        self.write_frame(&frame).await
+    }
+
+    async fn write_frame(&mut self, frame: &str) -> io::Result<()> {
+        if frame.is_empty() {
+            return Ok(());
+        }
+
+        let write = async {
+            match &mut self.deflater {
+                Some(deflater) => {
+                    let out = deflater.write(frame.as_bytes())?;
+                    self.stream.write_all(&out).await?;
+                }
+                None => self.stream.write_all(frame.as_bytes()).await?,
+            }
+
+            self.stream.flush().await
+        };
+
+        match tokio::time::timeout(WRITE_TIMEOUT, write).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "Write timed out")),
+        }
+    }
+
+    // None for messages this client doesn't get
+    fn render(&self, msg: &Message) -> Option<String> {
+        // Text clients already see the join or leave message, and can't take
+        // back lines they've shown
+        if self.protocol == Protocol::Text
+            && matches!(msg.kind, MessageKind::Members | MessageKind::Expire)
+        {
+            return None;
+        }
+
+        let mut out = match (self.protocol, self.color) {
+            (Protocol::Text, true) => msg.render_colored(),
+            // Users could otherwise send escapes that clear screens or set titles
+            (Protocol::Text, false) => color::strip(&msg.render(self.protocol)),
+            (Protocol::Json, _) => msg.render(self.protocol),
+        };
+
+        // Archive lines are written as they'd be saved, and renames already
+        // name the room, which may not have been renamed here yet
+        if let (Protocol::Text, Some(room)) = (self.protocol, &msg.room) {
+            if self.active_room.as_ref() != Some(room)
+                && !matches!(msg.kind, MessageKind::Export | MessageKind::RoomRename)
+            {
+                out = format!("{} {}", self.paint(color::DIM, &format!("[{}]", room)), out);
+            }
+        }
+
+        if let (Protocol::Text, true, Some(id)) = (self.protocol, self.ids, &msg.id) {
+            // Edits, deletes and reactions already show the id they're about
+            if !matches!(
+                msg.kind,
+                MessageKind::Edit | MessageKind::Delete | MessageKind::Reaction
+            ) {
+                out = format!("{} {}", self.paint(color::DIM, &format!("#{}", id)), out);
+            }
+        }
+
+        // JSON clients already get the raw timestamp
+        if self.protocol == Protocol::Text && self.timestamps && is_timestamped(msg.kind) {
+            let time = Message::format_time(msg.timestamp, self.tz_offset_mins);
+            out = format!("{} {}", self.paint(color::DIM, &format!("[{}]", time)), out);
+        }
+
+        // After the prefixes, so continuation lines line up past them too
+        if self.protocol == Protocol::Text && is_multiline(msg.kind) {
+            out = message::break_lines(&out);
+        }
+
+        // Before the newline, so clients reading lines don't see it start the next one
+        let notifies = matches!(msg.kind, MessageKind::Mention | MessageKind::Dm);
+        if self.protocol == Protocol::Text && self.bell && !self.dnd && notifies {
+            let end = out.trim_end_matches('\n').len();
+            out.insert(end, BEL);
+        }
+
+        Some(out)
+    }
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        match self.color {
+            true => color::paint(code, text),
+            false => text.to_owned(),
+        }
+    }
+}
+
+// What people write, which can have `\n` escapes in it. Our own text, like
+// help and pastes, is shown as it is.
+fn is_multiline(kind: MessageKind) -> bool {
+    matches!(
+        kind,
+        MessageKind::Chat
+            | MessageKind::Dm
+            | MessageKind::Whisper
+            | MessageKind::Mention
+            | MessageKind::Edit
+            | MessageKind::Topic
+            | MessageKind::History
+    )
+}
+
+// Server notices aren't part of the conversation, so they aren't stamped
+fn is_timestamped(kind: MessageKind) -> bool {
+    !matches!(
+        kind,
+        MessageKind::Typing
+            | MessageKind::Members
+            | MessageKind::Expire
+            | MessageKind::System
+            | MessageKind::Error
+            | MessageKind::Ack
+            | MessageKind::Export
+    )
+}
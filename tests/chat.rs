@@ -0,0 +1,1395 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64ct::{Base64, Encoding};
+use chatsapp::config::Config;
+use chatsapp::message::Message;
+use chatsapp::permissions::{self, Role};
+use chatsapp::pow::Challenge;
+use chatsapp::ratelimit::RateLimit;
+use chatsapp::testing::{TestClient, TestServer};
+use chatsapp::writer::WriterHandle;
+use chatsapp::{ed25519, session, signing};
+use tokio::io::AsyncWrite;
+
+// Rooms and accounts are kept in Redis between runs, so every test picks
+// names nobody has used
+fn unique(prefix: &str) -> String {
+    format!("{}-{}", prefix, &session::gen_token()[..8])
+}
+
+// Skips the test, rather than failing it, when there's no Redis to use
+macro_rules! server {
+    () => {
+        match TestServer::start(Config::load().unwrap()).await {
+            Some(server) => server,
+            None => {
+                eprintln!("Skipping, Redis isn't reachable");
+                return;
+            }
+        }
+    };
+}
+
+async fn register(server: &TestServer, name: &str) -> TestClient {
+    let mut client = server.connect();
+
+    client
+        .send(&format!(">register {} hunter2", name))
+        .await
+        .unwrap();
+    client.expect("Logged in").await;
+
+    client
+}
+
+#[tokio::test]
+async fn join_message_leave() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    // Commands run in order, so she's in once this is answered
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    a.expect(&format!("{} has joined the room", bob)).await;
+
+    b.send("hello").await.unwrap();
+    a.expect(&format!("{}: hello", bob)).await;
+
+    b.send(">leave").await.unwrap();
+    a.expect(&format!("{} has left the room", bob)).await;
+}
+
+#[tokio::test]
+async fn whispers_only_reach_one_member() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+    let carol = unique("carol");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    a.expect(&format!("{} has joined the room", bob)).await;
+
+    let mut c = register(&server, &carol).await;
+    c.send(&format!(">join-room {}", room)).await.unwrap();
+    a.expect(&format!("{} has joined the room", carol)).await;
+
+    a.send(&format!(">whisper {} psst", bob)).await.unwrap();
+    b.expect(&format!("[whisper] {}: psst", alice)).await;
+    c.expect_none("psst").await;
+}
+
+#[tokio::test]
+async fn guests_cant_join_rooms() {
+    let server = server!();
+
+    let mut guest = server.connect();
+    guest
+        .send(&format!(">set-username {}", unique("guest")))
+        .await
+        .unwrap();
+    guest.send(">join-room anywhere").await.unwrap();
+    guest.expect("You need to log in first").await;
+}
+
+#[tokio::test]
+async fn enveloped_messages_are_acked() {
+    let server = server!();
+    let room = unique("room");
+
+    let mut bot = register(&server, &unique("bot")).await;
+    bot.send(&format!(">create-room {}", room)).await.unwrap();
+    bot.send(&format!(">join-room {}", room)).await.unwrap();
+    bot.send(">protocol json").await.unwrap();
+
+    bot.send(r#"{"ref":"1","line":"hello"}"#).await.unwrap();
+    let ack = bot.expect(r#""type":"ack""#).await;
+    assert!(ack.contains(r#""ref":"1""#));
+    assert!(ack.contains(r#""id":"#));
+
+    bot.send(r#"{"ref":"2","line":">edit nope hi"}"#)
+        .await
+        .unwrap();
+    let error = bot.expect(r#""type":"error""#).await;
+    assert!(error.contains(r#""ref":"2""#));
+    assert!(error.contains(r#""code":"#));
+
+    bot.send(&format!(
+        r#"{{"ref":"3","line":">join-room {}"}}"#,
+        unique("nowhere")
+    ))
+    .await
+    .unwrap();
+    let error = bot.expect(r#""ref":"3""#).await;
+    assert!(error.contains(r#""code":"ROOM_NOT_FOUND""#));
+}
+
+#[tokio::test]
+async fn messages_carry_the_rooms_language() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">lang en_US").await.unwrap();
+    a.expect("Languages are tags").await;
+    a.send(">lang he").await.unwrap();
+    a.expect("Language: he").await;
+
+    let mut b = register(&server, &unique("bob")).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.expect("Language: he").await;
+    b.send(">protocol json").await.unwrap();
+
+    a.send("שלום").await.unwrap();
+    let msg = b.expect(r#""type":"chat""#).await;
+    assert!(msg.contains(r#""lang":"he""#));
+    assert!(msg.contains("\u{2067}שלום\u{2069}"));
+
+    a.send(">lang off").await.unwrap();
+    a.expect("No language set").await;
+    a.send("hello").await.unwrap();
+    let msg = b.expect("hello").await;
+    assert!(!msg.contains("lang"));
+}
+
+#[tokio::test]
+async fn reports_are_queued_for_admins() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.send("buy cheap watches").await.unwrap();
+    a.expect("buy cheap watches").await;
+
+    a.send(&format!(">report {}", alice)).await.unwrap();
+    a.expect("You can't report yourself").await;
+    a.send(&format!(">report {} spamming", bob)).await.unwrap();
+    a.expect(&format!("Reported {}", bob)).await;
+
+    a.send(">reports").await.unwrap();
+    a.expect("That needs the admin role").await;
+
+    permissions::grant(server.redis(), None, &alice, Role::Admin)
+        .await
+        .unwrap();
+    a.send(">reports").await.unwrap();
+    let line = a
+        .expect(&format!("{} reported {} in {}: spamming", alice, bob, room))
+        .await;
+    a.expect("buy cheap watches").await;
+
+    let id = line.split(' ').next().unwrap();
+    a.send(&format!(">resolve {}", id)).await.unwrap();
+    a.expect(&format!("Resolved {}'s report of {}", alice, bob))
+        .await;
+    a.send(&format!(">resolve {}", id)).await.unwrap();
+    a.expect("No open report with that id").await;
+}
+
+#[tokio::test]
+async fn disappearing_messages_are_removed() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">disappearing 1").await.unwrap();
+    a.expect("disappear after 1 second").await;
+
+    let mut b = register(&server, &unique("bob")).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.expect("disappear after 1 second").await;
+    b.send(">protocol json").await.unwrap();
+
+    a.send(">set ids on").await.unwrap();
+    a.send("gone soon").await.unwrap();
+    let sent = a.expect("Sent").await;
+    let id = sent.split(' ').next().unwrap().trim_start_matches('#');
+    b.expect("gone soon").await;
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    server.trim().await;
+
+    let expire = b.expect(r#""type":"expire""#).await;
+    assert!(expire.contains(id));
+
+    a.send(">history").await.unwrap();
+    a.expect_none("gone soon").await;
+}
+
+#[tokio::test]
+async fn moderators_can_set_topics() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    a.expect(&format!("{} has joined the room", bob)).await;
+
+    b.send(">topic mine now").await.unwrap();
+    b.expect("needs the moderator role").await;
+
+    a.send(&format!(">grant {} moderator", bob)).await.unwrap();
+    a.expect(&format!("{} is a moderator in {} now", bob, room))
+        .await;
+
+    b.send(">topic mine now").await.unwrap();
+    a.expect("mine now").await;
+}
+
+#[tokio::test]
+async fn claimed_commands_reach_the_bot() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bot = unique("bot");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    a.send(">roll 2d6").await.unwrap();
+    a.expect("Invalid command").await;
+
+    let mut b = register(&server, &bot).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    a.expect(&format!("{} has joined the room", bot)).await;
+
+    a.send(&format!(">grant {} moderator", bot)).await.unwrap();
+    a.expect("is a moderator").await;
+
+    b.send(">claim >roll").await.unwrap();
+    b.expect("You'll be sent >roll").await;
+
+    a.send(">roll 2d6").await.unwrap();
+    b.expect(&format!("[command] {}: >roll 2d6", alice)).await;
+}
+
+#[tokio::test]
+async fn reactions_are_counted_in_history() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">set ids on").await.unwrap();
+    a.send("hello").await.unwrap();
+
+    // Text clients see `#<id> Sent`
+    let sent = a.expect("Sent").await;
+    let id = sent.split(' ').next().unwrap().trim_start_matches('#');
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.send(&format!(">react {} 👍", id)).await.unwrap();
+    a.expect(&format!("{} reacted to {}: 👍 1", bob, id)).await;
+
+    b.send(">history 5").await.unwrap();
+    b.expect("  👍 1").await;
+}
+
+#[tokio::test]
+async fn escaped_newlines_break_lines() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    a.expect(&format!("{} has joined the room", bob)).await;
+
+    // Invalid UTF-8 is replaced rather than dropping the connection
+    b.send_bytes(b"one \xff\\ntwo\n").await.unwrap();
+    a.expect(&format!("{}: one \u{fffd}", bob)).await;
+    assert_eq!(
+        a.recv().await.unwrap(),
+        format!("{}two", " ".repeat(bob.len() + 2))
+    );
+}
+
+#[tokio::test]
+async fn bell_rings_for_direct_messages() {
+    let server = server!();
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(">set bell on").await.unwrap();
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">msg {} psst", alice)).await.unwrap();
+    a.expect("psst\x07").await;
+}
+
+#[tokio::test]
+async fn direct_messages_wait_for_offline_users() {
+    let server = server!();
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut b = register(&server, &bob).await;
+    b.send(">exit").await.unwrap();
+    // Closed once they're no longer online
+    while b.recv().await.is_some() {}
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">msg {} are you there?", bob))
+        .await
+        .unwrap();
+    a.expect("is offline, they'll get it when they next log in")
+        .await;
+
+    let mut b = server.connect();
+    b.send(&format!(">login {} hunter2", bob)).await.unwrap();
+    b.expect("While you were away").await;
+    b.expect(&format!("[dm] {}: are you there?", alice)).await;
+}
+
+#[tokio::test]
+async fn digest_counts_recent_messages() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">set ids on").await.unwrap();
+    a.send("one").await.unwrap();
+    a.expect("Sent").await;
+    a.send("two").await.unwrap();
+    a.expect("Sent").await;
+
+    // Open rooms can be summed up without joining them
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">digest {} 1h", room)).await.unwrap();
+    b.expect(&format!("{} in the last 1h: 2 messages", room))
+        .await;
+    b.expect(&format!("Most active: {} (2)", alice)).await;
+}
+
+#[tokio::test]
+async fn stats_count_messages_and_people() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send("one").await.unwrap();
+    a.send("two").await.unwrap();
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.send("three").await.unwrap();
+    a.expect(&format!("{}: three", bob)).await;
+    b.send(">leave").await.unwrap();
+
+    a.send(&format!(">stats {}", room)).await.unwrap();
+    a.expect(&format!(
+        "{}: 3 message(s) from 2 participant(s), at most 2 member(s) at once",
+        room
+    ))
+    .await;
+}
+
+#[tokio::test]
+async fn signed_messages_are_verified() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let secret = [42; 32];
+    let key = Base64::encode_string(&ed25519::public_key(&secret));
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">pubkey {}", key)).await.unwrap();
+    a.expect("Key registered").await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let payload = signing::payload(&room, ts as isize, "it's me");
+    let sig = Base64::encode_string(&ed25519::sign(&secret, payload.as_bytes()));
+    let signed = format!(">signed {} {} it's me", ts, sig);
+
+    a.send(&signed).await.unwrap();
+    b.expect(&format!("{}✓: it's me", alice)).await;
+
+    // The same signature can't be sent again
+    a.send(&signed).await.unwrap();
+    a.expect("already been sent").await;
+
+    // Nor used for different text
+    a.send(&format!(">signed {} {} it's not me", ts, sig))
+        .await
+        .unwrap();
+    a.expect("Signature doesn't match").await;
+}
+
+#[tokio::test]
+async fn owners_can_export_rooms() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">set ids on").await.unwrap();
+    a.send("for the record").await.unwrap();
+    a.expect("Sent").await;
+
+    a.send(&format!(">export {} text", room)).await.unwrap();
+    a.expect(&format!("] {}: for the record", alice)).await;
+    a.expect(&format!("Exported 2 messages from {}", room))
+        .await;
+
+    // Members can't
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">export {}", room)).await.unwrap();
+    b.expect("Error").await;
+}
+
+#[tokio::test]
+async fn usage_errors_name_the_argument() {
+    let server = server!();
+
+    let mut a = register(&server, &unique("alice")).await;
+    a.send(">join-room").await.unwrap();
+    a.expect("Error: >join-room needs room").await;
+    a.expect("Usage: >join-room room [password|invite]").await;
+
+    a.send(">history ten").await.unwrap();
+    a.expect("\"ten\" isn't a valid n").await;
+}
+
+#[tokio::test]
+async fn room_owners_are_limited() {
+    let config = Config {
+        max_rooms_per_user: Some(1),
+        ..Config::load().unwrap()
+    };
+    let server = match TestServer::start(config).await {
+        Some(server) => server,
+        None => return eprintln!("Skipping, Redis isn't reachable"),
+    };
+    let alice = unique("alice");
+    let bob = unique("bob");
+    let room = unique("room");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">create-room {}", unique("room")))
+        .await
+        .unwrap();
+    a.expect("You can own at most 1 rooms").await;
+
+    // Everyone has their own
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">create-room {}", unique("room")))
+        .await
+        .unwrap();
+    b.send(">list --mine").await.unwrap();
+    b.expect_none("You can own at most").await;
+
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">delete-room").await.unwrap();
+    a.send(&format!(">create-room {}", unique("room")))
+        .await
+        .unwrap();
+    a.expect_none("You can own at most").await;
+}
+
+#[tokio::test]
+async fn sharded_rooms_deliver_to_everyone() {
+    let config = Config {
+        broker_shards: 4,
+        ..Config::load().unwrap()
+    };
+    let server = match TestServer::start(config).await {
+        Some(server) => server,
+        None => return eprintln!("Skipping, Redis isn't reachable"),
+    };
+    let room = unique("room");
+    let alice = unique("alice");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    let mut others = Vec::new();
+    for _ in 0..5 {
+        let mut b = register(&server, &unique("bob")).await;
+        b.send(&format!(">join-room {}", room)).await.unwrap();
+        b.send(">who").await.unwrap();
+        b.expect(&alice).await;
+        others.push(b);
+    }
+
+    a.send("hello all").await.unwrap();
+    for b in &mut others {
+        b.expect(&format!("{}: hello all", alice)).await;
+    }
+}
+
+#[tokio::test]
+async fn plugins_answer_commands() {
+    let dir = std::env::temp_dir().join(unique("plugins"));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Answers any command it's sent with a reply
+    let wasm = wat::parse_str(
+        r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 0) "{\"reply\":\"pong\"}")
+            (func (export "alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "on_command") (param i32 i32) (result i64) i64.const 16))"#,
+    )
+    .unwrap();
+    std::fs::write(dir.join("ping.wasm"), wasm).unwrap();
+
+    let config = Config {
+        plugin_dir: Some(dir.to_string_lossy().into_owned()),
+        ..Config::load().unwrap()
+    };
+    let server = match TestServer::start(config).await {
+        Some(server) => server,
+        None => return eprintln!("Skipping, Redis isn't reachable"),
+    };
+
+    let mut a = register(&server, &unique("alice")).await;
+    a.send(">marco").await.unwrap();
+    a.expect("pong").await;
+}
+
+#[tokio::test]
+async fn history_preference_limits_replay() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+    let carol = unique("carol");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    for msg in ["one", "two", "three"] {
+        a.send(msg).await.unwrap();
+    }
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(">set history 1").await.unwrap();
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.expect(&format!("{}: three", alice)).await;
+    b.expect_none(&format!("{}: two", alice)).await;
+
+    let mut c = register(&server, &carol).await;
+    c.send(&format!(">join-room {} --no-history", room))
+        .await
+        .unwrap();
+    c.expect_none(&format!("{}: three", alice)).await;
+}
+
+#[tokio::test]
+async fn renamed_rooms_keep_their_members() {
+    let server = server!();
+    let room = unique("room");
+    let renamed = unique("renamed");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send("before").await.unwrap();
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    a.expect(&format!("{} has joined the room", bob)).await;
+
+    b.send(&format!(">rename-room {}", renamed)).await.unwrap();
+    b.expect("That needs the admin role").await;
+
+    a.send(&format!(">rename-room {}", renamed)).await.unwrap();
+    b.expect(&format!("{} renamed {} to {}", alice, room, renamed))
+        .await;
+
+    // Still in it under the new name, with its history
+    b.send("after").await.unwrap();
+    a.expect(&format!("{}: after", bob)).await;
+    b.send(">history 5").await.unwrap();
+    b.expect(&format!("{}: before", alice)).await;
+
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.expect("Room not found").await;
+}
+
+#[tokio::test]
+async fn admins_broadcast_to_every_room() {
+    let server = server!();
+    let rust = unique("rust");
+    let go = unique("go");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", rust)).await.unwrap();
+    a.send(&format!(">join-room {}", rust)).await.unwrap();
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">create-room {}", go)).await.unwrap();
+    b.send(&format!(">join-room {}", go)).await.unwrap();
+    b.send(">who").await.unwrap();
+    b.expect(&bob).await;
+
+    a.send(">broadcast maintenance at 5").await.unwrap();
+    a.expect("That needs the admin role").await;
+
+    permissions::grant(server.redis(), None, &alice, Role::Admin)
+        .await
+        .unwrap();
+    a.send(">broadcast maintenance at 5").await.unwrap();
+    let broadcast = format!("Broadcast from {}: maintenance at 5", alice);
+    b.expect(&broadcast).await;
+
+    // Saved, so it's there for anyone who comes later
+    b.send(">history 5").await.unwrap();
+    b.expect(&broadcast).await;
+}
+
+#[tokio::test]
+async fn history_can_hide_joins() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send("hello").await.unwrap();
+    a.expect("Sent").await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {} --no-history", room))
+        .await
+        .unwrap();
+    a.expect(&format!("{} has joined the room", bob)).await;
+
+    // Her message is older than his join, so it's only the one shown if
+    // joins are skipped
+    b.send(">history 1 --no-joins").await.unwrap();
+    b.expect(&format!("{}: hello", alice)).await;
+}
+
+#[tokio::test]
+async fn quiet_members_miss_joins() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(">set quiet on").await.unwrap();
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.send(">who").await.unwrap();
+    b.expect(&bob).await;
+    a.expect_none(&format!("{} has joined the room", bob)).await;
+
+    b.send("hello").await.unwrap();
+    a.expect(&format!("{}: hello", bob)).await;
+}
+
+#[tokio::test]
+async fn ping_times_each_step() {
+    let server = server!();
+    let room = unique("room");
+
+    let mut a = register(&server, &unique("alice")).await;
+    a.send(">ping").await.unwrap();
+    let pong = a.expect("Pong in").await;
+    assert!(!pong.contains("broker"));
+
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">ping").await.unwrap();
+    let pong = a.expect("Pong in").await;
+    assert!(pong.contains("redis") && pong.contains("broker") && pong.contains("write"));
+}
+
+#[tokio::test]
+async fn ownership_is_transferred_once_accepted() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    let mut b = register(&server, &bob).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+
+    b.send(&format!(">accept-ownership {}", room))
+        .await
+        .unwrap();
+    b.expect("Nobody has offered you that room").await;
+
+    a.send(&format!(">transfer-ownership {}", bob))
+        .await
+        .unwrap();
+    b.expect(&format!(">accept-ownership {}", room)).await;
+    b.send(&format!(">accept-ownership {}", room))
+        .await
+        .unwrap();
+    b.expect(&format!("You own {} now", room)).await;
+    a.expect(&format!("{} accepted {}", bob, room)).await;
+
+    // Alice is back to being a member
+    a.send(&format!(">transfer-ownership {}", bob))
+        .await
+        .unwrap();
+    a.expect("Only the room's owner can do that").await;
+}
+
+#[tokio::test]
+async fn forgotten_users_are_redacted() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.send("my number is 555").await.unwrap();
+    a.expect(&format!("{}: my number is 555", bob)).await;
+
+    permissions::grant(server.redis(), None, &alice, Role::Admin)
+        .await
+        .unwrap();
+    a.send(&format!(">forget-user {}", bob)).await.unwrap();
+    b.expect("Your account has been deleted").await;
+    a.expect(&format!("Forgot {}: 1 message(s) redacted", bob))
+        .await;
+
+    a.send(">history 5").await.unwrap();
+    a.expect(&format!("{}: [deleted]", bob)).await;
+
+    let mut b = server.connect();
+    b.send(&format!(">login {} hunter2", bob)).await.unwrap();
+    b.expect("Invalid username or password").await;
+}
+
+#[tokio::test]
+async fn motd_is_shown_on_connect() {
+    let server = server!();
+    let alice = unique("alice");
+    let motd = unique("motd");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">set-motd {}", motd)).await.unwrap();
+    a.expect("That needs the admin role or higher").await;
+
+    permissions::grant(server.redis(), None, &alice, Role::Admin)
+        .await
+        .unwrap();
+    a.send(&format!(">set-motd {}", motd)).await.unwrap();
+    a.expect("Message of the day set").await;
+
+    let mut b = server.connect();
+    b.expect(&motd).await;
+    b.expect("for a list of commands").await;
+
+    // Other tests share the Redis
+    a.send(">set-motd default").await.unwrap();
+    a.expect("Message of the day reset").await;
+}
+
+#[tokio::test]
+async fn names_ignore_case() {
+    let server = server!();
+    let room = unique("Rust");
+    let alice = unique("Alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room.to_uppercase()))
+        .await
+        .unwrap();
+    a.expect(&format!("{} has joined the room", bob)).await;
+    b.send("hi").await.unwrap();
+    a.expect(&format!("{}: hi", bob)).await;
+
+    b.send(&format!(">create-room {}", room.to_lowercase()))
+        .await
+        .unwrap();
+    b.expect("Room name taken").await;
+
+    // Nobody else can be `alice`, as a guest or an account
+    let mut c = server.connect();
+    c.send(&format!(">register {} hunter2", alice.to_lowercase()))
+        .await
+        .unwrap();
+    c.expect("Username taken").await;
+    c.send(&format!(">set-username {}", alice.to_uppercase()))
+        .await
+        .unwrap();
+    c.expect("That username belongs to an account").await;
+
+    c.send(&format!(">login {} hunter2", alice.to_lowercase()))
+        .await
+        .unwrap();
+    c.expect("Logged in").await;
+    c.send(">me").await.unwrap();
+    c.expect(&alice).await;
+}
+
+#[tokio::test]
+async fn room_list_is_paged() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(">list --mine").await.unwrap();
+    a.expect(&room).await;
+
+    a.send(">list --page 1000000").await.unwrap();
+    a.expect("No rooms on that page").await;
+    a.send(">list --page 0").await.unwrap();
+    a.expect("\"0\" isn't a valid n for >list").await;
+}
+
+#[tokio::test]
+async fn rooms_are_found_by_name_and_topic() {
+    let server = server!();
+    let room = unique("room");
+    let topic = unique("zebras");
+    let alice = unique("alice");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(&format!(">topic all about {}", topic))
+        .await
+        .unwrap();
+    a.expect("set the topic").await;
+
+    a.send(&format!(">find {}", topic)).await.unwrap();
+    a.expect(&format!("{} (1 here) - all about {}", room, topic))
+        .await;
+    a.send(&format!(">find {}", room.to_uppercase()))
+        .await
+        .unwrap();
+    a.expect(&format!("{} (1 here)", room)).await;
+
+    a.send(">delete-room").await.unwrap();
+    a.send(&format!(">find {}", topic)).await.unwrap();
+    a.expect("No rooms match that").await;
+}
+
+#[tokio::test]
+async fn reloads_reach_open_connections() {
+    let server = server!();
+    let alice = unique("alice");
+
+    let mut a = register(&server, &alice).await;
+    a.send(">me").await.unwrap();
+    a.expect(&alice).await;
+
+    server.reload(Config {
+        rate_limit: RateLimit {
+            capacity: 1.0,
+            refill_per_sec: 0.001,
+            max_warnings: 5,
+        },
+        ..Config::load().unwrap()
+    });
+
+    a.send(">me").await.unwrap();
+    a.send(">me").await.unwrap();
+    a.expect("Slow down").await;
+}
+
+#[tokio::test]
+async fn connections_answer_the_challenge_first() {
+    let config = Config {
+        pow_difficulty: 8,
+        ..Config::load().unwrap()
+    };
+    let server = match TestServer::start(config).await {
+        Some(server) => server,
+        None => return eprintln!("Skipping, Redis isn't reachable"),
+    };
+    let alice = unique("alice");
+
+    let mut client = server.connect();
+    let line = client.expect("Challenge: ").await;
+    let challenge = Challenge::parse(&line).unwrap();
+    assert_eq!(challenge.difficulty, 8);
+
+    client
+        .send(&format!(">register {} hunter2", alice))
+        .await
+        .unwrap();
+    client.expect("Answer the challenge").await;
+
+    let wrong = (0..).find(|n| !challenge.check(&n.to_string())).unwrap();
+    client.send(&format!(">pow {}", wrong)).await.unwrap();
+    client.expect("doesn't solve the challenge").await;
+
+    client
+        .send(&format!(">pow {}", challenge.solve()))
+        .await
+        .unwrap();
+    client.expect("Challenge solved").await;
+
+    client
+        .send(&format!(">register {} hunter2", alice))
+        .await
+        .unwrap();
+    client.expect("Logged in").await;
+}
+
+#[tokio::test]
+async fn hello_sets_up_the_connection() {
+    let server = server!();
+    let alice = unique("alice");
+
+    let mut client = server.connect();
+    client
+        .send(">hello version=9 caps=json,ids,holograms")
+        .await
+        .unwrap();
+    client.expect("Hello version=1 caps=json,ids").await;
+
+    client
+        .send(&format!(">register {} hunter2", alice))
+        .await
+        .unwrap();
+    client.expect(r#""type":"system""#).await;
+
+    client.send(">hello").await.unwrap();
+    client.expect(r#"Hello version=1 caps=\n"#).await;
+    client.send(">me").await.unwrap();
+    let me = client.expect("Logged in: true").await;
+    assert!(!me.starts_with('{'));
+}
+
+#[tokio::test]
+async fn dms_lists_conversations() {
+    let server = server!();
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    let mut b = register(&server, &bob).await;
+
+    b.send(">dms").await.unwrap();
+    b.expect("No conversations").await;
+
+    a.send(&format!(">msg {} hi there", bob)).await.unwrap();
+    a.send(&format!(">msg {} are you around?", bob))
+        .await
+        .unwrap();
+    b.expect("are you around?").await;
+
+    b.send(">dms").await.unwrap();
+    b.expect(&format!(
+        "{} (just now, 2 unread) {}: are you around?",
+        alice, alice
+    ))
+    .await;
+
+    b.send(&format!(">msg {} yes", alice)).await.unwrap();
+    a.expect("yes").await;
+
+    b.send(">dms").await.unwrap();
+    b.expect(&format!("{} (just now) {}: yes", alice, bob))
+        .await;
+    a.send(">dms").await.unwrap();
+    a.expect(&format!("{} (just now, 1 unread) {}: yes", bob, bob))
+        .await;
+}
+
+#[tokio::test]
+async fn private_rooms_take_join_requests() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+    let carol = unique("carol");
+
+    let mut a = register(&server, &alice).await;
+    let mut b = register(&server, &bob).await;
+    let mut c = register(&server, &carol).await;
+    a.send(&format!(">create-room {} --private", room))
+        .await
+        .unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.expect("you've asked to be let in").await;
+    a.expect(&format!("{} asked to join {}", bob, room)).await;
+    c.send(&format!(">join-room {}", room)).await.unwrap();
+    c.expect("you've asked to be let in").await;
+
+    a.send(">requests").await.unwrap();
+    a.expect(&format!("{} (just now)", bob)).await;
+
+    a.send(&format!(">deny {}", carol)).await.unwrap();
+    a.expect(&format!("Turned down {}", carol)).await;
+    c.expect(&format!("Your request to join {} was turned down", room))
+        .await;
+
+    a.send(&format!(">approve {}", bob)).await.unwrap();
+    a.expect(&format!("{} was let in by {}", bob, alice)).await;
+    b.expect(&format!("You've been let into {}", room)).await;
+    a.send(&format!(">approve {}", bob)).await.unwrap();
+    a.expect("hasn't asked to join").await;
+
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    a.expect(&format!("{} has joined", bob)).await;
+}
+
+#[tokio::test]
+async fn statuses_show_in_who() {
+    let config = Config {
+        clear_away_on_activity: true,
+        ..Config::load().unwrap()
+    };
+    let server = match TestServer::start(config).await {
+        Some(server) => server,
+        None => return eprintln!("Skipping, Redis isn't reachable"),
+    };
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">set bell on").await.unwrap();
+    a.send(">status dnd in a meeting").await.unwrap();
+    a.expect("Your status is now do not disturb: in a meeting")
+        .await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.send(">who").await.unwrap();
+    b.expect(&format!("{} (do not disturb: in a meeting)", alice))
+        .await;
+
+    b.send(&format!(">msg {} psst", alice)).await.unwrap();
+    let line = a.expect("psst").await;
+    assert!(!line.contains('\x07'));
+
+    a.send(">status away lunch").await.unwrap();
+    a.expect("Your status is now away: lunch").await;
+    a.send("back already").await.unwrap();
+    a.expect("You're back").await;
+
+    b.send(">who").await.unwrap();
+    b.expect_none("away").await;
+}
+
+#[tokio::test]
+async fn joining_creates_missing_rooms() {
+    let config = Config {
+        auto_create_rooms: true,
+        ..Config::load().unwrap()
+    };
+    let server = match TestServer::start(config).await {
+        Some(server) => server,
+        None => return eprintln!("Skipping, Redis isn't reachable"),
+    };
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    a.expect(&format!("{} has joined the room", bob)).await;
+
+    // The first to join owns it
+    a.send(">topic made on the spot").await.unwrap();
+    b.expect("made on the spot").await;
+
+    a.send(">join-room no:such:room").await.unwrap();
+    a.expect("Room names can only contain").await;
+}
+
+#[tokio::test]
+async fn history_replays_in_order_after_a_separator() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    for msg in ["one", "two", "three"] {
+        a.send(msg).await.unwrap();
+    }
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(">set history 3").await.unwrap();
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.expect("--- 3 earlier messages ---").await;
+    // Each is read after the one before
+    for msg in ["one", "two", "three"] {
+        b.expect(&format!("{}: {}", alice, msg)).await;
+    }
+}
+
+#[tokio::test]
+async fn history_can_be_filtered_by_type() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send("hello").await.unwrap();
+    a.send(">topic news only").await.unwrap();
+    a.expect("news only").await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(">set types chat").await.unwrap();
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    b.expect("--- 1 earlier message ---").await;
+    b.expect(&format!("{}: hello", alice)).await;
+
+    b.send(">history 5 --types topic").await.unwrap();
+    b.expect("news only").await;
+    b.expect_none(&format!("{}: hello", alice)).await;
+
+    b.send(">history 5 --types chats").await.unwrap();
+    b.expect("chats").await;
+}
+
+#[tokio::test]
+async fn webhooks_cant_reach_internal_addresses() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    a.send(">webhook add http://127.0.0.1:9000/hook")
+        .await
+        .unwrap();
+    a.expect("can't be sent to internal addresses").await;
+    a.send(">webhook add http://[::1]/hook").await.unwrap();
+    a.expect("can't be sent to internal addresses").await;
+}
+
+// Keeps the size of every write it's given
+#[derive(Clone, Default)]
+struct WriteSizes(Arc<Mutex<Vec<usize>>>);
+
+impl AsyncWrite for WriteSizes {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.0.lock().unwrap().push(buf.len());
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn writes_stay_under_max_write_bytes() {
+    let sizes = WriteSizes::default();
+    let writer = WriterHandle::spawn(Box::new(sizes.clone()), 100);
+
+    let line = |len: usize| Message::system(&format!("{}\n", "x".repeat(len - 1)));
+    writer
+        .write_messages(vec![line(40), line(40), line(40), line(250), line(40)])
+        .await
+        .unwrap();
+    writer.flush().await.unwrap();
+
+    // A third 40 byte line would go over, and the long one is sent alone
+    assert_eq!(*sizes.0.lock().unwrap(), vec![80, 40, 250, 40]);
+}
+
+#[tokio::test]
+async fn closing_an_old_connection_keeps_presence() {
+    let server = server!();
+    let alice = unique("alice");
+
+    let mut old = register(&server, &alice).await;
+    let mut new = server.connect();
+    new.send(&format!(">login {} hunter2", alice))
+        .await
+        .unwrap();
+    new.expect("Logged in").await;
+
+    old.send(">exit").await.unwrap();
+    while old.recv().await.is_some() {}
+
+    new.send(&format!(">presence {}", alice)).await.unwrap();
+    new.expect(&format!("{} is online", alice)).await;
+}
+
+#[tokio::test]
+async fn invites_follow_renamed_rooms() {
+    let server = server!();
+    let room = unique("room");
+    let renamed = unique("renamed");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {} --private", room))
+        .await
+        .unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+
+    a.send(&format!(">invite {}", bob)).await.unwrap();
+    let line = a.expect("they can join with").await;
+    let token = line.split(' ').next_back().unwrap().to_owned();
+
+    a.send(&format!(">rename-room {}", renamed)).await.unwrap();
+    a.expect(&format!("renamed {} to {}", room, renamed)).await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {} {}", renamed, token))
+        .await
+        .unwrap();
+    a.expect(&format!("{} has joined", bob)).await;
+}
+
+#[tokio::test]
+async fn grants_stay_below_the_granter() {
+    let server = server!();
+    let room = unique("room");
+    let alice = unique("alice");
+    let bob = unique("bob");
+
+    let mut a = register(&server, &alice).await;
+    a.send(&format!(">create-room {}", room)).await.unwrap();
+    a.send(&format!(">join-room {}", room)).await.unwrap();
+    a.send(">who").await.unwrap();
+    a.expect(&alice).await;
+
+    let mut b = register(&server, &bob).await;
+    b.send(&format!(">join-room {}", room)).await.unwrap();
+    a.expect(&format!("{} has joined the room", bob)).await;
+
+    a.send(&format!(">grant {} admin", bob)).await.unwrap();
+    a.expect("only give roles below your own").await;
+
+    a.send(&format!(">grant {} guest", alice)).await.unwrap();
+    a.expect("has the same role as you or higher").await;
+
+    a.send(&format!(">grant {} moderator", bob)).await.unwrap();
+    a.expect(&format!("{} is a moderator in {} now", bob, room))
+        .await;
+}